@@ -0,0 +1,44 @@
+/* C interface of libmethfast, built by `cargo build --release` into
+ * target/release/libmethfast.so (.dylib on macOS, methfast.dll on Windows). */
+#ifndef METHFAST_H
+#define METHFAST_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The records of one methylation file, loaded by methfast_open. */
+typedef struct methfast_ranges methfast_ranges;
+
+/* The aggregate of one region, filled in by methfast_query. */
+typedef struct {
+    uint64_t num_positions; /* records overlapping the region */
+    int64_t coverage;       /* reads summed over those records */
+    int64_t methylated;     /* methylated reads summed over those records */
+    double fraction;        /* coverage-weighted mean fraction; NaN without coverage */
+} methfast_summary;
+
+/* Loads the methylation file at path, in format (a --format name such as
+ * "bismark-cov", or NULL to detect it), keeping records with at least
+ * min_coverage reads. Returns NULL on error. */
+methfast_ranges *methfast_open(const char *path, const char *format, int32_t min_coverage);
+
+/* Aggregates the records overlapping the 0-based, half-open region
+ * [start, end) of chrom into out. Returns 0, or -1 on error. */
+int32_t methfast_query(const methfast_ranges *ranges, const char *chrom, int32_t start,
+                       int32_t end, methfast_summary *out);
+
+/* Releases the records loaded by methfast_open; NULL is ignored. */
+void methfast_free(methfast_ranges *ranges);
+
+/* The message of the last error on this thread, or NULL; valid until the
+ * next failing call on the thread. */
+const char *methfast_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
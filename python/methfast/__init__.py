@@ -0,0 +1,215 @@
+"""Aggregation of methylation records over target regions, from Python.
+
+A methylation file is loaded once and then aggregated over any number of
+target sets, each returned as a ``pyarrow.Table``::
+
+    import methfast
+
+    records = methfast.open("sample.bismark.cov.gz", format="bismark-cov", min_coverage=5)
+    table = records.aggregate("promoters.bed")
+    df = table.to_pandas()            # or polars.from_arrow(table)
+    records.aggregate([("chr1", 10000, 12000), ("chr2", 500, 900, "enhancer")])
+
+The native library is ``libmethfast`` built with the ``python`` feature. It is
+looked up in ``$METHFAST_LIBRARY``, next to this file, then on the library path.
+"""
+
+import ctypes
+import ctypes.util
+import os
+import sys
+
+import pyarrow as pa
+
+__all__ = ["MethfastError", "Records", "open"]
+
+
+class MethfastError(Exception):
+    """A failed call into the native library, with its message."""
+
+
+class _ArrowSchema(ctypes.Structure):
+    _fields_ = [
+        ("format", ctypes.c_char_p),
+        ("name", ctypes.c_char_p),
+        ("metadata", ctypes.c_char_p),
+        ("flags", ctypes.c_int64),
+        ("n_children", ctypes.c_int64),
+        ("children", ctypes.c_void_p),
+        ("dictionary", ctypes.c_void_p),
+        ("release", ctypes.c_void_p),
+        ("private_data", ctypes.c_void_p),
+    ]
+
+
+class _ArrowArray(ctypes.Structure):
+    _fields_ = [
+        ("length", ctypes.c_int64),
+        ("null_count", ctypes.c_int64),
+        ("offset", ctypes.c_int64),
+        ("n_buffers", ctypes.c_int64),
+        ("n_children", ctypes.c_int64),
+        ("buffers", ctypes.c_void_p),
+        ("children", ctypes.c_void_p),
+        ("dictionary", ctypes.c_void_p),
+        ("release", ctypes.c_void_p),
+        ("private_data", ctypes.c_void_p),
+    ]
+
+
+def _library_names():
+    if sys.platform == "win32":
+        return ["methfast.dll"]
+    if sys.platform == "darwin":
+        return ["libmethfast.dylib"]
+    return ["libmethfast.so"]
+
+
+def _load():
+    candidates = []
+    if os.environ.get("METHFAST_LIBRARY"):
+        candidates.append(os.environ["METHFAST_LIBRARY"])
+    here = os.path.dirname(os.path.abspath(__file__))
+    candidates += [os.path.join(here, name) for name in _library_names()]
+    found = ctypes.util.find_library("methfast")
+    if found:
+        candidates.append(found)
+    for candidate in candidates:
+        if os.path.isabs(candidate) and not os.path.exists(candidate):
+            continue
+        try:
+            return ctypes.CDLL(candidate)
+        except OSError:
+            continue
+    raise ImportError(
+        "libmethfast not found; build it with `cargo build --release --features python` "
+        "and set METHFAST_LIBRARY to its path"
+    )
+
+
+_lib = _load()
+_lib.methfast_open.argtypes = [ctypes.c_char_p, ctypes.c_char_p, ctypes.c_int32]
+_lib.methfast_open.restype = ctypes.c_void_p
+_lib.methfast_free.argtypes = [ctypes.c_void_p]
+_lib.methfast_free.restype = None
+_lib.methfast_last_error.argtypes = []
+_lib.methfast_last_error.restype = ctypes.c_char_p
+_lib.methfast_aggregate_file_arrow.argtypes = [
+    ctypes.c_void_p,
+    ctypes.c_char_p,
+    ctypes.POINTER(_ArrowArray),
+    ctypes.POINTER(_ArrowSchema),
+]
+_lib.methfast_aggregate_file_arrow.restype = ctypes.c_int32
+_lib.methfast_aggregate_arrow.argtypes = [
+    ctypes.c_void_p,
+    ctypes.POINTER(ctypes.c_char_p),
+    ctypes.POINTER(ctypes.c_int32),
+    ctypes.POINTER(ctypes.c_int32),
+    ctypes.POINTER(ctypes.c_char_p),
+    ctypes.c_size_t,
+    ctypes.POINTER(_ArrowArray),
+    ctypes.POINTER(_ArrowSchema),
+]
+_lib.methfast_aggregate_arrow.restype = ctypes.c_int32
+
+
+def _error():
+    message = _lib.methfast_last_error()
+    return MethfastError(message.decode() if message else "unknown error")
+
+
+def _rows(regions):
+    """The (chrom, start, end[, name]) rows of a sequence or data frame."""
+    if hasattr(regions, "itertuples"):  # pandas
+        return regions.itertuples(index=False)
+    if hasattr(regions, "iter_rows"):  # polars
+        return regions.iter_rows()
+    if isinstance(regions, (pa.Table, pa.RecordBatch)):
+        return zip(*(column.to_pylist() for column in regions.columns))
+    return regions
+
+
+class Records:
+    """The records of one methylation file, loaded by :func:`open`."""
+
+    def __init__(self, handle):
+        self._handle = handle
+
+    def aggregate(self, regions):
+        """Aggregates the records over ``regions`` into a ``pyarrow.Table``.
+
+        ``regions`` is a path to a BED or GTF/GFF3 file, or rows of
+        ``(chrom, start, end)`` or ``(chrom, start, end, name)`` with 0-based,
+        half-open coordinates: a list of tuples, or a pandas, polars or
+        pyarrow table whose first columns are those. The table has one row per
+        region, in order, with columns ``chrom``, ``start``, ``end``, ``name``,
+        ``num_positions``, ``coverage``, ``methylated`` and ``fraction``;
+        ``fraction`` is null for regions without coverage.
+        """
+        if self._handle is None:
+            raise MethfastError("Error: the records have been closed")
+        array = _ArrowArray()
+        schema = _ArrowSchema()
+        if isinstance(regions, (str, bytes, os.PathLike)):
+            path = os.fsencode(regions)
+            status = _lib.methfast_aggregate_file_arrow(
+                self._handle, path, ctypes.byref(array), ctypes.byref(schema)
+            )
+        else:
+            rows = [tuple(row) for row in _rows(regions)]
+            n = len(rows)
+            chroms = (ctypes.c_char_p * n)(*(str(row[0]).encode() for row in rows))
+            starts = (ctypes.c_int32 * n)(*(int(row[1]) for row in rows))
+            ends = (ctypes.c_int32 * n)(*(int(row[2]) for row in rows))
+            names = (ctypes.c_char_p * n)(
+                *(
+                    str(row[3]).encode() if len(row) > 3 and row[3] is not None else None
+                    for row in rows
+                )
+            )
+            status = _lib.methfast_aggregate_arrow(
+                self._handle,
+                chroms,
+                starts,
+                ends,
+                names,
+                n,
+                ctypes.byref(array),
+                ctypes.byref(schema),
+            )
+        if status != 0:
+            raise _error()
+        batch = pa.RecordBatch._import_from_c(
+            ctypes.addressof(array), ctypes.addressof(schema)
+        )
+        return pa.Table.from_batches([batch])
+
+    def close(self):
+        """Releases the records; later calls to :meth:`aggregate` fail."""
+        if self._handle is not None:
+            _lib.methfast_free(self._handle)
+            self._handle = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+
+def open(path, format=None, min_coverage=0):
+    """Loads the methylation file at ``path`` into :class:`Records`.
+
+    ``format`` is a ``--format`` name such as ``"bismark-cov"``, or ``None``
+    to detect it; records with fewer than ``min_coverage`` reads are dropped.
+    """
+    handle = _lib.methfast_open(
+        os.fsencode(path), format.encode() if format else None, int(min_coverage)
+    )
+    if not handle:
+        raise _error()
+    return Records(handle)
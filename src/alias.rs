@@ -0,0 +1,84 @@
+//! Chromosome-name aliases, so UCSC (`chr1`, `chrM`) and Ensembl (`1`, `MT`)
+//! style inputs can be overlapped.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::compression;
+
+#[derive(Debug, Default)]
+pub struct ChromAliases {
+    /// Alias to canonical name, from an alias file.
+    names: HashMap<String, String>,
+    /// Also drop the `chr` prefix and spell the mitochondrion `MT`.
+    builtin: bool,
+}
+
+impl ChromAliases {
+    /// Reads an alias file whose tab-separated lines list one canonical name
+    /// followed by its aliases, as in UCSC `chromAlias.txt`.
+    pub fn new(path: Option<&Path>, builtin: bool) -> Result<ChromAliases, Box<dyn Error>> {
+        let mut names = HashMap::new();
+        if let Some(path) = path {
+            for line in compression::open(path)?.lines() {
+                let line = line?;
+                if line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split('\t').map(str::trim).filter(|f| !f.is_empty());
+                let Some(canonical) = fields.next() else {
+                    continue;
+                };
+                for alias in fields {
+                    names.insert(alias.to_string(), canonical.to_string());
+                }
+            }
+        }
+        Ok(ChromAliases { names, builtin })
+    }
+
+    pub fn canonical<'a>(&'a self, name: &'a str) -> &'a str {
+        let name = self.names.get(name).map_or(name, String::as_str);
+        if !self.builtin {
+            return name;
+        }
+        match name.strip_prefix("chr").unwrap_or(name) {
+            "M" => "MT",
+            bare => bare,
+        }
+    }
+
+    /// Maps every name with the same canonical form as one of `target_chroms`
+    /// to that target chromosome; other names are kept as they are.
+    pub fn renamer<'a>(
+        &'a self,
+        target_chroms: impl IntoIterator<Item = &'a str>,
+    ) -> impl Fn(&str) -> String + 'a {
+        let by_canonical: HashMap<&str, &str> = target_chroms
+            .into_iter()
+            .map(|chrom| (self.canonical(chrom), chrom))
+            .collect();
+        move |name| {
+            by_canonical
+                .get(self.canonical(name))
+                .map_or(name, |chrom| chrom)
+                .to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_ensembl_chroms_to_target_style() {
+        let aliases = ChromAliases::new(None, true).unwrap();
+        let rename = aliases.renamer(["chr1", "chrM"]);
+        assert_eq!(rename("1"), "chr1");
+        assert_eq!(rename("MT"), "chrM");
+        assert_eq!(rename("GL000220.1"), "GL000220.1");
+    }
+}
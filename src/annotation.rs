@@ -0,0 +1,79 @@
+//! GTF and GFF3 annotations as target intervals.
+
+use std::error::Error;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::{Strand, TargetInterval, compression};
+
+/// Whether `path` looks like a GTF/GFF file, ignoring compression extensions.
+pub fn is_annotation(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_ascii_lowercase();
+    let name = [".gz", ".zst", ".xz"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name);
+    [".gtf", ".gff", ".gff3"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Reads the features of type `feature` as 0-based half-open targets, named by
+/// the value of `attribute` when given (e.g. `gene_name` in GTF, `Name` in GFF3).
+pub fn parse_annotation(
+    path: &Path,
+    feature: &str,
+    attribute: Option<&str>,
+) -> Result<Vec<TargetInterval>, Box<dyn Error>> {
+    let reader = compression::open(path)?;
+    let mut targets = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 || fields[2] != feature {
+            continue;
+        }
+        let (Ok(start), Ok(end)) = (fields[3].parse::<i32>(), fields[4].parse::<i32>()) else {
+            continue;
+        };
+        let name = attribute.map(|key| attribute_value(fields[8], key).unwrap_or(".").to_string());
+        targets.push(TargetInterval {
+            chrom: fields[0].to_string(),
+            start: start - 1,
+            end,
+            name,
+            strand: Strand::parse(fields[6]),
+            extra: Vec::new(),
+        });
+    }
+    Ok(targets)
+}
+
+/// Looks up `key` in a GTF (`key "value";`) or GFF3 (`key=value;`) attribute column.
+fn attribute_value<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|pair| {
+        let pair = pair.trim();
+        let value = pair
+            .strip_prefix(key)
+            .filter(|rest| rest.starts_with([' ', '=']))?;
+        Some(value[1..].trim().trim_matches('"'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_gtf_and_gff3_attributes() {
+        let gtf = r#"gene_id "ENSG1"; gene_name "TP53"; gene_type "protein_coding";"#;
+        assert_eq!(attribute_value(gtf, "gene_name"), Some("TP53"));
+        assert_eq!(attribute_value(gtf, "gene"), None);
+        assert_eq!(attribute_value("ID=gene:1;Name=TP53", "Name"), Some("TP53"));
+        assert!(is_annotation(Path::new("gencode.v44.gtf.gz")));
+        assert!(!is_annotation(Path::new("targets.bed")));
+    }
+}
@@ -0,0 +1,264 @@
+//! The library interface: reading methylation records and targets, and
+//! aggregating the records over the targets into [`Aggregate`]s, the values
+//! the command line writes as its default columns.
+
+use crate::parallel::*;
+use std::error::Error;
+use std::path::Path;
+
+use crate::format::{ColumnNames, Context, Format};
+use crate::input::Input;
+use crate::{
+    Aggregation, DETECT_LINES, MethInterval, MethRanges, RecordFilter, Strand, TargetInterval,
+    annotation, bam, compression, detect_format, parse_meth_bed, parse_targets, summarize_ranges,
+};
+
+/// Reads methylation files into [`MethRanges`], with the filters of the
+/// command line's options of the same names:
+/// `MethReader::new().format(Format::BismarkCov).min_coverage(5).read(path)`.
+#[derive(Debug, Clone)]
+pub struct MethReader {
+    format: Format,
+    filter: RecordFilter,
+}
+
+impl Default for MethReader {
+    fn default() -> MethReader {
+        MethReader {
+            format: Format::Auto,
+            filter: RecordFilter::default(),
+        }
+    }
+}
+
+impl MethReader {
+    /// A reader detecting the format of each file and keeping every record.
+    pub fn new() -> MethReader {
+        MethReader::default()
+    }
+
+    pub fn format(mut self, format: Format) -> MethReader {
+        self.format = format;
+        self
+    }
+
+    /// Drops records with fewer reads.
+    pub fn min_coverage(mut self, reads: i32) -> MethReader {
+        self.filter.min_coverage = Some(reads);
+        self
+    }
+
+    /// Drops records with more reads.
+    pub fn max_coverage(mut self, reads: i32) -> MethReader {
+        self.filter.max_coverage = Some(reads);
+        self
+    }
+
+    /// Keeps only records in `context`, for formats with a context column.
+    pub fn context(mut self, context: Context) -> MethReader {
+        self.filter.context = Some(context);
+        self
+    }
+
+    /// Keeps only bedMethyl records, or BAM calls, of this modification code.
+    pub fn mod_code(mut self, code: impl Into<String>) -> MethReader {
+        self.filter.mod_code = Some(code.into());
+        self
+    }
+
+    /// Rejects records with numeric fields that do not parse instead of
+    /// reading them as 0.
+    pub fn strict(mut self, strict: bool) -> MethReader {
+        self.filter.strict = strict;
+        self
+    }
+
+    /// Sorts the records of each chromosome instead of rejecting unsorted input.
+    pub fn sort(mut self, sort: bool) -> MethReader {
+        self.filter.sort = sort;
+        self
+    }
+
+    /// Reads the methylation file at `path`, plain or compressed, or a BAM
+    /// file with modification tags; CRAM files are rejected.
+    pub fn read(&self, path: impl AsRef<Path>) -> Result<MethRanges, Box<dyn Error>> {
+        let path = path.as_ref();
+        match bam::sniff(path)? {
+            Some(bam::AlignmentKind::Cram) => return Err(bam::cram_unsupported(path)),
+            Some(bam::AlignmentKind::Bam) => {
+                let mut ranges =
+                    bam::pileup_bam(path, self.filter.mod_code.as_deref().unwrap_or("m"))?;
+                ranges.retain(|iv| self.filter.apply(iv));
+                return Ok(ranges);
+            }
+            None => {}
+        }
+        let format = match self.format {
+            Format::Auto => detect_format(path)?,
+            format => format,
+        };
+        self.parse(path, format)
+    }
+
+    /// Reads a methylation file already in memory, plain or gzip- or
+    /// zstd-compressed, such as one handed to a web page.
+    pub fn read_bytes(&self, bytes: &[u8]) -> Result<MethRanges, Box<dyn Error>> {
+        let format = match self.format {
+            Format::Auto => {
+                let start = compression::bytes_batches(bytes)?
+                    .next()
+                    .transpose()?
+                    .unwrap_or_default();
+                Format::detect(String::from_utf8_lossy(&start).lines().take(DETECT_LINES))
+            }
+            format => format,
+        };
+        self.parse(bytes, format)
+    }
+
+    fn parse(
+        &self,
+        input: &(impl Input + ?Sized),
+        format: Format,
+    ) -> Result<MethRanges, Box<dyn Error>> {
+        let mut ranges = parse_meth_bed(
+            input,
+            &format.layout(),
+            &ColumnNames::default(),
+            &self.filter,
+            &[None],
+        )?;
+        Ok(ranges.remove(0))
+    }
+}
+
+impl MethRanges {
+    /// The chromosomes with records, in no particular order.
+    pub fn chroms(&self) -> impl Iterator<Item = &str> {
+        self.by_chrom.keys().map(String::as_str)
+    }
+
+    /// The records of `chrom`, sorted by start.
+    pub fn records(&self, chrom: &str) -> &[MethInterval] {
+        self.by_chrom.get(chrom).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Target regions to aggregate over, in order.
+#[derive(Debug, Clone, Default)]
+pub struct TargetSet {
+    targets: Vec<TargetInterval>,
+}
+
+impl TargetSet {
+    /// Reads a BED file of targets, with names from its fourth column, or the
+    /// genes of a GTF/GFF3 annotation.
+    pub fn read(path: impl AsRef<Path>) -> Result<TargetSet, Box<dyn Error>> {
+        let path = path.as_ref();
+        let targets = match annotation::is_annotation(path) {
+            true => annotation::parse_annotation(path, "gene", None)?,
+            false => parse_targets(path, true, false)?,
+        };
+        Ok(TargetSet { targets })
+    }
+
+    /// Adds the 0-based, half-open target `[start, end)` of `chrom`.
+    pub fn push(&mut self, chrom: impl Into<String>, start: i32, end: i32, name: Option<String>) {
+        self.targets.push(TargetInterval {
+            chrom: chrom.into(),
+            start,
+            end,
+            name,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+}
+
+/// The records of one [`TargetSet`] target, aggregated by [`aggregate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub chrom: String,
+    pub start: i32,
+    pub end: i32,
+    pub name: Option<String>,
+    /// Records overlapping the target.
+    pub num_positions: usize,
+    /// Reads summed over those records.
+    pub coverage: i32,
+    /// Methylated reads summed over those records.
+    pub methylated: i32,
+    /// Coverage-weighted mean methylated fraction, `None` without coverage.
+    pub fraction: Option<f32>,
+}
+
+/// Aggregates the records of `ranges` overlapping each target, in the order
+/// of `targets`, as the command line's default output does.
+pub fn aggregate(ranges: &MethRanges, targets: &TargetSet) -> Vec<Aggregate> {
+    targets
+        .targets
+        .par_iter()
+        .map(|target| aggregate_target(ranges, target))
+        .collect()
+}
+
+/// The [`Aggregate`] of one target.
+pub fn aggregate_target(ranges: &MethRanges, target: &TargetInterval) -> Aggregate {
+    let summary = summarize_ranges(ranges, target, Strand::Unknown, &Aggregation::default());
+    Aggregate {
+        chrom: target.chrom.clone(),
+        start: target.start,
+        end: target.end,
+        name: target.name.clone(),
+        num_positions: summary.num_positions,
+        coverage: summary.sum_total_coverage,
+        methylated: summary.methylated(),
+        fraction: (summary.sum_total_coverage > 0).then_some(summary.weighted_fraction),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn aggregates_records_over_targets() {
+        let path = std::env::temp_dir().join(format!("methfast-api-{}.bed", std::process::id()));
+        let bytes = b"chr1\t10\t11\t1.0\t4\nchr1\t12\t13\t0.5\t4\nchr1\t30\t31\t0.0\t1\n";
+        std::fs::write(&path, bytes).unwrap();
+        let reader = MethReader::new().format(Format::Generic).min_coverage(2);
+        let ranges = reader.read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let ranges = ranges.unwrap();
+        assert_eq!(ranges.chroms().collect::<Vec<_>>(), vec!["chr1"]);
+        assert_eq!(ranges.records("chr1").len(), 2);
+        // The same file in memory, plain and gzip-compressed.
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(bytes).unwrap();
+        let gzip = gzip.finish().unwrap();
+        for bytes in [&bytes[..], &gzip[..]] {
+            let in_memory = MethReader::new().min_coverage(2).read_bytes(bytes).unwrap();
+            assert_eq!(in_memory.records("chr1").len(), 2);
+        }
+
+        let mut targets = TargetSet::default();
+        targets.push("chr1", 0, 20, Some("first".to_string()));
+        targets.push("chr1", 25, 40, None);
+        let aggregates = aggregate(&ranges, &targets);
+        assert_eq!(aggregates[0].name.as_deref(), Some("first"));
+        assert_eq!(aggregates[0].num_positions, 2);
+        assert_eq!(aggregates[0].coverage, 8);
+        assert_eq!(aggregates[0].methylated, 6);
+        assert_eq!(aggregates[0].fraction, Some(0.75));
+        assert_eq!(aggregates[1].fraction, None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Illumina 450K/EPIC array input: a probe-level beta matrix plus a probe manifest.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::{MethInterval, MethRanges, Strand};
+
+/// Loads the betas of one sample column as single-coverage intervals, so the
+/// weighted fraction of a target is the mean beta of its probes.
+///
+/// The manifest is BED-like (`chrom, start, end, probe_id`); the beta matrix is
+/// tab-separated with probe IDs in the first column and one sample per column.
+pub fn load_betas(
+    betas: &Path,
+    manifest: &Path,
+    sample: Option<&str>,
+) -> Result<MethRanges, Box<dyn Error>> {
+    let positions = read_manifest(manifest)?;
+
+    let mut reader = BufReader::new(File::open(betas)?);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let samples: Vec<&str> = header.trim_end().split('\t').skip(1).collect();
+    let column = match sample {
+        Some(name) => samples
+            .iter()
+            .position(|s| *s == name)
+            .ok_or_else(|| format!("Error: sample {name} not found in {}", betas.display()))?,
+        None if samples.is_empty() => {
+            return Err(format!("Error: no sample columns in {}", betas.display()).into());
+        }
+        None => 0,
+    };
+
+    let mut by_chrom: HashMap<String, Vec<MethInterval>> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let Some(probe) = fields.next() else {
+            continue;
+        };
+        let Some((chrom, start, end)) = positions.get(probe) else {
+            continue;
+        };
+        let Some(beta) = fields.nth(column).and_then(|v| v.parse::<f32>().ok()) else {
+            continue;
+        };
+        if beta.is_nan() {
+            continue;
+        }
+        by_chrom
+            .entry(chrom.clone())
+            .or_default()
+            .push(MethInterval {
+                start: *start,
+                end: *end,
+                fraction: beta,
+                coverage: 1,
+                strand: Strand::Unknown,
+            });
+    }
+    for intervals in by_chrom.values_mut() {
+        intervals.sort_by_key(|iv| (iv.start, iv.end));
+    }
+    Ok(MethRanges { by_chrom })
+}
+
+/// Probe ID to `(chrom, start, end)`.
+type Manifest = HashMap<String, (String, i32, i32)>;
+
+fn read_manifest(path: &Path) -> Result<Manifest, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut positions = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        // Header lines and unmapped probes have non-numeric coordinates.
+        let (Ok(start), Ok(end)) = (fields[1].parse::<i32>(), fields[2].parse::<i32>()) else {
+            continue;
+        };
+        positions.insert(fields[3].to_string(), (fields[0].to_string(), start, end));
+    }
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_selected_sample_betas() {
+        let dir = std::env::temp_dir().join(format!("methfast-array-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("manifest.bed");
+        let betas = dir.join("betas.tsv");
+        std::fs::write(
+            &manifest,
+            "chrm\tbeg\tend\tprobeID\nchr1\t20\t22\tcg2\nchr1\t10\t12\tcg1\nNA\tNA\tNA\tcg3\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &betas,
+            "probe\tS1\tS2\ncg1\t0.1\t0.9\ncg2\t0.2\tNA\ncg3\t0.5\t0.5\n",
+        )
+        .unwrap();
+
+        let ranges = load_betas(&betas, &manifest, Some("S2")).unwrap();
+        let intervals = &ranges.by_chrom["chr1"];
+        assert_eq!(intervals.len(), 1);
+        assert_eq!((intervals[0].start, intervals[0].fraction), (10, 0.9));
+
+        let ranges = load_betas(&betas, &manifest, None).unwrap();
+        let starts: Vec<i32> = ranges.by_chrom["chr1"].iter().map(|iv| iv.start).collect();
+        assert_eq!(starts, vec![10, 20]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
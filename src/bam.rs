@@ -0,0 +1,536 @@
+//! Minimal modBAM reader that piles up base-modification calls from MM/ML tags.
+//! CRAM files are only recognized, to be rejected with a hint to convert them.
+
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::{MethInterval, MethRanges, Strand};
+
+const FLAG_REVERSE: u16 = 0x10;
+/// Unmapped, secondary, QC-fail, duplicate and supplementary records are skipped.
+const FLAG_SKIP: u16 = 0x4 | 0x100 | 0x200 | 0x400 | 0x800;
+/// Calls with an ML probability at or above this value count as modified.
+const MOD_PROB_THRESHOLD: f32 = 0.5;
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Kind of alignment file, detected from its leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentKind {
+    Bam,
+    Cram,
+}
+
+/// Returns the alignment kind if `path` is a BAM or CRAM file.
+pub fn sniff(path: &Path) -> Result<Option<AlignmentKind>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut header = [0_u8; 4];
+    let n = file.read(&mut header)?;
+    if n == 4 && &header == b"CRAM" {
+        return Ok(Some(AlignmentKind::Cram));
+    }
+    if n < 3 || header[..3] != [0x1F, 0x8B, 0x08] {
+        return Ok(None);
+    }
+    let mut decoder = MultiGzDecoder::new(File::open(path)?);
+    let mut magic = [0_u8; 4];
+    match decoder.read_exact(&mut magic) {
+        Ok(()) => Ok((&magic == b"BAM\x01").then_some(AlignmentKind::Bam)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The error for CRAM input at `path`, which is not decoded.
+pub fn cram_unsupported(path: &Path) -> Box<dyn Error> {
+    format!(
+        "Error: {}: CRAM input is not supported; convert it with `samtools view -b` first",
+        path.display()
+    )
+    .into()
+}
+
+/// Piles up calls for `mod_code` (e.g. `m` for 5mC) on C bases of a coordinate-sorted BAM.
+pub fn pileup_bam(path: &Path, mod_code: &str) -> Result<MethRanges, Box<dyn Error>> {
+    let reader = BufReader::new(MultiGzDecoder::new(BufReader::new(File::open(path)?)));
+    pileup(reader, mod_code)
+}
+
+fn pileup<R: Read>(mut reader: R, mod_code: &str) -> Result<MethRanges, Box<dyn Error>> {
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"BAM\x01" {
+        return Err("Error: not a BAM file".into());
+    }
+    let l_text = read_len(&mut reader, "header")?;
+    io::copy(&mut (&mut reader).take(l_text as u64), &mut io::sink())?;
+    let n_ref = read_len(&mut reader, "header")?;
+    let mut ref_names = Vec::new();
+    for _ in 0..n_ref {
+        let l_name = read_len(&mut reader, "header")?;
+        let mut name = read_bytes(&mut reader, l_name, "header")?;
+        if name.last() == Some(&0) {
+            name.pop();
+        }
+        ref_names.push(String::from_utf8_lossy(&name).into_owned());
+        read_i32(&mut reader)?;
+    }
+
+    let mut by_chrom: HashMap<String, Vec<MethInterval>> = HashMap::new();
+    let mut counts = Counts::new();
+    let mut current_ref: i32 = -1;
+    let mut prev_pos: i32 = -1;
+
+    loop {
+        let block_size = match read_i32(&mut reader) {
+            Ok(size) => usize::try_from(size).map_err(|_| truncated("record"))?,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let block = read_bytes(&mut reader, block_size, "record")?;
+        let record = Record::parse(&block)?;
+        if record.ref_id < 0 {
+            // Unmapped reads are sorted last.
+            break;
+        }
+        if record.ref_id < current_ref || (record.ref_id == current_ref && record.pos < prev_pos) {
+            return Err("Error: BAM file is not coordinate-sorted. Exiting...".into());
+        }
+        if record.ref_id != current_ref {
+            if current_ref >= 0 {
+                flush(&ref_names, current_ref, &mut counts, &mut by_chrom);
+            }
+            current_ref = record.ref_id;
+        } else {
+            let pending = counts.split_off(&(record.pos, false));
+            flush(&ref_names, current_ref, &mut counts, &mut by_chrom);
+            counts = pending;
+        }
+        prev_pos = record.pos;
+
+        if record.flag & FLAG_SKIP == 0 {
+            record.add_calls(mod_code, &mut counts);
+        }
+    }
+    if current_ref >= 0 {
+        flush(&ref_names, current_ref, &mut counts, &mut by_chrom);
+    }
+
+    Ok(MethRanges { by_chrom })
+}
+
+/// `(methylated, coverage)` calls by reference position and strand, `true`
+/// for reverse-strand reads, whose calls land on the G of a CpG as in
+/// modkit's bedMethyl.
+type Counts = BTreeMap<(i32, bool), (i32, i32)>;
+
+fn flush(
+    ref_names: &[String],
+    ref_id: i32,
+    counts: &mut Counts,
+    by_chrom: &mut HashMap<String, Vec<MethInterval>>,
+) {
+    if counts.is_empty() {
+        return;
+    }
+    let name = ref_names
+        .get(ref_id as usize)
+        .cloned()
+        .unwrap_or_else(|| ref_id.to_string());
+    let intervals = by_chrom.entry(name).or_default();
+    for ((pos, reverse), (methylated, coverage)) in std::mem::take(counts) {
+        intervals.push(MethInterval {
+            start: pos,
+            end: pos + 1,
+            fraction: methylated as f32 / coverage as f32,
+            coverage,
+            strand: if reverse { Strand::Minus } else { Strand::Plus },
+        });
+    }
+}
+
+struct Record<'a> {
+    ref_id: i32,
+    pos: i32,
+    flag: u16,
+    cigar: Vec<u32>,
+    seq: Vec<u8>,
+    aux: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn parse(block: &'a [u8]) -> Result<Record<'a>, Box<dyn Error>> {
+        if block.len() < 32 {
+            return Err(truncated("record"));
+        }
+        let ref_id = le_i32(&block[0..4]);
+        let pos = le_i32(&block[4..8]);
+        let l_read_name = block[8] as usize;
+        let n_cigar = u16::from_le_bytes([block[12], block[13]]) as usize;
+        let flag = u16::from_le_bytes([block[14], block[15]]);
+        let l_seq = usize::try_from(le_i32(&block[16..20])).map_err(|_| truncated("record"))?;
+
+        let cigar_start = 32 + l_read_name;
+        let seq_start = cigar_start + 4 * n_cigar;
+        let aux_start = l_seq
+            .checked_add(l_seq.div_ceil(2))
+            .and_then(|seq_len| seq_start.checked_add(seq_len))
+            .filter(|&aux_start| aux_start <= block.len())
+            .ok_or_else(|| truncated("record"))?;
+        let cigar = block[cigar_start..seq_start]
+            .chunks_exact(4)
+            .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+            .collect();
+        let seq = (0..l_seq)
+            .map(|i| {
+                let byte = block[seq_start + i / 2];
+                let code = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+                SEQ_CODES[code as usize]
+            })
+            .collect();
+
+        Ok(Record {
+            ref_id,
+            pos,
+            flag,
+            cigar,
+            seq,
+            aux: &block[aux_start..],
+        })
+    }
+
+    /// Reference position of every query base, or -1 for inserted/clipped bases.
+    fn reference_positions(&self) -> Vec<i32> {
+        let mut positions = vec![-1; self.seq.len()];
+        let mut qpos = 0_usize;
+        let mut rpos = self.pos;
+        for op in &self.cigar {
+            let len = (op >> 4) as usize;
+            match op & 0xF {
+                0 | 7 | 8 => {
+                    for _ in 0..len {
+                        if let Some(slot) = positions.get_mut(qpos) {
+                            *slot = rpos;
+                        }
+                        qpos += 1;
+                        rpos += 1;
+                    }
+                }
+                1 | 4 => qpos += len,
+                2 | 3 => rpos += len as i32,
+                _ => {}
+            }
+        }
+        positions
+    }
+
+    fn add_calls(&self, mod_code: &str, counts: &mut Counts) {
+        let (Some(mm), Some(ml)) = (
+            find_string_tag(self.aux, b"MM").or_else(|| find_string_tag(self.aux, b"Mm")),
+            find_u8_array_tag(self.aux, b"ML").or_else(|| find_u8_array_tag(self.aux, b"Ml")),
+        ) else {
+            return;
+        };
+
+        let reverse = self.flag & FLAG_REVERSE != 0;
+        let original: Vec<u8> = if reverse {
+            self.seq.iter().rev().map(|&b| complement(b)).collect()
+        } else {
+            self.seq.clone()
+        };
+        let ref_positions = self.reference_positions();
+        let mut record_call = |query_index: usize, modified: bool| {
+            let stored = if reverse {
+                original.len() - 1 - query_index
+            } else {
+                query_index
+            };
+            let rpos = ref_positions[stored];
+            if rpos >= 0 {
+                let entry = counts.entry((rpos, reverse)).or_insert((0, 0));
+                entry.0 += i32::from(modified);
+                entry.1 += 1;
+            }
+        };
+
+        let mut ml_offset = 0_usize;
+        for entry in mm.split(';').filter(|entry| !entry.is_empty()) {
+            let Some(mods) = ModEntry::parse(entry) else {
+                return;
+            };
+            let n_codes = mods.codes.len();
+            let code_index = mods.codes.iter().position(|&code| code == mod_code);
+            let probabilities = ml.get(ml_offset..ml_offset + n_codes * mods.deltas.len());
+            ml_offset += n_codes * mods.deltas.len();
+            let (Some(code_index), Some(probabilities), b'C', b'+') =
+                (code_index, probabilities, mods.base, mods.strand)
+            else {
+                continue;
+            };
+
+            let mut occurrences = original
+                .iter()
+                .enumerate()
+                .filter(|&(_, &b)| b == mods.base)
+                .map(|(i, _)| i);
+            for (call, &delta) in mods.deltas.iter().enumerate() {
+                for _ in 0..delta {
+                    match occurrences.next() {
+                        Some(i) if mods.implicit_canonical => record_call(i, false),
+                        Some(_) => {}
+                        None => return,
+                    }
+                }
+                let Some(i) = occurrences.next() else {
+                    return;
+                };
+                let prob = (probabilities[call * n_codes + code_index] as f32 + 0.5) / 256.0;
+                record_call(i, prob >= MOD_PROB_THRESHOLD);
+            }
+            if mods.implicit_canonical {
+                for i in occurrences {
+                    record_call(i, false);
+                }
+            }
+        }
+    }
+}
+
+/// One `base strand codes [.?], deltas...` entry of an MM tag.
+struct ModEntry<'a> {
+    base: u8,
+    strand: u8,
+    codes: Vec<&'a str>,
+    /// Skipped bases are unmodified (`.` or no flag) rather than unknown (`?`).
+    implicit_canonical: bool,
+    deltas: Vec<usize>,
+}
+
+impl<'a> ModEntry<'a> {
+    fn parse(entry: &'a str) -> Option<ModEntry<'a>> {
+        let mut parts = entry.split(',');
+        let head = parts.next()?;
+        let bytes = head.as_bytes();
+        if bytes.len() < 3 {
+            return None;
+        }
+        let (mut codes_str, implicit_canonical) = match bytes[bytes.len() - 1] {
+            b'?' => (&head[2..head.len() - 1], false),
+            b'.' => (&head[2..head.len() - 1], true),
+            _ => (&head[2..], true),
+        };
+        if codes_str.is_empty() {
+            return None;
+        }
+        let codes = if codes_str.bytes().all(|b| b.is_ascii_digit()) {
+            vec![codes_str]
+        } else {
+            let mut codes = Vec::new();
+            while !codes_str.is_empty() {
+                let split = codes_str.chars().next()?.len_utf8();
+                codes.push(&codes_str[..split]);
+                codes_str = &codes_str[split..];
+            }
+            codes
+        };
+        let deltas = parts
+            .map(|delta| delta.trim().parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(ModEntry {
+            base: bytes[0],
+            strand: bytes[1],
+            codes,
+            implicit_canonical,
+            deltas,
+        })
+    }
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        other => other,
+    }
+}
+
+fn le_i32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut buf = [0_u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn truncated(what: &str) -> Box<dyn Error> {
+    format!("Error: truncated BAM {what}").into()
+}
+
+/// A length or count field of the `what` part of the file, which must not be negative.
+fn read_len<R: Read>(reader: &mut R, what: &str) -> Result<usize, Box<dyn Error>> {
+    usize::try_from(read_i32(reader)?).map_err(|_| truncated(what))
+}
+
+/// The next `len` bytes, read without allocating them up front, so a
+/// corrupt length fails at the end of the file rather than in the allocator.
+fn read_bytes<R: Read>(reader: &mut R, len: usize, what: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(truncated(what));
+    }
+    Ok(bytes)
+}
+
+/// Iterates `(tag, type, value)` over the auxiliary fields of a record.
+fn aux_fields(mut aux: &[u8]) -> impl Iterator<Item = ([u8; 2], u8, &[u8])> {
+    std::iter::from_fn(move || {
+        if aux.len() < 3 {
+            return None;
+        }
+        let tag = [aux[0], aux[1]];
+        let kind = aux[2];
+        let rest = &aux[3..];
+        let size = match kind {
+            b'A' | b'c' | b'C' => 1,
+            b's' | b'S' => 2,
+            b'i' | b'I' | b'f' => 4,
+            b'Z' | b'H' => rest.iter().position(|&b| b == 0)? + 1,
+            b'B' => {
+                let width: usize = match *rest.first()? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    _ => 4,
+                };
+                let count = usize::try_from(le_i32(rest.get(1..5)?)).ok()?;
+                width.checked_mul(count)?.checked_add(5)?
+            }
+            _ => return None,
+        };
+        let value = rest.get(..size)?;
+        aux = &rest[size..];
+        Some((tag, kind, value))
+    })
+}
+
+fn find_string_tag<'a>(aux: &'a [u8], name: &[u8; 2]) -> Option<&'a str> {
+    aux_fields(aux)
+        .find(|(tag, kind, _)| tag == name && *kind == b'Z')
+        .and_then(|(_, _, value)| std::str::from_utf8(&value[..value.len() - 1]).ok())
+}
+
+fn find_u8_array_tag<'a>(aux: &'a [u8], name: &[u8; 2]) -> Option<&'a [u8]> {
+    aux_fields(aux)
+        .find(|(tag, kind, value)| tag == name && *kind == b'B' && value[0] == b'C')
+        .map(|(_, _, value)| &value[5..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_record(pos: i32, flag: u16, seq: &[u8], mm: &str, ml: &[u8]) -> Vec<u8> {
+        let name = b"r\0";
+        let mut body = Vec::new();
+        body.extend_from_slice(&0_i32.to_le_bytes());
+        body.extend_from_slice(&pos.to_le_bytes());
+        body.push(name.len() as u8);
+        body.push(60);
+        body.extend_from_slice(&0_u16.to_le_bytes());
+        body.extend_from_slice(&1_u16.to_le_bytes());
+        body.extend_from_slice(&flag.to_le_bytes());
+        body.extend_from_slice(&(seq.len() as i32).to_le_bytes());
+        body.extend_from_slice(&(-1_i32).to_le_bytes());
+        body.extend_from_slice(&(-1_i32).to_le_bytes());
+        body.extend_from_slice(&0_i32.to_le_bytes());
+        body.extend_from_slice(name);
+        body.extend_from_slice(&((seq.len() as u32) << 4).to_le_bytes());
+        for pair in seq.chunks(2) {
+            let code = |b: u8| SEQ_CODES.iter().position(|&c| c == b).unwrap() as u8;
+            let low = pair.get(1).map_or(0, |&b| code(b));
+            body.push((code(pair[0]) << 4) | low);
+        }
+        body.extend(std::iter::repeat_n(30_u8, seq.len()));
+        body.extend_from_slice(b"MMZ");
+        body.extend_from_slice(mm.as_bytes());
+        body.push(0);
+        body.extend_from_slice(b"MLBC");
+        body.extend_from_slice(&(ml.len() as i32).to_le_bytes());
+        body.extend_from_slice(ml);
+
+        let mut record = (body.len() as i32).to_le_bytes().to_vec();
+        record.extend(body);
+        record
+    }
+
+    fn encode_bam(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend_from_slice(&0_i32.to_le_bytes());
+        bam.extend_from_slice(&1_i32.to_le_bytes());
+        bam.extend_from_slice(&5_i32.to_le_bytes());
+        bam.extend_from_slice(b"chr1\0");
+        bam.extend_from_slice(&100_i32.to_le_bytes());
+        for record in records {
+            bam.extend_from_slice(record);
+        }
+        bam
+    }
+
+    #[test]
+    fn piles_up_forward_and_reverse_calls() {
+        // Both reads align to ACGTCG, with CpGs at 11 and 14. The reverse one
+        // is CGACGT in sequencing orientation, so its C calls land on the Gs
+        // at 12 and 15, on the minus strand.
+        let bam = encode_bam(&[
+            encode_record(10, 0, b"ACGTCG", "C+m?,0,0;", &[250, 10]),
+            encode_record(10, FLAG_REVERSE, b"ACGTCG", "C+m.,1;", &[5]),
+            encode_record(10, FLAG_REVERSE, b"ACGTCG", "C+m?,0,0;", &[240, 250]),
+        ]);
+        let ranges = pileup(bam.as_slice(), "m").unwrap();
+        let calls: Vec<(i32, Strand, f32, i32)> = ranges.by_chrom["chr1"]
+            .iter()
+            .map(|iv| (iv.start, iv.strand, iv.fraction, iv.coverage))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (11, Strand::Plus, 1.0, 1),
+                (12, Strand::Minus, 0.5, 2),
+                (14, Strand::Plus, 0.0, 1),
+                (15, Strand::Minus, 0.5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_negative_lengths() {
+        let mut record = encode_record(10, 0, b"ACGT", "C+m?,0;", &[250]);
+        // l_seq, after the block size and the first 16 bytes of the record.
+        record[20..24].copy_from_slice(&(-2_i32).to_le_bytes());
+        let err = pileup(encode_bam(&[record]).as_slice(), "m").unwrap_err();
+        assert_eq!(err.to_string(), "Error: truncated BAM record");
+
+        let mut bam = encode_bam(&[]);
+        bam.extend_from_slice(&(-1_i32).to_le_bytes());
+        let err = pileup(bam.as_slice(), "m").unwrap_err();
+        assert_eq!(err.to_string(), "Error: truncated BAM record");
+        let mut bam = encode_bam(&[]);
+        bam.extend_from_slice(&1000_i32.to_le_bytes());
+        bam.extend_from_slice(&[0; 40]);
+        assert!(pileup(bam.as_slice(), "m").is_err());
+    }
+
+    #[test]
+    fn parses_multi_code_mm_entries() {
+        let entry = ModEntry::parse("C+hm?,2,0").unwrap();
+        assert_eq!(entry.codes, vec!["h", "m"]);
+        assert!(!entry.implicit_canonical);
+        assert_eq!(entry.deltas, vec![2, 0]);
+    }
+}
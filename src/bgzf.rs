@@ -0,0 +1,306 @@
+//! Block-gzip (BGZF) reading with virtual-offset seeking, and writing.
+
+use crate::parallel::*;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::remote;
+
+/// Uncompressed bytes per written block, leaving room for incompressible data.
+const BLOCK_DATA_SIZE: usize = 0xFF00;
+
+/// The empty block that marks the end of a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1F, 0x8B, 0x08, 0x04, 0, 0, 0, 0, 0, 0xFF, 0x06, 0, b'B', b'C', 0x02, 0, 0x1B, 0, 0x03, 0, 0,
+    0, 0, 0, 0, 0, 0, 0,
+];
+
+/// The compressed bytes behind a [`BgzfReader`]: a file, or a URL read
+/// through range requests.
+trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+/// Reads a BGZF file block by block so that positions can be expressed as
+/// virtual offsets (`compressed_offset << 16 | offset_in_block`).
+pub struct BgzfReader {
+    path: PathBuf,
+    file: BufReader<Box<dyn Source>>,
+    block: Vec<u8>,
+    /// Compressed offset of the loaded block, or `u64::MAX` when none is loaded.
+    block_offset: u64,
+    /// Compressed offset of the following block; the file cursor always sits here.
+    next_block_offset: u64,
+    pos: usize,
+}
+
+impl BgzfReader {
+    pub fn open(path: &Path) -> io::Result<BgzfReader> {
+        let source: Box<dyn Source> = match remote::is_url(path) {
+            true => Box::new(remote::RangeReader::new(path)),
+            false => Box::new(File::open(path)?),
+        };
+        Ok(BgzfReader {
+            path: path.to_path_buf(),
+            file: BufReader::new(source),
+            block: Vec::new(),
+            block_offset: u64::MAX,
+            next_block_offset: 0,
+            pos: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Positions the reader at `voffset`, reusing the loaded block when possible.
+    pub fn seek(&mut self, voffset: u64) -> io::Result<()> {
+        let coffset = voffset >> 16;
+        if coffset != self.block_offset {
+            self.file.seek(SeekFrom::Start(coffset))?;
+            self.next_block_offset = coffset;
+            self.load_block()?;
+        }
+        self.pos = (voffset & 0xFFFF) as usize;
+        Ok(())
+    }
+
+    /// Virtual offset of the next unread byte.
+    pub fn virtual_offset(&self) -> u64 {
+        if self.pos >= self.block.len() {
+            self.next_block_offset << 16
+        } else {
+            (self.block_offset << 16) | self.pos as u64
+        }
+    }
+
+    /// Loads the block at `next_block_offset`; returns `false` at end of file.
+    fn load_block(&mut self) -> io::Result<bool> {
+        let Some(block) = RawBlock::read(&mut self.file)? else {
+            self.block.clear();
+            self.block_offset = u64::MAX;
+            self.pos = 0;
+            return Ok(false);
+        };
+        self.block.clear();
+        block.inflate_into(&mut self.block)?;
+        self.block_offset = self.next_block_offset;
+        self.next_block_offset += block.size as u64;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+/// A compressed BGZF block as stored in the file.
+struct RawBlock {
+    cdata: Vec<u8>,
+    /// Uncompressed length from the gzip trailer.
+    isize: usize,
+    /// Compressed size of the whole block, header and trailer included.
+    size: usize,
+}
+
+impl RawBlock {
+    /// Reads the block at the cursor of `file`; `None` at end of file.
+    fn read(file: &mut impl Read) -> io::Result<Option<RawBlock>> {
+        let mut header = [0_u8; 12];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if header[0..4] != [0x1F, 0x8B, 0x08, 0x04] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Error: input is not BGZF-compressed",
+            ));
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0_u8; xlen];
+        file.read_exact(&mut extra)?;
+        let Some(bsize) = block_size(&extra) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Error: gzip block is missing the BGZF size field",
+            ));
+        };
+        let cdata_len = (bsize + 1)
+            .checked_sub(12 + xlen + 8)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Error: bad BGZF block"))?;
+        let mut cdata = vec![0_u8; cdata_len];
+        file.read_exact(&mut cdata)?;
+        let mut trailer = [0_u8; 8];
+        file.read_exact(&mut trailer)?;
+        let isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]) as usize;
+        Ok(Some(RawBlock {
+            cdata,
+            isize,
+            size: bsize + 1,
+        }))
+    }
+
+    fn inflate_into(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        out.reserve(self.isize);
+        DeflateDecoder::new(self.cdata.as_slice()).read_to_end(out)?;
+        Ok(())
+    }
+}
+
+/// The `BSIZE` of the `BC` subfield of a gzip extra field.
+fn block_size(extra: &[u8]) -> Option<usize> {
+    let mut bsize = None;
+    let mut i = 0;
+    while i + 4 <= extra.len() {
+        let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+        if extra[i] == b'B' && extra[i + 1] == b'C' && slen == 2 && i + 6 <= extra.len() {
+            bsize = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize);
+        }
+        i += 4 + slen;
+    }
+    bsize
+}
+
+/// Whether `path` starts with a BGZF block rather than plain gzip.
+pub fn is_bgzf(path: &Path) -> io::Result<bool> {
+    let mut header = Vec::with_capacity(18);
+    File::open(path)?.take(18).read_to_end(&mut header)?;
+    Ok(header.len() == 18
+        && header[0..4] == [0x1F, 0x8B, 0x08, 0x04]
+        && block_size(&header[12..]).is_some())
+}
+
+/// Reads a BGZF file front to back, inflating batches of blocks in parallel.
+pub struct BlockStream {
+    file: BufReader<File>,
+}
+
+impl BlockStream {
+    pub fn open(path: &Path) -> io::Result<BlockStream> {
+        Ok(BlockStream {
+            file: BufReader::new(File::open(path)?),
+        })
+    }
+
+    /// The uncompressed bytes of the next `n` blocks; `None` at end of file.
+    pub fn inflate_batch(&mut self, n: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut blocks = Vec::with_capacity(n);
+        while blocks.len() < n {
+            match RawBlock::read(&mut self.file)? {
+                Some(block) => blocks.push(block),
+                None => break,
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        let inflated = blocks
+            .par_iter()
+            .map(|block| {
+                let mut out = Vec::new();
+                block.inflate_into(&mut out).map(|()| out)
+            })
+            .collect::<io::Result<Vec<Vec<u8>>>>()?;
+        Ok(Some(inflated.concat()))
+    }
+}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for BgzfReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.block.len() {
+            if !self.load_block()? {
+                break;
+            }
+        }
+        Ok(&self.block[self.pos.min(self.block.len())..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Writes BGZF blocks, tracking the virtual offset of the next byte so that
+/// records can be indexed as they are written.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    /// Compressed offset of the block being filled.
+    block_offset: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner,
+            buf: Vec::with_capacity(BLOCK_DATA_SIZE),
+            block_offset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte to be written.
+    pub fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.buf.len() as u64
+    }
+
+    fn write_block(&mut self, len: usize) -> io::Result<()> {
+        let data = &self.buf[..len];
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let cdata = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        let bsize = 12 + 6 + cdata.len() + 8;
+        let mut header = [
+            0x1F, 0x8B, 0x08, 0x04, 0, 0, 0, 0, 0, 0xFF, 0x06, 0, b'B', b'C', 0x02, 0, 0, 0,
+        ];
+        header[16..18].copy_from_slice(&((bsize - 1) as u16).to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&cdata)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(len as u32).to_le_bytes())?;
+        self.block_offset += bsize as u64;
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    /// Writes the pending data and the end-of-file block, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.write_block(self.buf.len())?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BLOCK_DATA_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BLOCK_DATA_SIZE {
+            self.write_block(BLOCK_DATA_SIZE)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,569 @@
+//! bigWig reading, used to pair fraction and coverage tracks into methylation
+//! intervals, and writing of per-target values as a track.
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{MethInterval, MethRanges, Strand};
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const RTREE_MAGIC: u32 = 0x2468_ACE0;
+
+/// One `[start, end)` interval with its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: i32,
+    pub end: i32,
+    pub value: f32,
+}
+
+struct Reader {
+    file: File,
+    big_endian: bool,
+}
+
+impl Reader {
+    fn bytes(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0_u8; len];
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Decodes fixed-width integers in the file's byte order.
+#[derive(Clone, Copy)]
+struct Fields<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Fields<'_> {
+    fn u16(&self, at: usize) -> u16 {
+        let b = [self.data[at], self.data[at + 1]];
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn u32(&self, at: usize) -> u32 {
+        let b = [
+            self.data[at],
+            self.data[at + 1],
+            self.data[at + 2],
+            self.data[at + 3],
+        ];
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    fn u64(&self, at: usize) -> u64 {
+        let mut b = [0_u8; 8];
+        b.copy_from_slice(&self.data[at..at + 8]);
+        if self.big_endian {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        }
+    }
+
+    fn f32(&self, at: usize) -> f32 {
+        f32::from_bits(self.u32(at))
+    }
+}
+
+/// Reads every interval of a bigWig file, grouped by chromosome and sorted by start.
+pub fn read_bigwig(path: &Path) -> Result<HashMap<String, Vec<Span>>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut header = [0_u8; 64];
+    file.read_exact(&mut header)?;
+    let big_endian = match u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+        BIGWIG_MAGIC => false,
+        magic if magic.swap_bytes() == BIGWIG_MAGIC => true,
+        _ => return Err(format!("Error: {} is not a bigWig file", path.display()).into()),
+    };
+    let fields = Fields {
+        data: &header,
+        big_endian,
+    };
+    let chrom_tree_offset = fields.u64(8);
+    let full_index_offset = fields.u64(24);
+    let uncompress_buf_size = fields.u32(52);
+
+    let mut reader = Reader { file, big_endian };
+    let chrom_names = read_chrom_tree(&mut reader, chrom_tree_offset)?;
+
+    let mut blocks = Vec::new();
+    let rtree = reader.bytes(full_index_offset, 48)?;
+    let rtree_fields = Fields {
+        data: &rtree,
+        big_endian,
+    };
+    if rtree_fields.u32(0) != RTREE_MAGIC {
+        return Err(format!("Error: {}: bad bigWig data index", path.display()).into());
+    }
+    collect_blocks(&mut reader, full_index_offset + 48, &mut blocks)?;
+
+    let mut by_chrom: HashMap<String, Vec<Span>> = HashMap::new();
+    for (offset, size) in blocks {
+        let raw = reader.bytes(offset, size as usize)?;
+        let data = if uncompress_buf_size > 0 {
+            let mut out = Vec::with_capacity(uncompress_buf_size as usize);
+            ZlibDecoder::new(raw.as_slice()).read_to_end(&mut out)?;
+            out
+        } else {
+            raw
+        };
+        decode_section(&data, big_endian, &chrom_names, &mut by_chrom)?;
+    }
+    for spans in by_chrom.values_mut() {
+        spans.sort_by_key(|span| span.start);
+    }
+    Ok(by_chrom)
+}
+
+fn read_chrom_tree(
+    reader: &mut Reader,
+    offset: u64,
+) -> Result<HashMap<u32, String>, Box<dyn Error>> {
+    let header = reader.bytes(offset, 32)?;
+    let fields = Fields {
+        data: &header,
+        big_endian: reader.big_endian,
+    };
+    if fields.u32(0) != CHROM_TREE_MAGIC {
+        return Err("Error: bad bigWig chromosome tree".into());
+    }
+    let key_size = fields.u32(8) as usize;
+    let mut names = HashMap::new();
+    let mut pending = vec![offset + 32];
+    while let Some(node_offset) = pending.pop() {
+        let node_header = reader.bytes(node_offset, 4)?;
+        let is_leaf = node_header[0] == 1;
+        let count = Fields {
+            data: &node_header,
+            big_endian: reader.big_endian,
+        }
+        .u16(2) as usize;
+        let item_size = key_size + 8;
+        let items = reader.bytes(node_offset + 4, count * item_size)?;
+        let items = Fields {
+            data: &items,
+            big_endian: reader.big_endian,
+        };
+        for i in 0..count {
+            let at = i * item_size;
+            if is_leaf {
+                let key = &items.data[at..at + key_size];
+                let name_len = key.iter().position(|&b| b == 0).unwrap_or(key_size);
+                let name = String::from_utf8_lossy(&key[..name_len]).into_owned();
+                names.insert(items.u32(at + key_size), name);
+            } else {
+                pending.push(items.u64(at + key_size));
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Collects `(offset, size)` of every data block referenced by the R-tree node at `offset`.
+fn collect_blocks(
+    reader: &mut Reader,
+    offset: u64,
+    blocks: &mut Vec<(u64, u64)>,
+) -> Result<(), Box<dyn Error>> {
+    let node_header = reader.bytes(offset, 4)?;
+    let is_leaf = node_header[0] == 1;
+    let count = Fields {
+        data: &node_header,
+        big_endian: reader.big_endian,
+    }
+    .u16(2) as usize;
+    let item_size = if is_leaf { 32 } else { 24 };
+    let items = reader.bytes(offset + 4, count * item_size)?;
+    let items = Fields {
+        data: &items,
+        big_endian: reader.big_endian,
+    };
+    for i in 0..count {
+        let at = i * item_size;
+        if is_leaf {
+            blocks.push((items.u64(at + 16), items.u64(at + 24)));
+        } else {
+            collect_blocks(reader, items.u64(at + 16), blocks)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode_section(
+    data: &[u8],
+    big_endian: bool,
+    chrom_names: &HashMap<u32, String>,
+    by_chrom: &mut HashMap<String, Vec<Span>>,
+) -> Result<(), Box<dyn Error>> {
+    if data.len() < 24 {
+        return Err("Error: truncated bigWig data block".into());
+    }
+    let fields = Fields { data, big_endian };
+    let chrom_id = fields.u32(0);
+    let section_start = fields.u32(4) as i32;
+    let item_step = fields.u32(12) as i32;
+    let item_span = fields.u32(16) as i32;
+    let kind = data[20];
+    let item_count = fields.u16(22) as usize;
+    let Some(chrom) = chrom_names.get(&chrom_id) else {
+        return Err(format!("Error: bigWig block references unknown chromosome {chrom_id}").into());
+    };
+    let spans = by_chrom.entry(chrom.clone()).or_default();
+
+    let item_size = match kind {
+        1 => 12,
+        2 => 8,
+        3 => 4,
+        _ => return Err(format!("Error: unknown bigWig section type {kind}").into()),
+    };
+    if data.len() < 24 + item_count * item_size {
+        return Err("Error: truncated bigWig data block".into());
+    }
+    for i in 0..item_count {
+        let at = 24 + i * item_size;
+        let span = match kind {
+            1 => Span {
+                start: fields.u32(at) as i32,
+                end: fields.u32(at + 4) as i32,
+                value: fields.f32(at + 8),
+            },
+            2 => {
+                let start = fields.u32(at) as i32;
+                Span {
+                    start,
+                    end: start + item_span,
+                    value: fields.f32(at + 4),
+                }
+            }
+            _ => {
+                let start = section_start + i as i32 * item_step;
+                Span {
+                    start,
+                    end: start + item_span,
+                    value: fields.f32(at),
+                }
+            }
+        };
+        spans.push(span);
+    }
+    Ok(())
+}
+
+/// Joins a fraction track and a coverage track on their common sub-intervals.
+pub fn pair_tracks(
+    fraction: &HashMap<String, Vec<Span>>,
+    coverage: &HashMap<String, Vec<Span>>,
+    percent: bool,
+) -> MethRanges {
+    let scale = if percent { 0.01 } else { 1.0 };
+    let mut by_chrom = HashMap::new();
+    for (chrom, fractions) in fraction {
+        let Some(coverages) = coverage.get(chrom) else {
+            continue;
+        };
+        let mut intervals = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < fractions.len() && j < coverages.len() {
+            let (f, c) = (fractions[i], coverages[j]);
+            let start = f.start.max(c.start);
+            let end = f.end.min(c.end);
+            if start < end {
+                intervals.push(MethInterval {
+                    start,
+                    end,
+                    fraction: f.value * scale,
+                    coverage: c.value.round() as i32,
+                    strand: Strand::Unknown,
+                });
+            }
+            if f.end <= c.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        by_chrom.insert(chrom.clone(), intervals);
+    }
+    MethRanges { by_chrom }
+}
+
+/// Items per data section and children per index node when writing.
+const ITEMS_PER_SLOT: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+
+/// One data section of the file being written.
+struct Section {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// Writes `spans` as an uncompressed-index, zlib-compressed bedGraph bigWig
+/// without zoom levels. `chrom_sizes` fills the chromosome tree; spans must be
+/// sorted and non-overlapping within each chromosome.
+pub fn write_bigwig(
+    out: &mut impl Write,
+    chrom_sizes: &[(String, i32)],
+    spans: &HashMap<String, Vec<Span>>,
+) -> Result<(), Box<dyn Error>> {
+    // The chromosome B+ tree is searched by key, so IDs follow byte order.
+    let mut chroms: Vec<&(String, i32)> = chrom_sizes.iter().collect();
+    chroms.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    chroms.dedup_by(|a, b| a.0 == b.0);
+    for chrom in spans.keys() {
+        if !chroms.iter().any(|(name, _)| name == chrom) {
+            return Err(
+                format!("Error: chromosome {chrom} is missing from the chrom sizes").into(),
+            );
+        }
+    }
+
+    let mut file = vec![0_u8; 64];
+    let summary_offset = file.len() as u64;
+    file.extend_from_slice(&[0_u8; 40]);
+
+    let chrom_tree_offset = file.len() as u64;
+    let key_size = chroms.iter().map(|(name, _)| name.len()).max().unwrap_or(1);
+    put_u32(&mut file, CHROM_TREE_MAGIC);
+    put_u32(&mut file, chroms.len().max(1) as u32);
+    put_u32(&mut file, key_size as u32);
+    put_u32(&mut file, 8);
+    put_u64(&mut file, chroms.len() as u64);
+    put_u64(&mut file, 0);
+    file.extend_from_slice(&[1, 0]);
+    file.extend_from_slice(&(chroms.len() as u16).to_le_bytes());
+    for (id, (name, size)) in chroms.iter().enumerate() {
+        let mut key = name.as_bytes().to_vec();
+        key.resize(key_size, 0);
+        file.extend_from_slice(&key);
+        put_u32(&mut file, id as u32);
+        put_u32(&mut file, *size as u32);
+    }
+
+    let full_data_offset = file.len() as u64;
+    put_u64(&mut file, 0);
+    let mut sections = Vec::new();
+    let mut uncompress_buf_size = 0;
+    let (mut bases, mut sum, mut sum_squares) = (0_u64, 0_f64, 0_f64);
+    let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+    for (id, (name, _)) in chroms.iter().enumerate() {
+        let Some(chrom_spans) = spans.get(name) else {
+            continue;
+        };
+        for slot in chrom_spans.chunks(ITEMS_PER_SLOT) {
+            let mut data = Vec::with_capacity(24 + slot.len() * 12);
+            put_u32(&mut data, id as u32);
+            put_u32(&mut data, slot[0].start as u32);
+            put_u32(&mut data, slot[slot.len() - 1].end as u32);
+            put_u32(&mut data, 0);
+            put_u32(&mut data, 0);
+            data.extend_from_slice(&[1, 0]);
+            data.extend_from_slice(&(slot.len() as u16).to_le_bytes());
+            for span in slot {
+                put_u32(&mut data, span.start as u32);
+                put_u32(&mut data, span.end as u32);
+                data.extend_from_slice(&span.value.to_le_bytes());
+                let (len, value) = ((span.end - span.start) as f64, span.value as f64);
+                bases += (span.end - span.start) as u64;
+                sum += value * len;
+                sum_squares += value * value * len;
+                min = min.min(value);
+                max = max.max(value);
+            }
+            uncompress_buf_size = uncompress_buf_size.max(data.len());
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            let compressed = encoder.finish()?;
+            sections.push(Section {
+                chrom_id: id as u32,
+                start: slot[0].start as u32,
+                end: slot[slot.len() - 1].end as u32,
+                offset: file.len() as u64,
+                size: compressed.len() as u64,
+            });
+            file.extend_from_slice(&compressed);
+        }
+    }
+    file[full_data_offset as usize..full_data_offset as usize + 8]
+        .copy_from_slice(&(sections.len() as u64).to_le_bytes());
+
+    let full_index_offset = file.len() as u64;
+    write_rtree(&mut file, &sections);
+
+    let mut header = Vec::with_capacity(64);
+    put_u32(&mut header, BIGWIG_MAGIC);
+    header.extend_from_slice(&4_u16.to_le_bytes());
+    header.extend_from_slice(&0_u16.to_le_bytes());
+    put_u64(&mut header, chrom_tree_offset);
+    put_u64(&mut header, full_data_offset);
+    put_u64(&mut header, full_index_offset);
+    header.extend_from_slice(&[0; 4]);
+    put_u64(&mut header, 0);
+    put_u64(&mut header, summary_offset);
+    put_u32(&mut header, uncompress_buf_size as u32);
+    put_u64(&mut header, 0);
+    file[..64].copy_from_slice(&header);
+
+    let mut summary = Vec::with_capacity(40);
+    put_u64(&mut summary, bases);
+    for value in [min, max, sum, sum_squares] {
+        let value = if value.is_finite() { value } else { 0.0 };
+        summary.extend_from_slice(&value.to_le_bytes());
+    }
+    file[summary_offset as usize..summary_offset as usize + 40].copy_from_slice(&summary);
+
+    out.write_all(&file)?;
+    Ok(())
+}
+
+/// Writes the R-tree over `sections`, root first, levels in order down to the leaves.
+fn write_rtree(file: &mut Vec<u8>, sections: &[Section]) {
+    // Each level holds the (start_chrom, start, end_chrom, end) bounds of its nodes.
+    type Bounds = (u32, u32, u32, u32);
+    let leaf_bounds: Vec<Bounds> = sections
+        .iter()
+        .map(|s| (s.chrom_id, s.start, s.chrom_id, s.end))
+        .collect();
+    let mut levels: Vec<Vec<Bounds>> = vec![leaf_bounds];
+    while levels.last().is_some_and(|level| level.len() > BLOCK_SIZE) {
+        let parents = levels
+            .last()
+            .unwrap()
+            .chunks(BLOCK_SIZE)
+            .map(|children| {
+                let (first, last) = (children[0], children[children.len() - 1]);
+                (first.0, first.1, last.2, last.3)
+            })
+            .collect();
+        levels.push(parents);
+    }
+
+    let index_offset = file.len() as u64;
+    let (first, last) = match (sections.first(), sections.last()) {
+        (Some(first), Some(last)) => ((first.chrom_id, first.start), (last.chrom_id, last.end)),
+        _ => ((0, 0), (0, 0)),
+    };
+    put_u32(file, RTREE_MAGIC);
+    put_u32(file, BLOCK_SIZE as u32);
+    put_u64(file, sections.len() as u64);
+    put_u32(file, first.0);
+    put_u32(file, first.1);
+    put_u32(file, last.0);
+    put_u32(file, last.1);
+    put_u64(file, index_offset);
+    put_u32(file, ITEMS_PER_SLOT as u32);
+    put_u32(file, 0);
+
+    // Node sizes per level, leaves being levels[0].
+    let node_size = |level: usize, count: usize| 4 + count * if level == 0 { 32 } else { 24 };
+    let mut level_offset = file.len() as u64;
+    for depth in (0..levels.len()).rev() {
+        let items = &levels[depth];
+        let level_size: u64 = items
+            .chunks(BLOCK_SIZE)
+            .map(|node| node_size(depth, node.len()) as u64)
+            .sum();
+        // Children of this level's items are the nodes of the level below, in order.
+        let mut child_offset = level_offset + level_size;
+        for (node_index, node) in items.chunks(BLOCK_SIZE).enumerate() {
+            file.extend_from_slice(&[u8::from(depth == 0), 0]);
+            file.extend_from_slice(&(node.len() as u16).to_le_bytes());
+            for (i, bounds) in node.iter().enumerate() {
+                put_u32(file, bounds.0);
+                put_u32(file, bounds.1);
+                put_u32(file, bounds.2);
+                put_u32(file, bounds.3);
+                if depth == 0 {
+                    let section = &sections[node_index * BLOCK_SIZE + i];
+                    put_u64(file, section.offset);
+                    put_u64(file, section.size);
+                } else {
+                    put_u64(file, child_offset);
+                    let child = node_index * BLOCK_SIZE + i;
+                    let child_len = levels[depth - 1]
+                        .chunks(BLOCK_SIZE)
+                        .nth(child)
+                        .map_or(0, |c| c.len());
+                    child_offset += node_size(depth - 1, child_len) as u64;
+                }
+            }
+        }
+        level_offset += level_size;
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: i32, end: i32, value: f32) -> Span {
+        Span { start, end, value }
+    }
+
+    #[test]
+    fn pairs_tracks_on_shared_bases() {
+        let fraction = HashMap::from([(
+            "chr1".to_string(),
+            vec![span(10, 11, 50.0), span(20, 21, 100.0), span(30, 31, 0.0)],
+        )]);
+        let coverage = HashMap::from([("chr1".to_string(), vec![span(0, 25, 8.0)])]);
+        let ranges = pair_tracks(&fraction, &coverage, true);
+        let intervals: Vec<(i32, i32, f32, i32)> = ranges.by_chrom["chr1"]
+            .iter()
+            .map(|iv| (iv.start, iv.end, iv.fraction, iv.coverage))
+            .collect();
+        assert_eq!(intervals, vec![(10, 11, 0.5, 8), (20, 21, 1.0, 8)]);
+    }
+
+    #[test]
+    fn round_trips_written_bigwig() {
+        let sizes = vec![
+            ("chr2".to_string(), 5_000_000),
+            ("chr1".to_string(), 5_000_000),
+        ];
+        let chr1: Vec<Span> = (0..300_000)
+            .map(|i| span(i * 10, i * 10 + 5, (i % 100) as f32 / 100.0))
+            .collect();
+        let spans = HashMap::from([
+            ("chr1".to_string(), chr1),
+            ("chr2".to_string(), vec![span(100, 200, 0.5)]),
+        ]);
+        let path = std::env::temp_dir().join(format!("methfast-{}.bw", std::process::id()));
+        let mut out = File::create(&path).unwrap();
+        write_bigwig(&mut out, &sizes, &spans).unwrap();
+        drop(out);
+
+        let read = read_bigwig(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, spans);
+    }
+}
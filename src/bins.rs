@@ -0,0 +1,227 @@
+//! `methfast bins`: fixed-size genome windows at several resolutions in one pass.
+
+use crate::parallel::*;
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cli::write_lines;
+use crate::error::ThreadError;
+use crate::format::{ColumnNames, Format};
+use crate::{MethInterval, RecordFilter, bigwig, detect_format, parse_meth_bed, read_chrom_sizes};
+
+#[derive(Args, Debug)]
+pub struct BinsArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        help = "Methylation input; `-` for stdin"
+    )]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "bin-sizes",
+        value_name = "LIST",
+        value_delimiter = ',',
+        required = true,
+        value_parser = clap::value_parser!(i32).range(1..),
+        help = "Comma-separated window sizes in bases, e.g. 100,1000,10000"
+    )]
+    bin_sizes: Vec<i32>,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = BinsFormat::Bedgraph,
+        help = "Track format"
+    )]
+    output_format: BinsFormat,
+    #[arg(
+        long = "chrom-sizes",
+        value_name = "FILE",
+        help = "Chromosome sizes; clips the last window of each chromosome (required for bigwig)"
+    )]
+    chrom_sizes: Option<PathBuf>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "PREFIX",
+        help = "Write one track per size to PREFIX.<size>.bedgraph (or .bw)"
+    )]
+    output: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BinsFormat {
+    /// chrom, start, end and weighted fraction per covered window.
+    Bedgraph,
+    /// The same values as a bigWig track (needs --chrom-sizes).
+    Bigwig,
+}
+
+impl BinsFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            BinsFormat::Bedgraph => "bedgraph",
+            BinsFormat::Bigwig => "bw",
+        }
+    }
+}
+
+/// Coverage-weighted fraction of every covered `size`-base window of one
+/// chromosome, as `(window index, fraction)` in window order. Records count
+/// toward the window holding their start.
+pub fn bin_fractions(intervals: &[MethInterval], size: i32) -> Vec<(i32, f32)> {
+    let mut bins: Vec<(i32, f64, f64)> = Vec::new();
+    for iv in intervals.iter().filter(|iv| iv.coverage > 0) {
+        let bin = iv.start / size;
+        let weight = iv.coverage as f64;
+        match bins.last_mut() {
+            Some((last, sum_weight, sum_weighted)) if *last == bin => {
+                *sum_weight += weight;
+                *sum_weighted += weight * iv.fraction as f64;
+            }
+            _ => bins.push((bin, weight, weight * iv.fraction as f64)),
+        }
+    }
+    bins.into_iter()
+        .map(|(bin, sum_weight, sum_weighted)| (bin, (sum_weighted / sum_weight) as f32))
+        .collect()
+}
+
+pub fn run(args: &BinsArgs) -> Result<(), Box<dyn Error>> {
+    if args.output_format == BinsFormat::Bigwig && args.chrom_sizes.is_none() {
+        return Err("Error: --output-format bigwig requires --chrom-sizes".into());
+    }
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    // The input is parsed once and binned at every resolution.
+    let ranges = parse_meth_bed(
+        &args.input,
+        &format.layout(),
+        &ColumnNames::default(),
+        &filter,
+        &[None],
+    )?
+    .remove(0);
+    let chrom_sizes = match &args.chrom_sizes {
+        Some(path) => read_chrom_sizes(path)?,
+        None => Vec::new(),
+    };
+    let size_of: HashMap<&str, i32> = chrom_sizes
+        .iter()
+        .map(|(chrom, size)| (chrom.as_str(), *size))
+        .collect();
+    let mut chroms: Vec<(&String, &Vec<MethInterval>)> = ranges.by_chrom.iter().collect();
+    chroms.sort_by(|a, b| a.0.cmp(b.0));
+
+    args.bin_sizes.par_iter().try_for_each(|&size| {
+        let spans: Vec<(&str, Vec<bigwig::Span>)> = chroms
+            .iter()
+            .map(|(chrom, intervals)| {
+                let limit = size_of.get(chrom.as_str()).copied().unwrap_or(i32::MAX);
+                let spans = bin_fractions(intervals, size)
+                    .into_iter()
+                    .map(|(bin, value)| bigwig::Span {
+                        start: bin * size,
+                        end: (bin * size).saturating_add(size).min(limit),
+                        value,
+                    })
+                    .collect();
+                (chrom.as_str(), spans)
+            })
+            .collect();
+        let path = PathBuf::from(format!(
+            "{}.{size}.{}",
+            args.output.display(),
+            args.output_format.extension()
+        ));
+        write_track(args, &path, &chrom_sizes, spans).map_err(ThreadError::from)
+    })?;
+    Ok(())
+}
+
+fn write_track(
+    args: &BinsArgs,
+    path: &Path,
+    chrom_sizes: &[(String, i32)],
+    spans: Vec<(&str, Vec<bigwig::Span>)>,
+) -> Result<(), Box<dyn Error>> {
+    match args.output_format {
+        BinsFormat::Bedgraph => {
+            let lines: Vec<String> = spans
+                .iter()
+                .flat_map(|(chrom, spans)| {
+                    spans.iter().map(move |span| {
+                        format!(
+                            "{chrom}\t{}\t{}\t{:.*}",
+                            span.start, span.end, args.precision, span.value
+                        )
+                    })
+                })
+                .collect();
+            write_lines(Some(path), None, &lines)
+        }
+        BinsFormat::Bigwig => {
+            let spans: HashMap<String, Vec<bigwig::Span>> = spans
+                .into_iter()
+                .map(|(chrom, spans)| (chrom.to_string(), spans))
+                .collect();
+            let mut out = BufWriter::new(File::create(path)?);
+            bigwig::write_bigwig(&mut out, chrom_sizes, &spans)?;
+            out.flush()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn bins_records_by_start_at_each_size() {
+        let site = |start, fraction, coverage| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage,
+            strand: Strand::Unknown,
+        };
+        let intervals = vec![
+            site(10, 1.0, 3),
+            site(90, 0.0, 1),
+            site(150, 0.5, 2),
+            site(1500, 0.2, 0),
+        ];
+        assert_eq!(bin_fractions(&intervals, 100), vec![(0, 0.75), (1, 0.5)]);
+        assert_eq!(bin_fractions(&intervals, 1000), vec![(0, 4.0 / 6.0)]);
+    }
+}
@@ -0,0 +1,114 @@
+//! `--blacklist` regions, such as the ENCODE blacklist, masked out of the
+//! methylation records and optionally trimmed off the targets.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::{MethInterval, MethRanges, TargetInterval, parse_targets};
+
+/// Merged, sorted blacklist regions of each chromosome.
+#[derive(Debug, Default)]
+pub struct Blacklist {
+    by_chrom: HashMap<String, Vec<(i32, i32)>>,
+}
+
+impl Blacklist {
+    pub fn read(path: &Path) -> Result<Blacklist, Box<dyn Error>> {
+        Ok(Blacklist::new(parse_targets(path, false, false)?))
+    }
+
+    fn new(regions: Vec<TargetInterval>) -> Blacklist {
+        let mut by_chrom: HashMap<String, Vec<(i32, i32)>> = HashMap::new();
+        for region in regions {
+            by_chrom
+                .entry(region.chrom)
+                .or_default()
+                .push((region.start, region.end));
+        }
+        for regions in by_chrom.values_mut() {
+            regions.sort_unstable();
+            let mut merged: Vec<(i32, i32)> = Vec::with_capacity(regions.len());
+            for &(start, end) in regions.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *regions = merged;
+        }
+        Blacklist { by_chrom }
+    }
+
+    /// Whether `[start, end)` on `chrom` overlaps a blacklisted region.
+    pub fn overlaps(&self, chrom: &str, start: i32, end: i32) -> bool {
+        let Some(regions) = self.by_chrom.get(chrom) else {
+            return false;
+        };
+        let i = regions.partition_point(|&(_, region_end)| region_end <= start);
+        regions
+            .get(i)
+            .is_some_and(|&(region_start, _)| region_start < end)
+    }
+
+    pub fn masks(&self, chrom: &str, iv: &MethInterval) -> bool {
+        self.overlaps(chrom, iv.start, iv.end)
+    }
+
+    /// Drops the records of `ranges` that overlap the blacklist.
+    pub fn mask(&self, ranges: &mut MethRanges) {
+        for (chrom, intervals) in &mut ranges.by_chrom {
+            intervals.retain(|iv| !self.masks(chrom, iv));
+        }
+    }
+
+    /// Trims blacklisted bases off both ends of `target`; `None` when nothing
+    /// is left. Blacklisted regions inside the target leave it whole.
+    pub fn clip(&self, mut target: TargetInterval) -> Option<TargetInterval> {
+        let regions = self
+            .by_chrom
+            .get(&target.chrom)
+            .map_or(&[][..], Vec::as_slice);
+        let i = regions.partition_point(|&(_, end)| end <= target.start);
+        if let Some(&(start, end)) = regions.get(i)
+            && start <= target.start
+        {
+            target.start = end;
+        }
+        let j = regions.partition_point(|&(start, _)| start < target.end);
+        if let Some(&(start, end)) = regions[..j].last()
+            && end >= target.end
+        {
+            target.end = start;
+        }
+        (target.start < target.end).then_some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn masks_and_clips_against_merged_regions() {
+        let region = |start, end| TargetInterval {
+            chrom: "chr1".to_string(),
+            start,
+            end,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let blacklist = Blacklist::new(vec![region(100, 200), region(150, 250), region(400, 500)]);
+        assert!(blacklist.overlaps("chr1", 249, 250));
+        assert!(!blacklist.overlaps("chr1", 250, 400));
+        assert!(!blacklist.overlaps("chr2", 100, 200));
+
+        let clipped = blacklist.clip(region(200, 450)).unwrap();
+        assert_eq!((clipped.start, clipped.end), (250, 400));
+        let whole = blacklist.clip(region(50, 300)).unwrap();
+        assert_eq!((whole.start, whole.end), (50, 300));
+        assert!(blacklist.clip(region(120, 240)).is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! Chromosomes of the targets without methylation records and of the inputs
+//! without targets, noted on stderr after loading: naming mismatches such as
+//! a missing `chr` prefix or patch contigs otherwise only show as empty rows.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::TargetInterval;
+
+/// Names listed in a note before the rest are only counted.
+const LISTED: usize = 10;
+
+/// The chromosomes whose records were skipped for having no targets.
+#[derive(Debug)]
+pub struct SkippedChroms {
+    /// Unique among the collectors of the process, unlike their addresses,
+    /// which a later collector can reuse.
+    id: u64,
+    chroms: Mutex<HashSet<String>>,
+}
+
+/// The id of the next [`SkippedChroms`]; 0 is left to no collector.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The last chromosome recorded on this thread and the id of its
+    /// collector; sorted inputs skip many records of one chromosome in a row.
+    static LAST: RefCell<(u64, String)> = const { RefCell::new((0, String::new())) };
+}
+
+impl Default for SkippedChroms {
+    fn default() -> SkippedChroms {
+        SkippedChroms {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            chroms: Mutex::default(),
+        }
+    }
+}
+
+impl SkippedChroms {
+    pub fn record(&self, chrom: &str) {
+        let repeated = LAST.with(|last| {
+            let mut last = last.borrow_mut();
+            if last.0 == self.id && last.1 == chrom {
+                return true;
+            }
+            *last = (self.id, chrom.to_string());
+            false
+        });
+        if !repeated && let Ok(mut chroms) = self.chroms.lock() {
+            chroms.insert(chrom.to_string());
+        }
+    }
+
+    fn names(&self) -> HashSet<String> {
+        self.chroms
+            .lock()
+            .map(|chroms| chroms.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// `names`, sorted, with those past the first [`LISTED`] counted.
+fn list<'a>(names: impl IntoIterator<Item = &'a str>) -> String {
+    let names: BTreeSet<&str> = names.into_iter().collect();
+    let mut listed: Vec<&str> = names.iter().copied().take(LISTED).collect();
+    let more = names.len().saturating_sub(LISTED);
+    let more = format!("and {more} more");
+    if names.len() > LISTED {
+        listed.push(&more);
+    }
+    listed.join(", ")
+}
+
+/// The notes for `targets` against the chromosomes with records in some
+/// input, `loaded`, and those `skipped` while parsing: none when every target
+/// chromosome has records, since inputs usually cover more of the genome than
+/// the targets.
+pub fn notes(
+    targets: &[TargetInterval],
+    loaded: &HashSet<&str>,
+    skipped: Option<&SkippedChroms>,
+) -> Vec<String> {
+    let mut notes = Vec::new();
+    let target_chroms: HashSet<&str> = targets.iter().map(|target| target.chrom.as_str()).collect();
+    let missing: HashSet<&str> = target_chroms
+        .iter()
+        .copied()
+        .filter(|chrom| !loaded.contains(chrom))
+        .collect();
+    if missing.is_empty() {
+        return notes;
+    }
+    let affected = targets
+        .iter()
+        .filter(|target| missing.contains(target.chrom.as_str()))
+        .count();
+    let plural = if affected == 1 { "" } else { "s" };
+    notes.push(format!(
+        "Note: {} of {} target chromosomes ({affected} target{plural}) have no methylation records in any input: {}",
+        missing.len(),
+        target_chroms.len(),
+        list(missing.iter().copied())
+    ));
+    let skipped = skipped.map(SkippedChroms::names).unwrap_or_default();
+    let untargeted: HashSet<&str> = loaded
+        .iter()
+        .copied()
+        .chain(skipped.iter().map(String::as_str))
+        .filter(|chrom| !target_chroms.contains(chrom))
+        .collect();
+    if !untargeted.is_empty() {
+        let chroms = match untargeted.len() {
+            1 => "1 chromosome of the inputs has".to_string(),
+            n => format!("{n} chromosomes of the inputs have"),
+        };
+        notes.push(format!(
+            "Note: {chroms} no targets: {}",
+            list(untargeted.iter().copied())
+        ));
+        notes.push(
+            "Note: if these are the same chromosomes under other names, pass --normalize-chroms or --chrom-alias"
+                .to_string(),
+        );
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn notes_chromosomes_missing_on_either_side() {
+        let target = |chrom: &str| TargetInterval {
+            chrom: chrom.to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let targets = [
+            target("chr1"),
+            target("chr1"),
+            target("chr2"),
+            target("chrX"),
+        ];
+        let skipped = SkippedChroms::default();
+        for chrom in ["1", "1", "MT", "1"] {
+            skipped.record(chrom);
+        }
+        assert_eq!(
+            skipped.names(),
+            HashSet::from(["1".to_string(), "MT".to_string()])
+        );
+        // Collectors of one run after another, likely at the same address.
+        for _ in 0..2 {
+            let run = SkippedChroms::default();
+            run.record("chrUn");
+            assert_eq!(run.names(), HashSet::from(["chrUn".to_string()]));
+        }
+
+        let loaded = HashSet::from(["chr2"]);
+        assert_eq!(
+            notes(&targets, &loaded, Some(&skipped)),
+            vec![
+                "Note: 2 of 3 target chromosomes (3 targets) have no methylation records in any input: chr1, chrX",
+                "Note: 2 chromosomes of the inputs have no targets: 1, MT",
+                "Note: if these are the same chromosomes under other names, pass --normalize-chroms or --chrom-alias",
+            ]
+        );
+        // Inputs covering more than the targets are no mismatch.
+        let loaded = HashSet::from(["chr1", "chr2", "chrX", "chrY"]);
+        assert!(notes(&targets, &loaded, Some(&skipped)).is_empty());
+        assert_eq!(
+            notes(&targets, &HashSet::from(["chr1", "chr2"]), None).len(),
+            1
+        );
+        let names: Vec<String> = (1..=12).map(|i| format!("chr{i:02}")).collect();
+        assert_eq!(
+            list(names.iter().map(String::as_str)),
+            "chr01, chr02, chr03, chr04, chr05, chr06, chr07, chr08, chr09, chr10, and 2 more"
+        );
+    }
+}
@@ -0,0 +1,3802 @@
+//! The `methfast` command line: the options of the main command and of the
+//! subcommands, loading the inputs they name, and writing the summaries of
+//! the targets as text, Parquet or bigWig. Built with the `cli` feature.
+
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::confidence::CiMethod;
+use crate::error::ThreadError;
+use crate::format::{ColumnNames, Layout};
+use crate::parallel::*;
+use crate::samples::SampleSpec;
+use crate::{
+    Aggregation, Context, Format, MeanMode, MethInterval, MethRanges, OnOverlap, RecordFilter,
+    Stat, Strand, TargetInterval, TargetSummary, alias, annotation, array, bam, bgzf, bigwig, bins,
+    blacklist, chrom_report, compact, compare, compression, confidence, config, convert,
+    deconvolve, destrand, detect_format, dmr, error, is_stdin, is_stream, json, liftover, log,
+    matrix, memory, merge, meth_index, nearest, numbers, overlapping, parallel, parse_meth_bed,
+    parse_targets, pca, profile, progress, qc, read_chrom_sizes, read_header, samples, segment,
+    serve, smooth, streaming, summarize, summarize_ranges, tabix, tile_genome,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "methfast",
+    version,
+    about = "Extract weighted methylation values for target BED intervals.",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        value_name = "INPUTS",
+        help = "One or more METHYLATION_BED files followed by TARGET_BED (omitted with --targets); any one input may be `-` for stdin"
+    )]
+    inputs: Vec<PathBuf>,
+    #[arg(
+        long = "samples",
+        value_name = "TSV",
+        help = "Sample manifest with label<TAB>path[<TAB>format] lines; labels name the output columns"
+    )]
+    samples: Option<PathBuf>,
+    #[arg(
+        long = "targets",
+        value_name = "TARGET_BED",
+        help = "Target BED; all positional arguments are then methylation inputs"
+    )]
+    targets: Option<PathBuf>,
+    #[arg(
+        long = "region",
+        value_name = "CHROM:START-END",
+        value_parser = parse_region,
+        conflicts_with = "targets",
+        help = "Target region (1-based, inclusive, like samtools); repeatable, replaces TARGET_BED"
+    )]
+    regions: Vec<TargetInterval>,
+    #[arg(
+        long = "window",
+        value_name = "INT",
+        value_parser = clap::value_parser!(i32).range(1..),
+        conflicts_with_all = ["regions", "feature"],
+        help = "Read TARGET_BED as a chrom.sizes file and tile each chromosome into windows of this size"
+    )]
+    window: Option<i32>,
+
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Generic,
+        help = "Methylation file layout preset; explicit column flags override it"
+    )]
+    format: Format,
+    #[arg(
+        long = "mod-code",
+        value_name = "CODE",
+        help = "Only aggregate records with this modification code (bedMethyl column 4)"
+    )]
+    mod_code: Option<String>,
+    #[arg(
+        long = "context",
+        value_enum,
+        ignore_case = true,
+        help = "Only aggregate cytosines in this sequence context (formats with a context column)"
+    )]
+    context: Option<Context>,
+    #[arg(
+        long = "split-contexts",
+        conflicts_with_all = ["context", "fraction_bw", "array_betas"],
+        help = "Aggregate CpG, CHG and CHH records separately, as one labelled sample each"
+    )]
+    split_contexts: bool,
+    #[arg(
+        long = "min-coverage",
+        value_name = "N",
+        help = "Skip methylation records with a coverage below N"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "max-coverage",
+        value_name = "N|P%",
+        value_parser = parse_coverage_limit,
+        help = "Skip methylation records with a coverage above N, or above the P-th percentile of each sample (e.g. 99.9%)"
+    )]
+    max_coverage: Option<CoverageLimit>,
+    #[arg(
+        long = "clamp-coverage",
+        requires = "max_coverage",
+        help = "Cap the coverage of records above --max-coverage instead of skipping them"
+    )]
+    clamp_coverage: bool,
+    #[arg(
+        long = "destrand",
+        conflicts_with_all = ["stranded", "split_strands"],
+        help = "Merge the plus- and minus-strand records of each CpG before aggregating"
+    )]
+    destrand: bool,
+    #[arg(
+        long = "sort",
+        help = "Sort methylation records in memory instead of rejecting unsorted input"
+    )]
+    sort: bool,
+    #[arg(
+        long = "on-overlap",
+        value_enum,
+        value_name = "POLICY",
+        help = "What to do with methylation records overlapping the previous one: error (default), merge their reads, keep the first, or average them"
+    )]
+    on_overlap: Option<OnOverlap>,
+    #[arg(
+        long = "strict",
+        help = "Fail on methylation records with a coordinate, count, coverage or fraction that is not a number, instead of reading it as 0"
+    )]
+    strict: bool,
+    #[arg(
+        long = "blacklist",
+        value_name = "BED",
+        help = "Drop methylation records overlapping the regions of BED, e.g. the ENCODE blacklist"
+    )]
+    blacklist: Option<PathBuf>,
+    #[arg(
+        long = "clip-targets",
+        requires = "blacklist",
+        help = "Also trim blacklisted bases off the ends of the targets, dropping targets left empty"
+    )]
+    clip_targets: bool,
+    #[arg(
+        long = "mean-mode",
+        value_enum,
+        default_value_t = MeanMode::Weighted,
+        help = "Average the fractions of a target weighted by coverage, or unweighted"
+    )]
+    mean_mode: MeanMode,
+    #[arg(
+        long = "overlap-weighted",
+        help = "Weight records spanning several bases by the share of their bases inside the target"
+    )]
+    overlap_weighted: bool,
+    #[arg(
+        long = "trim",
+        value_name = "SHARE",
+        value_parser = parse_trim,
+        help = "Leave this share (0-0.5) of the lowest and of the highest record fractions out of each target's mean"
+    )]
+    trim: Option<f64>,
+    #[arg(
+        long = "winsorize",
+        requires = "trim",
+        help = "Clamp the --trim extremes to the remaining range instead of dropping them"
+    )]
+    winsorize: bool,
+    #[arg(
+        long = "weight-cap",
+        value_name = "K",
+        value_parser = clap::value_parser!(i32).range(1..),
+        help = "Weight each record by min(coverage, K) in the weighted mean"
+    )]
+    weight_cap: Option<i32>,
+    #[arg(
+        short = 'f',
+        long = "fraction-col",
+        help = "Methylation fraction column (1-based) [default: 4]"
+    )]
+    frac_col: Option<usize>,
+    #[arg(
+        short = 'c',
+        long = "coverage-col",
+        help = "Total coverage column (1-based) [default: 5]"
+    )]
+    cov_col: Option<usize>,
+    #[arg(short = 'm', long = "methylated-col")]
+    meth_col: Option<usize>,
+    #[arg(short = 'u', long = "unmethylated-col")]
+    unmeth_col: Option<usize>,
+    #[arg(
+        long = "frac-col-name",
+        value_name = "NAME",
+        help = "Select the fraction column by its header name"
+    )]
+    frac_col_name: Option<String>,
+    #[arg(
+        long = "cov-col-name",
+        value_name = "NAME",
+        help = "Select the coverage column by its header name"
+    )]
+    cov_col_name: Option<String>,
+    #[arg(
+        long = "meth-col-name",
+        value_name = "NAME",
+        help = "Select the methylated count column by its header name"
+    )]
+    meth_col_name: Option<String>,
+    #[arg(
+        long = "unmeth-col-name",
+        value_name = "NAME",
+        help = "Select the unmethylated count column by its header name"
+    )]
+    unmeth_col_name: Option<String>,
+    #[arg(
+        long = "one-based",
+        conflicts_with = "zero_based",
+        help = "Methylation positions are 1-based and fully closed, overriding the format preset"
+    )]
+    one_based: bool,
+    #[arg(
+        long = "zero-based",
+        help = "Methylation positions are 0-based and half-open, overriding the format preset"
+    )]
+    zero_based: bool,
+    #[arg(
+        long = "header",
+        value_enum,
+        value_name = "STYLE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "plain",
+        help = "Write a header line naming the output columns, optionally commented with #"
+    )]
+    header: Option<HeaderStyle>,
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = OutputFormat::Tsv,
+        help = "Output format"
+    )]
+    output_format: OutputFormat,
+    #[arg(
+        long = "matrix",
+        value_enum,
+        value_name = "SHAPE",
+        help = "Write a target x sample matrix of weighted fractions (wide) or one row per target and sample (long)"
+    )]
+    matrix: Option<Matrix>,
+    #[arg(
+        long = "matrix-coverage",
+        requires = "matrix",
+        help = "Also write a coverage column per sample in --matrix wide"
+    )]
+    matrix_coverage: bool,
+    #[arg(
+        long = "impute",
+        value_enum,
+        requires = "matrix",
+        help = "Fill in the fractions of targets missing from some samples of a --matrix"
+    )]
+    impute: Option<Impute>,
+    #[arg(
+        long = "impute-k",
+        value_name = "K",
+        default_value_t = 10,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Neighbouring targets averaged by --impute knn"
+    )]
+    impute_k: usize,
+    #[arg(
+        long = "columns",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "COLUMNS",
+        conflicts_with = "matrix",
+        help = "Comma-separated output columns in order: chrom, start, end, name, n_sites, coverage, fraction, methylated, unmethylated, median, sd, min, max, entropy, sites_per_kb, cpg_share, shrunk, ci_low, ci_high"
+    )]
+    columns: Option<Vec<Column>>,
+    #[arg(
+        long = "sort-output",
+        help = "Write targets sorted by chromosome and start instead of in TARGET_BED order"
+    )]
+    sort_output: bool,
+    #[arg(
+        long = "dedup-targets",
+        help = "Drop targets repeating the chromosome, start, end, strand and name of an earlier one instead of writing their rows again"
+    )]
+    dedup_targets: bool,
+    #[arg(
+        long = "sites",
+        conflicts_with_all = ["matrix", "columns", "split_strands"],
+        help = "Write one line per methylation record overlapping a target instead of target summaries"
+    )]
+    sites: bool,
+    #[arg(
+        long = "bins",
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        conflicts_with_all = ["sites", "columns", "split_strands", "keep_target_columns"],
+        help = "Also report the weighted fraction of N equal bins of every target (tsv output)"
+    )]
+    bins: Option<usize>,
+    #[arg(
+        long = "bin-layout",
+        value_enum,
+        default_value_t = BinLayout::Wide,
+        requires = "bins",
+        help = "Bin fractions as extra columns, or one line per bin instead of target summaries"
+    )]
+    bin_layout: BinLayout,
+    #[arg(
+        long = "counts",
+        conflicts_with = "columns",
+        help = "Also write summed methylated and unmethylated read counts per target"
+    )]
+    counts: bool,
+    #[arg(
+        long = "shrink",
+        help = "Also write a beta-binomial (empirical Bayes) shrunken fraction per target"
+    )]
+    shrink: bool,
+    #[arg(
+        long = "ci",
+        value_name = "LEVEL",
+        value_parser = parse_ci_level,
+        help = "Also write a LEVEL (e.g. 0.95) confidence interval of the methylation level per target"
+    )]
+    ci: Option<f64>,
+    #[arg(
+        long = "ci-method",
+        value_enum,
+        default_value_t = CiMethod::Wilson,
+        requires = "ci",
+        help = "Binomial interval for --ci"
+    )]
+    ci_method: CiMethod,
+    #[arg(
+        long = "density",
+        help = "Also write the overlapping sites per kilobase of target, and the covered share of --cpg-bed CpGs"
+    )]
+    density: bool,
+    #[arg(
+        long = "cpg-bed",
+        value_name = "BED",
+        help = "Reference CpG positions, for the cpg_share column of --density"
+    )]
+    cpg_bed: Option<PathBuf>,
+    #[arg(
+        long = "stats",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "STATS",
+        conflicts_with = "columns",
+        help = "Also write these statistics of the record fractions per target: median, sd, min, max, entropy"
+    )]
+    stats: Vec<Stat>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of weighted fractions in text output"
+    )]
+    precision: usize,
+    #[arg(
+        long = "percent",
+        help = "Write weighted fractions as percentages (0-100) in text output"
+    )]
+    percent: bool,
+    #[arg(
+        long = "na-value",
+        value_name = "STRING",
+        help = "Write this instead of the weighted fraction of targets without coverage (e.g. NA)"
+    )]
+    na_value: Option<String>,
+    #[arg(
+        long = "min-sites",
+        value_name = "N",
+        default_value_t = 0,
+        help = "Write NA (or --na-value) as the fraction of targets with fewer than N overlapping sites"
+    )]
+    min_sites: usize,
+    #[arg(
+        long = "chrom-sizes",
+        value_name = "FILE",
+        help = "chrom<TAB>size file for the bigWig header of --output-format bigwig"
+    )]
+    chrom_sizes: Option<PathBuf>,
+    #[arg(long = "bgzip", help = "Compress the output with BGZF (block gzip)")]
+    bgzip: bool,
+    #[arg(
+        long = "tabix",
+        help = "Write bgzipped output with a tabix index (<output>.tbi); targets must be sorted"
+    )]
+    tabix: bool,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    #[arg(
+        long = "fraction-bw",
+        value_name = "BIGWIG",
+        requires = "coverage_bw",
+        help = "Read methylation fractions from a bigWig instead of METHYLATION_BED"
+    )]
+    fraction_bw: Option<PathBuf>,
+    #[arg(
+        long = "coverage-bw",
+        value_name = "BIGWIG",
+        requires = "fraction_bw",
+        help = "Coverage bigWig paired with --fraction-bw"
+    )]
+    coverage_bw: Option<PathBuf>,
+    #[arg(
+        long = "bw-percent",
+        help = "The --fraction-bw track holds percentages (0-100)"
+    )]
+    bw_percent: bool,
+    #[arg(
+        long = "array-betas",
+        value_name = "TSV",
+        requires = "array_manifest",
+        help = "Probe x sample beta matrix from a methylation array"
+    )]
+    array_betas: Option<PathBuf>,
+    #[arg(
+        long = "array-manifest",
+        value_name = "BED",
+        requires = "array_betas",
+        help = "Probe positions (chrom, start, end, probe_id) for --array-betas"
+    )]
+    array_manifest: Option<PathBuf>,
+    #[arg(
+        long = "array-sample",
+        value_name = "NAME",
+        help = "Sample column of --array-betas to aggregate [default: first]"
+    )]
+    array_sample: Option<String>,
+    #[arg(
+        long = "feature",
+        value_name = "TYPE",
+        help = "Read TARGET_BED as GTF/GFF and use features of this type (gene, exon, transcript, ...) [default for .gtf/.gff files: gene]"
+    )]
+    feature: Option<String>,
+    #[arg(
+        long = "attribute",
+        value_name = "KEY",
+        help = "GTF/GFF attribute (e.g. gene_name) written after the target coordinates"
+    )]
+    attribute: Option<String>,
+    #[arg(
+        long = "no-names",
+        conflicts_with = "attribute",
+        help = "Do not copy the name column (4th column) of TARGET_BED into the output"
+    )]
+    no_names: bool,
+    #[arg(
+        long = "keep-target-columns",
+        conflicts_with_all = ["attribute", "columns", "sites"],
+        help = "Append the columns after end of TARGET_BED (name, score, ...) to each output line"
+    )]
+    keep_target_columns: bool,
+    #[arg(
+        long = "stranded",
+        help = "Only aggregate records on the target's strand (6th BED column or GTF strand)"
+    )]
+    stranded: bool,
+    #[arg(
+        long = "split-strands",
+        conflicts_with = "stranded",
+        help = "Report plus- and minus-strand records of each target separately"
+    )]
+    split_strands: bool,
+    #[arg(
+        long = "strand-col",
+        value_name = "INT",
+        help = "Strand column of the methylation input (1-based), overriding the format preset"
+    )]
+    strand_col: Option<usize>,
+    #[arg(
+        long = "chrom-alias",
+        value_name = "TSV",
+        help = "Chromosome alias file: a canonical name followed by its aliases on each line"
+    )]
+    chrom_alias: Option<PathBuf>,
+    #[arg(
+        long = "normalize-chroms",
+        help = "Match chromosome names with and without the chr prefix (chr1/1, chrM/MT)"
+    )]
+    normalize_chroms: bool,
+    #[arg(
+        long = "liftover",
+        value_name = "CHAIN",
+        help = "Lift the targets to the methylation assembly through a UCSC chain file"
+    )]
+    liftover: Option<PathBuf>,
+    #[arg(
+        long = "liftover-min-match",
+        value_name = "FRACTION",
+        default_value_t = 0.95,
+        value_parser = parse_min_match,
+        requires = "liftover",
+        help = "Share of a target's bases that must align for it to lift"
+    )]
+    liftover_min_match: f64,
+    #[arg(
+        long = "liftover-unmapped",
+        value_name = "FILE",
+        requires = "liftover",
+        help = "Write the targets that could not be lifted, with the reason, to FILE"
+    )]
+    liftover_unmapped: Option<PathBuf>,
+    #[arg(
+        long = "no-index",
+        help = "Read the whole methylation file even when a tabix/CSI index is present"
+    )]
+    no_index: bool,
+    #[arg(
+        long = "streaming",
+        conflicts_with_all = [
+            "sites", "bins", "split_contexts", "destrand", "sort", "fraction_bw", "array_betas",
+            "chrom_alias", "normalize_chroms", "on_overlap"
+        ],
+        help = "Summarize in one sweep over each sorted methylation file instead of loading it into memory"
+    )]
+    streaming: bool,
+    #[arg(
+        long = "compact",
+        conflicts_with = "streaming",
+        help = "Hold the methylation records packed in about half the memory; fractions are kept to 1/65535 and coverage saturates at 65535"
+    )]
+    compact: bool,
+    #[arg(
+        long = "cache",
+        conflicts_with = "streaming",
+        help = "Write the parsed records of each methylation file to <file>.mfi and load them from it on later runs"
+    )]
+    cache: bool,
+    #[arg(
+        long = "progress",
+        help = "Report the bytes of each input parsed and the targets summarized on stderr"
+    )]
+    progress: bool,
+    #[arg(
+        long = "max-memory",
+        value_name = "SIZE",
+        value_parser = memory::parse_size,
+        help = "Cap the memory of the loaded methylation records, e.g. 8G; larger inputs are summarized with --streaming when possible, otherwise the run stops"
+    )]
+    max_memory: Option<u64>,
+    #[arg(
+        long = "config",
+        value_name = "FILE",
+        global = true,
+        help = "Read long options from a TOML file; options on the command line take precedence"
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long = "profile",
+        value_name = "NAME",
+        global = true,
+        help = "Apply the [profile.NAME] table of the config file (methfast.toml without --config)"
+    )]
+    profile: Option<String>,
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        action = ArgAction::Count,
+        global = true,
+        help = "Log inputs, formats, record counts and stage timings on stderr; -vv adds per-chromosome records and skipped lines"
+    )]
+    verbose: u8,
+    #[arg(
+        long = "error-format",
+        value_enum,
+        default_value_t = ErrorFormat::Text,
+        global = true,
+        help = "How failures are reported on stderr; json writes one object with the kind of failure, its exit code and location"
+    )]
+    error_format: ErrorFormat,
+    #[arg(
+        short = 't',
+        long = "threads",
+        help = "Number of worker threads for parsing inputs and processing target intervals"
+    )]
+    threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Tab-separated columns.
+    Tsv,
+    /// One JSON object per target.
+    Ndjson,
+    /// Apache Parquet table; requires the `parquet` feature.
+    Parquet,
+    /// bigWig track of the weighted fractions; requires `--chrom-sizes`.
+    Bigwig,
+    /// chrom, start, end, weighted fraction.
+    Bedgraph,
+}
+
+/// Multi-sample table shapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Matrix {
+    /// One row per target, one fraction column per sample.
+    Wide,
+    /// One row per target and sample.
+    Long,
+}
+
+/// Shapes of `--bins` output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BinLayout {
+    /// `bin1 .. binN` fraction columns after the target summary.
+    Wide,
+    /// One line per target bin: the target, `bin, bin_start, bin_end` and a fraction per sample.
+    Long,
+}
+
+/// `--error-format` choices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ErrorFormat {
+    /// `Error: ...` lines.
+    #[default]
+    Text,
+    /// One JSON object with the kind of failure, its exit code and location.
+    Json,
+}
+
+/// How the `--header` line is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HeaderStyle {
+    Plain,
+    /// Prefixed with `#`, so BED tools skip it.
+    Comment,
+}
+
+/// How `--impute` fills in missing matrix values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Impute {
+    /// Mean fraction of the target across the samples that cover it.
+    Mean,
+    /// Mean fraction in the sample of the K targets with the closest values in
+    /// the other samples.
+    Knn,
+}
+
+/// Replaces the summaries of `rows` that `missing` rejects with imputed
+/// fractions; targets missing from every sample are left as they are.
+fn impute_fractions(
+    rows: &mut [Vec<TargetSummary>],
+    method: Impute,
+    k: usize,
+    missing: impl Fn(&TargetSummary) -> bool,
+) {
+    let observed: Vec<Vec<Option<f32>>> = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|summary| (!missing(summary)).then_some(summary.weighted_fraction))
+                .collect()
+        })
+        .collect();
+    let row_mean = |values: &[Option<f32>]| {
+        let present: Vec<f32> = values.iter().flatten().copied().collect();
+        (!present.is_empty()).then(|| present.iter().sum::<f32>() / present.len() as f32)
+    };
+    for (i, row) in rows.iter_mut().enumerate() {
+        for (column, summary) in row.iter_mut().enumerate() {
+            if observed[i][column].is_some() {
+                continue;
+            }
+            let value = match method {
+                Impute::Mean => row_mean(&observed[i]),
+                Impute::Knn => nearest_mean(&observed, i, column, k).or(row_mean(&observed[i])),
+            };
+            if let Some(value) = value {
+                summary.weighted_fraction = value;
+                summary.imputed = true;
+            }
+        }
+    }
+}
+
+/// Mean value in `column` of the `k` rows closest to row `i`, by root mean
+/// squared difference over the columns both rows observe.
+fn nearest_mean(observed: &[Vec<Option<f32>>], i: usize, column: usize, k: usize) -> Option<f32> {
+    let mut neighbours: Vec<(f32, f32)> = observed
+        .iter()
+        .enumerate()
+        .filter(|&(j, _)| j != i)
+        .filter_map(|(_, other)| {
+            let value = other[column]?;
+            let differences: Vec<f32> = observed[i]
+                .iter()
+                .zip(other)
+                .filter_map(|(a, b)| Some((a.as_ref()? - b.as_ref()?).powi(2)))
+                .collect();
+            if differences.is_empty() {
+                return None;
+            }
+            let distance = (differences.iter().sum::<f32>() / differences.len() as f32).sqrt();
+            Some((distance, value))
+        })
+        .collect();
+    if neighbours.is_empty() {
+        return None;
+    }
+    neighbours.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let nearest = &neighbours[..k.min(neighbours.len())];
+    Some(nearest.iter().map(|(_, value)| value).sum::<f32>() / nearest.len() as f32)
+}
+
+/// Beta prior fitted to the per-target methylation levels of one sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BetaPrior {
+    alpha: f64,
+    beta: f64,
+}
+
+impl BetaPrior {
+    /// Method-of-moments fit to the methylated share of covered targets;
+    /// `None` without at least two targets with differing levels.
+    fn fit(summaries: &[&TargetSummary]) -> Option<BetaPrior> {
+        let levels: Vec<f64> = summaries
+            .iter()
+            .filter(|summary| summary.sum_total_coverage > 0)
+            .map(|summary| summary.sum_methylated as f64 / summary.sum_total_coverage as f64)
+            .collect();
+        let n = levels.len() as f64;
+        if levels.len() < 2 {
+            return None;
+        }
+        let mean = levels.iter().sum::<f64>() / n;
+        let var = levels.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        let strength = mean * (1.0 - mean) / var - 1.0;
+        (var > 0.0 && strength > 0.0).then_some(BetaPrior {
+            alpha: mean * strength,
+            beta: (1.0 - mean) * strength,
+        })
+    }
+
+    /// Posterior mean methylation level of a target.
+    fn posterior_mean(&self, summary: &TargetSummary) -> f32 {
+        ((summary.sum_methylated as f64 + self.alpha)
+            / (summary.sum_total_coverage as f64 + self.alpha + self.beta)) as f32
+    }
+}
+
+/// Fills in the shrunk fractions of every summary column of `rows`, with a prior
+/// fitted per column (sample and strand).
+fn shrink_fractions(rows: &mut [Vec<TargetSummary>]) {
+    let columns = rows.first().map_or(0, Vec::len);
+    for column in 0..columns {
+        let summaries: Vec<&TargetSummary> = rows.iter().map(|row| &row[column]).collect();
+        let Some(prior) = BetaPrior::fit(&summaries) else {
+            continue;
+        };
+        for row in rows.iter_mut() {
+            row[column].shrunk_fraction = Some(prior.posterior_mean(&row[column]));
+        }
+    }
+}
+
+/// A per-sample value written for each summary; the default output writes
+/// `n_positions, total_coverage, weighted_fraction` for every sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    NSites,
+    Coverage,
+    Fraction,
+    Methylated,
+    Unmethylated,
+    Stat(Stat),
+    SitesPerKb,
+    /// Share of the reference CpGs covered.
+    CpgShare,
+    Shrunk,
+    /// Lower and upper `--ci` bounds.
+    CiLow,
+    CiHigh,
+}
+
+pub const SUMMARY_FIELDS: [Field; 3] = [Field::NSites, Field::Coverage, Field::Fraction];
+
+impl Field {
+    /// Column name, either bare or following a sample label.
+    fn name(self, labelled: bool) -> &'static str {
+        match (self, labelled) {
+            (Field::NSites, _) => "n_sites",
+            (Field::Coverage, true) => "coverage",
+            (Field::Coverage, false) => "total_coverage",
+            (Field::Fraction, true) => "fraction",
+            (Field::Fraction, false) => "weighted_fraction",
+            (Field::Methylated, true) => "methylated",
+            (Field::Methylated, false) => "sum_methylated",
+            (Field::Unmethylated, true) => "unmethylated",
+            (Field::Unmethylated, false) => "sum_unmethylated",
+            (Field::Stat(Stat::Median), _) => "fraction_median",
+            (Field::Stat(Stat::Sd), _) => "fraction_sd",
+            (Field::Stat(Stat::Min), _) => "fraction_min",
+            (Field::Stat(Stat::Max), _) => "fraction_max",
+            (Field::Stat(Stat::Entropy), _) => "fraction_entropy",
+            (Field::SitesPerKb, _) => "sites_per_kb",
+            (Field::CpgShare, _) => "cpg_share",
+            (Field::Shrunk, _) => "shrunk_fraction",
+            (Field::CiLow, _) => "ci_low",
+            (Field::CiHigh, _) => "ci_high",
+        }
+    }
+
+    fn format(self, summary: &TargetSummary, fractions: &FractionFormat) -> String {
+        let mut value = String::new();
+        self.push(&mut value, summary, fractions);
+        value
+    }
+
+    /// Appends the value of this field to `line`.
+    fn push(self, line: &mut String, summary: &TargetSummary, fractions: &FractionFormat) {
+        let na_value = || fractions.na_value.as_deref().unwrap_or("NA");
+        match self {
+            Field::NSites => numbers::push_int(line, summary.num_positions as i64),
+            Field::Coverage => numbers::push_int(line, summary.sum_total_coverage),
+            Field::Fraction => match fractions.missing(summary) {
+                Some(na_value) => line.push_str(na_value),
+                None => fractions.push(line, summary.weighted_fraction),
+            },
+            Field::Methylated => numbers::push_int(line, summary.methylated()),
+            Field::Unmethylated => {
+                numbers::push_int(line, summary.sum_total_coverage - summary.methylated())
+            }
+            // Entropy is in bits, not a fraction, so --percent does not apply.
+            Field::Stat(Stat::Entropy) => match (summary.stats, fractions.missing(summary)) {
+                (Some(stats), None) => {
+                    numbers::push_fixed(line, stats.entropy, fractions.precision)
+                }
+                (_, na_value) => line.push_str(na_value.unwrap_or("NA")),
+            },
+            Field::Stat(stat) => match (summary.stats, fractions.missing(summary)) {
+                (Some(stats), None) => fractions.push(line, stats.get(stat)),
+                (_, na_value) => line.push_str(na_value.unwrap_or("NA")),
+            },
+            Field::SitesPerKb => numbers::push_fixed(line, summary.sites_per_kb, 2),
+            Field::CpgShare => match summary.reference_sites {
+                Some(reference) if reference > 0 => {
+                    fractions.push(line, summary.num_positions as f32 / reference as f32)
+                }
+                _ => line.push_str(na_value()),
+            },
+            Field::Shrunk => match summary.shrunk_fraction {
+                Some(fraction) => fractions.push(line, fraction),
+                None => line.push_str(na_value()),
+            },
+            Field::CiLow | Field::CiHigh => match summary.ci {
+                Some((low, high)) => {
+                    fractions.push(line, if self == Field::CiLow { low } else { high })
+                }
+                None => line.push_str(na_value()),
+            },
+        }
+    }
+}
+
+/// How weighted fractions are written in text output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FractionFormat {
+    pub precision: usize,
+    /// Scale fractions to 0-100.
+    pub percent: bool,
+    /// Written instead of the fraction of uncovered targets.
+    pub na_value: Option<String>,
+    /// Targets with fewer sites are reported as missing, as `NA` without `na_value`.
+    pub min_sites: usize,
+}
+
+impl Default for FractionFormat {
+    fn default() -> Self {
+        FractionFormat {
+            precision: 4,
+            percent: false,
+            na_value: None,
+            min_sites: 0,
+        }
+    }
+}
+
+impl FractionFormat {
+    /// The `na_value` to write for a summary without a meaningful fraction.
+    pub fn missing(&self, summary: &TargetSummary) -> Option<&str> {
+        let na_value = match &self.na_value {
+            Some(na_value) => na_value.as_str(),
+            None if self.min_sites > 0 => "NA",
+            None => return None,
+        };
+        (!summary.imputed && self.lacks_fraction(summary)).then_some(na_value)
+    }
+
+    /// Whether a summary has no coverage or fewer than `min_sites` sites.
+    fn lacks_fraction(&self, summary: &TargetSummary) -> bool {
+        summary.sum_total_coverage == 0 || summary.num_positions < self.min_sites
+    }
+
+    pub fn format(&self, fraction: f32) -> String {
+        let mut value = String::new();
+        self.push(&mut value, fraction);
+        value
+    }
+
+    fn push(&self, line: &mut String, fraction: f32) {
+        let value = if self.percent {
+            fraction * 100.0
+        } else {
+            fraction
+        };
+        numbers::push_fixed(line, value, self.precision);
+    }
+}
+
+/// An output column chosen with `--columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Column {
+    Chrom,
+    Start,
+    End,
+    Name,
+    #[value(name = "n_sites")]
+    NSites,
+    Coverage,
+    Fraction,
+    Methylated,
+    Unmethylated,
+    Median,
+    Sd,
+    Min,
+    Max,
+    Entropy,
+    #[value(name = "sites_per_kb")]
+    SitesPerKb,
+    #[value(name = "cpg_share")]
+    CpgShare,
+    Shrunk,
+    #[value(name = "ci_low")]
+    CiLow,
+    #[value(name = "ci_high")]
+    CiHigh,
+}
+
+impl Column {
+    /// The per-sample field of this column, if it is not a target column.
+    fn field(self) -> Option<Field> {
+        match self {
+            Column::NSites => Some(Field::NSites),
+            Column::Coverage => Some(Field::Coverage),
+            Column::Fraction => Some(Field::Fraction),
+            Column::Methylated => Some(Field::Methylated),
+            Column::Unmethylated => Some(Field::Unmethylated),
+            Column::Median => Some(Field::Stat(Stat::Median)),
+            Column::Sd => Some(Field::Stat(Stat::Sd)),
+            Column::Min => Some(Field::Stat(Stat::Min)),
+            Column::Max => Some(Field::Stat(Stat::Max)),
+            Column::Entropy => Some(Field::Stat(Stat::Entropy)),
+            Column::SitesPerKb => Some(Field::SitesPerKb),
+            Column::CpgShare => Some(Field::CpgShare),
+            Column::Shrunk => Some(Field::Shrunk),
+            Column::CiLow => Some(Field::CiLow),
+            Column::CiHigh => Some(Field::CiHigh),
+            _ => None,
+        }
+    }
+}
+
+/// Target columns, or a run of per-sample fields repeated for every summary.
+#[derive(Debug, Clone, PartialEq)]
+enum ColumnGroup {
+    Target(Column),
+    Summary(Vec<Field>),
+}
+
+/// Groups consecutive per-sample columns so that they are written together
+/// for each sample, like the default `n_sites, total_coverage, weighted_fraction`.
+fn group_columns(columns: &[Column]) -> Vec<ColumnGroup> {
+    let mut groups: Vec<ColumnGroup> = Vec::new();
+    for &column in columns {
+        match (column.field(), groups.last_mut()) {
+            (Some(field), Some(ColumnGroup::Summary(fields))) => fields.push(field),
+            (Some(field), _) => groups.push(ColumnGroup::Summary(vec![field])),
+            (None, _) => groups.push(ColumnGroup::Target(column)),
+        }
+    }
+    groups
+}
+
+/// Formats the `--columns` selection of one target.
+fn format_columns(
+    target: &TargetInterval,
+    summaries: &[TargetSummary],
+    groups: &[ColumnGroup],
+    fractions: &FractionFormat,
+) -> String {
+    let mut values = Vec::new();
+    for group in groups {
+        match group {
+            ColumnGroup::Target(Column::Chrom) => values.push(target.chrom.clone()),
+            ColumnGroup::Target(Column::Start) => values.push(target.start.to_string()),
+            ColumnGroup::Target(Column::End) => values.push(target.end.to_string()),
+            ColumnGroup::Target(_) => {
+                values.push(target.name.clone().unwrap_or_else(|| ".".to_string()))
+            }
+            ColumnGroup::Summary(fields) => {
+                for summary in summaries {
+                    values.extend(fields.iter().map(|field| field.format(summary, fractions)));
+                }
+            }
+        }
+    }
+    values.join("\t")
+}
+
+/// Header of `--columns` output; per-sample columns are named as in [`header_line`].
+fn columns_header_line(
+    specs: Option<&[SampleSpec]>,
+    split_strands: bool,
+    groups: &[ColumnGroup],
+) -> String {
+    let mut names = Vec::new();
+    for group in groups {
+        match group {
+            ColumnGroup::Target(column) => names.push(
+                column
+                    .to_possible_value()
+                    .expect("no skipped columns")
+                    .get_name()
+                    .to_string(),
+            ),
+            ColumnGroup::Summary(fields) => {
+                let header = header_line(specs, false, split_strands, fields);
+                names.extend(header.split('\t').skip(3).map(str::to_string));
+            }
+        }
+    }
+    names.join("\t")
+}
+
+/// The target coordinates, followed by the name of named targets.
+pub fn format_target(target: &TargetInterval) -> String {
+    let mut line = String::with_capacity(64);
+    line.push_str(&target.chrom);
+    line.push('\t');
+    numbers::push_int(&mut line, target.start);
+    line.push('\t');
+    numbers::push_int(&mut line, target.end);
+    if let Some(name) = &target.name {
+        line.push('\t');
+        line.push_str(name);
+    }
+    line
+}
+
+/// Formats the target coordinates followed by `fields` of every summary.
+pub fn format_row(
+    target: &TargetInterval,
+    summaries: &[TargetSummary],
+    fields: &[Field],
+    fractions: &FractionFormat,
+) -> String {
+    let mut line = format_target(target);
+    for summary in summaries {
+        for field in fields {
+            line.push('\t');
+            field.push(&mut line, summary, fractions);
+        }
+    }
+    push_extra(&mut line, target);
+    line
+}
+
+/// Appends the kept target columns to an output line.
+fn push_extra(line: &mut String, target: &TargetInterval) {
+    for column in &target.extra {
+        line.push('\t');
+        line.push_str(column);
+    }
+}
+
+/// Formats one line per sample, or per sample and strand with `split_strands`,
+/// each holding the target, the sample label and its summary.
+fn format_long_rows(
+    target: &TargetInterval,
+    summaries: &[TargetSummary],
+    labels: &[String],
+    split_strands: bool,
+    fields: &[Field],
+    fractions: &FractionFormat,
+) -> String {
+    let prefix = format_target(target);
+    let strands: &[&str] = if split_strands { &["+", "-"] } else { &[""] };
+    let mut lines = Vec::with_capacity(summaries.len());
+    for (label, sample) in labels.iter().zip(summaries.chunks(strands.len())) {
+        for (strand, summary) in strands.iter().zip(sample) {
+            let mut line = format!("{prefix}\t{label}");
+            if split_strands {
+                line.push('\t');
+                line.push_str(strand);
+            }
+            for field in fields {
+                line.push('\t');
+                line.push_str(&field.format(summary, fractions));
+            }
+            push_extra(&mut line, target);
+            lines.push(line);
+        }
+    }
+    lines.join("\n")
+}
+
+/// Formats one `--sites` line: the target, the sample label when there is
+/// one, and the site's coordinates, fraction and coverage.
+fn format_site_line(
+    target: &TargetInterval,
+    label: Option<&str>,
+    site: &MethInterval,
+    fractions: &FractionFormat,
+) -> String {
+    let mut line = format_target(target);
+    if let Some(label) = label {
+        line.push('\t');
+        line.push_str(label);
+    }
+    line.push_str(&format!(
+        "\t{}\t{}\t{}\t{}",
+        site.start,
+        site.end,
+        fractions.format(site.fraction),
+        site.coverage
+    ));
+    line
+}
+
+/// Formats a 4-column bedGraph line holding the weighted fraction.
+fn format_bedgraph_line(
+    target: &TargetInterval,
+    summary: &TargetSummary,
+    fractions: &FractionFormat,
+) -> String {
+    format!(
+        "{}\t{}\t{}\t{}",
+        target.chrom,
+        target.start,
+        target.end,
+        Field::Fraction.format(summary, fractions)
+    )
+}
+
+/// Layout of `format` with the explicit column flags applied on top.
+fn resolve_layout(cli: &Cli, format: Format) -> Layout {
+    let mut layout = format.layout();
+    if let Some(col) = cli.frac_col {
+        layout.frac_col = col;
+    }
+    if let Some(col) = cli.cov_col {
+        layout.cov_col = col;
+    }
+    if let Some(col) = cli.meth_col {
+        layout.meth_col = col;
+    }
+    if let Some(col) = cli.unmeth_col {
+        layout.unmeth_col = col;
+    }
+    if let Some(col) = cli.strand_col {
+        layout.strand_col = col;
+    }
+    if cli.one_based {
+        layout.one_based = true;
+    } else if cli.zero_based {
+        layout.one_based = false;
+    }
+    layout
+}
+
+fn column_names(cli: &Cli) -> ColumnNames {
+    ColumnNames {
+        frac: cli.frac_col_name.clone(),
+        cov: cli.cov_col_name.clone(),
+        meth: cli.meth_col_name.clone(),
+        unmeth: cli.unmeth_col_name.clone(),
+    }
+}
+
+/// A loaded methylation input.
+enum Sample {
+    Ranges(MethRanges),
+    /// Records packed with `--compact`, unpacked per target.
+    Compact(compact::CompactRanges),
+    /// Bgzipped file with a tabix/CSI index; records are fetched per target.
+    Indexed {
+        path: PathBuf,
+        index: tabix::Index,
+        layout: Layout,
+        /// Sequence context kept with `--split-contexts`.
+        context: Option<Context>,
+    },
+}
+
+impl Sample {
+    /// The chromosomes with records.
+    fn chroms(&self) -> Vec<&str> {
+        match self {
+            Sample::Ranges(ranges) => ranges
+                .by_chrom
+                .iter()
+                .filter(|(_, intervals)| !intervals.is_empty())
+                .map(|(chrom, _)| chrom.as_str())
+                .collect(),
+            Sample::Compact(ranges) => ranges.chroms().collect(),
+            Sample::Indexed { index, .. } => index.chroms().collect(),
+        }
+    }
+
+    /// Loads `spec` once per entry of `contexts`, keeping only the records in
+    /// that sequence context; `None` keeps every record.
+    fn load(
+        spec: &SampleSpec,
+        cli: &Cli,
+        filter: &RecordFilter,
+        contexts: &[Option<Context>],
+    ) -> Result<Vec<Sample>, Box<dyn Error>> {
+        let path = spec.path.as_path();
+        let format = match spec.format {
+            Format::Auto => detect_format(path)?,
+            format => format,
+        };
+        log::info(|| {
+            let size = std::fs::metadata(path)
+                .map_or("unknown".to_string(), |meta| meta.len().to_string());
+            format!(
+                "input path={} format={} bytes={size}",
+                path.display(),
+                format.name()
+            )
+        });
+        let layout = resolve_layout(cli, format);
+        let names = column_names(cli);
+        let alignment = if is_stream(path) {
+            None
+        } else {
+            bam::sniff(path)?
+        };
+        let split = contexts.iter().any(Option::is_some);
+        if split && (alignment.is_some() || layout.context_col == 0) {
+            return Err(format!(
+                "Error: {}: --split-contexts needs an input with a context column",
+                path.display()
+            )
+            .into());
+        }
+        match alignment {
+            Some(bam::AlignmentKind::Cram) => Err(bam::cram_unsupported(path)),
+            Some(bam::AlignmentKind::Bam) => {
+                let mut ranges = bam::pileup_bam(path, cli.mod_code.as_deref().unwrap_or("m"))?;
+                ranges.by_chrom.retain(|chrom, _| filter.keeps_chrom(chrom));
+                if let Some(blacklist) = &filter.blacklist {
+                    blacklist.mask(&mut ranges);
+                }
+                Ok(vec![Sample::from_ranges(ranges, cli.compact)])
+            }
+            None => {
+                let index = if cli.no_index || is_stdin(path) {
+                    None
+                } else {
+                    tabix::Index::find(path)?
+                };
+                match index {
+                    Some(index) => {
+                        let layout = if names.is_empty() {
+                            layout
+                        } else {
+                            names.apply(
+                                &layout,
+                                &read_header(&mut compression::open(path)?, path)?,
+                            )?
+                        };
+                        Ok(contexts
+                            .iter()
+                            .map(|&context| Sample::Indexed {
+                                path: path.to_path_buf(),
+                                index: index.clone(),
+                                layout: layout.clone(),
+                                context,
+                            })
+                            .collect())
+                    }
+                    None => {
+                        // The binary index holds every record of the format's
+                        // own layout, so it only stands in for plain parsing.
+                        if !is_stream(path)
+                            && names.is_empty()
+                            && !split
+                            && filter.context.is_none()
+                            && filter.on_overlap == OnOverlap::Error
+                            && !filter.strict
+                            && layout == format.layout()
+                            && let Some(ranges) = match cli.cache {
+                                true => meth_index::load_cached(path, format, filter)?,
+                                false => meth_index::load(path, format, filter)?,
+                            }
+                        {
+                            return Ok(vec![Sample::from_ranges(ranges, cli.compact)]);
+                        }
+                        if cli.compact {
+                            return Ok(compact::CompactRanges::parse(
+                                path, &layout, &names, filter, contexts,
+                            )?
+                            .into_iter()
+                            .map(Sample::Compact)
+                            .collect());
+                        }
+                        Ok(parse_meth_bed(path, &layout, &names, filter, contexts)?
+                            .into_iter()
+                            .map(Sample::Ranges)
+                            .collect())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loaded records, packed with `--compact`.
+    fn from_ranges(ranges: MethRanges, compact: bool) -> Sample {
+        match compact {
+            true => Sample::Compact(compact::CompactRanges::pack(ranges)),
+            false => Sample::Ranges(ranges),
+        }
+    }
+
+    fn rename_chroms(&mut self, rename: &dyn Fn(&str) -> String) {
+        match self {
+            Sample::Ranges(ranges) => ranges.rename_chroms(rename),
+            Sample::Compact(ranges) => ranges.rename_chroms(rename),
+            Sample::Indexed { index, .. } => index.rename_chroms(rename),
+        }
+    }
+
+    /// The sorted intervals of `target`'s chromosome that may overlap it;
+    /// `reader` caches the per-thread handle of indexed samples.
+    fn intervals<'a>(
+        &'a self,
+        target: &TargetInterval,
+        reader: &mut Option<bgzf::BgzfReader>,
+        filter: &RecordFilter,
+    ) -> Result<Cow<'a, [MethInterval]>, Box<dyn Error>> {
+        match self {
+            Sample::Ranges(ranges) => Ok(Cow::Borrowed(
+                ranges
+                    .by_chrom
+                    .get(&target.chrom)
+                    .map_or(&[][..], Vec::as_slice),
+            )),
+            Sample::Compact(ranges) if filter.destrand => {
+                // Unpack a base either side so CpGs on the target edges keep both strands.
+                let intervals = ranges.fetch(&target.chrom, target.start - 1, target.end + 1);
+                Ok(Cow::Owned(destrand(intervals)))
+            }
+            Sample::Compact(ranges) => Ok(Cow::Owned(ranges.fetch(
+                &target.chrom,
+                target.start,
+                target.end,
+            ))),
+            Sample::Indexed {
+                path,
+                index,
+                layout,
+                context,
+            } => {
+                let reader = match reader {
+                    Some(reader) => reader,
+                    None => reader.insert(bgzf::BgzfReader::open(path)?),
+                };
+                let context_filter;
+                let filter = match context {
+                    Some(_) => {
+                        context_filter = RecordFilter {
+                            context: *context,
+                            ..filter.clone()
+                        };
+                        &context_filter
+                    }
+                    None => filter,
+                };
+                if !filter.destrand {
+                    return Ok(Cow::Owned(index.fetch(reader, target, layout, filter)?));
+                }
+                // Fetch a base either side so CpGs on the target edges keep both strands.
+                let widened = TargetInterval {
+                    start: (target.start - 1).max(0),
+                    end: target.end + 1,
+                    ..target.clone()
+                };
+                let intervals = index.fetch(reader, &widened, layout, filter)?;
+                Ok(Cow::Owned(destrand(intervals)))
+            }
+        }
+    }
+
+    /// Summarizes `target` once per entry of `strands`.
+    fn summarize(
+        &self,
+        target: &TargetInterval,
+        strands: &[Strand],
+        reader: &mut Option<bgzf::BgzfReader>,
+        filter: &RecordFilter,
+        aggregation: &Aggregation,
+    ) -> Result<Vec<TargetSummary>, Box<dyn Error>> {
+        match self {
+            Sample::Ranges(ranges) => Ok(strands
+                .iter()
+                .map(|&strand| summarize_ranges(ranges, target, strand, aggregation))
+                .collect()),
+            Sample::Compact(_) | Sample::Indexed { .. } => {
+                let intervals = self.intervals(target, reader, filter)?;
+                Ok(strands
+                    .iter()
+                    .map(|&strand| summarize(&intervals, target, strand, aggregation))
+                    .collect())
+            }
+        }
+    }
+
+    /// The records overlapping `target` on `strand`.
+    fn sites(
+        &self,
+        target: &TargetInterval,
+        strand: Strand,
+        reader: &mut Option<bgzf::BgzfReader>,
+        filter: &RecordFilter,
+    ) -> Result<Vec<MethInterval>, Box<dyn Error>> {
+        let intervals = self.intervals(target, reader, filter)?;
+        Ok(overlapping(&intervals, target, strand).cloned().collect())
+    }
+}
+
+/// Reads the reference CpG positions of `--cpg-bed` as sorted intervals.
+fn read_reference_sites(path: &Path) -> Result<MethRanges, Box<dyn Error>> {
+    let mut by_chrom: HashMap<String, Vec<MethInterval>> = HashMap::new();
+    for site in parse_targets(path, false, false)? {
+        by_chrom.entry(site.chrom).or_default().push(MethInterval {
+            start: site.start,
+            end: site.end,
+            fraction: 0.0,
+            coverage: 0,
+            strand: site.strand,
+        });
+    }
+    for sites in by_chrom.values_mut() {
+        sites.sort_by_key(|site| (site.start, site.end));
+    }
+    Ok(MethRanges { by_chrom })
+}
+
+/// Counts the targets repeating the chromosome, span, strand and name of an
+/// earlier one, and with `drop` removes them.
+fn duplicate_targets(targets: &mut Vec<TargetInterval>, drop: bool) -> usize {
+    let first: Vec<bool> = {
+        let mut seen = HashSet::new();
+        targets
+            .iter()
+            .map(|target| {
+                seen.insert((
+                    target.chrom.as_str(),
+                    target.start,
+                    target.end,
+                    target.strand,
+                    target.name.as_deref(),
+                ))
+            })
+            .collect()
+    };
+    let duplicates = first.iter().filter(|&&first| !first).count();
+    if drop && duplicates > 0 {
+        let mut first = first.into_iter();
+        targets.retain(|_| first.next().unwrap_or(true));
+    }
+    duplicates
+}
+
+/// Whether targets are in the order [`sort_targets`] puts them in.
+fn targets_sorted(targets: &[TargetInterval]) -> bool {
+    targets.windows(2).all(|pair| {
+        (pair[0].chrom.as_str(), pair[0].start, pair[0].end)
+            <= (pair[1].chrom.as_str(), pair[1].start, pair[1].end)
+    })
+}
+
+/// Sorts targets by chromosome name, then start and end, like `sort -k1,1 -k2,2n`.
+fn sort_targets(targets: &mut [TargetInterval]) {
+    targets.sort_by(|a, b| {
+        (a.chrom.as_str(), a.start, a.end).cmp(&(b.chrom.as_str(), b.start, b.end))
+    });
+}
+
+/// Applies the coverage thresholds to loaded samples: BAM, bigWig and array
+/// inputs are not parsed record by record, and percentile ceilings need every
+/// record of a sample.
+fn apply_coverage_limits(
+    samples: &mut [Sample],
+    specs: &[SampleSpec],
+    filter: &RecordFilter,
+    limit: Option<CoverageLimit>,
+) -> Result<(), Box<dyn Error>> {
+    let percentile = match limit {
+        Some(CoverageLimit::Percentile(percentile)) => Some(percentile),
+        _ => None,
+    };
+    if !filter.filters_intervals() && percentile.is_none() {
+        return Ok(());
+    }
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let ranges = match sample {
+            Sample::Ranges(ranges) => ranges,
+            Sample::Indexed { path, .. } if percentile.is_some() => {
+                return Err(format!(
+                    "Error: {}: a percentile --max-coverage needs every record; pass --no-index",
+                    path.display()
+                )
+                .into());
+            }
+            Sample::Indexed { .. } => continue,
+            Sample::Compact(_) if percentile.is_some() => {
+                return Err(
+                    "Error: a percentile --max-coverage cannot be combined with --compact".into(),
+                );
+            }
+            // The thresholds were applied while parsing.
+            Sample::Compact(_) => continue,
+        };
+        let mut filter = filter.clone();
+        if let Some(percentile) = percentile {
+            filter.max_coverage = ranges.coverage_percentile(percentile);
+            if let (Some(spec), Some(cap)) = (specs.get(i), filter.max_coverage) {
+                log::info(|| {
+                    format!(
+                        "max_coverage sample={} percentile={percentile} reads={cap}",
+                        spec.label
+                    )
+                });
+            }
+        }
+        ranges.retain(|interval| filter.apply(interval));
+    }
+    Ok(())
+}
+
+/// A `--max-coverage` ceiling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CoverageLimit {
+    Reads(i32),
+    /// Percentile (0-100) of the coverages of each sample.
+    Percentile(f64),
+}
+
+fn parse_trim(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(trim) if (0.0..0.5).contains(&trim) => Ok(trim),
+        _ => Err(format!(
+            "invalid share {value}; expected a number in [0, 0.5)"
+        )),
+    }
+}
+
+fn parse_ci_level(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(level) if level > 0.0 && level < 1.0 => Ok(level),
+        _ => Err(format!(
+            "invalid confidence level {value}; expected a number in (0, 1)"
+        )),
+    }
+}
+
+fn parse_min_match(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(format!(
+            "invalid fraction {value}; expected a number in (0, 1]"
+        )),
+    }
+}
+
+fn parse_coverage_limit(value: &str) -> Result<CoverageLimit, String> {
+    match value.strip_suffix('%') {
+        Some(percentile) => match percentile.parse::<f64>() {
+            Ok(p) if p > 0.0 && p <= 100.0 => Ok(CoverageLimit::Percentile(p)),
+            _ => Err(format!("invalid percentile {value}; expected 0-100%")),
+        },
+        None => value
+            .parse()
+            .map(CoverageLimit::Reads)
+            .map_err(|_| format!("invalid coverage {value}; expected N or P%")),
+    }
+}
+
+/// Parses a `chrom:start-end` region with 1-based inclusive coordinates into a
+/// BED interval. Thousands separators are allowed, as in `chr1:100,000-200,000`.
+pub fn parse_region(region: &str) -> Result<TargetInterval, String> {
+    let invalid = || format!("invalid region {region}; expected CHROM:START-END");
+    let (chrom, range) = region.rsplit_once(':').ok_or_else(invalid)?;
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let position = |s: &str| s.replace(',', "").parse::<i32>().map_err(|_| invalid());
+    let (start, end) = (position(start)?, position(end)?);
+    if chrom.is_empty() || start < 1 || end < start {
+        return Err(invalid());
+    }
+    Ok(TargetInterval {
+        chrom: chrom.to_string(),
+        start: start - 1,
+        end,
+        name: None,
+        strand: Strand::Unknown,
+        extra: Vec::new(),
+    })
+}
+
+/// Methylation inputs and the optional target BED.
+type SplitInputs<'a> = (&'a [PathBuf], Option<&'a Path>);
+
+/// Splits the positionals into methylation inputs and the target BED, which
+/// is either `--targets` or the last positional. With `--region` there is no
+/// target BED.
+fn split_inputs(cli: &Cli) -> Result<SplitInputs<'_>, Box<dyn Error>> {
+    let (methylation, target_bed) = match &cli.targets {
+        Some(target_bed) => (cli.inputs.as_slice(), Some(target_bed.as_path())),
+        None if !cli.regions.is_empty() => (cli.inputs.as_slice(), None),
+        None => match cli.inputs.split_last() {
+            Some((target_bed, methylation)) => (methylation, Some(target_bed.as_path())),
+            None => return Err("Error: expected TARGET_BED, --targets or --region".into()),
+        },
+    };
+    let stdin_inputs = methylation.iter().filter(|path| is_stdin(path)).count()
+        + usize::from(target_bed.is_some_and(is_stdin));
+    if stdin_inputs > 1 {
+        return Err("Error: only one input can be read from stdin".into());
+    }
+    Ok((methylation, target_bed))
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    if let Some(threads) = cli.threads
+        && threads > 0
+    {
+        parallel::set_threads(threads);
+    }
+    if cli.progress {
+        progress::enable();
+    }
+
+    let mut filter = build_filter(&cli)?;
+    let (methylation, target_bed) = split_inputs(&cli)?;
+    let specs = sample_specs(&cli, methylation)?;
+    let mut targets = load_targets(&cli, target_bed, filter.blacklist.as_deref())?;
+    // Records on other chromosomes are skipped while parsing, unless renamed
+    // chromosomes may match or a coverage percentile needs every record.
+    if !(cli.chrom_alias.is_some()
+        || cli.normalize_chroms
+        || matches!(cli.max_coverage, Some(CoverageLimit::Percentile(_))))
+    {
+        filter.chroms = Some(Arc::new(
+            targets.iter().map(|target| target.chrom.clone()).collect(),
+        ));
+        filter.skipped_chroms = Some(Arc::default());
+    }
+    let streaming = use_streaming(&cli, &specs)?;
+    let (mut samples, specs) = load_samples(&cli, &specs, &filter, streaming)?;
+
+    // Summarizing, and writing as the rows are summarized.
+    let _stage = log::Stage::start("summarize");
+    let mut reference = cli
+        .cpg_bed
+        .as_deref()
+        .map(read_reference_sites)
+        .transpose()?;
+    if cli.chrom_alias.is_some() || cli.normalize_chroms {
+        let aliases = alias::ChromAliases::new(cli.chrom_alias.as_deref(), cli.normalize_chroms)?;
+        let rename = aliases.renamer(targets.iter().map(|target| target.chrom.as_str()));
+        for sample in &mut samples {
+            sample.rename_chroms(&rename);
+        }
+        if let Some(reference) = &mut reference {
+            reference.rename_chroms(&rename);
+        }
+    }
+    if !streaming && !targets.is_empty() {
+        let loaded: HashSet<&str> = samples.iter().flat_map(Sample::chroms).collect();
+        for note in chrom_report::notes(&targets, &loaded, filter.skipped_chroms.as_deref()) {
+            eprintln!("{note}");
+        }
+    }
+    // Keep the columns aligned when only some targets are named.
+    if targets.iter().any(|target| target.name.is_some()) {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+    if cli.sort_output {
+        sort_targets(&mut targets);
+    }
+    let summarizer = Summarizer {
+        cli: &cli,
+        samples: &samples,
+        filter: &filter,
+        aggregation: Aggregation {
+            mean: cli.mean_mode,
+            overlap_weighted: cli.overlap_weighted,
+            trim: cli.trim.unwrap_or(0.0),
+            winsorize: cli.winsorize,
+            weight_cap: cli.weight_cap,
+            stats: !cli.stats.is_empty()
+                || cli
+                    .columns
+                    .iter()
+                    .flatten()
+                    .any(|column| matches!(column.field(), Some(Field::Stat(_)))),
+        },
+        reference: reference.as_ref(),
+        progress: progress::Progress::targets(targets.len()),
+    };
+    if cli.sites {
+        return write_sites(&summarizer, &targets, &specs);
+    }
+    write_summaries(&summarizer, &targets, &specs, streaming)
+}
+
+/// The record filter of the command line, before the target chromosomes are known.
+fn build_filter(cli: &Cli) -> Result<RecordFilter, Box<dyn Error>> {
+    Ok(RecordFilter {
+        mod_code: cli.mod_code.clone(),
+        context: cli.context,
+        min_coverage: cli.min_coverage,
+        max_coverage: match cli.max_coverage {
+            Some(CoverageLimit::Reads(reads)) => Some(reads),
+            _ => None,
+        },
+        clamp_coverage: cli.clamp_coverage,
+        destrand: cli.destrand,
+        sort: cli.sort,
+        on_overlap: cli.on_overlap.unwrap_or_default(),
+        strict: cli.strict,
+        blacklist: cli
+            .blacklist
+            .as_deref()
+            .map(blacklist::Blacklist::read)
+            .transpose()?
+            .map(Arc::new),
+        chroms: None,
+        skipped_chroms: None,
+    })
+}
+
+/// The inputs to load, from `--samples` or the METHYLATION_BED arguments,
+/// checked against the inputs that replace them.
+fn sample_specs(cli: &Cli, methylation: &[PathBuf]) -> Result<Vec<SampleSpec>, Box<dyn Error>> {
+    let specs = match &cli.samples {
+        Some(_) if !methylation.is_empty() => {
+            return Err("Error: expected only TARGET_BED with --samples".into());
+        }
+        Some(manifest) => samples::read_manifest(manifest, cli.format)?,
+        None => methylation
+            .iter()
+            .map(|path| SampleSpec::from_path(path, cli.format))
+            .collect(),
+    };
+    let alternative = if cli.fraction_bw.is_some() {
+        Some("--fraction-bw")
+    } else if cli.array_betas.is_some() {
+        Some("--array-betas")
+    } else {
+        None
+    };
+    match alternative {
+        Some(option) if !methylation.is_empty() => {
+            Err(format!("Error: expected only TARGET_BED with {option}").into())
+        }
+        None if specs.is_empty() => Err("Error: expected at least one METHYLATION_BED".into()),
+        _ => Ok(specs),
+    }
+}
+
+/// The targets of TARGET_BED, the genome tiles or `--region`, lifted over,
+/// clipped and checked for duplicates as the options ask.
+fn load_targets(
+    cli: &Cli,
+    target_bed: Option<&Path>,
+    blacklist: Option<&blacklist::Blacklist>,
+) -> Result<Vec<TargetInterval>, Box<dyn Error>> {
+    let _stage = log::Stage::start("targets");
+    let mut targets = match (target_bed, cli.window) {
+        (None, _) => cli.regions.clone(),
+        (Some(sizes), Some(window)) => tile_genome(sizes, window)?,
+        (Some(path), None) if cli.feature.is_some() || annotation::is_annotation(path) => {
+            annotation::parse_annotation(
+                path,
+                cli.feature.as_deref().unwrap_or("gene"),
+                cli.attribute.as_deref(),
+            )?
+        }
+        (Some(path), None) => parse_targets(path, !cli.no_names, cli.keep_target_columns)?,
+    };
+    if let Some(chain) = &cli.liftover {
+        targets = lift_targets(
+            targets,
+            &liftover::Chains::read(chain)?,
+            cli.liftover_min_match,
+            cli.liftover_unmapped.as_deref(),
+        )?;
+    }
+    if cli.clip_targets
+        && let Some(blacklist) = blacklist
+    {
+        targets = targets
+            .into_iter()
+            .filter_map(|target| blacklist.clip(target))
+            .collect();
+    }
+    let duplicates = duplicate_targets(&mut targets, cli.dedup_targets);
+    match duplicates {
+        0 => {}
+        n if cli.dedup_targets => eprintln!("Note: dropped {n} duplicated targets"),
+        n => eprintln!(
+            "Note: {n} targets repeat the chromosome, start, end, strand and name of an earlier one \
+             and get repeated rows; pass --dedup-targets to drop them"
+        ),
+    }
+    let sorted = targets_sorted(&targets);
+    if !sorted && !cli.sort_output {
+        eprintln!(
+            "Note: the targets are not sorted by chromosome and start and are written in \
+             TARGET_BED order; pass --sort-output to write them sorted"
+        );
+    }
+    log::info(|| {
+        let chroms: HashSet<&str> = targets.iter().map(|target| target.chrom.as_str()).collect();
+        format!(
+            "targets count={} chroms={} sorted={sorted} duplicates={duplicates}",
+            targets.len(),
+            chroms.len(),
+        )
+    });
+    Ok(targets)
+}
+
+/// Whether the inputs are streamed against the targets rather than loaded,
+/// as `--streaming` asks or as `--max-memory` needs; sets the memory limit.
+fn use_streaming(cli: &Cli, specs: &[SampleSpec]) -> Result<bool, Box<dyn Error>> {
+    let Some(limit) = cli.max_memory else {
+        return Ok(cli.streaming);
+    };
+    if cli.streaming {
+        return Ok(true);
+    }
+    let mut streaming = false;
+    let needed = estimate_memory(specs, cli)?;
+    if needed > limit {
+        match streaming_blocker(specs, cli)? {
+            None => {
+                eprintln!(
+                    "Note: the methylation records need about {}, more than --max-memory {}; summarizing with --streaming",
+                    progress::human_bytes(needed),
+                    progress::human_bytes(limit)
+                );
+                streaming = true;
+            }
+            Some(option) => {
+                return Err(format!(
+                    "Error: the methylation records need about {}, more than --max-memory {}, and {option} cannot be streamed; \
+                     bgzip and tabix-index the inputs or raise the limit",
+                    progress::human_bytes(needed),
+                    progress::human_bytes(limit)
+                )
+                .into());
+            }
+        }
+    }
+    memory::set_limit(limit);
+    Ok(streaming)
+}
+
+/// The loaded samples, none when `streaming`, and the spec of each: one
+/// `<label>_<context>` sample per input and context with `--split-contexts`.
+fn load_samples(
+    cli: &Cli,
+    specs: &[SampleSpec],
+    filter: &RecordFilter,
+    streaming: bool,
+) -> Result<(Vec<Sample>, Vec<SampleSpec>), Box<dyn Error>> {
+    let _stage = log::Stage::start("load");
+    let contexts = if cli.split_contexts {
+        vec![Some(Context::CpG), Some(Context::Chg), Some(Context::Chh)]
+    } else {
+        vec![None]
+    };
+    let mut samples = if streaming {
+        // Streamed once the targets are known.
+        Vec::new()
+    } else if let (Some(fraction_bw), Some(coverage_bw)) = (&cli.fraction_bw, &cli.coverage_bw) {
+        vec![Sample::Ranges(bigwig::pair_tracks(
+            &bigwig::read_bigwig(fraction_bw)?,
+            &bigwig::read_bigwig(coverage_bw)?,
+            cli.bw_percent,
+        ))]
+    } else if let (Some(betas), Some(manifest)) = (&cli.array_betas, &cli.array_manifest) {
+        vec![Sample::Ranges(array::load_betas(
+            betas,
+            manifest,
+            cli.array_sample.as_deref(),
+        )?)]
+    } else {
+        specs
+            .par_iter()
+            .map(|spec| Sample::load(spec, cli, filter, &contexts).map_err(ThreadError::from))
+            .collect::<Result<Vec<Vec<Sample>>, ThreadError>>()?
+            .into_iter()
+            .flatten()
+            .collect()
+    };
+    let specs: Vec<SampleSpec> = specs
+        .iter()
+        .flat_map(|spec| {
+            contexts.iter().map(|context| match context {
+                Some(context) => SampleSpec {
+                    label: format!("{}_{}", spec.label, context.name()),
+                    ..spec.clone()
+                },
+                None => spec.clone(),
+            })
+        })
+        .collect();
+    apply_coverage_limits(&mut samples, &specs, filter, cli.max_coverage)?;
+    if cli.destrand {
+        for sample in &mut samples {
+            if let Sample::Ranges(ranges) = sample {
+                ranges.destrand();
+            }
+        }
+    }
+    Ok((samples, specs))
+}
+
+/// The loaded samples and how their records are summarized over a target.
+struct Summarizer<'a> {
+    cli: &'a Cli,
+    samples: &'a [Sample],
+    filter: &'a RecordFilter,
+    aggregation: Aggregation,
+    /// The reference sites of `--cpg-bed`.
+    reference: Option<&'a MethRanges>,
+    progress: progress::Progress,
+}
+
+impl Summarizer<'_> {
+    /// The summaries of each target, one per sample and strand.
+    fn rows(&self, targets: &[TargetInterval]) -> Result<Vec<Vec<TargetSummary>>, ThreadError> {
+        targets
+            .par_iter()
+            .map_init(
+                || {
+                    std::iter::repeat_with(|| None)
+                        .take(self.samples.len())
+                        .collect::<Vec<_>>()
+                },
+                |readers, target| {
+                    let summaries = self
+                        .samples
+                        .iter()
+                        .zip(readers.iter_mut())
+                        .map(|(sample, reader)| {
+                            sample
+                                .summarize(
+                                    target,
+                                    target_strands(self.cli, target),
+                                    reader,
+                                    self.filter,
+                                    &self.aggregation,
+                                )
+                                .map_err(ThreadError::from)
+                        })
+                        .collect::<Result<Vec<Vec<TargetSummary>>, ThreadError>>()?;
+                    self.progress.add(1);
+                    Ok(summaries.concat())
+                },
+            )
+            .collect()
+    }
+
+    /// Fills in what each row needs besides the records of its target.
+    fn complete(&self, targets: &[TargetInterval], rows: &mut [Vec<TargetSummary>]) {
+        if let Some(reference) = self.reference {
+            for (target, summaries) in targets.iter().zip(rows.iter_mut()) {
+                let intervals = reference
+                    .by_chrom
+                    .get(&target.chrom)
+                    .map_or(&[][..], Vec::as_slice);
+                let sites = overlapping(intervals, target, Strand::Unknown).count();
+                for summary in summaries {
+                    summary.reference_sites = Some(sites);
+                }
+            }
+        }
+        if let Some(level) = self.cli.ci {
+            for summary in rows.iter_mut().flatten() {
+                summary.ci = confidence::interval(
+                    self.cli.ci_method,
+                    summary.sum_methylated as f64,
+                    summary.sum_total_coverage as f64,
+                    level,
+                )
+                .map(|(low, high)| (low as f32, high as f32));
+            }
+        }
+    }
+}
+
+/// Whether the rows are labelled by sample, and their labels.
+fn sample_labels(cli: &Cli, specs: &[SampleSpec]) -> Option<Vec<String>> {
+    let labelled =
+        cli.samples.is_some() || cli.matrix.is_some() || cli.split_contexts || specs.len() > 1;
+    labelled.then(|| specs.iter().map(|spec| spec.label.clone()).collect())
+}
+
+/// How fractions are written, from the command line.
+fn fraction_format(cli: &Cli) -> FractionFormat {
+    FractionFormat {
+        precision: cli.precision,
+        percent: cli.percent,
+        na_value: cli.na_value.clone(),
+        min_sites: cli.min_sites,
+    }
+}
+
+/// Writes the records of each sample inside each target, for `--sites`.
+fn write_sites(
+    summarizer: &Summarizer,
+    targets: &[TargetInterval],
+    specs: &[SampleSpec],
+) -> Result<(), Box<dyn Error>> {
+    let Summarizer {
+        cli,
+        samples,
+        filter,
+        ..
+    } = *summarizer;
+    if cli.output_format != OutputFormat::Tsv {
+        return Err("Error: --sites applies to tsv output".into());
+    }
+    let labels = sample_labels(cli, specs);
+    let fractions = fraction_format(cli);
+    let named = targets.iter().any(|target| target.name.is_some());
+    let columns = sites_header_line(named, labels.is_some());
+    write_chunks(cli, &columns, targets, |targets| {
+        Ok(targets
+            .par_iter()
+            .map_init(
+                || {
+                    std::iter::repeat_with(|| None)
+                        .take(samples.len())
+                        .collect::<Vec<_>>()
+                },
+                |readers, target| {
+                    let strand = if cli.stranded {
+                        target.strand
+                    } else {
+                        Strand::Unknown
+                    };
+                    let mut lines = Vec::new();
+                    for (i, (sample, reader)) in samples.iter().zip(readers.iter_mut()).enumerate()
+                    {
+                        let sites = sample
+                            .sites(target, strand, reader, filter)
+                            .map_err(ThreadError::from)?;
+                        let label = labels.as_ref().map(|labels| labels[i].as_str());
+                        lines.extend(
+                            sites
+                                .iter()
+                                .map(|site| format_site_line(target, label, site, &fractions)),
+                        );
+                    }
+                    summarizer.progress.add(1);
+                    Ok(lines.join("\n"))
+                },
+            )
+            .collect::<Result<Vec<String>, ThreadError>>()?)
+    })
+}
+
+/// The summary fields of each sample in the output, checked against the
+/// output format.
+fn output_fields(cli: &Cli, reference: bool) -> Result<Vec<Field>, Box<dyn Error>> {
+    let mut fields = match cli.matrix {
+        Some(Matrix::Wide) if cli.matrix_coverage => vec![Field::Fraction, Field::Coverage],
+        Some(Matrix::Wide) => vec![Field::Fraction],
+        Some(Matrix::Long) | None => SUMMARY_FIELDS.to_vec(),
+    };
+    if cli.counts {
+        if cli.output_format != OutputFormat::Tsv {
+            return Err("Error: --counts applies to tsv output".into());
+        }
+        fields.extend([Field::Methylated, Field::Unmethylated]);
+    }
+    if cli.shrink {
+        if cli.output_format != OutputFormat::Tsv {
+            return Err("Error: --shrink applies to tsv output".into());
+        }
+        fields.push(Field::Shrunk);
+    }
+    if cli.ci.is_some() {
+        if cli.output_format != OutputFormat::Tsv {
+            return Err("Error: --ci applies to tsv output".into());
+        }
+        fields.extend([Field::CiLow, Field::CiHigh]);
+    }
+    if cli.density {
+        if cli.output_format != OutputFormat::Tsv {
+            return Err("Error: --density applies to tsv output".into());
+        }
+        fields.push(Field::SitesPerKb);
+        if reference {
+            fields.push(Field::CpgShare);
+        }
+    }
+    if !cli.stats.is_empty() {
+        if cli.output_format != OutputFormat::Tsv {
+            return Err("Error: --stats applies to tsv output".into());
+        }
+        fields.extend(cli.stats.iter().map(|&stat| Field::Stat(stat)));
+    }
+    if cli.matrix.is_some() && cli.output_format != OutputFormat::Tsv {
+        return Err("Error: --matrix applies to tsv output".into());
+    }
+    if cli.columns.is_some() && cli.output_format != OutputFormat::Tsv {
+        return Err("Error: --columns applies to tsv output".into());
+    }
+    if cli.keep_target_columns && cli.output_format != OutputFormat::Tsv {
+        return Err("Error: --keep-target-columns applies to tsv output".into());
+    }
+    Ok(fields)
+}
+
+/// Writes one line per bin of each target, for `--bin-layout long`.
+fn write_long_bins(
+    cli: &Cli,
+    targets: &[TargetInterval],
+    specs: &[SampleSpec],
+    bins: &[TargetBins],
+    fractions: &FractionFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut columns = "chrom\tstart\tend".to_string();
+    if targets.iter().any(|target| target.name.is_some()) {
+        columns.push_str("\tname");
+    }
+    columns.push_str("\tbin\tbin_start\tbin_end");
+    let labelled = sample_labels(cli, specs).is_some();
+    for spec in specs {
+        match labelled {
+            true => columns.push_str(&format!("\t{}_fraction", spec.label)),
+            false => columns.push_str("\tweighted_fraction"),
+        }
+    }
+    let lines: Vec<String> = targets
+        .iter()
+        .zip(bins)
+        .map(|(target, bins)| {
+            let prefix = format_target(target);
+            bins.iter()
+                .enumerate()
+                .map(|(i, ((start, end), summaries))| {
+                    let values: Vec<String> = summaries
+                        .iter()
+                        .map(|summary| Field::Fraction.format(summary, fractions))
+                        .collect();
+                    format!("{prefix}\t{}\t{start}\t{end}\t{}", i + 1, values.join("\t"))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect();
+    write_text(cli, &columns, targets, &lines)
+}
+
+/// Summarizes the targets and writes their rows, all at once when every row
+/// is needed first and a chunk of targets at a time otherwise.
+fn write_summaries(
+    summarizer: &Summarizer,
+    targets: &[TargetInterval],
+    specs: &[SampleSpec],
+    streaming: bool,
+) -> Result<(), Box<dyn Error>> {
+    let cli = summarizer.cli;
+    let labels = sample_labels(cli, specs);
+    let labelled = labels.is_some();
+    let named = targets.iter().any(|target| target.name.is_some());
+    let fractions = fraction_format(cli);
+    let whole_rows = streaming
+        || cli.impute.is_some()
+        || cli.shrink
+        || cli.bins.is_some()
+        || matches!(
+            cli.output_format,
+            OutputFormat::Parquet | OutputFormat::Bigwig
+        );
+    let mut rows = if streaming {
+        stream_samples(
+            specs,
+            cli,
+            summarizer.filter,
+            targets,
+            &summarizer.aggregation,
+        )?
+    } else if whole_rows {
+        summarizer.rows(targets)?
+    } else {
+        Vec::new()
+    };
+    summarizer.complete(targets, &mut rows);
+    if let Some(method) = cli.impute {
+        impute_fractions(&mut rows, method, cli.impute_k, |summary| {
+            fractions.lacks_fraction(summary)
+        });
+    }
+    if cli.shrink {
+        shrink_fractions(&mut rows);
+    }
+    let bins = match cli.bins {
+        Some(_) if cli.output_format != OutputFormat::Tsv => {
+            return Err("Error: --bins applies to tsv output".into());
+        }
+        Some(_) if cli.matrix == Some(Matrix::Long) => {
+            return Err("Error: --bins cannot be combined with --matrix long".into());
+        }
+        Some(n) => Some(summarize_bins(
+            summarizer.samples,
+            targets,
+            n,
+            cli.stranded,
+            summarizer.filter,
+            &summarizer.aggregation,
+        )?),
+        None => None,
+    };
+    if let Some(bins) = &bins
+        && cli.bin_layout == BinLayout::Long
+    {
+        return write_long_bins(cli, targets, specs, bins, &fractions);
+    }
+    let fields = output_fields(cli, summarizer.reference.is_some())?;
+    let groups = cli.columns.as_deref().map(group_columns);
+    let mut columns = match (&groups, cli.matrix) {
+        (Some(groups), _) => {
+            columns_header_line(labelled.then_some(specs), cli.split_strands, groups)
+        }
+        (_, Some(Matrix::Long)) => long_header_line(named, cli.split_strands, &fields),
+        _ => header_line(labelled.then_some(specs), named, cli.split_strands, &fields),
+    };
+    if let Some(n) = cli.bins {
+        for spec in specs {
+            for bin in 1..=n {
+                match labelled {
+                    true => columns.push_str(&format!("\t{}_bin{bin}", spec.label)),
+                    false => columns.push_str(&format!("\tbin{bin}")),
+                }
+            }
+        }
+    }
+    // Kept columns are named after their position in TARGET_BED.
+    let kept = targets.iter().map(|target| target.extra.len()).max();
+    for column in 0..kept.unwrap_or(0) {
+        columns.push_str(&format!("\ttarget_{}", column + 4));
+    }
+    match cli.output_format {
+        OutputFormat::Parquet => {
+            return write_parquet(cli.output.as_deref(), &columns, named, targets, &rows);
+        }
+        OutputFormat::Bigwig => return write_fraction_bigwig(cli, targets, &rows),
+        _ => {}
+    }
+    let format_lines = |targets: &[TargetInterval], rows: &[Vec<TargetSummary>]| {
+        if cli.output_format == OutputFormat::Bedgraph
+            && rows.first().is_some_and(|row| row.len() != 1)
+        {
+            return Err("Error: --output-format bedgraph takes a single sample and strand".into());
+        }
+        let lines: Vec<String> = targets
+            .par_iter()
+            .zip(rows)
+            .map(|(target, summaries)| match cli.output_format {
+                OutputFormat::Ndjson => json::format_target_json(
+                    target,
+                    summaries,
+                    labels.as_deref(),
+                    cli.split_strands,
+                    &fractions,
+                ),
+                OutputFormat::Bedgraph => format_bedgraph_line(target, &summaries[0], &fractions),
+                _ if let Some(groups) = &groups => {
+                    format_columns(target, summaries, groups, &fractions)
+                }
+                _ if cli.matrix == Some(Matrix::Long) => format_long_rows(
+                    target,
+                    summaries,
+                    labels.as_deref().unwrap_or_default(),
+                    cli.split_strands,
+                    &fields,
+                    &fractions,
+                ),
+                _ => format_row(target, summaries, &fields, &fractions),
+            })
+            .collect();
+        Ok::<_, Box<dyn Error>>(match &bins {
+            Some(bins) => lines
+                .into_iter()
+                .zip(bins)
+                .map(|(line, bins)| {
+                    let samples = bins.first().map_or(0, |(_, summaries)| summaries.len());
+                    let fractions = &fractions;
+                    let values: Vec<String> = (0..samples)
+                        .flat_map(|sample| {
+                            bins.iter().map(move |(_, summaries)| {
+                                Field::Fraction.format(&summaries[sample], fractions)
+                            })
+                        })
+                        .collect();
+                    format!("{line}\t{}", values.join("\t"))
+                })
+                .collect(),
+            None => lines,
+        })
+    };
+    if whole_rows {
+        return write_text(cli, &columns, targets, &format_lines(targets, &rows)?);
+    }
+    write_chunks(cli, &columns, targets, |targets| {
+        let mut rows = summarizer.rows(targets)?;
+        summarizer.complete(targets, &mut rows);
+        format_lines(targets, &rows)
+    })
+}
+
+/// The strands each target is summarized on.
+fn target_strands<'a>(cli: &Cli, target: &'a TargetInterval) -> &'a [Strand] {
+    if cli.split_strands {
+        &[Strand::Plus, Strand::Minus]
+    } else if cli.stranded {
+        std::slice::from_ref(&target.strand)
+    } else {
+        &[Strand::Unknown]
+    }
+}
+
+/// `--max-memory`: the memory the records of `specs` would take once loaded.
+/// Tabix-indexed inputs are read a target at a time and count for nothing,
+/// as do alignments and inputs of unknown size, which are only counted while
+/// parsing.
+fn estimate_memory(specs: &[SampleSpec], cli: &Cli) -> Result<u64, Box<dyn Error>> {
+    let record_bytes = match cli.compact {
+        true => compact::RECORD_BYTES,
+        false => size_of::<MethInterval>(),
+    };
+    let mut needed = 0;
+    for spec in specs {
+        let path = spec.path.as_path();
+        if is_stream(path)
+            || bam::sniff(path)?.is_some()
+            || (!cli.no_index && tabix::Index::find(path)?.is_some())
+        {
+            continue;
+        }
+        needed += memory::estimate(path, record_bytes)?.unwrap_or(0);
+    }
+    Ok(needed)
+}
+
+/// The option keeping `--max-memory` from falling back to `--streaming`, if any.
+fn streaming_blocker(specs: &[SampleSpec], cli: &Cli) -> Result<Option<String>, Box<dyn Error>> {
+    let options = [
+        (cli.sites, "--sites"),
+        (cli.bins.is_some(), "--bins"),
+        (cli.split_contexts, "--split-contexts"),
+        (cli.destrand, "--destrand"),
+        (cli.sort, "--sort"),
+        (cli.on_overlap.is_some(), "--on-overlap"),
+        (cli.chrom_alias.is_some(), "--chrom-alias"),
+        (cli.normalize_chroms, "--normalize-chroms"),
+        (cli.compact, "--compact"),
+        (cli.cache, "--cache"),
+        (
+            matches!(cli.max_coverage, Some(CoverageLimit::Percentile(_))),
+            "a percentile --max-coverage",
+        ),
+    ];
+    if let Some((_, option)) = options.iter().find(|(set, _)| *set) {
+        return Ok(Some(option.to_string()));
+    }
+    for spec in specs {
+        if !is_stream(&spec.path) && bam::sniff(&spec.path)?.is_some() {
+            return Ok(Some(format!("the alignment {}", spec.path.display())));
+        }
+    }
+    Ok(None)
+}
+
+/// `--streaming`: summarizes the targets in one sweep over each sample, giving
+/// the same rows as loading the samples.
+fn stream_samples(
+    specs: &[SampleSpec],
+    cli: &Cli,
+    filter: &RecordFilter,
+    targets: &[TargetInterval],
+    aggregation: &Aggregation,
+) -> Result<Vec<Vec<TargetSummary>>, Box<dyn Error>> {
+    if matches!(cli.max_coverage, Some(CoverageLimit::Percentile(_))) {
+        return Err(
+            "Error: a percentile --max-coverage needs every record; drop --streaming".into(),
+        );
+    }
+    let columns = specs
+        .par_iter()
+        .map(|spec| {
+            stream_sample(spec, cli, filter, targets, aggregation).map_err(ThreadError::from)
+        })
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+    Ok((0..targets.len())
+        .map(|i| {
+            columns
+                .iter()
+                .flat_map(|column| column[i].clone())
+                .collect()
+        })
+        .collect())
+}
+
+/// Streams one sample of `--streaming`.
+fn stream_sample(
+    spec: &SampleSpec,
+    cli: &Cli,
+    filter: &RecordFilter,
+    targets: &[TargetInterval],
+    aggregation: &Aggregation,
+) -> Result<Vec<Vec<TargetSummary>>, Box<dyn Error>> {
+    let path = spec.path.as_path();
+    if !is_stream(path) && bam::sniff(path)?.is_some() {
+        return Err(format!(
+            "Error: {}: --streaming reads methylation files, not alignments",
+            path.display()
+        )
+        .into());
+    }
+    let format = match spec.format {
+        Format::Auto => detect_format(path)?,
+        format => format,
+    };
+    streaming::summarize_targets(
+        path,
+        &resolve_layout(cli, format),
+        &column_names(cli),
+        filter,
+        targets,
+        |target| target_strands(cli, target),
+        aggregation,
+    )
+}
+
+/// Bins of every target with the summary of each bin in every sample.
+type TargetBins = Vec<((i32, i32), Vec<TargetSummary>)>;
+
+/// Splits every target into `n` equal bins and summarizes each bin per sample,
+/// fetching the records of a target once per sample.
+fn summarize_bins(
+    samples: &[Sample],
+    targets: &[TargetInterval],
+    n: usize,
+    stranded: bool,
+    filter: &RecordFilter,
+    aggregation: &Aggregation,
+) -> Result<Vec<TargetBins>, ThreadError> {
+    targets
+        .par_iter()
+        .map_init(
+            || {
+                std::iter::repeat_with(|| None)
+                    .take(samples.len())
+                    .collect::<Vec<_>>()
+            },
+            |readers, target| {
+                let strand = if stranded {
+                    target.strand
+                } else {
+                    Strand::Unknown
+                };
+                let mut bins: TargetBins = profile::split(target.start, target.end, n)
+                    .map(|bin| (bin, Vec::with_capacity(samples.len())))
+                    .collect();
+                for (sample, reader) in samples.iter().zip(readers.iter_mut()) {
+                    let intervals = sample
+                        .intervals(target, reader, filter)
+                        .map_err(ThreadError::from)?;
+                    for ((start, end), summaries) in &mut bins {
+                        let bin = TargetInterval {
+                            start: *start,
+                            end: *end,
+                            ..target.clone()
+                        };
+                        summaries.push(summarize(&intervals, &bin, strand, aggregation));
+                    }
+                }
+                Ok(bins)
+            },
+        )
+        .collect()
+}
+
+/// The header line of tab-separated output, if one is requested.
+fn text_header(cli: &Cli, columns: &str) -> Option<String> {
+    // Sample manifests and matrices always get a header, labelled after their samples.
+    let style = match (cli.output_format, cli.header) {
+        (OutputFormat::Ndjson | OutputFormat::Bedgraph, _) => None,
+        (_, Some(style)) => Some(style),
+        _ if cli.samples.is_some() || cli.matrix.is_some() => Some(HeaderStyle::Plain),
+        _ => None,
+    };
+    style.map(|style| match style {
+        HeaderStyle::Plain => columns.to_string(),
+        HeaderStyle::Comment => format!("#{columns}"),
+    })
+}
+
+/// Writes tab-separated or NDJSON `lines`, one entry per target, preceded by
+/// the `columns` header when one is requested.
+fn write_text(
+    cli: &Cli,
+    columns: &str,
+    targets: &[TargetInterval],
+    lines: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let mut out = TextOutput::create(cli, columns)?;
+    out.write(targets, lines)?;
+    out.finish()
+}
+
+/// Targets formatted at a time by [`write_chunks`].
+const OUTPUT_CHUNK: usize = 1 << 16;
+
+/// Writes the lines `format` gives for each chunk of targets as [`write_text`]
+/// does, in order, while the next chunk is formatted. Only two chunks of lines
+/// are held at a time.
+fn write_chunks(
+    cli: &Cli,
+    columns: &str,
+    targets: &[TargetInterval],
+    format: impl Fn(&[TargetInterval]) -> Result<Vec<String>, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut chunks = targets.chunks(OUTPUT_CHUNK);
+    // Nothing is created before the first chunk is ready, so early errors leave no output.
+    let first_targets = chunks.next().unwrap_or_default();
+    let first = format(first_targets)?;
+    let mut out = TextOutput::create(cli, columns)?;
+    std::thread::scope(|scope| {
+        let (sender, receiver) =
+            std::sync::mpsc::sync_channel::<(&[TargetInterval], Vec<String>)>(1);
+        let writer = scope.spawn(move || {
+            for (targets, lines) in receiver {
+                out.write(targets, &lines).map_err(ThreadError::from)?;
+            }
+            out.finish().map_err(ThreadError::from)
+        });
+        let formatted = (move || {
+            let mut lines = (first_targets, first);
+            for chunk in chunks {
+                // The writer only hangs up on an error, returned below.
+                if sender.send(lines).is_err() {
+                    return Ok(());
+                }
+                lines = (chunk, format(chunk)?);
+            }
+            let _ = sender.send(lines);
+            Ok::<_, Box<dyn Error>>(())
+        })();
+        let written = writer.join().map_err(|_| "Error: output writer panicked")?;
+        formatted?;
+        Ok(written?)
+    })
+}
+
+/// Text output as it is written: plain, or block-gzipped and indexed as
+/// it goes with `--bgzip` and `--tabix`.
+enum TextOutput {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    Bgzip {
+        writer: bgzf::BgzfWriter<BufWriter<Box<dyn Write + Send>>>,
+        /// Where the `.tbi` goes, with `--tabix`.
+        index_path: Option<PathBuf>,
+        index: tabix::IndexBuilder,
+        /// Lines before the records that tabix skips by count.
+        skip: u32,
+    },
+}
+
+impl TextOutput {
+    /// Creates the output and writes the `columns` header when one is requested.
+    fn create(cli: &Cli, columns: &str) -> Result<TextOutput, Box<dyn Error>> {
+        let header = text_header(cli, columns);
+        if !(cli.bgzip || cli.tabix) {
+            let mut out = create_output(cli.output.as_deref())?;
+            if let Some(header) = &header {
+                writeln!(out, "{header}")?;
+            }
+            return Ok(TextOutput::Plain(out));
+        }
+        if cli.tabix && cli.output_format == OutputFormat::Ndjson {
+            return Err("Error: --tabix needs tab-separated output".into());
+        }
+        let coordinates = [Column::Chrom, Column::Start, Column::End];
+        if cli.tabix
+            && let Some(columns) = &cli.columns
+            && !columns.starts_with(&coordinates)
+        {
+            return Err("Error: --tabix needs --columns to start with chrom,start,end".into());
+        }
+        let index_path = match &cli.output {
+            Some(path) => cli.tabix.then(|| {
+                let mut index_path = path.as_os_str().to_owned();
+                index_path.push(".tbi");
+                PathBuf::from(index_path)
+            }),
+            None if cli.tabix => return Err("Error: --tabix requires --output".into()),
+            None => None,
+        };
+        let mut writer = bgzf::BgzfWriter::new(create_output(cli.output.as_deref())?);
+        if let Some(header) = &header {
+            writeln!(writer, "{header}")?;
+        }
+        Ok(TextOutput::Bgzip {
+            writer,
+            index_path,
+            index: tabix::IndexBuilder::default(),
+            // A plain header is skipped by line count; a commented one by its `#`.
+            skip: u32::from(header.is_some_and(|header| !header.starts_with('#'))),
+        })
+    }
+
+    /// Writes the line of each of `targets`, skipping empty ones.
+    fn write(
+        &mut self,
+        targets: &[TargetInterval],
+        lines: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let (writer, mut index) = match self {
+            TextOutput::Plain(out) => return Ok(write_each(out, lines)?),
+            TextOutput::Bgzip {
+                writer,
+                index_path,
+                index,
+                ..
+            } => (writer, index_path.is_some().then_some(index)),
+        };
+        for (target, line) in targets.iter().zip(lines) {
+            if line.is_empty() {
+                continue;
+            }
+            let vbeg = writer.virtual_offset();
+            writeln!(writer, "{line}")?;
+            if let Some(index) = index.as_deref_mut() {
+                index
+                    .add(
+                        &target.chrom,
+                        target.start,
+                        target.end,
+                        vbeg,
+                        writer.virtual_offset(),
+                    )
+                    .map_err(|err| {
+                        format!("Error: cannot index the output: {err}; sort the targets or pass --sort-output")
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the output and writes the `.tbi` index next to it with `--tabix`.
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            TextOutput::Plain(mut out) => Ok(out.flush()?),
+            TextOutput::Bgzip {
+                writer,
+                index_path,
+                index,
+                skip,
+            } => {
+                writer.finish()?.flush()?;
+                if let Some(index_path) = index_path {
+                    let mut writer =
+                        bgzf::BgzfWriter::new(BufWriter::new(File::create(index_path)?));
+                    writer.write_all(&index.finish(skip))?;
+                    writer.finish()?.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes the weighted fraction of every covered target as a bigWig track.
+fn write_fraction_bigwig(
+    cli: &Cli,
+    targets: &[TargetInterval],
+    rows: &[Vec<TargetSummary>],
+) -> Result<(), Box<dyn Error>> {
+    let (Some(output), Some(chrom_sizes)) = (&cli.output, &cli.chrom_sizes) else {
+        return Err("Error: --output-format bigwig requires --output and --chrom-sizes".into());
+    };
+    if rows.first().is_some_and(|row| row.len() != 1) {
+        return Err("Error: --output-format bigwig takes a single sample and strand".into());
+    }
+    let mut spans: HashMap<String, Vec<bigwig::Span>> = HashMap::new();
+    for (target, row) in targets.iter().zip(rows) {
+        if row[0].num_positions > 0 {
+            spans
+                .entry(target.chrom.clone())
+                .or_default()
+                .push(bigwig::Span {
+                    start: target.start,
+                    end: target.end,
+                    value: row[0].weighted_fraction,
+                });
+        }
+    }
+    for (chrom, chrom_spans) in &mut spans {
+        chrom_spans.sort_by_key(|span| span.start);
+        if chrom_spans
+            .windows(2)
+            .any(|pair| pair[1].start < pair[0].end)
+        {
+            return Err(
+                format!("Error: bigWig output needs non-overlapping targets ({chrom})").into(),
+            );
+        }
+    }
+    let mut out = BufWriter::new(File::create(output)?);
+    bigwig::write_bigwig(&mut out, &read_chrom_sizes(chrom_sizes)?, &spans)?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+pub fn write_parquet(
+    output: Option<&Path>,
+    columns: &str,
+    named: bool,
+    targets: &[TargetInterval],
+    rows: &[Vec<TargetSummary>],
+) -> Result<(), Box<dyn Error>> {
+    let columns: Vec<&str> = columns.split('\t').collect();
+    match output {
+        Some(path) => crate::parquet_output::write_parquet(
+            File::create(path)?,
+            &columns,
+            named,
+            targets,
+            rows,
+        ),
+        None => {
+            crate::parquet_output::write_parquet(std::io::stdout(), &columns, named, targets, rows)
+        }
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn write_parquet(
+    _output: Option<&Path>,
+    _columns: &str,
+    _named: bool,
+    _targets: &[TargetInterval],
+    _rows: &[Vec<TargetSummary>],
+) -> Result<(), Box<dyn Error>> {
+    Err("Error: methfast was built without Parquet support (the `parquet` feature)".into())
+}
+
+/// Header naming the output columns; with `specs`, the per-sample columns are
+/// prefixed by the sample labels.
+pub fn header_line(
+    specs: Option<&[SampleSpec]>,
+    named: bool,
+    split_strands: bool,
+    fields: &[Field],
+) -> String {
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    let strands: &[&str] = if split_strands {
+        &["plus_", "minus_"]
+    } else {
+        &[""]
+    };
+    let labels: Vec<String> = match specs {
+        Some(specs) => specs
+            .iter()
+            .map(|spec| format!("{}_", spec.label))
+            .collect(),
+        None => vec![String::new()],
+    };
+    for label in &labels {
+        for strand in strands {
+            for field in fields {
+                header.push_str(&format!("\t{label}{strand}{}", field.name(specs.is_some())));
+            }
+        }
+    }
+    header
+}
+
+/// Header of `--matrix long` output.
+fn long_header_line(named: bool, split_strands: bool, fields: &[Field]) -> String {
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    header.push_str("\tsample");
+    if split_strands {
+        header.push_str("\tstrand");
+    }
+    for field in fields {
+        header.push('\t');
+        header.push_str(field.name(true));
+    }
+    header
+}
+
+/// Header of `--sites` output.
+fn sites_header_line(named: bool, labelled: bool) -> String {
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    if labelled {
+        header.push_str("\tsample");
+    }
+    header.push_str("\tsite_start\tsite_end\tfraction\tcoverage");
+    header
+}
+
+/// Lifts `targets` through `chains`, dropping those that do not lift and
+/// listing them with the reason in `unmapped` as UCSC `liftOver` does.
+fn lift_targets(
+    targets: Vec<TargetInterval>,
+    chains: &liftover::Chains,
+    min_match: f64,
+    unmapped: Option<&Path>,
+) -> Result<Vec<TargetInterval>, Box<dyn Error>> {
+    let total = targets.len();
+    let mut lifted = Vec::with_capacity(total);
+    let mut failed = Vec::new();
+    for target in targets {
+        match chains.lift(&target, min_match) {
+            Ok(target) => lifted.push(target),
+            Err(reason) => {
+                failed.push(format!("#{}", reason.reason()));
+                let mut line = format!("{}\t{}\t{}", target.chrom, target.start, target.end);
+                if let Some(name) = &target.name {
+                    line.push('\t');
+                    line.push_str(name);
+                }
+                failed.push(line);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        eprintln!(
+            "--liftover: {} of {total} targets could not be lifted",
+            failed.len() / 2
+        );
+    }
+    if let Some(path) = unmapped {
+        write_lines(Some(path), None, &failed)?;
+    }
+    Ok(lifted)
+}
+
+/// Buffered output to `output`, or stdout without one.
+fn create_output(
+    output: Option<&Path>,
+) -> Result<BufWriter<Box<dyn Write + Send>>, Box<dyn Error>> {
+    let out: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    Ok(BufWriter::new(out))
+}
+
+/// Writes `lines` one per line.
+fn write_each(out: &mut impl Write, lines: &[String]) -> std::io::Result<()> {
+    // Targets without sites have no line in --sites output.
+    for line in lines.iter().filter(|line| !line.is_empty()) {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+pub fn write_lines(
+    output: Option<&Path>,
+    header: Option<&str>,
+    lines: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let mut out = create_output(output)?;
+    if let Some(header) = header {
+        writeln!(out, "{header}")?;
+    }
+    write_each(&mut out, lines)?;
+    out.flush()?;
+    Ok(())
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Smooth per-CpG methylation levels over neighbouring sites (BSmooth-style).
+    Smooth(smooth::SmoothArgs),
+    /// Aggregate methylation into fixed-size windows at several resolutions.
+    Bins(bins::BinsArgs),
+    /// Build a targets-by-samples matrix from a sample manifest, streaming each sample.
+    Matrix(matrix::MatrixArgs),
+    /// Test regions for differential methylation between two groups of samples.
+    Dmr(dmr::DmrArgs),
+    /// Average methylation over relative bins of regions (metagene profiles).
+    Profile(profile::ProfileArgs),
+    /// Pool replicate per-CpG files by position, summing their read counts.
+    Merge(merge::MergeArgs),
+    /// Rewrite a methylation file in another format.
+    Convert(convert::ConvertArgs),
+    /// Report QC statistics of a methylation file.
+    Stats(qc::StatsArgs),
+    /// Call unmethylated and low-methylated regions (UMRs/LMRs) from per-CpG data.
+    Segment(segment::SegmentArgs),
+    /// Write a binary index of a methylation file's records to `<file>.mfi`.
+    Index(meth_index::IndexArgs),
+    /// Compare the methylation of two samples over target regions.
+    Compare(compare::CompareArgs),
+    /// Principal components and clustering of the samples of a methylation matrix.
+    Pca(pca::PcaArgs),
+    /// Estimate cell-type proportions from a reference methylation atlas.
+    Deconvolve(deconvolve::DeconvolveArgs),
+    /// Answer region queries over HTTP from data loaded once.
+    Serve(serve::ServeArgs),
+    /// Report the closest covered records up- and downstream of each target.
+    Nearest(nearest::NearestArgs),
+}
+
+/// Runs the `methfast` command line on the arguments of the process,
+/// reporting a failure on stderr; returns the exit status.
+pub fn run_cli() -> std::process::ExitCode {
+    let args = match config::expand(std::env::args_os().collect(), &Cli::command()) {
+        Ok(args) => args,
+        Err(err) => return error::report(err.as_ref(), false).into(),
+    };
+    let cli = Cli::parse_from(args);
+    let json = cli.error_format == ErrorFormat::Json;
+    log::set_verbosity(cli.verbose);
+    let result = match &cli.command {
+        Some(Command::Smooth(args)) => smooth::run(args),
+        Some(Command::Bins(args)) => bins::run(args),
+        Some(Command::Matrix(args)) => matrix::run(args),
+        Some(Command::Dmr(args)) => dmr::run(args),
+        Some(Command::Profile(args)) => profile::run(args),
+        Some(Command::Merge(args)) => merge::run(args),
+        Some(Command::Convert(args)) => convert::run(args),
+        Some(Command::Stats(args)) => qc::run(args),
+        Some(Command::Segment(args)) => segment::run(args),
+        Some(Command::Index(args)) => meth_index::run(args),
+        Some(Command::Compare(args)) => compare::run(args),
+        Some(Command::Pca(args)) => pca::run(args),
+        Some(Command::Deconvolve(args)) => deconvolve::run(args),
+        Some(Command::Serve(args)) => serve::run(args),
+        Some(Command::Nearest(args)) => nearest::run(args),
+        None => run(cli),
+    };
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => error::report(err.as_ref(), json).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FractionStats, record_span, trimmed_mean};
+
+    #[test]
+    fn computes_weighted_fraction_from_intervals() {
+        let mut by_chrom: HashMap<String, Vec<MethInterval>> = HashMap::new();
+        by_chrom.insert(
+            "chr1".to_string(),
+            vec![
+                MethInterval {
+                    start: 10,
+                    end: 11,
+                    fraction: 1.0,
+                    coverage: 5,
+                    strand: Strand::Unknown,
+                },
+                MethInterval {
+                    start: 12,
+                    end: 13,
+                    fraction: 0.5,
+                    coverage: 10,
+                    strand: Strand::Unknown,
+                },
+                MethInterval {
+                    start: 20,
+                    end: 21,
+                    fraction: 0.0,
+                    coverage: 3,
+                    strand: Strand::Unknown,
+                },
+            ],
+        );
+
+        let ranges = MethRanges { by_chrom };
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 9,
+            end: 14,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let line = format_row(
+            &target,
+            &[summarize_ranges(
+                &ranges,
+                &target,
+                Strand::Unknown,
+                &Aggregation::default(),
+            )],
+            &SUMMARY_FIELDS,
+            &FractionFormat::default(),
+        );
+        assert_eq!(line, "chr1\t9\t14\t2\t15\t0.6667");
+    }
+
+    #[test]
+    fn formats_one_triple_per_sample() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summaries = [
+            TargetSummary {
+                num_positions: 2,
+                sum_total_coverage: 8,
+                weighted_fraction: 0.25,
+                sum_methylated: 2.0,
+                stats: None,
+                sites_per_kb: 0.0,
+                reference_sites: None,
+                shrunk_fraction: None,
+                ci: None,
+                imputed: false,
+            },
+            TargetSummary::default(),
+        ];
+        assert_eq!(
+            format_row(
+                &target,
+                &summaries,
+                &SUMMARY_FIELDS,
+                &FractionFormat::default()
+            ),
+            "chr1\t0\t10\t2\t8\t0.2500\t0\t0\t0.0000"
+        );
+    }
+
+    #[test]
+    fn coordinate_flags_override_preset() {
+        let cli = Cli::parse_from([
+            "methfast",
+            "--format",
+            "bismark-cx",
+            "--zero-based",
+            "m",
+            "t",
+        ]);
+        assert!(!resolve_layout(&cli, cli.format).one_based);
+        let cli = Cli::parse_from(["methfast", "--one-based", "m", "t"]);
+        let layout = resolve_layout(&cli, cli.format);
+        let fields: Vec<&str> = "chr1\t10\t12\t0.5\t4".split('\t').collect();
+        assert_eq!(record_span(&fields, &layout, false), Ok((9, 12)));
+    }
+
+    #[test]
+    fn writes_chunks_of_lines_in_order() {
+        let path = std::env::temp_dir().join(format!("methfast-chunks-{}.tsv", std::process::id()));
+        let cli = Cli::parse_from([
+            "methfast",
+            "--header",
+            "-o",
+            path.to_str().unwrap(),
+            "m",
+            "t",
+        ]);
+        let targets: Vec<TargetInterval> = (0..OUTPUT_CHUNK as i32 * 2 + 5)
+            .map(|start| TargetInterval {
+                chrom: "chr1".to_string(),
+                start,
+                end: start + 1,
+                name: None,
+                strand: Strand::Unknown,
+                extra: Vec::new(),
+            })
+            .collect();
+        write_chunks(&cli, "chrom\tstart\tend", &targets, |chunk| {
+            Ok(chunk.iter().map(format_target).collect())
+        })
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let mut lines = written.lines();
+        assert_eq!(lines.next(), Some("chrom\tstart\tend"));
+        assert!(lines.eq(targets.iter().map(format_target)));
+
+        let failed = write_chunks(&cli, "", &targets, |chunk| match chunk[0].start {
+            0 => Ok(vec![String::new(); chunk.len()]),
+            _ => Err("Error: late".into()),
+        });
+        assert_eq!(failed.unwrap_err().to_string(), "Error: late");
+        std::fs::remove_file(&path).unwrap();
+
+        // Block-gzipped chunks are indexed as they are written.
+        let path = path.with_extension("tsv.gz");
+        let cli = Cli::parse_from([
+            "methfast",
+            "--tabix",
+            "-o",
+            path.to_str().unwrap(),
+            "m",
+            "t",
+        ]);
+        write_chunks(&cli, "", &targets, |chunk| {
+            Ok(chunk
+                .iter()
+                .map(|target| format!("{}\t0.5\t2", format_target(target)))
+                .collect())
+        })
+        .unwrap();
+        let index = tabix::Index::find(&path).unwrap().unwrap();
+        let target = TargetInterval {
+            start: OUTPUT_CHUNK as i32 - 2,
+            end: OUTPUT_CHUNK as i32 + 2,
+            ..targets[0].clone()
+        };
+        let mut reader = bgzf::BgzfReader::open(&path).unwrap();
+        let fetched = index
+            .fetch(
+                &mut reader,
+                &target,
+                &Format::Generic.layout(),
+                &RecordFilter::default(),
+            )
+            .unwrap();
+        let mut index_path = path.clone().into_os_string();
+        index_path.push(".tbi");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(index_path).unwrap();
+        let starts: Vec<i32> = fetched.iter().map(|iv| iv.start).collect();
+        assert_eq!(starts, (target.start..target.end).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn writes_target_name_after_coordinates() {
+        let target = TargetInterval {
+            chrom: "chr17".to_string(),
+            start: 7_661_778,
+            end: 7_687_538,
+            name: Some("TP53".to_string()),
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        assert_eq!(
+            format_row(
+                &target,
+                &[TargetSummary::default()],
+                &SUMMARY_FIELDS,
+                &FractionFormat::default()
+            ),
+            "chr17\t7661778\t7687538\tTP53\t0\t0\t0.0000"
+        );
+    }
+
+    #[test]
+    fn parses_samtools_style_regions() {
+        let region = parse_region("chr1:100,001-200,000").unwrap();
+        assert_eq!(
+            (region.chrom.as_str(), region.start, region.end),
+            ("chr1", 100_000, 200_000)
+        );
+        assert!(parse_region("chr1:200-100").is_err());
+        assert!(parse_region("chr1").is_err());
+    }
+
+    #[test]
+    fn names_output_columns() {
+        assert_eq!(
+            header_line(None, false, false, &SUMMARY_FIELDS),
+            "chrom\tstart\tend\tn_sites\ttotal_coverage\tweighted_fraction"
+        );
+        let specs = [SampleSpec::from_path(
+            Path::new("ctrl.bed"),
+            Format::Generic,
+        )];
+        assert_eq!(
+            header_line(Some(&specs), true, true, &SUMMARY_FIELDS),
+            "chrom\tstart\tend\tname\tctrl_plus_n_sites\tctrl_plus_coverage\tctrl_plus_fraction\tctrl_minus_n_sites\tctrl_minus_coverage\tctrl_minus_fraction"
+        );
+    }
+
+    #[test]
+    fn formats_bedgraph_without_counts() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: Some("p1".to_string()),
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summary = TargetSummary {
+            num_positions: 2,
+            sum_total_coverage: 8,
+            weighted_fraction: 0.25,
+            sum_methylated: 2.0,
+            stats: None,
+            sites_per_kb: 0.0,
+            reference_sites: None,
+            shrunk_fraction: None,
+            ci: None,
+            imputed: false,
+        };
+        assert_eq!(
+            format_bedgraph_line(&target, &summary, &FractionFormat::default()),
+            "chr1\t0\t10\t0.2500"
+        );
+    }
+
+    #[test]
+    fn writes_wide_matrix_row() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summaries = [
+            TargetSummary {
+                num_positions: 2,
+                sum_total_coverage: 8,
+                weighted_fraction: 0.25,
+                sum_methylated: 2.0,
+                stats: None,
+                sites_per_kb: 0.0,
+                reference_sites: None,
+                shrunk_fraction: None,
+                ci: None,
+                imputed: false,
+            },
+            TargetSummary::default(),
+        ];
+        let fields = [Field::Fraction, Field::Coverage];
+        assert_eq!(
+            format_row(&target, &summaries, &fields, &FractionFormat::default()),
+            "chr1\t0\t10\t0.2500\t8\t0.0000\t0"
+        );
+        let specs = [
+            SampleSpec::from_path(Path::new("a.bed"), Format::Generic),
+            SampleSpec::from_path(Path::new("b.bed"), Format::Generic),
+        ];
+        assert_eq!(
+            header_line(Some(&specs), false, false, &fields),
+            "chrom\tstart\tend\ta_fraction\ta_coverage\tb_fraction\tb_coverage"
+        );
+    }
+
+    #[test]
+    fn writes_long_rows_per_sample_and_strand() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: Some("r1".to_string()),
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summaries = [
+            TargetSummary {
+                num_positions: 2,
+                sum_total_coverage: 8,
+                weighted_fraction: 0.25,
+                sum_methylated: 2.0,
+                stats: None,
+                sites_per_kb: 0.0,
+                reference_sites: None,
+                shrunk_fraction: None,
+                ci: None,
+                imputed: false,
+            },
+            TargetSummary::default(),
+        ];
+        let labels = ["a".to_string(), "b".to_string()];
+        assert_eq!(
+            format_long_rows(
+                &target,
+                &summaries,
+                &labels,
+                false,
+                &SUMMARY_FIELDS,
+                &FractionFormat::default()
+            ),
+            "chr1\t0\t10\tr1\ta\t2\t8\t0.2500\nchr1\t0\t10\tr1\tb\t0\t0\t0.0000"
+        );
+        assert_eq!(
+            format_long_rows(
+                &target,
+                &summaries,
+                &labels[..1],
+                true,
+                &SUMMARY_FIELDS,
+                &FractionFormat::default()
+            ),
+            "chr1\t0\t10\tr1\ta\t+\t2\t8\t0.2500\nchr1\t0\t10\tr1\ta\t-\t0\t0\t0.0000"
+        );
+        assert_eq!(
+            long_header_line(true, false, &SUMMARY_FIELDS),
+            "chrom\tstart\tend\tname\tsample\tn_sites\tcoverage\tfraction"
+        );
+    }
+
+    #[test]
+    fn selects_and_orders_columns() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summaries = [
+            TargetSummary {
+                num_positions: 2,
+                sum_total_coverage: 8,
+                weighted_fraction: 0.25,
+                sum_methylated: 2.0,
+                stats: None,
+                sites_per_kb: 0.0,
+                reference_sites: None,
+                shrunk_fraction: None,
+                ci: None,
+                imputed: false,
+            },
+            TargetSummary::default(),
+        ];
+        let groups = group_columns(&[
+            Column::Name,
+            Column::Start,
+            Column::Fraction,
+            Column::Coverage,
+            Column::Chrom,
+        ]);
+        assert_eq!(
+            format_columns(&target, &summaries, &groups, &FractionFormat::default()),
+            ".\t0\t0.2500\t8\t0.0000\t0\tchr1"
+        );
+        let specs = [
+            SampleSpec::from_path(Path::new("a.bed"), Format::Generic),
+            SampleSpec::from_path(Path::new("b.bed"), Format::Generic),
+        ];
+        assert_eq!(
+            columns_header_line(Some(&specs), false, &groups),
+            "name\tstart\ta_fraction\ta_coverage\tb_fraction\tb_coverage\tchrom"
+        );
+    }
+
+    #[test]
+    fn formats_fractions_with_precision_and_percent() {
+        let fractions = FractionFormat {
+            precision: 1,
+            percent: true,
+            ..FractionFormat::default()
+        };
+        assert_eq!(fractions.format(0.66667), "66.7");
+        assert_eq!(FractionFormat::default().format(0.5), "0.5000");
+    }
+
+    #[test]
+    fn writes_na_value_for_uncovered_targets() {
+        let fractions = FractionFormat {
+            na_value: Some("NA".to_string()),
+            min_sites: 2,
+            ..FractionFormat::default()
+        };
+        let one_site = TargetSummary {
+            num_positions: 1,
+            sum_total_coverage: 4,
+            weighted_fraction: 0.5,
+            sum_methylated: 2.0,
+            stats: None,
+            sites_per_kb: 0.0,
+            reference_sites: None,
+            shrunk_fraction: None,
+            ci: None,
+            imputed: false,
+        };
+        let two_sites = TargetSummary {
+            num_positions: 2,
+            ..one_site
+        };
+        assert_eq!(
+            Field::Fraction.format(&TargetSummary::default(), &fractions),
+            "NA"
+        );
+        assert_eq!(Field::Fraction.format(&one_site, &fractions), "NA");
+        assert_eq!(Field::Fraction.format(&two_sites, &fractions), "0.5000");
+        assert_eq!(
+            Field::Fraction.format(&TargetSummary::default(), &FractionFormat::default()),
+            "0.0000"
+        );
+        let fractions = FractionFormat {
+            min_sites: 3,
+            ..FractionFormat::default()
+        };
+        assert_eq!(Field::Fraction.format(&two_sites, &fractions), "NA");
+    }
+
+    #[test]
+    fn lists_sites_within_target() {
+        let intervals = vec![
+            MethInterval {
+                start: 5,
+                end: 6,
+                fraction: 1.0,
+                coverage: 2,
+                strand: Strand::Plus,
+            },
+            MethInterval {
+                start: 10,
+                end: 11,
+                fraction: 0.25,
+                coverage: 4,
+                strand: Strand::Minus,
+            },
+            MethInterval {
+                start: 20,
+                end: 21,
+                fraction: 0.5,
+                coverage: 6,
+                strand: Strand::Plus,
+            },
+        ];
+        let ranges = MethRanges {
+            by_chrom: HashMap::from([("chr1".to_string(), intervals)]),
+        };
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 8,
+            end: 30,
+            name: Some("r1".to_string()),
+            strand: Strand::Plus,
+            extra: Vec::new(),
+        };
+        let sites = Sample::Ranges(ranges)
+            .sites(&target, Strand::Plus, &mut None, &RecordFilter::default())
+            .unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(
+            format_site_line(&target, Some("s1"), &sites[0], &FractionFormat::default()),
+            "chr1\t8\t30\tr1\ts1\t20\t21\t0.5000\t6"
+        );
+    }
+
+    #[test]
+    fn summarizes_equal_bins_of_targets() {
+        let site = |start, fraction| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage: 2,
+            strand: Strand::Unknown,
+        };
+        let sample = Sample::Ranges(MethRanges {
+            by_chrom: HashMap::from([(
+                "chr1".to_string(),
+                vec![site(100, 1.0), site(120, 0.5), site(160, 0.0)],
+            )]),
+        });
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 100,
+            end: 200,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let bins = summarize_bins(
+            &[sample],
+            &[target],
+            4,
+            false,
+            &RecordFilter::default(),
+            &Aggregation::default(),
+        )
+        .unwrap();
+        let fractions: Vec<((i32, i32), usize, f32)> = bins[0]
+            .iter()
+            .map(|(bin, summaries)| {
+                (
+                    *bin,
+                    summaries[0].num_positions,
+                    summaries[0].weighted_fraction,
+                )
+            })
+            .collect();
+        assert_eq!(
+            fractions,
+            vec![
+                ((100, 125), 2, 0.75),
+                ((125, 150), 0, 0.0),
+                ((150, 175), 1, 0.0),
+                ((175, 200), 0, 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sorts_targets_by_chrom_and_start() {
+        let target = |chrom: &str, start| TargetInterval {
+            chrom: chrom.to_string(),
+            start,
+            end: start + 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let mut targets = vec![target("chr2", 5), target("chr10", 50), target("chr2", 1)];
+        sort_targets(&mut targets);
+        let order: Vec<(&str, i32)> = targets
+            .iter()
+            .map(|target| (target.chrom.as_str(), target.start))
+            .collect();
+        assert_eq!(order, vec![("chr10", 50), ("chr2", 1), ("chr2", 5)]);
+        assert!(targets_sorted(&targets));
+
+        // A repeated target; the same span on the other strand is kept.
+        let mut targets = vec![target("chr2", 5), target("chr1", 1), target("chr2", 5)];
+        targets.push(TargetInterval {
+            strand: Strand::Minus,
+            ..target("chr2", 5)
+        });
+        assert!(!targets_sorted(&targets));
+        assert_eq!(duplicate_targets(&mut targets, false), 1);
+        assert_eq!(targets.len(), 4);
+        assert_eq!(duplicate_targets(&mut targets, true), 1);
+        let kept: Vec<(&str, Strand)> = targets
+            .iter()
+            .map(|target| (target.chrom.as_str(), target.strand))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                ("chr2", Strand::Unknown),
+                ("chr1", Strand::Unknown),
+                ("chr2", Strand::Minus)
+            ]
+        );
+    }
+
+    #[test]
+    fn appends_kept_target_columns() {
+        let path = std::env::temp_dir().join(format!("methfast-keep-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t0\t10\tr1\t5\t+\tpromoter\n").unwrap();
+        let targets = parse_targets(&path, true, true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(targets[0].name, None);
+        assert_eq!(targets[0].strand, Strand::Plus);
+        assert_eq!(
+            format_row(
+                &targets[0],
+                &[TargetSummary::default()],
+                &SUMMARY_FIELDS,
+                &FractionFormat::default()
+            ),
+            "chr1\t0\t10\t0\t0\t0.0000\tr1\t5\t+\tpromoter"
+        );
+    }
+
+    #[test]
+    fn counts_methylated_and_unmethylated_reads() {
+        let intervals = vec![
+            MethInterval {
+                start: 1,
+                end: 2,
+                fraction: 1.0 / 3.0,
+                coverage: 3,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 5,
+                end: 6,
+                fraction: 0.7,
+                coverage: 10,
+                strand: Strand::Unknown,
+            },
+        ];
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summary = summarize(
+            &intervals,
+            &target,
+            Strand::Unknown,
+            &Aggregation::default(),
+        );
+        let fractions = FractionFormat::default();
+        assert_eq!(Field::Methylated.format(&summary, &fractions), "8");
+        assert_eq!(Field::Unmethylated.format(&summary, &fractions), "5");
+        assert_eq!(Field::Methylated.name(false), "sum_methylated");
+    }
+
+    #[test]
+    fn caps_coverage_by_reads_or_percentile() {
+        assert_eq!(parse_coverage_limit("500"), Ok(CoverageLimit::Reads(500)));
+        assert_eq!(
+            parse_coverage_limit("99.5%"),
+            Ok(CoverageLimit::Percentile(99.5))
+        );
+        assert!(parse_coverage_limit("150%").is_err());
+
+        let interval = |coverage| MethInterval {
+            start: 0,
+            end: 1,
+            fraction: 0.5,
+            coverage,
+            strand: Strand::Unknown,
+        };
+        let ranges = MethRanges {
+            by_chrom: HashMap::from([(
+                "chr1".to_string(),
+                (1..=10).map(|c| interval(c * 10)).collect(),
+            )]),
+        };
+        assert_eq!(ranges.coverage_percentile(90.0), Some(90));
+        assert_eq!(ranges.coverage_percentile(100.0), Some(100));
+
+        let mut filter = RecordFilter {
+            max_coverage: Some(50),
+            ..RecordFilter::default()
+        };
+        assert!(!filter.apply(&mut interval(60)));
+        filter.clamp_coverage = true;
+        let mut clamped = interval(60);
+        assert!(filter.apply(&mut clamped));
+        assert_eq!(clamped.coverage, 50);
+    }
+
+    #[test]
+    fn reports_spread_of_record_fractions() {
+        let mut fractions = [0.9, 0.1, 0.5, 0.3];
+        let stats = FractionStats::compute(&mut fractions).unwrap();
+        assert_eq!((stats.median, stats.min, stats.max), (0.4, 0.1, 0.9));
+        assert!((stats.sd - 0.3416).abs() < 1e-4);
+        // Half-methylated sites and a 0/1 mix share a mean but not an entropy.
+        let entropy = |fractions: &mut [f32]| FractionStats::compute(fractions).unwrap().entropy;
+        assert_eq!(entropy(&mut [0.5, 0.5]), 1.0);
+        assert_eq!(entropy(&mut [0.0, 1.0]), 0.0);
+        assert_eq!(FractionStats::compute(&mut []), None);
+
+        let fractions = FractionFormat::default();
+        assert_eq!(
+            Field::Stat(Stat::Median).format(&TargetSummary::default(), &fractions),
+            "NA"
+        );
+    }
+
+    #[test]
+    fn trims_or_winsorizes_extreme_fractions() {
+        let records = [(0.5, 1.0), (0.0, 1.0), (0.6, 1.0), (1.0, 1.0), (0.4, 1.0)];
+        assert_eq!(trimmed_mean(&mut records.clone(), 0.2, false), 0.5);
+        // Winsorized: 0.4, 0.4, 0.5, 0.6, 0.6.
+        assert!((trimmed_mean(&mut records.clone(), 0.2, true) - 0.5).abs() < 1e-6);
+        assert!((trimmed_mean(&mut records.clone(), 0.1, false) - 0.5).abs() < 1e-6);
+        assert_eq!(trimmed_mean(&mut [], 0.2, false), 0.0);
+        assert!(parse_trim("0.5").is_err());
+    }
+
+    #[test]
+    fn reports_site_density() {
+        let path = std::env::temp_dir().join(format!("methfast-cpgs-{}.bed", std::process::id()));
+        std::fs::write(
+            &path,
+            "chr1\t30\t31\nchr1\t10\t11\nchr1\t20\t21\nchr1\t900\t901\n",
+        )
+        .unwrap();
+        let reference = read_reference_sites(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 500,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let mut summary = summarize(
+            &reference.by_chrom["chr1"][..2],
+            &target,
+            Strand::Unknown,
+            &Aggregation::default(),
+        );
+        summary.reference_sites =
+            Some(overlapping(&reference.by_chrom["chr1"], &target, Strand::Unknown).count());
+        let fractions = FractionFormat::default();
+        assert_eq!(Field::SitesPerKb.format(&summary, &fractions), "4.00");
+        assert_eq!(Field::CpgShare.format(&summary, &fractions), "0.6667");
+    }
+
+    #[test]
+    fn shrinks_low_coverage_targets_toward_the_mean() {
+        let summary = |methylated: f32, coverage| TargetSummary {
+            sum_methylated: methylated,
+            sum_total_coverage: coverage,
+            ..TargetSummary::default()
+        };
+        let mut rows = vec![
+            vec![summary(40.0, 100)],
+            vec![summary(50.0, 100)],
+            vec![summary(60.0, 100)],
+            vec![summary(1.0, 1)],
+            vec![summary(0.0, 0)],
+        ];
+        shrink_fractions(&mut rows);
+        let shrunk: Vec<f32> = rows
+            .iter()
+            .map(|row| row[0].shrunk_fraction.unwrap())
+            .collect();
+        // One methylated read is pulled most of the way to the prior mean.
+        assert!(shrunk[3] > 0.625 && shrunk[3] < 0.8);
+        assert!((shrunk[0] - 0.4).abs() < 0.01);
+        // Without coverage the estimate is the prior mean.
+        assert!((shrunk[4] - 0.625).abs() < 1e-4);
+    }
+
+    #[test]
+    fn imputes_missing_matrix_values() {
+        let sample = |fraction, coverage| TargetSummary {
+            num_positions: 1,
+            sum_total_coverage: coverage,
+            weighted_fraction: fraction,
+            ..TargetSummary::default()
+        };
+        let matrix = || {
+            vec![
+                vec![sample(0.2, 10), sample(0.4, 10), sample(0.0, 0)],
+                vec![sample(0.2, 10), sample(0.3, 10), sample(0.9, 10)],
+                vec![sample(0.8, 10), sample(0.9, 10), sample(0.1, 10)],
+                vec![sample(0.0, 0), sample(0.0, 0), sample(0.0, 0)],
+            ]
+        };
+        let missing = |summary: &TargetSummary| summary.sum_total_coverage == 0;
+
+        let mut rows = matrix();
+        impute_fractions(&mut rows, Impute::Mean, 1, missing);
+        assert!(rows[0][2].imputed);
+        assert!((rows[0][2].weighted_fraction - 0.3).abs() < 1e-6);
+        assert!(!rows[3][0].imputed);
+
+        let mut rows = matrix();
+        impute_fractions(&mut rows, Impute::Knn, 1, missing);
+        assert_eq!(rows[0][2].weighted_fraction, 0.9);
+        assert!(!rows[1][0].imputed);
+    }
+}
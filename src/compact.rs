@@ -0,0 +1,227 @@
+//! `--compact`: methylation records held in packed, struct-of-arrays tracks
+//! instead of [`MethInterval`]s, for genome-wide inputs that would not fit in
+//! memory otherwise. Fractions are kept to 1/65535 and coverage saturates at
+//! 65535 reads.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::format::{ColumnNames, Context, Layout};
+use crate::{
+    MethInterval, MethRanges, OnOverlap, RecordFilter, Strand, memory, parse_meth_bed,
+    read_meth_records,
+};
+
+const SCALE: f32 = u16::MAX as f32;
+
+/// Bytes of a packed single-base record.
+pub const RECORD_BYTES: usize = 9;
+
+/// The records of one chromosome, sorted by start.
+#[derive(Debug, Default)]
+pub struct Track {
+    starts: Vec<u32>,
+    /// Record ends, kept only once a record spans more than one base.
+    ends: Option<Vec<u32>>,
+    fractions: Vec<u16>,
+    coverages: Vec<u16>,
+    strands: Vec<Strand>,
+}
+
+impl Track {
+    pub fn push(&mut self, iv: &MethInterval) {
+        let start = iv.start.max(0) as u32;
+        let end = iv.end.max(0) as u32;
+        if end != start + 1 && self.ends.is_none() {
+            self.ends = Some(self.starts.iter().map(|start| start + 1).collect());
+        }
+        if let Some(ends) = &mut self.ends {
+            ends.push(end);
+        }
+        self.starts.push(start);
+        self.fractions
+            .push((iv.fraction.clamp(0.0, 1.0) * SCALE).round() as u16);
+        self.coverages
+            .push(iv.coverage.clamp(0, u16::MAX as i32) as u16);
+        self.strands.push(iv.strand);
+    }
+
+    fn end(&self, i: usize) -> u32 {
+        match &self.ends {
+            Some(ends) => ends[i],
+            None => self.starts[i] + 1,
+        }
+    }
+
+    fn get(&self, i: usize) -> MethInterval {
+        MethInterval {
+            start: self.starts[i] as i32,
+            end: self.end(i) as i32,
+            fraction: self.fractions[i] as f32 / SCALE,
+            coverage: self.coverages[i] as i32,
+            strand: self.strands[i],
+        }
+    }
+
+    /// The records that may overlap `[start, end)`, unpacked.
+    pub fn fetch(&self, start: i32, end: i32) -> Vec<MethInterval> {
+        let (start, end) = (start.max(0) as u32, end.max(0) as u32);
+        // Sorted, non-overlapping records have sorted ends too.
+        let first = match &self.ends {
+            Some(ends) => ends.partition_point(|&e| e <= start),
+            None => self.starts.partition_point(|&s| s < start),
+        };
+        (first..self.starts.len())
+            .take_while(|&i| self.starts[i] < end)
+            .map(|i| self.get(i))
+            .collect()
+    }
+
+    fn unpack(&self) -> Vec<MethInterval> {
+        (0..self.starts.len()).map(|i| self.get(i)).collect()
+    }
+}
+
+/// Packed records of every chromosome.
+#[derive(Debug, Default)]
+pub struct CompactRanges {
+    by_chrom: HashMap<String, Track>,
+}
+
+impl CompactRanges {
+    /// The chromosomes with records.
+    pub fn chroms(&self) -> impl Iterator<Item = &str> {
+        self.by_chrom
+            .iter()
+            .filter(|(_, track)| !track.starts.is_empty())
+            .map(|(chrom, _)| chrom.as_str())
+    }
+
+    /// Packs `ranges` one chromosome at a time.
+    pub fn pack(mut ranges: MethRanges) -> CompactRanges {
+        let chroms: Vec<String> = ranges.by_chrom.keys().cloned().collect();
+        let mut by_chrom = HashMap::with_capacity(chroms.len());
+        for chrom in chroms {
+            let intervals = ranges.by_chrom.remove(&chrom).unwrap_or_default();
+            let mut track = Track::default();
+            for iv in &intervals {
+                track.push(iv);
+            }
+            by_chrom.insert(chrom, track);
+        }
+        CompactRanges { by_chrom }
+    }
+
+    /// Parses a methylation file straight into packed tracks, one per entry
+    /// of `contexts` as with [`parse_meth_bed`].
+    pub fn parse(
+        path: &Path,
+        layout: &Layout,
+        names: &ColumnNames,
+        filter: &RecordFilter,
+        contexts: &[Option<Context>],
+    ) -> Result<Vec<CompactRanges>, Box<dyn Error>> {
+        if filter.sort || filter.on_overlap != OnOverlap::Error {
+            // Sorting and resolving overlaps need the records unpacked.
+            return Ok(parse_meth_bed(path, layout, names, filter, contexts)?
+                .into_iter()
+                .map(CompactRanges::pack)
+                .collect());
+        }
+        let mut by_context: Vec<Vec<Track>> = contexts.iter().map(|_| Vec::new()).collect();
+        let mut meter = memory::Meter::new(RECORD_BYTES);
+        let chroms = read_meth_records(
+            path,
+            layout,
+            names,
+            filter,
+            contexts,
+            &mut |i, chrom, iv| {
+                let tracks = &mut by_context[i];
+                if tracks.len() <= chrom as usize {
+                    tracks.resize_with(chrom as usize + 1, Track::default);
+                }
+                tracks[chrom as usize].push(&iv);
+                meter.record()
+            },
+        )?
+        .into_names();
+        Ok(by_context
+            .into_iter()
+            .map(|tracks| CompactRanges {
+                by_chrom: chroms
+                    .iter()
+                    .cloned()
+                    .zip(tracks)
+                    .filter(|(_, track)| !track.starts.is_empty())
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// The records of `chrom` that may overlap `[start, end)`.
+    pub fn fetch(&self, chrom: &str, start: i32, end: i32) -> Vec<MethInterval> {
+        self.by_chrom
+            .get(chrom)
+            .map_or_else(Vec::new, |track| track.fetch(start, end))
+    }
+
+    /// Re-keys the chromosomes by `rename(name)`, merging any that collide.
+    pub fn rename_chroms(&mut self, rename: &dyn Fn(&str) -> String) {
+        let mut by_chrom: HashMap<String, Track> = HashMap::new();
+        for (chrom, track) in std::mem::take(&mut self.by_chrom) {
+            let chrom = rename(&chrom);
+            let track = match by_chrom.remove(&chrom) {
+                None => track,
+                Some(merged) => {
+                    let mut intervals = merged.unpack();
+                    intervals.extend(track.unpack());
+                    intervals.sort_by_key(|iv| (iv.start, iv.end));
+                    let mut track = Track::default();
+                    for iv in &intervals {
+                        track.push(iv);
+                    }
+                    track
+                }
+            };
+            by_chrom.insert(chrom, track);
+        }
+        self.by_chrom = by_chrom;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_and_fetches_records() {
+        let record = |start, end, fraction, coverage| MethInterval {
+            start,
+            end,
+            fraction,
+            coverage,
+            strand: Strand::Plus,
+        };
+        let mut track = Track::default();
+        track.push(&record(10, 11, 0.25, 4));
+        track.push(&record(20, 21, 1.0, 100_000));
+        assert!(track.ends.is_none());
+        let fetched = track.fetch(10, 20);
+        assert_eq!(fetched.len(), 1);
+        assert!((fetched[0].fraction - 0.25).abs() < 1e-4);
+        assert_eq!(track.fetch(15, 30)[0].coverage, u16::MAX as i32);
+
+        // A wider record keeps the ends of every record.
+        track.push(&record(30, 40, 0.0, 2));
+        assert_eq!(track.ends.as_deref(), Some(&[11, 21, 40][..]));
+        let spans: Vec<(i32, i32)> = track
+            .fetch(21, 35)
+            .iter()
+            .map(|iv| (iv.start, iv.end))
+            .collect();
+        assert_eq!(spans, vec![(30, 40)]);
+        assert_eq!(track.fetch(35, 36)[0].strand, Strand::Plus);
+    }
+}
@@ -0,0 +1,148 @@
+//! `methfast compare`: per-region methylation of two samples and their delta.
+
+use clap::Args;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::{format_target, write_lines};
+use crate::error::ThreadError;
+use crate::format::Format;
+use crate::matrix::{TargetIndex, summarize_spec};
+use crate::samples::SampleSpec;
+use crate::{RecordFilter, TargetInterval, TargetSummary, parallel, parse_targets};
+
+#[derive(Args, Debug)]
+pub struct CompareArgs {
+    #[arg(value_name = "METHYLATION_A", help = "First methylation input")]
+    a: PathBuf,
+    #[arg(value_name = "METHYLATION_B", help = "Second methylation input")]
+    b: PathBuf,
+    #[arg(value_name = "TARGET_BED", help = "Regions to compare")]
+    targets: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Format of both inputs (detected per file by default)"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "min-delta",
+        value_name = "F",
+        help = "Only write regions whose absolute delta is at least F"
+    )]
+    min_delta: Option<f64>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// `fraction_a, coverage_a, fraction_b, coverage_b, delta` of one region;
+/// fractions are `NA` without coverage and the delta needs both. `None` when
+/// the delta is below `min_delta`.
+fn compare_row(
+    a: &TargetSummary,
+    b: &TargetSummary,
+    precision: usize,
+    min_delta: Option<f64>,
+) -> Option<String> {
+    let fraction = |summary: &TargetSummary| {
+        (summary.sum_total_coverage > 0).then_some(summary.weighted_fraction as f64)
+    };
+    let delta = fraction(a).zip(fraction(b)).map(|(a, b)| b - a);
+    if let Some(min_delta) = min_delta
+        && delta.is_none_or(|delta| delta.abs() < min_delta)
+    {
+        return None;
+    }
+    let format = |value: Option<f64>| match value {
+        Some(value) => format!("{value:.precision$}"),
+        None => "NA".to_string(),
+    };
+    Some(format!(
+        "{}\t{}\t{}\t{}\t{}",
+        format(fraction(a)),
+        a.sum_total_coverage,
+        format(fraction(b)),
+        b.sum_total_coverage,
+        format(delta)
+    ))
+}
+
+pub fn run(args: &CompareArgs) -> Result<(), Box<dyn Error>> {
+    let mut targets: Vec<TargetInterval> = parse_targets(&args.targets, true, false)?;
+    let named = targets.iter().any(|target| target.name.is_some());
+    if named {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+    let index = TargetIndex::new(&targets);
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    // Each input is streamed once, both at the same time.
+    let summarize = |path: &PathBuf| {
+        summarize_spec(
+            &SampleSpec::from_path(path, args.format),
+            &filter,
+            &targets,
+            &index,
+        )
+        .map_err(ThreadError::from)
+    };
+    let (a, b) = parallel::join(|| summarize(&args.a), || summarize(&args.b));
+    let (a, b) = (a?, b?);
+
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    header.push_str("\tfraction_a\tcoverage_a\tfraction_b\tcoverage_b\tdelta");
+    let lines: Vec<String> = targets
+        .iter()
+        .zip(a.iter().zip(&b))
+        .filter_map(|(target, (a, b))| {
+            compare_row(a, b, args.precision, args.min_delta)
+                .map(|row| format!("{}\t{row}", format_target(target)))
+        })
+        .collect();
+    write_lines(args.output.as_deref(), Some(&header), &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_deltas_and_filters_small_ones() {
+        let summary = |fraction, coverage| TargetSummary {
+            weighted_fraction: fraction,
+            sum_total_coverage: coverage,
+            ..TargetSummary::default()
+        };
+        assert_eq!(
+            compare_row(&summary(0.25, 8), &summary(0.75, 4), 2, None).unwrap(),
+            "0.25\t8\t0.75\t4\t0.50"
+        );
+        assert_eq!(
+            compare_row(&summary(0.25, 8), &summary(0.0, 0), 2, None).unwrap(),
+            "0.25\t8\tNA\t0\tNA"
+        );
+        assert!(compare_row(&summary(0.25, 8), &summary(0.0, 0), 2, Some(0.1)).is_none());
+        assert!(compare_row(&summary(0.25, 8), &summary(0.3, 4), 2, Some(0.1)).is_none());
+    }
+}
@@ -0,0 +1,321 @@
+//! Transparent decompression of gzip, zstd and xz inputs.
+
+use flate2::read::MultiGzDecoder;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{Receiver, sync_channel};
+
+use crate::input::Batches;
+use crate::mmap::Mmap;
+use crate::{is_stdin, remote};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B, 0x08];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Opens a file, stdin for `-` or a URL, decompressing it according to its magic bytes.
+///
+/// zstd streams are decoded in process; xz streams are piped through the `xz`
+/// command-line tool.
+pub fn open(path: &Path) -> Result<Box<dyn BufRead + Send>, Box<dyn Error>> {
+    let mut raw: Box<dyn Read + Send> = if is_stdin(path) {
+        Box::new(io::stdin())
+    } else if remote::is_url(path) {
+        remote::open(path)?
+    } else {
+        Box::new(File::open(path)?)
+    };
+    // Peek at the magic bytes without seeking so pipes work too.
+    let mut header = Vec::with_capacity(XZ_MAGIC.len());
+    (&mut raw)
+        .take(XZ_MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+    let stream = io::Cursor::new(header.clone()).chain(raw);
+    if header.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(stream))))
+    } else if header.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(zstd_decoder(stream)?)))
+    } else if header.starts_with(XZ_MAGIC) {
+        Ok(Box::new(BufReader::new(decompress_with("xz", stream)?)))
+    } else {
+        Ok(Box::new(BufReader::new(stream)))
+    }
+}
+
+/// Memory-maps `path` when it is an uncompressed regular file; `None` for
+/// stdin, URLs, pipes and compressed files, which are read through [`open`].
+pub fn map_plain(path: &Path) -> Result<Option<Mmap>, Box<dyn Error>> {
+    if is_stdin(path) || remote::is_url(path) {
+        return Ok(None);
+    }
+    let file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+    let mapped = Mmap::map(&file)?;
+    Ok((!is_compressed(&mapped)).then_some(mapped))
+}
+
+/// Whether `bytes` start with the magic bytes of gzip, zstd or xz.
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    [GZIP_MAGIC, ZSTD_MAGIC, XZ_MAGIC]
+        .iter()
+        .any(|magic| bytes.starts_with(magic))
+}
+
+/// Bytes [`read_batches`] reads at a time.
+const BATCH_BYTES: usize = 8 << 20;
+
+/// The contents of `path`, opened as with [`open`], in batches of about
+/// [`BATCH_BYTES`] decompressed on a background thread while the caller
+/// parses the previous ones.
+pub fn read_batches(path: &Path) -> Result<ReadAhead, Box<dyn Error>> {
+    let mut reader = open(path)?;
+    Ok(ReadAhead::spawn(move || next_batch(&mut reader)))
+}
+
+/// The next [`BATCH_BYTES`] of `reader`, `None` at its end.
+fn next_batch(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut batch = Vec::with_capacity(BATCH_BYTES);
+    reader.take(BATCH_BYTES as u64).read_to_end(&mut batch)?;
+    Ok((!batch.is_empty()).then_some(batch))
+}
+
+/// A decoder of the zstd stream `input`, all of its frames.
+#[cfg(feature = "zstd")]
+fn zstd_decoder<'a>(input: impl Read + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(Box::new(zstd::stream::read::Decoder::new(input)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decoder<'a>(_input: impl Read + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Err(io::Error::other(
+        "Error: zstd input needs methfast built with the `zstd` feature",
+    ))
+}
+
+/// The contents of a file already in memory: `bytes` themselves, or their
+/// gzip/bgzip or zstd stream decompressed a batch at a time.
+pub fn bytes_batches(bytes: &[u8]) -> Result<Box<Batches<'_>>, Box<dyn Error>> {
+    if bytes.starts_with(XZ_MAGIC) {
+        return Err("Error: xz input in memory is not supported; decompress it first".into());
+    }
+    let mut decoder: Box<dyn Read + Send + '_> = if bytes.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(bytes))
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        zstd_decoder(bytes)?
+    } else {
+        return Ok(Box::new(std::iter::once(Ok(Cow::Borrowed(bytes)))));
+    };
+    let mut done = false;
+    Ok(Box::new(std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let batch = next_batch(&mut decoder).transpose();
+        done = !matches!(batch, Some(Ok(_)));
+        batch.map(|batch| batch.map(Cow::Owned))
+    })))
+}
+
+/// Batch producer of [`ReadAhead`].
+type NextBatch = Box<dyn FnMut() -> io::Result<Option<Vec<u8>>> + Send>;
+
+/// Batches produced on a background thread, at most two ahead of the
+/// consumer. With a single CPU they are produced as they are consumed
+/// instead, since the threads would only take turns.
+pub enum ReadAhead {
+    Thread(Receiver<io::Result<Vec<u8>>>),
+    Inline(Option<NextBatch>),
+}
+
+impl ReadAhead {
+    /// Calls `next` until it returns `None` or an error.
+    pub fn spawn(next: impl FnMut() -> io::Result<Option<Vec<u8>>> + Send + 'static) -> ReadAhead {
+        match std::thread::available_parallelism().is_ok_and(|cpus| cpus.get() == 1) {
+            true => ReadAhead::Inline(Some(Box::new(next))),
+            false => ReadAhead::in_thread(next),
+        }
+    }
+
+    fn in_thread(
+        mut next: impl FnMut() -> io::Result<Option<Vec<u8>>> + Send + 'static,
+    ) -> ReadAhead {
+        let (sender, receiver) = sync_channel(2);
+        std::thread::spawn(move || {
+            loop {
+                let batch = match next() {
+                    Ok(Some(batch)) => Ok(batch),
+                    Ok(None) => break,
+                    Err(err) => Err(err),
+                };
+                let failed = batch.is_err();
+                // The consumer hangs up when it stops early.
+                if sender.send(batch).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        ReadAhead::Thread(receiver)
+    }
+}
+
+impl Iterator for ReadAhead {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        match self {
+            ReadAhead::Thread(receiver) => receiver.recv().ok(),
+            ReadAhead::Inline(next) => {
+                let batch = next.as_mut()?().transpose();
+                if !matches!(batch, Some(Ok(_))) {
+                    *next = None;
+                }
+                batch
+            }
+        }
+    }
+}
+
+/// Streams `input` through `<tool> -dc`, feeding it from a background thread.
+fn decompress_with(
+    tool: &'static str,
+    mut input: impl Read + Send + 'static,
+) -> Result<ToolReader, Box<dyn Error>> {
+    let mut child = Command::new(tool)
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| {
+            format!("Error: decompressing {tool} input needs the `{tool}` command: {err}")
+        })?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    std::thread::spawn(move || {
+        // A failed copy closes the pipe early, which the tool reports as a truncated stream.
+        let _ = io::copy(&mut input, &mut stdin);
+    });
+    Ok(ToolReader::new(tool, child))
+}
+
+/// Output of a helper command; reports its exit status as an error at end of stream.
+pub struct ToolReader {
+    tool: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl ToolReader {
+    /// Wraps a child spawned with a piped stdout.
+    pub fn new(tool: &'static str, mut child: Child) -> ToolReader {
+        let stdout = child.stdout.take().expect("piped stdout");
+        ToolReader {
+            tool,
+            child,
+            stdout,
+        }
+    }
+}
+
+impl Drop for ToolReader {
+    fn drop(&mut self) {
+        // Inputs are often read only partially (format detection, early errors).
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl Read for ToolReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "Error: {} failed to read input ({status})",
+                    self.tool
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decodes_zstd_frames_in_memory_and_from_files() {
+        let text = "chr1\t10\t11\t0.5\t4\nchr1\t20\t21\t1.0\t2\n";
+        // Two frames, as `cat a.zst b.zst` writes.
+        let mut bytes = zstd::stream::encode_all(&text.as_bytes()[..18], 3).unwrap();
+        bytes.extend(zstd::stream::encode_all(&text.as_bytes()[18..], 3).unwrap());
+        let decoded: Vec<u8> = bytes_batches(&bytes)
+            .unwrap()
+            .flat_map(|batch| batch.unwrap().into_owned())
+            .collect();
+        assert_eq!(decoded, text.as_bytes());
+
+        let path =
+            std::env::temp_dir().join(format!("methfast-compression-{}.zst", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mut read = String::new();
+        open(&path).unwrap().read_to_string(&mut read).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, text);
+    }
+
+    #[test]
+    fn decompresses_xz_input() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-compression-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t0.5\t4\n").unwrap();
+        let status = Command::new("xz").arg("-f").arg(&path).status();
+        let xz_path = path.with_extension("bed.xz");
+        if !status.is_ok_and(|status| status.success()) {
+            return;
+        }
+
+        let mut text = String::new();
+        open(&xz_path).unwrap().read_to_string(&mut text).unwrap();
+        std::fs::remove_file(&xz_path).unwrap();
+        assert_eq!(text, "chr1\t10\t11\t0.5\t4\n");
+    }
+
+    #[test]
+    fn reads_batches_ahead_until_the_end_or_an_error() {
+        let batches = |fail_at: usize| {
+            let mut i = 0;
+            move || {
+                i += 1;
+                match i {
+                    i if i == fail_at => Err(io::Error::other("broken")),
+                    1..=3 => Ok(Some(vec![i as u8])),
+                    _ => Ok(None),
+                }
+            }
+        };
+        for read_ahead in [
+            ReadAhead::in_thread(batches(0)),
+            ReadAhead::Inline(Some(Box::new(batches(0)))),
+        ] {
+            let read: Vec<Vec<u8>> = read_ahead.map(Result::unwrap).collect();
+            assert_eq!(read, vec![vec![1], vec![2], vec![3]]);
+        }
+        for read_ahead in [
+            ReadAhead::in_thread(batches(2)),
+            ReadAhead::Inline(Some(Box::new(batches(2)))),
+        ] {
+            let read: Vec<bool> = read_ahead.map(|batch| batch.is_ok()).collect();
+            assert_eq!(read, vec![true, false]);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Binomial confidence intervals for the methylation level of a target.
+
+use clap::ValueEnum;
+
+/// How `--ci` bounds are computed from the methylated and total read counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CiMethod {
+    /// Wilson score interval.
+    #[default]
+    Wilson,
+    /// Equal-tailed interval of the Beta(x + 1/2, n - x + 1/2) posterior.
+    Jeffreys,
+}
+
+/// Two-sided `level` interval for `methylated` out of `total` reads, or `None`
+/// without coverage.
+pub fn interval(method: CiMethod, methylated: f64, total: f64, level: f64) -> Option<(f64, f64)> {
+    if total <= 0.0 {
+        return None;
+    }
+    let methylated = methylated.clamp(0.0, total);
+    let tail = (1.0 - level) / 2.0;
+    Some(match method {
+        CiMethod::Wilson => {
+            let z = normal_quantile(1.0 - tail);
+            let p = methylated / total;
+            let z2 = z * z;
+            let center = (p + z2 / (2.0 * total)) / (1.0 + z2 / total);
+            let half = z / (1.0 + z2 / total)
+                * (p * (1.0 - p) / total + z2 / (4.0 * total * total)).sqrt();
+            ((center - half).max(0.0), (center + half).min(1.0))
+        }
+        CiMethod::Jeffreys => {
+            let (a, b) = (methylated + 0.5, total - methylated + 0.5);
+            let low = if methylated <= 0.0 {
+                0.0
+            } else {
+                beta_quantile(tail, a, b)
+            };
+            let high = if methylated >= total {
+                1.0
+            } else {
+                beta_quantile(1.0 - tail, a, b)
+            };
+            (low, high)
+        }
+    })
+}
+
+/// Standard normal quantile (Acklam's rational approximation).
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < 0.02425 {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - 0.02425 {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+/// Inverts the regularized incomplete beta function by bisection.
+fn beta_quantile(p: f64, a: f64, b: f64) -> f64 {
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..60 {
+        let mid = (low + high) / 2.0;
+        if incomplete_beta(mid, a, b) < p {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Regularized incomplete beta function I_x(a, b).
+pub fn incomplete_beta(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The continued fraction converges quickly only on one side of the mean.
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(x, a, b) / a
+    } else {
+        1.0 - front * beta_fraction(1.0 - x, b, a) / b
+    }
+}
+
+/// Continued fraction for the incomplete beta function (modified Lentz).
+fn beta_fraction(x: f64, a: f64, b: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        for coefficient in [even, odd] {
+            d = 1.0 + coefficient * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1.0 + coefficient / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    h
+}
+
+/// Natural log of the gamma function (Lanczos approximation).
+pub fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let mut series = 1.000000000190015;
+    for (i, coefficient) in COEFFICIENTS.iter().enumerate() {
+        series += coefficient / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_reference_intervals() {
+        // 8 of 10 methylated, 95%: Wilson (0.490, 0.943), Jeffreys (0.4972, 0.9559).
+        let (low, high) = interval(CiMethod::Wilson, 8.0, 10.0, 0.95).unwrap();
+        assert!((low - 0.4902).abs() < 1e-3 && (high - 0.9433).abs() < 1e-3);
+        let (low, high) = interval(CiMethod::Jeffreys, 8.0, 10.0, 0.95).unwrap();
+        assert!((low - 0.4972).abs() < 1e-3 && (high - 0.9559).abs() < 1e-3);
+        assert_eq!(interval(CiMethod::Jeffreys, 0.0, 5.0, 0.95).unwrap().0, 0.0);
+        assert_eq!(interval(CiMethod::Wilson, 0.0, 0.0, 0.95), None);
+    }
+}
@@ -0,0 +1,476 @@
+//! `--config FILE` and `--profile NAME`: long options kept in a TOML file, so
+//! pipelines share one set of layouts, filters and output settings. Keys are
+//! the long options of the command being run (`min-coverage = 5`, with `-` or
+//! `_`); top-level keys always apply and those of a `[profile.NAME]` table on
+//! top of them with `--profile NAME`. Options given on the command line win.
+//!
+//! The file is read as the subset of TOML these options need: strings,
+//! numbers, booleans and one-line arrays, and `[profile.NAME]` tables.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// The config file read for `--profile` without `--config`.
+const DEFAULT_CONFIG: &str = "methfast.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bool(bool),
+    /// An integer or float, as written without `_` separators.
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+}
+
+/// A `key = value` line.
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    /// The key with `_` read as `-`.
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+/// The keys of a config file: top-level ones and those of each profile.
+#[derive(Debug, Default)]
+struct Config {
+    options: Vec<Entry>,
+    profiles: Vec<(String, Vec<Entry>)>,
+}
+
+impl Config {
+    fn parse(text: &str, path: &Path) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        // The profile the lines belong to, `None` before the first table.
+        let mut profile: Option<usize> = None;
+        for (i, line) in text.lines().enumerate() {
+            let linenum = i + 1;
+            let error = |message: String| format!("Error: {}:{linenum}: {message}", path.display());
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(table) = line.strip_prefix('[') {
+                let (table, rest) = table
+                    .split_once(']')
+                    .ok_or_else(|| error("unterminated table header".to_string()))?;
+                if !is_comment(rest) {
+                    return Err(error(format!("unexpected `{rest}` after the table header")).into());
+                }
+                let name = match table.trim().strip_prefix("profile.") {
+                    Some(name) if is_bare_key(name) => name.to_string(),
+                    _ => {
+                        return Err(error(format!(
+                            "unsupported table [{table}]; expected [profile.NAME]"
+                        ))
+                        .into());
+                    }
+                };
+                if config.profiles.iter().any(|(known, _)| *known == name) {
+                    return Err(error(format!("profile {name} is defined twice")).into());
+                }
+                config.profiles.push((name, Vec::new()));
+                profile = Some(config.profiles.len() - 1);
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".to_string()))?;
+            let key = key.trim();
+            if !is_bare_key(key) {
+                return Err(error(format!("invalid key `{key}`")).into());
+            }
+            let (value, rest) = parse_value(value.trim_start()).map_err(error)?;
+            if !is_comment(rest) {
+                return Err(error(format!("unexpected `{}` after the value", rest.trim())).into());
+            }
+            let entries = match profile {
+                Some(i) => &mut config.profiles[i].1,
+                None => &mut config.options,
+            };
+            let key = key.replace('_', "-");
+            if entries.iter().any(|entry| entry.key == key) {
+                return Err(error(format!("{key} is set twice")).into());
+            }
+            entries.push(Entry {
+                key,
+                value,
+                line: linenum,
+            });
+        }
+        Ok(config)
+    }
+
+    /// The top-level entries with those of `profile` in their place.
+    fn entries(&self, profile: Option<&str>, path: &Path) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let Some(profile) = profile else {
+            return Ok(self.options.clone());
+        };
+        let Some((_, overrides)) = self.profiles.iter().find(|(name, _)| name == profile) else {
+            let known: Vec<&str> = self
+                .profiles
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            return Err(format!(
+                "Error: {}: no profile {profile}; the file defines {}",
+                path.display(),
+                match known.is_empty() {
+                    true => "none".to_string(),
+                    false => known.join(", "),
+                }
+            )
+            .into());
+        };
+        let mut entries: Vec<Entry> = self
+            .options
+            .iter()
+            .filter(|entry| overrides.iter().all(|other| other.key != entry.key))
+            .cloned()
+            .collect();
+        entries.extend(overrides.iter().cloned());
+        Ok(entries)
+    }
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether `rest` of a line holds at most a comment.
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parses the value at the start of `text`, returning it and the text after it.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    other => {
+                        return Err(format!(
+                            "unsupported escape \\{}",
+                            other.map(String::from).unwrap_or_default()
+                        ));
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let (value, rest) = rest
+            .split_once('\'')
+            .ok_or_else(|| "unterminated string".to_string())?;
+        return Ok((Value::String(value.to_string()), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            if matches!(value, Value::Array(_)) {
+                return Err("nested arrays are not supported".to_string());
+            }
+            values.push(value);
+            rest = after.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after,
+                None if rest.starts_with(']') => {}
+                None => return Err("expected `,` or `]` in the array".to_string()),
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    match token {
+        "true" => Ok((Value::Bool(true), rest)),
+        "false" => Ok((Value::Bool(false), rest)),
+        "" => Err("expected a value".to_string()),
+        token => {
+            let number = token.replace('_', "");
+            match number.parse::<f64>() {
+                Ok(_) => Ok((Value::Number(number), rest)),
+                Err(_) => Err(format!(
+                    "invalid value `{token}`; quote strings, e.g. \"{token}\""
+                )),
+            }
+        }
+    }
+}
+
+/// `value` as it is passed on the command line.
+fn argument(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) | Value::String(value) => Some(value.clone()),
+        Value::Array(_) => None,
+    }
+}
+
+/// The value of `--NAME VALUE` or `--NAME=VALUE` in `args`.
+fn option_value(args: &[OsString], name: &str) -> Option<String> {
+    let long = format!("--{name}");
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == long {
+            return args.next().map(String::from);
+        }
+        if let Some(value) = arg.strip_prefix(&format!("{long}=")) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// `args`, the arguments of the process, with the options of the config file
+/// of `--config` (with `--profile`) inserted where `command` takes them.
+pub fn expand(
+    args: Vec<OsString>,
+    command: &clap::Command,
+) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let profile = option_value(&args, "profile");
+    let path = match option_value(&args, "config") {
+        Some(path) => path,
+        None if profile.is_some() => DEFAULT_CONFIG.to_string(),
+        None => return Ok(args),
+    };
+    let path = Path::new(&path);
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("Error: cannot read config {}: {err}", path.display()))?;
+    let entries = Config::parse(&text, path)?.entries(profile.as_deref(), path)?;
+
+    // Options follow the subcommand, if any: the first argument that is
+    // neither `--config`/`--profile` nor their value.
+    let mut position = 1;
+    while let Some(arg) = args.get(position).map(|arg| arg.to_string_lossy()) {
+        match arg.as_ref() {
+            "--config" | "--profile" => position += 2,
+            arg if arg.starts_with("--config=") || arg.starts_with("--profile=") => position += 1,
+            _ => break,
+        }
+    }
+    let subcommand = args
+        .get(position)
+        .and_then(|arg| command.find_subcommand(arg.to_string_lossy().as_ref()));
+    // A subcommand takes its own options and the global ones of `command`.
+    let find = |matches: &dyn Fn(&clap::Arg) -> bool| {
+        let global = command.get_arguments().filter(|arg| arg.is_global_set());
+        match subcommand {
+            Some(subcommand) => subcommand
+                .get_arguments()
+                .chain(global)
+                .find(|arg| matches(arg)),
+            None => command.get_arguments().find(|arg| matches(arg)),
+        }
+        .cloned()
+    };
+    let insert_at = match subcommand {
+        Some(_) => position + 1,
+        None => 1,
+    };
+
+    // Long options given on the command line, which the file does not override.
+    let mut given = HashSet::new();
+    for arg in &args[1..] {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            given.insert(long.split('=').next().unwrap_or(long).to_string());
+        } else if let Some(short) = arg.strip_prefix('-').and_then(|flags| flags.chars().next())
+            && let Some(long) = find(&|option| option.get_short() == Some(short))
+                .as_ref()
+                .and_then(clap::Arg::get_long)
+        {
+            given.insert(long.to_string());
+        }
+    }
+
+    let mut options: Vec<OsString> = Vec::new();
+    for entry in entries {
+        let error =
+            |message: String| format!("Error: {}:{}: {message}", path.display(), entry.line);
+        if entry.key == "config" || entry.key == "profile" {
+            return Err(error(format!("{} cannot be set in a config file", entry.key)).into());
+        }
+        let option = find(&|option| option.get_long() == Some(entry.key.as_str()))
+            .ok_or_else(|| error(format!("unknown option --{}", entry.key)))?;
+        if given.contains(&entry.key) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{}", entry.key));
+        if !option.get_action().takes_values() {
+            let times = match &entry.value {
+                Value::Bool(set) => usize::from(*set),
+                Value::Number(count) if matches!(option.get_action(), clap::ArgAction::Count) => {
+                    count
+                        .parse()
+                        .map_err(|_| error(format!("invalid count {count}")))?
+                }
+                _ => return Err(error(format!("--{} expects true or false", entry.key)).into()),
+            };
+            options.extend(std::iter::repeat_n(flag, times));
+            continue;
+        }
+        let values = match &entry.value {
+            Value::Array(values) => values.iter().filter_map(argument).collect(),
+            value => vec![argument(value).unwrap_or_default()],
+        };
+        for value in values {
+            options.push(flag.clone());
+            options.push(value.into());
+        }
+    }
+    let mut args = args;
+    args.splice(insert_at..insert_at, options);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn expands_config_options_under_command_line_ones() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "# shared settings\n\
+             format = \"bismark-cov\"\n\
+             min_coverage = 5\n\
+             region = ['chr1:1-100', \"chr2:5-10\"]  # two regions\n\
+             \n\
+             [profile.nanopore]\n\
+             format = \"bedmethyl\"\n\
+             mod-code = \"m\"\n\
+             sort = true\n\
+             verbose = 2\n\
+             threads = 4\n",
+        )
+        .unwrap();
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+        let config = path.to_str().unwrap();
+        let command = crate::cli::Cli::command();
+        let expanded = expand(
+            args(&[
+                "methfast",
+                "--config",
+                config,
+                "--profile",
+                "nanopore",
+                "--min-coverage=8",
+                "a.bed",
+            ]),
+            &command,
+        );
+        let top_level = expand(args(&["methfast", "--config", config, "a.bed"]), &command);
+        let subcommand = expand(
+            args(&["methfast", "--config", config, "nearest", "a.bed"]),
+            &command,
+        );
+        let missing = expand(
+            args(&["methfast", "--config", config, "--profile", "ont"]),
+            &command,
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // The profile replaces the format, and the command line the minimum coverage.
+        assert_eq!(
+            expanded.unwrap(),
+            args(&[
+                "methfast",
+                "--region",
+                "chr1:1-100",
+                "--region",
+                "chr2:5-10",
+                "--format",
+                "bedmethyl",
+                "--mod-code",
+                "m",
+                "--sort",
+                "--verbose",
+                "--verbose",
+                "--threads",
+                "4",
+                "--config",
+                config,
+                "--profile",
+                "nanopore",
+                "--min-coverage=8",
+                "a.bed",
+            ])
+        );
+        assert_eq!(
+            top_level.unwrap(),
+            args(&[
+                "methfast",
+                "--format",
+                "bismark-cov",
+                "--min-coverage",
+                "5",
+                "--region",
+                "chr1:1-100",
+                "--region",
+                "chr2:5-10",
+                "--config",
+                config,
+                "a.bed",
+            ])
+        );
+        // nearest has no --region.
+        assert!(
+            subcommand
+                .unwrap_err()
+                .to_string()
+                .ends_with(":4: unknown option --region")
+        );
+        assert!(
+            missing
+                .unwrap_err()
+                .to_string()
+                .ends_with("no profile ont; the file defines nanopore")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let path = Path::new("methfast.toml");
+        assert_eq!(
+            Config::parse("format = bismark-cov\n", path)
+                .unwrap_err()
+                .to_string(),
+            "Error: methfast.toml:1: invalid value `bismark-cov`; quote strings, e.g. \"bismark-cov\""
+        );
+        assert!(Config::parse("[filters]\n", path).is_err());
+        assert!(Config::parse("a = 1\na = 2\n", path).is_err());
+        assert!(Config::parse("a = \"open\n", path).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! `methfast convert`: rewrites a methylation file in another format.
+
+use clap::{Args, ValueEnum};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::format::Format;
+use crate::{MethInterval, RecordFilter, Strand, compression, detect_format, parse_record};
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    #[arg(value_name = "METHYLATION_BED", help = "Input file; `-` for stdin")]
+    input: PathBuf,
+    #[arg(
+        long = "from",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    from: Format,
+    #[arg(long = "to", value_enum, help = "Output format")]
+    to: Target,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Drop records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// Formats `convert` can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Target {
+    /// 0-based `chrom, start, end, fraction, coverage`.
+    Generic,
+    /// Bismark coverage: 1-based `chrom, start, end, percent, methylated, unmethylated`.
+    BismarkCov,
+    /// modkit/ONT bedMethyl with 18 columns and modification code `m`.
+    Bedmethyl,
+    /// MethylDackel bedGraph: 0-based, with a track line and integer percentages.
+    Methyldackel,
+}
+
+/// Formats one record in `target`'s layout; coordinates are converted from the
+/// 0-based half-open intervals every parser produces.
+fn format_record(target: Target, chrom: &str, iv: &MethInterval) -> String {
+    let methylated = (iv.fraction as f64 * iv.coverage as f64).round() as i32;
+    let methylated = methylated.clamp(0, iv.coverage);
+    let unmethylated = iv.coverage - methylated;
+    let percent = iv.fraction as f64 * 100.0;
+    match target {
+        Target::Generic => format!(
+            "{chrom}\t{}\t{}\t{:.4}\t{}",
+            iv.start, iv.end, iv.fraction, iv.coverage
+        ),
+        Target::BismarkCov => format!(
+            "{chrom}\t{}\t{}\t{percent:.4}\t{methylated}\t{unmethylated}",
+            iv.start + 1,
+            iv.end
+        ),
+        Target::Bedmethyl => {
+            let strand = match iv.strand {
+                Strand::Plus => "+",
+                Strand::Minus => "-",
+                Strand::Unknown => ".",
+            };
+            format!(
+                "{chrom}\t{start}\t{end}\tm\t{coverage}\t{strand}\t{start}\t{end}\t255,0,0\t{coverage}\t{percent:.2}\t{methylated}\t{unmethylated}\t0\t0\t0\t0\t0",
+                start = iv.start,
+                end = iv.end,
+                coverage = iv.coverage,
+            )
+        }
+        Target::Methyldackel => format!(
+            "{chrom}\t{}\t{}\t{}\t{methylated}\t{unmethylated}",
+            iv.start,
+            iv.end,
+            percent.round() as i32
+        ),
+    }
+}
+
+pub fn run(args: &ConvertArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.from {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let layout = format.layout();
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    if args.to == Target::Methyldackel {
+        writeln!(
+            out,
+            "track type=\"bedGraph\" description=\"CpG methylation levels\""
+        )?;
+    }
+    // Records are converted as they are read, so any input size streams through.
+    let mut reader = compression::open(&args.input)?;
+    let mut line = String::new();
+    let mut linenum = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        linenum += 1;
+        let record = parse_record(&line, &layout, &filter)
+            .map_err(|kind| kind.at(&args.input, Some(linenum)))?;
+        if let Some((chrom, iv)) = record {
+            writeln!(out, "{}", format_record(args.to, chrom, &iv))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_every_target_format() {
+        let iv = MethInterval {
+            start: 99,
+            end: 100,
+            fraction: 0.75,
+            coverage: 8,
+            strand: Strand::Plus,
+        };
+        assert_eq!(
+            format_record(Target::BismarkCov, "chr1", &iv),
+            "chr1\t100\t100\t75.0000\t6\t2"
+        );
+        assert_eq!(
+            format_record(Target::Methyldackel, "chr1", &iv),
+            "chr1\t99\t100\t75\t6\t2"
+        );
+        for (target, format) in [
+            (Target::Generic, Format::Generic),
+            (Target::BismarkCov, Format::BismarkCov),
+            (Target::Bedmethyl, Format::Bedmethyl),
+            (Target::Methyldackel, Format::Methyldackel),
+        ] {
+            let line = format_record(target, "chr1", &iv);
+            let (chrom, parsed) = parse_record(&line, &format.layout(), &RecordFilter::default())
+                .unwrap()
+                .unwrap();
+            assert_eq!(chrom, "chr1");
+            assert_eq!((parsed.start, parsed.end, parsed.coverage), (99, 100, 8));
+            assert!((parsed.fraction - 0.75).abs() < 1e-6, "{target:?}");
+        }
+    }
+}
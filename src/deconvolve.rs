@@ -0,0 +1,365 @@
+//! `methfast deconvolve`: cell-type proportions from a reference atlas of
+//! marker-region methylation, by non-negative least squares.
+
+use crate::parallel::*;
+use clap::Args;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use crate::cli::write_lines;
+use crate::error::ThreadError;
+use crate::format::Format;
+use crate::matrix::{TargetIndex, summarize_spec};
+use crate::samples::{self, SampleSpec};
+use crate::{RecordFilter, Strand, TargetInterval, TargetSummary, compression};
+
+#[derive(Args, Debug)]
+pub struct DeconvolveArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        required_unless_present = "samples",
+        help = "Methylation inputs to deconvolve"
+    )]
+    inputs: Vec<PathBuf>,
+    #[arg(
+        long = "atlas",
+        value_name = "TSV",
+        help = "Reference atlas: chrom, start, end, [name,] then one fraction column per cell type"
+    )]
+    atlas: PathBuf,
+    #[arg(
+        long = "samples",
+        value_name = "TSV",
+        conflicts_with = "inputs",
+        help = "Sample manifest with label<TAB>path[<TAB>format] lines"
+    )]
+    samples: Option<PathBuf>,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "min-region-coverage",
+        value_name = "INT",
+        default_value_t = 1,
+        help = "Leave out atlas regions covered by fewer reads in a sample"
+    )]
+    min_region_coverage: i32,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of the output"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// Marker regions and the reference fraction of every cell type in each.
+struct Atlas {
+    cell_types: Vec<String>,
+    regions: Vec<TargetInterval>,
+    fractions: Vec<Vec<f64>>,
+}
+
+fn read_atlas(path: &Path) -> Result<Atlas, Box<dyn Error>> {
+    let mut lines = compression::open(path)?.lines();
+    let header = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| format!("Error: {} is empty", path.display()))?;
+    let columns: Vec<&str> = header.split('\t').collect();
+    let first = if columns.get(3) == Some(&"name") {
+        4
+    } else {
+        3
+    };
+    if columns.len() <= first {
+        return Err(format!("Error: {}: expected cell type columns", path.display()).into());
+    }
+    let mut atlas = Atlas {
+        cell_types: columns[first..]
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        regions: Vec::new(),
+        fractions: Vec::new(),
+    };
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || format!("Error: {}: invalid line {}: {line}", path.display(), i + 2);
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != columns.len() {
+            return Err(invalid().into());
+        }
+        let fractions: Vec<f64> = fields[first..]
+            .iter()
+            .map(|value| value.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        atlas.regions.push(TargetInterval {
+            chrom: fields[0].to_string(),
+            start: fields[1].parse().map_err(|_| invalid())?,
+            end: fields[2].parse().map_err(|_| invalid())?,
+            name: (first == 4).then(|| fields[3].to_string()),
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        });
+        atlas.fractions.push(fractions);
+    }
+    Ok(atlas)
+}
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting; `None`
+/// for a singular system.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (done, rest) = a.split_at_mut(col + 1);
+        let pivot_row = &done[col];
+        for (offset, row) in rest.iter_mut().enumerate() {
+            let factor = row[col] / pivot_row[col];
+            for (value, pivot) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+            b[col + 1 + offset] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let rest: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Lawson-Hanson non-negative least squares: the `x >= 0` minimizing the
+/// residual of `a x = b`, with `a` given by rows.
+fn nnls(a: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = a.first().map_or(0, Vec::len);
+    let ata: Vec<Vec<f64>> = (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| a.iter().map(|row| row[i] * row[j]).sum())
+                .collect()
+        })
+        .collect();
+    let atb: Vec<f64> = (0..n)
+        .map(|i| a.iter().zip(b).map(|(row, b)| row[i] * b).sum())
+        .collect();
+    let gradient = |x: &[f64]| -> Vec<f64> {
+        (0..n)
+            .map(|i| atb[i] - (0..n).map(|j| ata[i][j] * x[j]).sum::<f64>())
+            .collect()
+    };
+    // Least squares over the passive set, zero elsewhere.
+    let restricted = |passive: &[bool]| -> Option<Vec<f64>> {
+        let set: Vec<usize> = (0..n).filter(|&i| passive[i]).collect();
+        let sub = set
+            .iter()
+            .map(|&i| set.iter().map(|&j| ata[i][j]).collect())
+            .collect();
+        let z = solve(sub, set.iter().map(|&i| atb[i]).collect())?;
+        let mut full = vec![0.0; n];
+        for (&i, z) in set.iter().zip(z) {
+            full[i] = z;
+        }
+        Some(full)
+    };
+    const TOLERANCE: f64 = 1e-10;
+    let mut x = vec![0.0; n];
+    let mut passive = vec![false; n];
+    for _ in 0..3 * n.max(1) {
+        let w = gradient(&x);
+        let Some(next) = (0..n)
+            .filter(|&i| !passive[i] && w[i] > TOLERANCE)
+            .max_by(|&i, &j| w[i].total_cmp(&w[j]))
+        else {
+            break;
+        };
+        passive[next] = true;
+        loop {
+            let Some(z) = restricted(&passive) else {
+                passive[next] = false;
+                return x;
+            };
+            if (0..n).all(|i| !passive[i] || z[i] > TOLERANCE) {
+                x = z;
+                break;
+            }
+            let alpha = (0..n)
+                .filter(|&i| passive[i] && z[i] <= TOLERANCE)
+                .map(|i| x[i] / (x[i] - z[i]))
+                .fold(f64::INFINITY, f64::min);
+            for i in 0..n {
+                x[i] += alpha * (z[i] - x[i]);
+                if passive[i] && x[i] <= TOLERANCE {
+                    passive[i] = false;
+                    x[i] = 0.0;
+                }
+            }
+        }
+    }
+    x
+}
+
+/// Proportions of the cell types in a sample, normalized to sum to 1, with
+/// the root mean square residual over the regions used, and their number.
+fn deconvolve(
+    atlas: &Atlas,
+    summaries: &[TargetSummary],
+    min_region_coverage: i32,
+) -> Option<(Vec<f64>, f64, usize)> {
+    let (a, b): (Vec<Vec<f64>>, Vec<f64>) = atlas
+        .fractions
+        .iter()
+        .zip(summaries)
+        .filter(|(_, summary)| summary.sum_total_coverage >= min_region_coverage.max(1))
+        .map(|(reference, summary)| (reference.clone(), summary.weighted_fraction as f64))
+        .unzip();
+    if b.is_empty() {
+        return None;
+    }
+    let x = nnls(&a, &b);
+    let residual = (a
+        .iter()
+        .zip(&b)
+        .map(|(row, b)| (row.iter().zip(&x).map(|(a, x)| a * x).sum::<f64>() - b).powi(2))
+        .sum::<f64>()
+        / b.len() as f64)
+        .sqrt();
+    let total: f64 = x.iter().sum();
+    let proportions = x
+        .iter()
+        .map(|x| if total > 0.0 { x / total } else { 0.0 })
+        .collect();
+    Some((proportions, residual, b.len()))
+}
+
+pub fn run(args: &DeconvolveArgs) -> Result<(), Box<dyn Error>> {
+    let atlas = read_atlas(&args.atlas)?;
+    let specs = match &args.samples {
+        Some(manifest) => samples::read_manifest(manifest, args.format)?,
+        None => args
+            .inputs
+            .iter()
+            .map(|path| SampleSpec::from_path(path, args.format))
+            .collect(),
+    };
+    let index = TargetIndex::new(&atlas.regions);
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let columns = specs
+        .par_iter()
+        .map(|spec| {
+            summarize_spec(spec, &filter, &atlas.regions, &index).map_err(ThreadError::from)
+        })
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+
+    let precision = args.precision;
+    let na = vec!["NA".to_string(); atlas.cell_types.len()].join("\t");
+    let lines: Vec<String> = specs
+        .iter()
+        .zip(&columns)
+        .map(
+            |(spec, summaries)| match deconvolve(&atlas, summaries, args.min_region_coverage) {
+                Some((proportions, residual, n_regions)) => format!(
+                    "{}\t{}\t{n_regions}\t{residual:.precision$}",
+                    spec.label,
+                    proportions
+                        .iter()
+                        .map(|p| format!("{p:.precision$}"))
+                        .collect::<Vec<_>>()
+                        .join("\t")
+                ),
+                None => format!("{}\t{na}\t0\tNA", spec.label),
+            },
+        )
+        .collect();
+    let header = format!("sample\t{}\tn_regions\trmse", atlas.cell_types.join("\t"));
+    write_lines(args.output.as_deref(), Some(&header), &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_mixture_proportions() {
+        let atlas = Atlas {
+            cell_types: vec!["neutrophil".into(), "hepatocyte".into(), "b_cell".into()],
+            regions: Vec::new(),
+            fractions: vec![
+                vec![0.9, 0.1, 0.1],
+                vec![0.1, 0.9, 0.1],
+                vec![0.1, 0.1, 0.9],
+                vec![0.8, 0.8, 0.2],
+            ],
+        };
+        // 70% neutrophil, 30% hepatocyte, no B cells.
+        let summaries: Vec<TargetSummary> = atlas
+            .fractions
+            .iter()
+            .map(|row| TargetSummary {
+                weighted_fraction: (0.7 * row[0] + 0.3 * row[1]) as f32,
+                sum_total_coverage: 20,
+                ..TargetSummary::default()
+            })
+            .collect();
+        let (proportions, residual, n_regions) = deconvolve(&atlas, &summaries, 1).unwrap();
+        assert_eq!(n_regions, 4);
+        assert!(residual < 1e-6);
+        for (p, expected) in proportions.iter().zip([0.7, 0.3, 0.0]) {
+            assert!((p - expected).abs() < 1e-5, "{proportions:?}");
+        }
+        // A negative unconstrained solution is clamped to zero.
+        assert_eq!(
+            nnls(&[vec![1.0, 1.0], vec![1.0, -1.0]], &[1.0, 3.0])[1],
+            0.0
+        );
+    }
+
+    #[test]
+    fn nnls_recovers_exact_mixture_weights() {
+        let a = vec![
+            vec![0.9, 0.1, 0.2, 0.5],
+            vec![0.1, 0.8, 0.3, 0.5],
+            vec![0.4, 0.2, 0.9, 0.1],
+            vec![0.6, 0.7, 0.1, 0.3],
+            vec![0.2, 0.3, 0.6, 0.8],
+        ];
+        // Unnormalized weights, one of them zero.
+        let weights = [0.5, 0.25, 0.0, 0.125];
+        let b: Vec<f64> = a
+            .iter()
+            .map(|row| row.iter().zip(weights).map(|(a, w)| a * w).sum())
+            .collect();
+        let x = nnls(&a, &b);
+        for (found, w) in x.iter().zip(weights) {
+            assert!((found - w).abs() < 1e-9, "{x:?}");
+        }
+        assert_eq!(x[2], 0.0);
+    }
+}
@@ -0,0 +1,350 @@
+//! `methfast dmr`: differential methylation between two groups of samples.
+
+use crate::parallel::*;
+use clap::{Args, ValueEnum};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::{format_target, write_lines};
+use crate::confidence::{incomplete_beta, ln_gamma};
+use crate::error::ThreadError;
+use crate::format::Format;
+use crate::matrix::{TargetIndex, summarize_spec};
+use crate::samples::{self, SampleSpec};
+use crate::{RecordFilter, TargetInterval, TargetSummary, parse_targets, tile_genome};
+
+#[derive(Args, Debug)]
+pub struct DmrArgs {
+    #[arg(
+        long = "samples",
+        value_name = "TSV",
+        help = "Sample manifest with label<TAB>path[<TAB>format] lines"
+    )]
+    samples: PathBuf,
+    #[arg(
+        long = "case",
+        value_name = "LABELS",
+        value_delimiter = ',',
+        required = true,
+        help = "Comma-separated labels of the case samples"
+    )]
+    case: Vec<String>,
+    #[arg(
+        long = "control",
+        value_name = "LABELS",
+        value_delimiter = ',',
+        required = true,
+        help = "Comma-separated labels of the control samples"
+    )]
+    control: Vec<String>,
+    #[arg(
+        value_name = "TARGET_BED",
+        required_unless_present = "window",
+        help = "Regions to test (omitted with --window)"
+    )]
+    targets: Option<PathBuf>,
+    #[arg(
+        long = "window",
+        value_name = "INT",
+        value_parser = clap::value_parser!(i32).range(1..),
+        requires = "chrom_sizes",
+        conflicts_with = "targets",
+        help = "Test consecutive INT-base windows of the genome instead of TARGET_BED"
+    )]
+    window: Option<i32>,
+    #[arg(
+        long = "chrom-sizes",
+        value_name = "FILE",
+        help = "Chromosome sizes for --window"
+    )]
+    chrom_sizes: Option<PathBuf>,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Format of the samples without one in the manifest"
+    )]
+    format: Format,
+    #[arg(
+        long = "test",
+        value_enum,
+        default_value_t = DmrTest::Fisher,
+        help = "Statistical test per region"
+    )]
+    test: DmrTest,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "max-q",
+        value_name = "Q",
+        help = "Only write regions with a q-value of at most Q"
+    )]
+    max_q: Option<f64>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DmrTest {
+    /// Fisher's exact test on the methylated and unmethylated reads pooled per group.
+    Fisher,
+    /// Welch's t-test on the per-sample fractions (two or more covered samples per group).
+    Welch,
+}
+
+/// One tested region.
+#[derive(Debug, Clone, PartialEq)]
+struct DmrRow {
+    target: usize,
+    case_fraction: f64,
+    control_fraction: f64,
+    p_value: f64,
+    q_value: f64,
+}
+
+/// Methylated and total reads of a group of summaries, pooled.
+fn pooled(summaries: &[&TargetSummary]) -> (f64, f64) {
+    summaries
+        .iter()
+        .fold((0.0, 0.0), |(methylated, total), summary| {
+            (
+                methylated + summary.sum_methylated as f64,
+                total + summary.sum_total_coverage as f64,
+            )
+        })
+}
+
+/// Two-sided p-value of Fisher's exact test on a 2x2 table of read counts.
+fn fisher_exact(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    let (row1, row2, col1) = (a + b, c + d, a + c);
+    let n = row1 + row2;
+    let ln_choose = |n: u64, k: u64| {
+        ln_gamma(n as f64 + 1.0) - ln_gamma(k as f64 + 1.0) - ln_gamma((n - k) as f64 + 1.0)
+    };
+    let ln_total = ln_choose(n, col1);
+    let probability = |x: u64| (ln_choose(row1, x) + ln_choose(row2, col1 - x) - ln_total).exp();
+    let observed = probability(a);
+    let low = col1.saturating_sub(row2);
+    let high = row1.min(col1);
+    let p: f64 = (low..=high)
+        .map(probability)
+        .filter(|&p| p <= observed * (1.0 + 1e-7))
+        .sum();
+    p.min(1.0)
+}
+
+/// Two-sided p-value of Welch's t-test, or `None` without two values and some
+/// variance in each group.
+fn welch(case: &[f64], control: &[f64]) -> Option<f64> {
+    let moments = |values: &[f64]| {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (n, mean, var)
+    };
+    if case.len() < 2 || control.len() < 2 {
+        return None;
+    }
+    let (n1, mean1, var1) = moments(case);
+    let (n2, mean2, var2) = moments(control);
+    let se2 = var1 / n1 + var2 / n2;
+    if se2 <= 0.0 {
+        return None;
+    }
+    let t = (mean1 - mean2) / se2.sqrt();
+    let df = se2.powi(2) / ((var1 / n1).powi(2) / (n1 - 1.0) + (var2 / n2).powi(2) / (n2 - 1.0));
+    Some(incomplete_beta(df / (df + t * t), df / 2.0, 0.5))
+}
+
+/// Benjamini-Hochberg q-values of `p_values`, in the same order.
+fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| p_values[b].total_cmp(&p_values[a]));
+    let mut q_values = vec![0.0; n];
+    let mut running = 1.0_f64;
+    for (rank, &i) in order.iter().enumerate() {
+        let rank = n - rank;
+        running = running.min(p_values[i] * n as f64 / rank as f64);
+        q_values[i] = running;
+    }
+    q_values
+}
+
+/// Tests every region covered in both groups; rows are ranked by p-value.
+fn test_regions(
+    columns: &[Vec<TargetSummary>],
+    case: &[usize],
+    control: &[usize],
+    test: DmrTest,
+) -> Vec<DmrRow> {
+    let n_targets = columns.first().map_or(0, Vec::len);
+    let mut rows: Vec<DmrRow> = (0..n_targets)
+        .filter_map(|target| {
+            let group = |samples: &[usize]| -> Vec<&TargetSummary> {
+                samples
+                    .iter()
+                    .map(|&sample| &columns[sample][target])
+                    .filter(|summary| summary.sum_total_coverage > 0)
+                    .collect()
+            };
+            let (case, control) = (group(case), group(control));
+            let (case_methylated, case_total) = pooled(&case);
+            let (control_methylated, control_total) = pooled(&control);
+            if case_total <= 0.0 || control_total <= 0.0 {
+                return None;
+            }
+            let p_value = match test {
+                DmrTest::Fisher => {
+                    let case_methylated = case_methylated.round().min(case_total);
+                    let control_methylated = control_methylated.round().min(control_total);
+                    fisher_exact(
+                        case_methylated as u64,
+                        (case_total - case_methylated) as u64,
+                        control_methylated as u64,
+                        (control_total - control_methylated) as u64,
+                    )
+                }
+                DmrTest::Welch => {
+                    let fractions = |group: &[&TargetSummary]| -> Vec<f64> {
+                        group.iter().map(|s| s.weighted_fraction as f64).collect()
+                    };
+                    welch(&fractions(&case), &fractions(&control))?
+                }
+            };
+            Some(DmrRow {
+                target,
+                case_fraction: case_methylated / case_total,
+                control_fraction: control_methylated / control_total,
+                p_value,
+                q_value: 0.0,
+            })
+        })
+        .collect();
+    let p_values: Vec<f64> = rows.iter().map(|row| row.p_value).collect();
+    for (row, q_value) in rows.iter_mut().zip(benjamini_hochberg(&p_values)) {
+        row.q_value = q_value;
+    }
+    rows.sort_by(|a, b| a.p_value.total_cmp(&b.p_value));
+    rows
+}
+
+/// Positions in `specs` of the samples labelled `labels`.
+fn group_indices(specs: &[SampleSpec], labels: &[String]) -> Result<Vec<usize>, Box<dyn Error>> {
+    labels
+        .iter()
+        .map(|label| {
+            specs
+                .iter()
+                .position(|spec| &spec.label == label)
+                .ok_or_else(|| format!("Error: sample {label} is not in the manifest").into())
+        })
+        .collect()
+}
+
+pub fn run(args: &DmrArgs) -> Result<(), Box<dyn Error>> {
+    let specs = samples::read_manifest(&args.samples, args.format)?;
+    let case = group_indices(&specs, &args.case)?;
+    let control = group_indices(&specs, &args.control)?;
+    if case.iter().any(|sample| control.contains(sample)) {
+        return Err("Error: a sample cannot be both --case and --control".into());
+    }
+    let mut targets: Vec<TargetInterval> = match (&args.targets, args.window, &args.chrom_sizes) {
+        (_, Some(window), Some(sizes)) => tile_genome(sizes, window)?,
+        (Some(path), _, _) => parse_targets(path, true, false)?,
+        _ => return Err("Error: expected TARGET_BED or --window".into()),
+    };
+    if targets.iter().any(|target| target.name.is_some()) {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+    let index = TargetIndex::new(&targets);
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let wanted: Vec<usize> = case.iter().chain(&control).copied().collect();
+    let mut columns = vec![Vec::new(); specs.len()];
+    let loaded = wanted
+        .par_iter()
+        .map(|&sample| {
+            summarize_spec(&specs[sample], &filter, &targets, &index).map_err(ThreadError::from)
+        })
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+    for (sample, column) in wanted.into_iter().zip(loaded) {
+        columns[sample] = column;
+    }
+
+    let rows = test_regions(&columns, &case, &control, args.test);
+    let named = targets.first().is_some_and(|target| target.name.is_some());
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    header.push_str("\tcase_fraction\tcontrol_fraction\tdifference\tp_value\tq_value");
+    let lines: Vec<String> = rows
+        .iter()
+        .filter(|row| args.max_q.is_none_or(|max_q| row.q_value <= max_q))
+        .map(|row| {
+            format!(
+                "{}\t{:.*}\t{:.*}\t{:.*}\t{:.3e}\t{:.3e}",
+                format_target(&targets[row.target]),
+                args.precision,
+                row.case_fraction,
+                args.precision,
+                row.control_fraction,
+                args.precision,
+                row.case_fraction - row.control_fraction,
+                row.p_value,
+                row.q_value
+            )
+        })
+        .collect();
+    write_lines(args.output.as_deref(), Some(&header), &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tests_and_ranks_regions() {
+        // R: fisher.test(matrix(c(8, 2, 1, 5), 2))$p.value
+        assert!((fisher_exact(8, 2, 1, 5) - 0.03497).abs() < 1e-4);
+        assert_eq!(
+            benjamini_hochberg(&[0.01, 0.04, 0.03]),
+            vec![0.03, 0.04, 0.04]
+        );
+
+        let summary = |methylated: f32, coverage| TargetSummary {
+            num_positions: 1,
+            sum_total_coverage: coverage,
+            weighted_fraction: methylated / coverage.max(1) as f32,
+            sum_methylated: methylated,
+            ..TargetSummary::default()
+        };
+        let columns = vec![
+            vec![summary(9.0, 10), summary(5.0, 10), summary(1.0, 1)],
+            vec![summary(1.0, 10), summary(5.0, 10), summary(0.0, 0)],
+        ];
+        let rows = test_regions(&columns, &[0], &[1], DmrTest::Fisher);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].target, 0);
+        assert!((rows[0].case_fraction - rows[0].control_fraction - 0.8).abs() < 1e-9);
+        assert!((rows[1].p_value - 1.0).abs() < 1e-6);
+        // Welch needs replicates.
+        assert!(test_regions(&columns, &[0], &[1], DmrTest::Welch).is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+//! Errors of parsing methylation files, located by file and line. They reach
+//! callers inside the `Box<dyn Error>` of the function that failed, so library
+//! users can get at the location with `downcast_ref::<ParseError>()`, and the
+//! command line reports them with an exit status of their own.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "cli")]
+use crate::json::quote;
+
+/// Exit status of failures without one of their own. Usage errors exit with
+/// 2, from the argument parser.
+pub const EXIT_FAILURE: u8 = 1;
+/// Exit status of unsorted, non-contiguous or overlapping records.
+pub const EXIT_UNSORTED: u8 = 3;
+/// Exit status of records lacking the value columns of their format, and of
+/// other malformed input.
+pub const EXIT_BAD_COLUMNS: u8 = 4;
+/// Exit status of failures to read or write a file.
+pub const EXIT_IO: u8 = 5;
+
+/// A methylation file that cannot be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub path: PathBuf,
+    /// The 1-based line, when known.
+    pub line: Option<usize>,
+    pub kind: ParseErrorKind,
+}
+
+/// What is wrong with the file of a [`ParseError`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A record with `fields` columns, too few for the value columns the
+    /// format reads, as `(value, 1-based column)`.
+    MissingValueColumns {
+        fields: usize,
+        columns: Vec<(&'static str, usize)>,
+    },
+    /// A record `(start, end)` starting before the end of the previous record
+    /// of its chromosome.
+    Unsorted {
+        chrom: String,
+        record: (i32, i32),
+        previous_end: i32,
+    },
+    /// The records of a chromosome after those of another one, with `--streaming`.
+    Noncontiguous {
+        chrom: String,
+    },
+    /// Two records `(start, end)` overlapping once sorted.
+    Overlapping {
+        chrom: String,
+        first: (i32, i32),
+        second: (i32, i32),
+    },
+    /// A field of 1-based `column` that should hold a number.
+    InvalidNumber {
+        column: usize,
+        value: String,
+    },
+    InvalidUtf8,
+    /// An empty file where a header line naming the columns was expected.
+    MissingHeader,
+}
+
+impl ParseErrorKind {
+    /// The error at `line` of `path`.
+    pub fn at(self, path: &Path, line: Option<usize>) -> ParseError {
+        ParseError {
+            path: path.to_path_buf(),
+            line,
+            kind: self,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: {}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingValueColumns { columns, .. } if columns.is_empty() => write!(
+                f,
+                "the format reads no fraction, coverage or count columns; set them with -f/-c/-m/-u"
+            ),
+            ParseErrorKind::MissingValueColumns { fields, columns } => {
+                let columns: Vec<String> = columns
+                    .iter()
+                    .map(|(value, column)| format!("{value} (column {column})"))
+                    .collect();
+                write!(
+                    f,
+                    "the record has {fields} fields, too few for {}",
+                    columns.join(", ")
+                )
+            }
+            ParseErrorKind::Unsorted {
+                chrom,
+                record: (start, end),
+                previous_end,
+            } => write!(
+                f,
+                "records are not sorted: {chrom} {start} {end} starts before the end of the previous record at {previous_end}"
+            ),
+            ParseErrorKind::Noncontiguous { chrom } => write!(
+                f,
+                "the records of {chrom} are not contiguous; --streaming needs a sorted file"
+            ),
+            ParseErrorKind::Overlapping {
+                chrom,
+                first: (first_start, first_end),
+                second: (second_start, second_end),
+            } => write!(
+                f,
+                "records overlap: {chrom} {first_start} {first_end} and {chrom} {second_start} {second_end}"
+            ),
+            ParseErrorKind::InvalidNumber { column, value } => {
+                write!(f, "column {column} is not a number: `{value}`")
+            }
+            ParseErrorKind::InvalidUtf8 => write!(f, "stream did not contain valid UTF-8"),
+            ParseErrorKind::MissingHeader => write!(f, "the file is empty; expected a header line"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// An error sent out of the loops over samples and targets, which run on
+/// several threads: the [`ParseError`] or `io::Error` it came from, so the
+/// exit status survives, or otherwise its message.
+#[derive(Debug)]
+pub struct ThreadError(Box<dyn Error + Send + Sync>);
+
+impl From<Box<dyn Error>> for ThreadError {
+    fn from(err: Box<dyn Error>) -> ThreadError {
+        let err = match err.downcast::<ParseError>() {
+            Ok(err) => return ThreadError(err),
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(err) => ThreadError(err),
+            Err(err) => ThreadError(err.to_string().into()),
+        }
+    }
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for ThreadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// `err`, or the error a [`ThreadError`] carries.
+fn unwrap<'a>(err: &'a (dyn Error + 'static)) -> &'a (dyn Error + 'static) {
+    match err.downcast_ref::<ThreadError>() {
+        Some(err) => err.0.as_ref(),
+        None => err,
+    }
+}
+
+/// The name of the failure `err` and its exit status.
+fn classify(err: &(dyn Error + 'static)) -> (&'static str, u8) {
+    let err = unwrap(err);
+    if let Some(err) = err.downcast_ref::<ParseError>() {
+        return match err.kind {
+            ParseErrorKind::Unsorted { .. } => ("unsorted", EXIT_UNSORTED),
+            ParseErrorKind::Noncontiguous { .. } => ("noncontiguous", EXIT_UNSORTED),
+            ParseErrorKind::Overlapping { .. } => ("overlapping", EXIT_UNSORTED),
+            ParseErrorKind::MissingValueColumns { .. } => ("missing_columns", EXIT_BAD_COLUMNS),
+            ParseErrorKind::InvalidNumber { .. } => ("invalid_number", EXIT_BAD_COLUMNS),
+            ParseErrorKind::InvalidUtf8 => ("invalid_utf8", EXIT_BAD_COLUMNS),
+            ParseErrorKind::MissingHeader => ("missing_header", EXIT_BAD_COLUMNS),
+        };
+    }
+    if err.downcast_ref::<io::Error>().is_some() {
+        return ("io", EXIT_IO);
+    }
+    ("other", EXIT_FAILURE)
+}
+
+#[cfg(feature = "cli")]
+/// `err` as the one-line JSON object of `--error-format json`.
+pub fn to_json(err: &(dyn Error + 'static)) -> String {
+    let (kind, status) = classify(err);
+    let err = unwrap(err);
+    let mut out = format!("{{\"error\":{},\"exit_code\":{status}", quote(kind));
+    let message = match err.downcast_ref::<ParseError>() {
+        Some(err) => {
+            out.push_str(&format!(
+                ",\"path\":{}",
+                quote(&err.path.display().to_string())
+            ));
+            if let Some(line) = err.line {
+                out.push_str(&format!(",\"line\":{line}"));
+            }
+            err.kind.to_string()
+        }
+        None => {
+            let message = err.to_string();
+            message
+                .strip_prefix("Error: ")
+                .unwrap_or(&message)
+                .to_string()
+        }
+    };
+    out.push_str(&format!(",\"message\":{}}}", quote(&message)));
+    out
+}
+
+#[cfg(feature = "cli")]
+/// Writes `err` to stderr, as text or with `json` as [`to_json`] does, and
+/// returns the exit status of its failure.
+pub fn report(err: &(dyn Error + 'static), json: bool) -> u8 {
+    match json {
+        true => eprintln!("{}", to_json(err)),
+        false => eprintln!("{err}"),
+    }
+    classify(err).1
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_errors_by_file_and_line() {
+        let err = ParseErrorKind::MissingValueColumns {
+            fields: 4,
+            columns: vec![("fraction", 4), ("coverage", 5)],
+        }
+        .at(Path::new("cohort/s1.bed"), Some(17));
+        assert_eq!(
+            err.to_string(),
+            "Error: cohort/s1.bed:17: the record has 4 fields, too few for fraction (column 4), coverage (column 5)"
+        );
+        let boxed: Box<dyn Error> = err.clone().into();
+        assert_eq!(boxed.downcast_ref::<ParseError>(), Some(&err));
+        assert_eq!(
+            to_json(&err),
+            "{\"error\":\"missing_columns\",\"exit_code\":4,\"path\":\"cohort/s1.bed\",\"line\":17,\
+             \"message\":\"the record has 4 fields, too few for fraction (column 4), coverage (column 5)\"}"
+        );
+        let err = ParseErrorKind::MissingHeader.at(Path::new("s2.bed"), None);
+        assert_eq!(
+            err.to_string(),
+            "Error: s2.bed: the file is empty; expected a header line"
+        );
+        let missing: Box<dyn Error> = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(classify(&ThreadError::from(missing)), ("io", EXIT_IO));
+        let other: Box<dyn Error> = "Error: expected at least one METHYLATION_BED".into();
+        assert_eq!(
+            to_json(other.as_ref()),
+            "{\"error\":\"other\",\"exit_code\":1,\"message\":\"expected at least one METHYLATION_BED\"}"
+        );
+    }
+}
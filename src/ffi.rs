@@ -0,0 +1,209 @@
+//! A C ABI over the library, built into `libmethfast.so` / `.dylib` / `.dll`
+//! and declared in `include/methfast.h`: a methylation file is loaded once
+//! with `methfast_open`, queried region by region with `methfast_query` and
+//! released with `methfast_free`. Errors, and panics, which must not unwind
+//! into the caller, leave a message for `methfast_last_error` on the calling
+//! thread.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::api::aggregate_target;
+use crate::format::Format;
+use crate::{MethRanges, MethReader, Strand, TargetInterval};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: impl Into<String>) {
+    let message = message.into().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+/// The result of `call`, or `failed` after leaving the message of its error
+/// or panic.
+pub(crate) fn guard<T>(failed: T, call: impl FnOnce() -> Result<T, String>) -> T {
+    let err = match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(err)) => err,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            format!("Error: internal error: {message}")
+        }
+    };
+    set_error(err);
+    failed
+}
+
+/// The string behind `ptr`, or an error naming `what`.
+pub(crate) unsafe fn string_arg<'a>(ptr: *const c_char, what: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("Error: {what} is NULL"));
+    }
+    // SAFETY: the caller passes a NUL-terminated string.
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| format!("Error: {what} is not valid UTF-8"))
+}
+
+/// The aggregate of one region, filled in by `methfast_query`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MethfastSummary {
+    /// Records overlapping the region.
+    pub num_positions: u64,
+    /// Reads summed over those records.
+    pub coverage: i64,
+    /// Methylated reads summed over those records.
+    pub methylated: i64,
+    /// Coverage-weighted mean methylated fraction; NaN without coverage.
+    pub fraction: f64,
+}
+
+/// Loads the methylation file at `path`, in `format` (a `--format` name such
+/// as `bismark-cov`, or NULL to detect it), keeping records with at least
+/// `min_coverage` reads. Returns NULL on error.
+///
+/// # Safety
+///
+/// `path` and `format`, when not NULL, must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn methfast_open(
+    path: *const c_char,
+    format: *const c_char,
+    min_coverage: i32,
+) -> *mut MethRanges {
+    guard(ptr::null_mut(), || {
+        // SAFETY: forwarded from the caller.
+        let path = unsafe { string_arg(path, "path") }?;
+        let format = match format.is_null() {
+            true => Format::Auto,
+            // SAFETY: forwarded from the caller.
+            false => {
+                // SAFETY: forwarded from the caller.
+                let name = unsafe { string_arg(format, "format") }?;
+                Format::from_name(name).ok_or_else(|| format!("Error: invalid format: {name}"))?
+            }
+        };
+        let ranges = MethReader::new()
+            .format(format)
+            .min_coverage(min_coverage)
+            .read(path)
+            .map_err(|err| err.to_string())?;
+        Ok(Box::into_raw(Box::new(ranges)))
+    })
+}
+
+/// Aggregates the records of `ranges` overlapping the 0-based, half-open
+/// region `[start, end)` of `chrom` into `out`. Returns 0, or -1 on error.
+///
+/// # Safety
+///
+/// `ranges` must come from `methfast_open` and not be freed, `chrom` must be
+/// a NUL-terminated string and `out` must point to a `MethfastSummary`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn methfast_query(
+    ranges: *const MethRanges,
+    chrom: *const c_char,
+    start: i32,
+    end: i32,
+    out: *mut MethfastSummary,
+) -> i32 {
+    guard(-1, || {
+        if ranges.is_null() || out.is_null() {
+            return Err("Error: ranges and out must not be NULL".to_string());
+        }
+        // SAFETY: forwarded from the caller.
+        let chrom = unsafe { string_arg(chrom, "chrom") }?;
+        let target = TargetInterval {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        // SAFETY: `ranges` is a live handle from `methfast_open`.
+        let aggregate = aggregate_target(unsafe { &*ranges }, &target);
+        let summary = MethfastSummary {
+            num_positions: aggregate.num_positions as u64,
+            coverage: aggregate.coverage as i64,
+            methylated: aggregate.methylated as i64,
+            fraction: aggregate.fraction.map_or(f64::NAN, f64::from),
+        };
+        // SAFETY: `out` points to a `MethfastSummary`.
+        unsafe { out.write(summary) };
+        Ok(0)
+    })
+}
+
+/// Releases the records loaded by `methfast_open`; NULL is ignored.
+///
+/// # Safety
+///
+/// `ranges` must come from `methfast_open` and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn methfast_free(ranges: *mut MethRanges) {
+    if !ranges.is_null() {
+        guard((), || {
+            // SAFETY: the handle was boxed by `methfast_open`.
+            drop(unsafe { Box::from_raw(ranges) });
+            Ok(())
+        });
+    }
+}
+
+/// The message of the last error on this thread, or NULL; valid until the
+/// next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn methfast_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_queries_and_frees_through_the_c_abi() {
+        let path = std::env::temp_dir().join(format!("methfast-ffi-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t1.0\t4\nchr1\t12\t13\t0.5\t4\n").unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let ranges = unsafe { methfast_open(c_path.as_ptr(), c"generic".as_ptr(), 0) };
+        std::fs::remove_file(&path).unwrap();
+        assert!(!ranges.is_null());
+
+        let mut summary = MethfastSummary::default();
+        let status = unsafe { methfast_query(ranges, c"chr1".as_ptr(), 0, 20, &mut summary) };
+        assert_eq!(status, 0);
+        assert_eq!(summary.num_positions, 2);
+        assert_eq!(summary.methylated, 6);
+        assert_eq!(summary.fraction, 0.75);
+        unsafe { methfast_query(ranges, c"chr2".as_ptr(), 0, 20, &mut summary) };
+        assert!(summary.fraction.is_nan());
+        unsafe { methfast_free(ranges) };
+
+        let missing = unsafe { methfast_open(c"/nonexistent.bed".as_ptr(), ptr::null(), 0) };
+        assert!(missing.is_null());
+        assert!(!methfast_last_error().is_null());
+
+        let status = guard(-1, || panic!("index out of bounds"));
+        assert_eq!(status, -1);
+        let message = unsafe { CStr::from_ptr(methfast_last_error()) };
+        assert_eq!(
+            message.to_str().unwrap(),
+            "Error: internal error: index out of bounds"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+/// Known methylation file layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    /// Detected from the first lines of each input.
+    Auto,
+    /// Column indices taken from `-f/-c/-m/-u`.
+    Generic,
+    /// Bismark coverage file: chrom, start, end, %meth, count_meth, count_unmeth (1-based).
+    BismarkCov,
+    /// modkit bedMethyl: 9 BED columns plus Nvalid_cov, percent_modified, Nmod, ...
+    Bedmethyl,
+    /// MethylDackel bedGraph: chrom, start, end, %meth, nMeth, nUnmeth.
+    Methyldackel,
+    /// Bismark cytosine report: chrom, pos, strand, count_meth, count_unmeth, context, trinuc (1-based).
+    BismarkCx,
+    /// methylpy allc: chrom, pos, strand, trinucleotide context, mc, cov, methylated (1-based).
+    Allc,
+}
+
+/// Cytosine sequence context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Context {
+    #[cfg_attr(feature = "cli", value(name = "CpG", alias = "CG"))]
+    CpG,
+    #[cfg_attr(feature = "cli", value(name = "CHG"))]
+    Chg,
+    #[cfg_attr(feature = "cli", value(name = "CHH"))]
+    Chh,
+}
+
+impl Context {
+    /// Name as written on the command line and in sample labels.
+    pub fn name(self) -> &'static str {
+        match self {
+            Context::CpG => "CpG",
+            Context::Chg => "CHG",
+            Context::Chh => "CHH",
+        }
+    }
+
+    /// Classifies a context column value, either a label (`CG`, `CHG`, `CHH`)
+    /// or a trinucleotide as written by methylpy (`CGA`, `CAG`, `CTT`).
+    pub fn classify(value: &str) -> Option<Context> {
+        match value {
+            "CG" | "CpG" => Some(Context::CpG),
+            "CHG" => Some(Context::Chg),
+            "CHH" => Some(Context::Chh),
+            _ => match value.as_bytes() {
+                [b'C', b'G', _] => Some(Context::CpG),
+                [b'C', _, b'G'] => Some(Context::Chg),
+                [b'C', _, _] => Some(Context::Chh),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Column layout of a methylation input. Column indices are 1-based, 0 means unused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub start_col: usize,
+    /// End column; 0 for single-position formats where each record covers one base.
+    pub end_col: usize,
+    pub frac_col: usize,
+    pub cov_col: usize,
+    pub meth_col: usize,
+    pub unmeth_col: usize,
+    /// Column holding the modification code (bedMethyl column 4).
+    pub mod_code_col: usize,
+    /// Column holding the cytosine context.
+    pub context_col: usize,
+    /// Column holding the strand (`+`/`-`).
+    pub strand_col: usize,
+    /// The fraction column holds a percentage (0-100) rather than a fraction.
+    pub percent: bool,
+    /// Positions are 1-based and fully closed; converted to 0-based half-open on parse.
+    pub one_based: bool,
+}
+
+impl Layout {
+    /// The highest column a record is read from.
+    pub fn last_column(&self) -> usize {
+        [
+            self.start_col,
+            self.end_col,
+            self.frac_col,
+            self.cov_col,
+            self.meth_col,
+            self.unmeth_col,
+            self.mod_code_col,
+            self.context_col,
+            self.strand_col,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+    }
+}
+
+const GENERIC: Layout = Layout {
+    start_col: 2,
+    end_col: 3,
+    frac_col: 4,
+    cov_col: 5,
+    meth_col: 0,
+    unmeth_col: 0,
+    mod_code_col: 0,
+    context_col: 0,
+    strand_col: 0,
+    percent: false,
+    one_based: false,
+};
+
+impl Format {
+    pub fn layout(self) -> Layout {
+        match self {
+            Format::Auto | Format::Generic => GENERIC,
+            Format::BismarkCov => Layout {
+                cov_col: 0,
+                meth_col: 5,
+                unmeth_col: 6,
+                percent: true,
+                one_based: true,
+                ..GENERIC
+            },
+            Format::Bedmethyl => Layout {
+                frac_col: 11,
+                cov_col: 10,
+                meth_col: 12,
+                mod_code_col: 4,
+                strand_col: 6,
+                percent: true,
+                ..GENERIC
+            },
+            Format::Methyldackel => Layout {
+                cov_col: 0,
+                meth_col: 5,
+                unmeth_col: 6,
+                percent: true,
+                ..GENERIC
+            },
+            Format::BismarkCx => Layout {
+                end_col: 0,
+                frac_col: 0,
+                cov_col: 0,
+                meth_col: 4,
+                unmeth_col: 5,
+                context_col: 6,
+                strand_col: 3,
+                one_based: true,
+                ..GENERIC
+            },
+            Format::Allc => Layout {
+                end_col: 0,
+                frac_col: 0,
+                cov_col: 6,
+                meth_col: 5,
+                context_col: 4,
+                strand_col: 3,
+                one_based: true,
+                ..GENERIC
+            },
+        }
+    }
+}
+
+impl Format {
+    const ALL: [Format; 7] = [
+        Format::Auto,
+        Format::Generic,
+        Format::BismarkCov,
+        Format::Bedmethyl,
+        Format::Methyldackel,
+        Format::BismarkCx,
+        Format::Allc,
+    ];
+
+    /// The format named `name` as on the command line, ignoring case.
+    pub fn from_name(name: &str) -> Option<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Auto => "auto",
+            Format::Generic => "generic",
+            Format::BismarkCov => "bismark-cov",
+            Format::Bedmethyl => "bedmethyl",
+            Format::Methyldackel => "methyldackel",
+            Format::BismarkCx => "bismark-cx",
+            Format::Allc => "allc",
+        }
+    }
+
+    /// Guesses the format from the leading lines of a file, using the header
+    /// (MethylDackel writes a `track` line) and the shape of the first record.
+    pub fn detect<'a>(lines: impl IntoIterator<Item = &'a str>) -> Format {
+        let mut track_line = false;
+        for line in lines {
+            if is_header_line(line) {
+                track_line |= line.starts_with("track");
+                continue;
+            }
+            let fields: Vec<&str> = line.trim_end().split('\t').collect();
+            return detect_record(&fields, track_line);
+        }
+        if track_line {
+            Format::Methyldackel
+        } else {
+            Format::Generic
+        }
+    }
+}
+
+fn detect_record(fields: &[&str], track_line: bool) -> Format {
+    let int = |i: usize| fields.get(i).is_some_and(|f| f.parse::<u64>().is_ok());
+    let percent = |i: usize| {
+        fields
+            .get(i)
+            .and_then(|f| f.parse::<f32>().ok())
+            .is_some_and(|v| (0.0..=100.0).contains(&v))
+    };
+    let strand = |i: usize| matches!(fields.get(i), Some(&("+" | "-")));
+
+    if fields.len() >= 12 && !int(3) && int(9) && percent(10) && int(11) {
+        return Format::Bedmethyl;
+    }
+    if fields.len() >= 6 && strand(2) {
+        if matches!(fields.get(5), Some(&("CG" | "CHG" | "CHH"))) && int(3) && int(4) {
+            return Format::BismarkCx;
+        }
+        if int(4) && int(5) && fields[3].len() == 3 {
+            return Format::Allc;
+        }
+    }
+    if fields.len() == 6 && int(1) && int(2) && percent(3) && int(4) && int(5) {
+        // Bismark reports single cytosines as start == end; MethylDackel as [start, start + 1).
+        return if track_line || fields[1] != fields[2] {
+            Format::Methyldackel
+        } else {
+            Format::BismarkCov
+        };
+    }
+    Format::Generic
+}
+
+/// Track, browser and comment lines that precede the records of bedGraph-like files.
+pub fn is_header_line(line: &str) -> bool {
+    line.starts_with('#') || line.starts_with("track") || line.starts_with("browser")
+}
+
+/// Columns selected by header name rather than index.
+#[derive(Debug, Clone, Default)]
+pub struct ColumnNames {
+    pub frac: Option<String>,
+    pub cov: Option<String>,
+    pub meth: Option<String>,
+    pub unmeth: Option<String>,
+}
+
+impl ColumnNames {
+    pub fn is_empty(&self) -> bool {
+        self.frac.is_none() && self.cov.is_none() && self.meth.is_none() && self.unmeth.is_none()
+    }
+
+    /// Returns `layout` with the named columns replaced by their position in `header`.
+    pub fn apply(&self, layout: &Layout, header: &str) -> Result<Layout, String> {
+        let columns: Vec<&str> = header.trim_start_matches('#').split_whitespace().collect();
+        let find = |name: &Option<String>, current: usize| match name {
+            Some(name) => columns
+                .iter()
+                .position(|column| column == name)
+                .map(|i| i + 1)
+                .ok_or_else(|| format!("Error: column {name} not found in header")),
+            None => Ok(current),
+        };
+        Ok(Layout {
+            frac_col: find(&self.frac, layout.frac_col)?,
+            cov_col: find(&self.cov, layout.cov_col)?,
+            meth_col: find(&self.meth, layout.meth_col)?,
+            unmeth_col: find(&self.unmeth, layout.unmeth_col)?,
+            ..layout.clone()
+        })
+    }
+}
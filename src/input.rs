@@ -0,0 +1,79 @@
+//! Where the bytes of a methylation input come from, so that parsing does
+//! not depend on a filesystem: paths (files, stdin for `-` and URLs), or
+//! bytes already in memory such as a file dropped on a web page.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::progress::Progress;
+use crate::{bgzf, compression, is_stream, parallel};
+
+/// Batches of the decompressed contents of an [`Input`], in order.
+pub type Batches<'a> = dyn Iterator<Item = io::Result<Cow<'a, [u8]>>> + 'a;
+
+/// A consumer of the [`Batches`] of an input and of the progress of reading them.
+pub type ParseBatches<'p> = dyn FnMut(&mut Batches, &Progress) -> Result<(), Box<dyn Error>> + 'p;
+
+pub trait Input {
+    /// The name of the input in messages.
+    fn name(&self) -> &Path;
+
+    /// Hands the decompressed contents to `parse`.
+    fn read(&self, parse: &mut ParseBatches) -> Result<(), Box<dyn Error>>;
+}
+
+/// Uncompressed files are memory-mapped; BGZF files are inflated a batch of
+/// blocks at a time and other inputs decompressed in batches, both on a
+/// background thread.
+impl Input for Path {
+    fn name(&self) -> &Path {
+        self
+    }
+
+    fn read(&self, parse: &mut ParseBatches) -> Result<(), Box<dyn Error>> {
+        if let Some(mapped) = compression::map_plain(self)? {
+            let progress = Progress::bytes(self, Some(mapped.len() as u64));
+            parse(
+                &mut std::iter::once(Ok(Cow::Borrowed(&mapped[..]))),
+                &progress,
+            )
+        } else if !is_stream(self) && bgzf::is_bgzf(self)? {
+            // Decompressed bytes, so the size of the file is no total.
+            let progress = Progress::bytes(self, None);
+            let mut blocks = bgzf::BlockStream::open(self)?;
+            let n = parallel::current_num_threads() * 16;
+            let mut batches = compression::ReadAhead::spawn(move || blocks.inflate_batch(n))
+                .map(|batch| batch.map(Cow::Owned));
+            parse(&mut batches, &progress)
+        } else {
+            let progress = Progress::bytes(self, None);
+            let mut batches = compression::read_batches(self)?.map(|batch| batch.map(Cow::Owned));
+            parse(&mut batches, &progress)
+        }
+    }
+}
+
+impl Input for PathBuf {
+    fn name(&self) -> &Path {
+        self
+    }
+
+    fn read(&self, parse: &mut ParseBatches) -> Result<(), Box<dyn Error>> {
+        self.as_path().read(parse)
+    }
+}
+
+/// The contents of a file, plain, gzip- or zstd-compressed.
+impl Input for [u8] {
+    fn name(&self) -> &Path {
+        Path::new("<memory>")
+    }
+
+    fn read(&self, parse: &mut ParseBatches) -> Result<(), Box<dyn Error>> {
+        let compressed = compression::is_compressed(self);
+        let progress = Progress::bytes(self.name(), (!compressed).then_some(self.len() as u64));
+        parse(&mut compression::bytes_batches(self)?, &progress)
+    }
+}
@@ -0,0 +1,55 @@
+//! Small integer IDs for chromosome names, so that work done per record
+//! compares and indexes integers instead of hashing and copying strings.
+
+use std::collections::HashMap;
+
+/// Interned chromosome names, numbered in order of first appearance.
+#[derive(Debug, Default)]
+pub struct ChromIds {
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
+    /// The most recent ID; sorted inputs repeat a name for many records.
+    last: Option<u32>,
+}
+
+impl ChromIds {
+    /// The ID of `name`, assigning the next one to names not seen before.
+    pub fn id(&mut self, name: &str) -> u32 {
+        if let Some(last) = self.last
+            && self.names[last as usize] == name
+        {
+            return last;
+        }
+        let id = match self.ids.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = self.names.len() as u32;
+                self.ids.insert(name.to_string(), id);
+                self.names.push(name.to_string());
+                id
+            }
+        };
+        self.last = Some(id);
+        id
+    }
+
+    /// The names indexed by ID.
+    pub fn into_names(self) -> Vec<String> {
+        self.names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_names_in_order_of_appearance() {
+        let mut chroms = ChromIds::default();
+        assert_eq!(chroms.id("chr2"), 0);
+        assert_eq!(chroms.id("chr2"), 0);
+        assert_eq!(chroms.id("chr1"), 1);
+        assert_eq!(chroms.id("chr2"), 0);
+        assert_eq!(chroms.into_names(), vec!["chr2", "chr1"]);
+    }
+}
@@ -0,0 +1,140 @@
+//! Newline-delimited JSON output, one object per target.
+
+use std::fmt::Write;
+
+use crate::cli::FractionFormat;
+use crate::{TargetInterval, TargetSummary};
+
+/// Formats one target as a JSON object. Without `labels` the summary fields
+/// sit at the top level; otherwise they go in a `samples` array, one labelled
+/// object per sample.
+/// With `split_strands`, every sample holds a `plus` and a `minus` summary.
+pub fn format_target_json(
+    target: &TargetInterval,
+    summaries: &[TargetSummary],
+    labels: Option<&[String]>,
+    split_strands: bool,
+    fractions: &FractionFormat,
+) -> String {
+    let mut out = String::from("{");
+    write!(out, "\"chrom\":{}", quote(&target.chrom)).unwrap();
+    write!(out, ",\"start\":{},\"end\":{}", target.start, target.end).unwrap();
+    if let Some(name) = &target.name {
+        write!(out, ",\"name\":{}", quote(name)).unwrap();
+    }
+    let per_sample = if split_strands { 2 } else { 1 };
+    let samples = summaries.chunks(per_sample);
+    match labels {
+        None => {
+            for sample in samples {
+                out.push(',');
+                write_sample(&mut out, sample, fractions);
+            }
+        }
+        Some(labels) => {
+            out.push_str(",\"samples\":[");
+            for (i, (label, sample)) in labels.iter().zip(samples).enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write!(out, "{{\"label\":{},", quote(label)).unwrap();
+                write_sample(&mut out, sample, fractions);
+                out.push('}');
+            }
+            out.push(']');
+        }
+    }
+    out.push('}');
+    out
+}
+
+/// Writes the fields of one sample: its summary, or `plus`/`minus` summaries.
+fn write_sample(out: &mut String, summaries: &[TargetSummary], fractions: &FractionFormat) {
+    match summaries {
+        [summary] => write_summary(out, summary, fractions),
+        [plus, minus] => {
+            out.push_str("\"plus\":{");
+            write_summary(out, plus, fractions);
+            out.push_str("},\"minus\":{");
+            write_summary(out, minus, fractions);
+            out.push('}');
+        }
+        _ => {}
+    }
+}
+
+fn write_summary(out: &mut String, summary: &TargetSummary, fractions: &FractionFormat) {
+    // Missing fractions are `null` whatever the --na-value string.
+    let fraction = match fractions.missing(summary) {
+        Some(_) => "null".to_string(),
+        None => fractions.format(summary.weighted_fraction),
+    };
+    write!(
+        out,
+        "\"n_sites\":{},\"total_coverage\":{},\"weighted_fraction\":{fraction}",
+        summary.num_positions, summary.sum_total_coverage
+    )
+    .unwrap();
+}
+
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn writes_flat_and_labelled_objects() {
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: Some("a\"b".to_string()),
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let summary = TargetSummary {
+            num_positions: 2,
+            sum_total_coverage: 8,
+            weighted_fraction: 0.25,
+            sum_methylated: 2.0,
+            stats: None,
+            sites_per_kb: 0.0,
+            reference_sites: None,
+            shrunk_fraction: None,
+            ci: None,
+            imputed: false,
+        };
+        assert_eq!(
+            format_target_json(&target, &[summary], None, false, &FractionFormat::default()),
+            r#"{"chrom":"chr1","start":0,"end":10,"name":"a\"b","n_sites":2,"total_coverage":8,"weighted_fraction":0.2500}"#
+        );
+        let labels = ["ctrl".to_string()];
+        assert_eq!(
+            format_target_json(
+                &target,
+                &[summary, TargetSummary::default()],
+                Some(&labels),
+                true,
+                &FractionFormat::default()
+            ),
+            r#"{"chrom":"chr1","start":0,"end":10,"name":"a\"b","samples":[{"label":"ctrl","plus":{"n_sites":2,"total_coverage":8,"weighted_fraction":0.2500},"minus":{"n_sites":0,"total_coverage":0,"weighted_fraction":0.0000}}]}"#
+        );
+    }
+}
@@ -0,0 +1,1919 @@
+//! Region-level aggregation of per-CpG methylation calls.
+//!
+//! The `methfast` command line is built on this crate; [`MethReader`],
+//! [`TargetSet`] and [`aggregate`] give the same summaries to other
+//! programs without going through text output:
+//!
+//! ```no_run
+//! use methfast::{MethReader, TargetSet, aggregate};
+//!
+//! let records = MethReader::new().min_coverage(5).read("sample.bismark.cov.gz")?;
+//! let targets = TargetSet::read("promoters.bed")?;
+//! for summary in aggregate(&records, &targets) {
+//!     println!("{}:{}-{}\t{:?}", summary.chrom, summary.start, summary.end, summary.fraction);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+// The readers share helpers with the `cli` feature that they do not call themselves.
+#![cfg_attr(not(feature = "cli"), allow(dead_code))]
+
+#[cfg(feature = "cli")]
+mod alias;
+mod annotation;
+mod api;
+#[cfg(feature = "cli")]
+mod array;
+mod bam;
+mod bgzf;
+#[cfg(feature = "cli")]
+mod bigwig;
+#[cfg(feature = "cli")]
+mod bins;
+mod blacklist;
+mod chrom_report;
+#[cfg(feature = "cli")]
+mod cli;
+#[cfg(feature = "cli")]
+mod compact;
+#[cfg(feature = "cli")]
+mod compare;
+mod compression;
+#[cfg(feature = "cli")]
+mod confidence;
+#[cfg(feature = "cli")]
+mod config;
+#[cfg(feature = "cli")]
+mod convert;
+#[cfg(feature = "cli")]
+mod deconvolve;
+#[cfg(feature = "cli")]
+mod dmr;
+mod error;
+mod ffi;
+mod format;
+mod input;
+mod intern;
+#[cfg(feature = "cli")]
+mod json;
+#[cfg(feature = "cli")]
+mod liftover;
+mod log;
+#[cfg(feature = "cli")]
+mod matrix;
+mod memory;
+#[cfg(feature = "cli")]
+mod merge;
+#[cfg(feature = "cli")]
+mod meth_index;
+mod mmap;
+#[cfg(feature = "cli")]
+mod nearest;
+mod numbers;
+mod parallel;
+#[cfg(feature = "parquet")]
+mod parquet_output;
+#[cfg(feature = "cli")]
+mod pca;
+#[cfg(feature = "cli")]
+mod profile;
+mod progress;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "cli")]
+mod qc;
+mod remote;
+#[cfg(feature = "cli")]
+mod samples;
+#[cfg(feature = "cli")]
+mod segment;
+#[cfg(feature = "cli")]
+mod serve;
+#[cfg(feature = "cli")]
+mod smooth;
+#[cfg(feature = "cli")]
+mod streaming;
+#[cfg(feature = "cli")]
+mod tabix;
+
+use parallel::*;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::Arc;
+
+use format::{ColumnNames, Layout};
+
+pub use api::{Aggregate, MethReader, TargetSet, aggregate};
+/// The entry point of the `methfast` binary; not part of the library interface.
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub use cli::run_cli;
+pub use error::{ParseError, ParseErrorKind};
+pub use format::{Context, Format};
+
+/// One methylation record: a cytosine, or a span of them, with its
+/// methylated fraction and read coverage. Coordinates are 0-based, half-open.
+#[derive(Debug, Clone)]
+pub struct MethInterval {
+    pub start: i32,
+    pub end: i32,
+    pub fraction: f32,
+    pub coverage: i32,
+    pub strand: Strand,
+}
+
+/// Strand of a methylation record or target; `Unknown` matches either strand.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Strand {
+    #[default]
+    Unknown,
+    Plus,
+    Minus,
+}
+
+impl Strand {
+    fn parse(value: &str) -> Strand {
+        match value {
+            "+" => Strand::Plus,
+            "-" => Strand::Minus,
+            _ => Strand::Unknown,
+        }
+    }
+
+    fn matches(self, other: Strand) -> bool {
+        self == Strand::Unknown || other == Strand::Unknown || self == other
+    }
+}
+
+/// The methylation records of one input, sorted by start on each chromosome.
+#[derive(Debug)]
+pub struct MethRanges {
+    by_chrom: HashMap<String, Vec<MethInterval>>,
+}
+
+impl MethRanges {
+    fn destrand(&mut self) {
+        for intervals in self.by_chrom.values_mut() {
+            *intervals = destrand(std::mem::take(intervals));
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&mut MethInterval) -> bool) {
+        for intervals in self.by_chrom.values_mut() {
+            intervals.retain_mut(&mut keep);
+        }
+    }
+
+    /// Coverage at `percentile` (0-100) over all records, by nearest rank.
+    fn coverage_percentile(&self, percentile: f64) -> Option<i32> {
+        let mut coverages: Vec<i32> = self
+            .by_chrom
+            .values()
+            .flatten()
+            .map(|iv| iv.coverage)
+            .collect();
+        if coverages.is_empty() {
+            return None;
+        }
+        let rank = (percentile / 100.0 * coverages.len() as f64).ceil() as usize;
+        let index = rank.clamp(1, coverages.len()) - 1;
+        Some(*coverages.select_nth_unstable(index).1)
+    }
+
+    /// Re-keys the chromosomes by `rename(name)`, merging any that collide.
+    fn rename_chroms(&mut self, rename: &dyn Fn(&str) -> String) {
+        let mut by_chrom: HashMap<String, Vec<MethInterval>> = HashMap::new();
+        for (chrom, intervals) in std::mem::take(&mut self.by_chrom) {
+            let merged = by_chrom.entry(rename(&chrom)).or_default();
+            if merged.is_empty() {
+                *merged = intervals;
+            } else {
+                merged.extend(intervals);
+                merged.sort_by_key(|iv| (iv.start, iv.end));
+            }
+        }
+        self.by_chrom = by_chrom;
+    }
+}
+
+/// Merges each plus-strand record with a minus-strand record starting one base
+/// later, the two cytosines of a CpG, into one record spanning both; coverage
+/// is summed and the fraction recomputed from the methylated reads.
+fn destrand(intervals: Vec<MethInterval>) -> Vec<MethInterval> {
+    let mut merged: Vec<MethInterval> = Vec::with_capacity(intervals.len());
+    for iv in intervals {
+        if let Some(last) = merged.last_mut()
+            && last.strand == Strand::Plus
+            && iv.strand == Strand::Minus
+            && iv.start == last.start + 1
+        {
+            let coverage = last.coverage + iv.coverage;
+            if coverage > 0 {
+                last.fraction = (last.fraction * last.coverage as f32
+                    + iv.fraction * iv.coverage as f32)
+                    / coverage as f32;
+            }
+            last.coverage = coverage;
+            last.end = last.end.max(iv.end);
+            last.strand = Strand::Unknown;
+            continue;
+        }
+        merged.push(iv);
+    }
+    merged
+}
+
+/// Resolves runs of overlapping records, sorted by start, as `policy` says:
+/// `First` keeps the first record of each run, `Merge` pools the reads of a
+/// run into one record spanning it, and `Average` gives that record the mean
+/// fraction and coverage of the run instead.
+fn resolve_overlaps(intervals: Vec<MethInterval>, policy: OnOverlap) -> Vec<MethInterval> {
+    let mut resolved: Vec<MethInterval> = Vec::with_capacity(intervals.len());
+    // The records, fractions and coverages of the last run, for `Average`.
+    let (mut run, mut fractions, mut coverages) = (0, 0.0f64, 0i64);
+    for iv in intervals {
+        if policy != OnOverlap::Error
+            && let Some(last) = resolved.last_mut()
+            && iv.start < last.end
+        {
+            match policy {
+                OnOverlap::Error | OnOverlap::First => continue,
+                OnOverlap::Merge => {
+                    let coverage = last.coverage + iv.coverage;
+                    if coverage > 0 {
+                        last.fraction = (last.fraction * last.coverage as f32
+                            + iv.fraction * iv.coverage as f32)
+                            / coverage as f32;
+                    }
+                    last.coverage = coverage;
+                }
+                OnOverlap::Average => {
+                    run += 1;
+                    fractions += f64::from(iv.fraction);
+                    coverages += i64::from(iv.coverage);
+                    last.fraction = (fractions / run as f64) as f32;
+                    last.coverage = (coverages as f64 / run as f64).round() as i32;
+                }
+            }
+            last.end = last.end.max(iv.end);
+            if last.strand != iv.strand {
+                last.strand = Strand::Unknown;
+            }
+            continue;
+        }
+        (run, fractions, coverages) = (1, f64::from(iv.fraction), i64::from(iv.coverage));
+        resolved.push(iv);
+    }
+    resolved
+}
+
+#[derive(Debug, Clone)]
+struct TargetInterval {
+    chrom: String,
+    start: i32,
+    end: i32,
+    /// Identifier carried into the output, e.g. a GTF `gene_name`.
+    name: Option<String>,
+    strand: Strand,
+    /// Columns after `end` kept with `--keep-target-columns`.
+    extra: Vec<String>,
+}
+
+/// Per-record filters applied while parsing the methylation file.
+#[derive(Debug, Clone, Default)]
+struct RecordFilter {
+    mod_code: Option<String>,
+    context: Option<Context>,
+    min_coverage: Option<i32>,
+    max_coverage: Option<i32>,
+    /// Clamp records above `max_coverage` to it instead of dropping them.
+    clamp_coverage: bool,
+    /// Merge the strand records of each CpG, see [`destrand`].
+    destrand: bool,
+    /// Sort the records of each chromosome instead of rejecting unsorted input.
+    sort: bool,
+    /// What to do with records overlapping the previous one.
+    on_overlap: OnOverlap,
+    /// Reject records with numeric fields that do not parse instead of reading them as 0.
+    strict: bool,
+    /// Drop records overlapping these regions.
+    blacklist: Option<Arc<blacklist::Blacklist>>,
+    /// Keep only records on these chromosomes, those of the targets.
+    chroms: Option<Arc<HashSet<String>>>,
+    /// Collects the chromosomes skipped for not being in `chroms`.
+    skipped_chroms: Option<Arc<chrom_report::SkippedChroms>>,
+}
+
+impl RecordFilter {
+    fn accepts(&self, fields: &[&str], layout: &Layout) -> bool {
+        if !self.keeps_chrom(fields[0]) {
+            return false;
+        }
+        if let Some(code) = &self.mod_code
+            && layout.mod_code_col > 0
+            && fields.get(layout.mod_code_col - 1) != Some(&code.as_str())
+        {
+            return false;
+        }
+        if let Some(context) = self.context
+            && layout.context_col > 0
+            && fields
+                .get(layout.context_col - 1)
+                .and_then(|value| Context::classify(value))
+                != Some(context)
+        {
+            return false;
+        }
+        true
+    }
+
+    /// Applies the coverage thresholds to a parsed record, clamping its
+    /// coverage if requested; returns whether the record is kept.
+    fn apply(&self, interval: &mut MethInterval) -> bool {
+        if self
+            .min_coverage
+            .is_some_and(|min_coverage| interval.coverage < min_coverage)
+        {
+            return false;
+        }
+        match self.max_coverage {
+            Some(max_coverage) if interval.coverage > max_coverage => {
+                interval.coverage = max_coverage;
+                self.clamp_coverage
+            }
+            _ => true,
+        }
+    }
+
+    fn keeps_chrom(&self, chrom: &str) -> bool {
+        let keeps = self
+            .chroms
+            .as_ref()
+            .is_none_or(|chroms| chroms.contains(chrom));
+        if !keeps && let Some(skipped) = &self.skipped_chroms {
+            skipped.record(chrom);
+        }
+        keeps
+    }
+
+    fn filters_intervals(&self) -> bool {
+        self.min_coverage.is_some() || self.max_coverage.is_some()
+    }
+}
+
+/// `--on-overlap` choices, for records starting before the end of the
+/// previous record of their chromosome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+enum OnOverlap {
+    /// Reject the file.
+    #[default]
+    Error,
+    /// One record spanning the run, with the reads of its records pooled.
+    Merge,
+    /// The first record of the run.
+    First,
+    /// One record spanning the run, with its mean fraction and coverage.
+    Average,
+}
+
+fn parse_i32_lossy(s: &str) -> i32 {
+    numbers::parse_i32(s).unwrap_or(0)
+}
+
+/// The number in 1-based column `col` of `fields`; one that does not parse
+/// reads as 0, or with `strict` is an error.
+fn number_field<T: Default>(
+    fields: &[&str],
+    col: usize,
+    strict: bool,
+    parse: fn(&str) -> Option<T>,
+) -> Result<T, ParseErrorKind> {
+    let field = fields[col - 1];
+    match parse(field) {
+        Some(value) => Ok(value),
+        None if strict => Err(ParseErrorKind::InvalidNumber {
+            column: col,
+            value: field.to_string(),
+        }),
+        None => Ok(T::default()),
+    }
+}
+
+/// Inputs that can only be streamed once, so they are not sniffed; of these,
+/// only URLs can be indexed, through range requests.
+fn is_stream(path: &Path) -> bool {
+    is_stdin(path) || remote::is_url(path)
+}
+
+/// `-` names standard input.
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn record_values(
+    fields: &[&str],
+    layout: &Layout,
+    strict: bool,
+) -> Result<(f32, i32), ParseErrorKind> {
+    let int = |col| number_field(fields, col, strict, numbers::parse_i32);
+    let Layout {
+        frac_col,
+        cov_col,
+        meth_col,
+        unmeth_col,
+        ..
+    } = *layout;
+    let field_count = fields.len();
+    if meth_col > 0 && meth_col <= field_count && unmeth_col > 0 && unmeth_col <= field_count {
+        let methylated = int(meth_col)?;
+        let unmethylated = int(unmeth_col)?;
+        let coverage = methylated + unmethylated;
+        let fraction = if coverage > 0 {
+            methylated as f32 / coverage as f32
+        } else {
+            0.0
+        };
+        Ok((fraction, coverage))
+    } else if meth_col > 0 && meth_col <= field_count && cov_col > 0 && cov_col <= field_count {
+        let methylated = int(meth_col)?;
+        let coverage = int(cov_col)?;
+        let fraction = if coverage > 0 {
+            methylated as f32 / coverage as f32
+        } else {
+            0.0
+        };
+        Ok((fraction, coverage))
+    } else if cov_col > 0 && cov_col <= field_count && frac_col > 0 && frac_col <= field_count {
+        let mut fraction = number_field(fields, frac_col, strict, numbers::parse_f32)?;
+        if layout.percent {
+            fraction /= 100.0;
+        }
+        let coverage = int(cov_col)?;
+        Ok((fraction, coverage))
+    } else {
+        let columns = [
+            ("fraction", frac_col),
+            ("coverage", cov_col),
+            ("methylated", meth_col),
+            ("unmethylated", unmeth_col),
+        ];
+        Err(ParseErrorKind::MissingValueColumns {
+            fields: field_count,
+            columns: columns
+                .into_iter()
+                .filter(|&(_, column)| column > 0)
+                .collect(),
+        })
+    }
+}
+
+/// Returns the 0-based half-open span of a record.
+fn record_span(
+    fields: &[&str],
+    layout: &Layout,
+    strict: bool,
+) -> Result<(i32, i32), ParseErrorKind> {
+    let int = |col| number_field(fields, col, strict, numbers::parse_i32);
+    let mut start = int(layout.start_col)?;
+    let mut end = if layout.end_col > 0 {
+        int(layout.end_col)?
+    } else {
+        start
+    };
+    if layout.one_based {
+        start -= 1;
+    } else if layout.end_col == 0 {
+        end += 1;
+    }
+    Ok((start, end))
+}
+
+/// Columns split into a stack buffer by [`parse_record`]; layouts reading
+/// further columns fall back to collecting them.
+const MAX_SPLIT_FIELDS: usize = 32;
+
+/// Splits the first `out.len()` whitespace-separated fields of `line` into
+/// `out`, scanning bytes for ASCII lines; returns the number of fields.
+fn split_fields<'a>(line: &'a str, out: &mut [&'a str]) -> usize {
+    if !line.is_ascii() {
+        let mut n = 0;
+        for (slot, field) in out.iter_mut().zip(line.split_whitespace()) {
+            *slot = field;
+            n += 1;
+        }
+        return n;
+    }
+    let bytes = line.as_bytes();
+    let mut n = 0;
+    let mut i = 0;
+    while n < out.len() {
+        while i < bytes.len() && matches!(bytes[i], b' ' | b'\t'..=b'\r') {
+            i += 1;
+        }
+        if i == bytes.len() {
+            break;
+        }
+        let start = i;
+        while i < bytes.len() && !matches!(bytes[i], b' ' | b'\t'..=b'\r') {
+            i += 1;
+        }
+        out[n] = &line[start..i];
+        n += 1;
+    }
+    n
+}
+
+/// Parses one methylation line into its chromosome and interval. Header,
+/// short and filtered-out lines yield `None`; the caller locates errors.
+fn parse_record<'a>(
+    line: &'a str,
+    layout: &Layout,
+    filter: &RecordFilter,
+) -> Result<Option<(&'a str, MethInterval)>, ParseErrorKind> {
+    if format::is_header_line(line) {
+        return Ok(None);
+    }
+    let mut buffer = [""; MAX_SPLIT_FIELDS];
+    let collected: Vec<&str>;
+    let fields: &[&str] = match layout.last_column().max(4) {
+        wanted if wanted <= MAX_SPLIT_FIELDS => {
+            let n = split_fields(line, &mut buffer[..wanted]);
+            &buffer[..n]
+        }
+        _ => {
+            collected = line.split_whitespace().collect();
+            &collected
+        }
+    };
+    if fields.len() < 4 || !filter.accepts(fields, layout) {
+        return Ok(None);
+    }
+
+    let (start, end) = record_span(fields, layout, filter.strict)?;
+    let (fraction, coverage) = record_values(fields, layout, filter.strict)?;
+    let strand = match layout.strand_col {
+        0 => Strand::Unknown,
+        col => fields
+            .get(col - 1)
+            .map_or(Strand::Unknown, |s| Strand::parse(s)),
+    };
+    let mut interval = MethInterval {
+        start,
+        end,
+        fraction,
+        coverage,
+        strand,
+    };
+    let masked = filter
+        .blacklist
+        .as_ref()
+        .is_some_and(|blacklist| blacklist.masks(fields[0], &interval));
+    Ok((!masked && filter.apply(&mut interval)).then_some((fields[0], interval)))
+}
+
+/// Reads the first line of `path`, which holds the column names when selecting by name.
+fn read_header(reader: &mut dyn BufRead, path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut header = String::new();
+    if reader.read_line(&mut header)? == 0 {
+        return Err(ParseErrorKind::MissingHeader.at(path, None).into());
+    }
+    Ok(header)
+}
+
+/// Parses a methylation file into one set of ranges per entry of `contexts`,
+/// each holding the records in that sequence context (all records for `None`).
+fn parse_meth_bed(
+    input: &(impl input::Input + ?Sized),
+    layout: &Layout,
+    names: &ColumnNames,
+    filter: &RecordFilter,
+    contexts: &[Option<Context>],
+) -> Result<Vec<MethRanges>, Box<dyn Error>> {
+    let mut by_context: Vec<Vec<Vec<MethInterval>>> = contexts.iter().map(|_| Vec::new()).collect();
+    let mut meter = memory::Meter::new(std::mem::size_of::<MethInterval>());
+    let chroms = read_meth_records(
+        input,
+        layout,
+        names,
+        filter,
+        contexts,
+        &mut |i, chrom, interval| {
+            let by_chrom = &mut by_context[i];
+            if by_chrom.len() <= chrom as usize {
+                by_chrom.resize_with(chrom as usize + 1, Vec::new);
+            }
+            by_chrom[chrom as usize].push(interval);
+            meter.record()
+        },
+    )?
+    .into_names();
+    let mut by_context: Vec<HashMap<String, Vec<MethInterval>>> = by_context
+        .into_iter()
+        .map(|by_chrom| {
+            chroms
+                .iter()
+                .cloned()
+                .zip(by_chrom)
+                .filter(|(_, intervals)| !intervals.is_empty())
+                .collect()
+        })
+        .collect();
+
+    if filter.sort || filter.on_overlap != OnOverlap::Error {
+        for (chrom, intervals) in by_context
+            .iter_mut()
+            .flat_map(|by_chrom| by_chrom.iter_mut())
+        {
+            // Records of a chromosome split across the file come unsorted.
+            if !intervals.is_sorted_by_key(|iv| (iv.start, iv.end)) {
+                intervals.sort_by_key(|iv| (iv.start, iv.end));
+            }
+            if filter.on_overlap != OnOverlap::Error {
+                *intervals = resolve_overlaps(std::mem::take(intervals), filter.on_overlap);
+                continue;
+            }
+            if let Some(pair) = intervals
+                .windows(2)
+                .find(|pair| pair[1].start < pair[0].end)
+            {
+                return Err(ParseErrorKind::Overlapping {
+                    chrom: chrom.clone(),
+                    first: (pair[0].start, pair[0].end),
+                    second: (pair[1].start, pair[1].end),
+                }
+                .at(input.name(), None)
+                .into());
+            }
+        }
+    }
+
+    log::info(|| {
+        let records: usize = by_context
+            .iter()
+            .flat_map(|by_chrom| by_chrom.values())
+            .map(Vec::len)
+            .sum();
+        let chroms = by_context.iter().map(HashMap::len).max().unwrap_or(0);
+        format!(
+            "parsed input={} records={records} chroms={chroms}",
+            input.name().display()
+        )
+    });
+    if log::debugging() {
+        for by_chrom in &by_context {
+            let mut chroms: Vec<_> = by_chrom.iter().collect();
+            chroms.sort_by(|a, b| a.0.cmp(b.0));
+            for (chrom, intervals) in chroms {
+                log::debug(|| {
+                    format!(
+                        "records input={} chrom={chrom} records={}",
+                        input.name().display(),
+                        intervals.len()
+                    )
+                });
+            }
+        }
+    }
+
+    Ok(by_context
+        .into_iter()
+        .map(|by_chrom| MethRanges { by_chrom })
+        .collect())
+}
+
+/// Reads the records of a methylation file in file order, handing each to
+/// `store` with the index of its entry of `contexts` and the ID of its
+/// chromosome; unsorted records are an error unless `filter.sort`, and
+/// overlapping ones unless `filter.on_overlap` resolves them.
+///
+/// The [`input::Input`] is parsed in parallel chunks of lines and the records
+/// then added in file order as when reading line by line.
+fn read_meth_records(
+    input: &(impl input::Input + ?Sized),
+    layout: &Layout,
+    names: &ColumnNames,
+    filter: &RecordFilter,
+    contexts: &[Option<Context>],
+    store: &mut dyn FnMut(usize, u32, MethInterval) -> Result<(), Box<dyn Error>>,
+) -> Result<intern::ChromIds, Box<dyn Error>> {
+    let mut chroms = intern::ChromIds::default();
+    let mut prev: Option<(u32, i32, i32)> = None;
+    let mut add = |linenum: usize,
+                   chrom: &str,
+                   interval: MethInterval,
+                   context: Option<Context>|
+     -> Result<(), Box<dyn Error>> {
+        let (start, end) = (interval.start, interval.end);
+        let id = chroms.id(chrom);
+        if !filter.sort
+            && let Some((prev_id, prev_start, prev_end)) = prev
+            && id == prev_id
+            && start < prev_end
+            && (filter.on_overlap == OnOverlap::Error || start < prev_start)
+        {
+            return Err(ParseErrorKind::Unsorted {
+                chrom: chrom.to_string(),
+                record: (start, end),
+                previous_end: prev_end,
+            }
+            .at(input.name(), Some(linenum))
+            .into());
+        }
+        if let Some(i) = contexts
+            .iter()
+            .position(|wanted| wanted.is_none() || *wanted == context)
+        {
+            store(i, id, interval)?;
+        }
+        prev = Some((id, start, end));
+        Ok(())
+    };
+
+    input.read(&mut |batches, progress| {
+        parse_batches(batches, layout, names, filter, contexts, progress, &mut add)
+    })?;
+    Ok(chroms)
+}
+
+/// Bytes of input each thread parses per window of [`parse_batches`].
+const PARSE_WINDOW_PER_THREAD: usize = 4 << 20;
+
+/// A parsed record added by [`read_meth_records`] with its line number.
+type AddRecord<'a> =
+    dyn FnMut(usize, &str, MethInterval, Option<Context>) -> Result<(), Box<dyn Error>> + 'a;
+
+/// Parses the lines of in-memory `batches` of the methylation file of
+/// `progress` in parallel chunks, handing the records to `add` in file order.
+/// Lines may span batches; with `names` the first line is the header.
+fn parse_batches<'b>(
+    batches: impl Iterator<Item = std::io::Result<Cow<'b, [u8]>>>,
+    layout: &Layout,
+    names: &ColumnNames,
+    filter: &RecordFilter,
+    contexts: &[Option<Context>],
+    progress: &progress::Progress,
+    add: &mut AddRecord,
+) -> Result<(), Box<dyn Error>> {
+    let path = progress.path();
+    let split_contexts = contexts != [None];
+    let mut skipped = SkippedLines::default();
+    let mut named_layout = None;
+    let mut linenum = 1;
+    let mut partial: Vec<u8> = Vec::new();
+    let mut batches = batches.peekable();
+    while let Some(batch) = batches.next() {
+        let batch = batch?;
+        let last = batches.peek().is_none();
+        let joined: Cow<[u8]> = if partial.is_empty() {
+            batch
+        } else {
+            partial.extend_from_slice(&batch);
+            Cow::Owned(std::mem::take(&mut partial))
+        };
+        let mut text = &joined[..];
+        if !names.is_empty() && named_layout.is_none() {
+            let end = match text.iter().position(|&byte| byte == b'\n') {
+                Some(i) => i + 1,
+                None if !last => {
+                    partial = text.to_vec();
+                    continue;
+                }
+                None if text.is_empty() => break,
+                None => text.len(),
+            };
+            let header = std::str::from_utf8(&text[..end])
+                .map_err(|_| ParseErrorKind::InvalidUtf8.at(path, Some(1)))?;
+            named_layout = Some(names.apply(layout, header)?);
+            linenum += 1;
+            progress.add(end as u64);
+            text = &text[end..];
+        }
+        let layout = named_layout.as_ref().unwrap_or(layout);
+        let whole = match last {
+            true => text.len(),
+            false => text
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |i| i + 1),
+        };
+        // Parse a window of lines at a time so the parsed records held before
+        // they are added stay small next to the input.
+        let threads = parallel::current_num_threads();
+        let windows = whole.div_ceil(threads * PARSE_WINDOW_PER_THREAD);
+        for window in line_chunks(&text[..whole], windows) {
+            let chunks = line_chunks(window, threads * 4);
+            // Each chunk stops at its first error, found by its line in the chunk.
+            let parsed: Vec<_> = chunks
+                .par_iter()
+                .map(|chunk| {
+                    let mut records = Vec::new();
+                    let mut skipped = SkippedLines::default();
+                    let text = match std::str::from_utf8(chunk) {
+                        Ok(text) => text,
+                        Err(err) => {
+                            let line = chunk[..err.valid_up_to()]
+                                .iter()
+                                .filter(|&&byte| byte == b'\n')
+                                .count();
+                            let error = Some((line, ParseErrorKind::InvalidUtf8));
+                            return (0, records, skipped, error);
+                        }
+                    };
+                    let mut lines = 0;
+                    for (i, line) in text.lines().enumerate() {
+                        lines = i + 1;
+                        match parse_record(line, layout, filter) {
+                            Ok(Some((chrom, interval))) => {
+                                let context = split_contexts
+                                    .then(|| record_context(line, layout))
+                                    .flatten();
+                                records.push((i, chrom, interval, context));
+                            }
+                            Ok(None) if format::is_header_line(line) => skipped.headers += 1,
+                            Ok(None) => skipped.filtered += 1,
+                            Err(kind) => return (lines, records, skipped, Some((i, kind))),
+                        }
+                    }
+                    (lines, records, skipped, None)
+                })
+                .collect();
+            for (lines, records, chunk_skipped, error) in parsed {
+                skipped.headers += chunk_skipped.headers;
+                skipped.filtered += chunk_skipped.filtered;
+                for (i, chrom, interval, context) in records {
+                    add(linenum + i, chrom, interval, context)?;
+                }
+                if let Some((i, kind)) = error {
+                    return Err(kind.at(path, Some(linenum + i)).into());
+                }
+                linenum += lines;
+            }
+            progress.add(window.len() as u64);
+        }
+        partial = text[whole..].to_vec();
+    }
+    if !names.is_empty() && named_layout.is_none() {
+        return Err(ParseErrorKind::MissingHeader.at(path, None).into());
+    }
+    log::debug(|| {
+        format!(
+            "skipped input={} header_lines={} filtered_lines={}",
+            path.display(),
+            skipped.headers,
+            skipped.filtered
+        )
+    });
+    Ok(())
+}
+
+/// Lines of a methylation file parsed without giving a record.
+#[derive(Debug, Default)]
+struct SkippedLines {
+    /// Headers, comments and `track`/`browser` lines.
+    headers: usize,
+    /// Short lines and records dropped by the filters: other chromosomes,
+    /// contexts or modification codes, coverage limits or the blacklist.
+    filtered: usize,
+}
+
+/// Splits `bytes` into about `n` chunks of whole lines.
+fn line_chunks(bytes: &[u8], n: usize) -> Vec<&[u8]> {
+    let size = bytes.len().div_ceil(n.max(1)).max(1);
+    let mut chunks = Vec::with_capacity(n);
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let end = match rest.get(size..) {
+            Some(tail) => tail
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(rest.len(), |i| size + i + 1),
+            None => rest.len(),
+        };
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Sequence context of a methylation line, from the layout's context column.
+fn record_context(line: &str, layout: &Layout) -> Option<Context> {
+    match layout.context_col {
+        0 => None,
+        col => line
+            .split_whitespace()
+            .nth(col - 1)
+            .and_then(Context::classify),
+    }
+}
+
+/// Reads BED targets; the optional fourth column names the target unless
+/// `names` is off, and the sixth holds its strand.
+fn parse_targets(
+    path: &Path,
+    names: bool,
+    keep_columns: bool,
+) -> Result<Vec<TargetInterval>, Box<dyn Error>> {
+    let reader = compression::open(path)?;
+    let mut targets = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut toks = line.split('\t');
+        let Some(chrom) = toks.next() else {
+            continue;
+        };
+        let Some(start_s) = toks.next() else {
+            continue;
+        };
+        let Some(end_s) = toks.next() else {
+            continue;
+        };
+        let rest: Vec<&str> = toks.collect();
+
+        targets.push(TargetInterval {
+            chrom: chrom.to_string(),
+            start: parse_i32_lossy(start_s),
+            end: parse_i32_lossy(end_s),
+            name: rest
+                .first()
+                .filter(|_| names && !keep_columns)
+                .map(|name| name.to_string()),
+            strand: rest.get(2).map_or(Strand::Unknown, |s| Strand::parse(s)),
+            extra: if keep_columns {
+                rest.iter().map(|col| col.to_string()).collect()
+            } else {
+                Vec::new()
+            },
+        });
+    }
+
+    Ok(targets)
+}
+
+/// Reads the `(chrom, size)` pairs of a `chrom<TAB>size` file, in file order.
+fn read_chrom_sizes(path: &Path) -> Result<Vec<(String, i32)>, Box<dyn Error>> {
+    let reader = compression::open(path)?;
+    let mut sizes = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut toks = line.split('\t');
+        let (Some(chrom), Some(size)) = (toks.next(), toks.next()) else {
+            continue;
+        };
+        let Ok(size) = size.trim().parse::<i32>() else {
+            continue;
+        };
+        sizes.push((chrom.to_string(), size));
+    }
+    Ok(sizes)
+}
+
+/// Tiles every chromosome of a `chrom<TAB>size` file into consecutive
+/// `window`-sized targets; the last tile of each chromosome is clipped.
+fn tile_genome(path: &Path, window: i32) -> Result<Vec<TargetInterval>, Box<dyn Error>> {
+    let mut targets = Vec::new();
+    for (chrom, size) in read_chrom_sizes(path)? {
+        for start in (0..size).step_by(window as usize) {
+            targets.push(TargetInterval {
+                chrom: chrom.clone(),
+                start,
+                end: start.saturating_add(window).min(size),
+                name: None,
+                strand: Strand::Unknown,
+                extra: Vec::new(),
+            });
+        }
+    }
+    Ok(targets)
+}
+
+fn lower_bound_end(intervals: &[MethInterval], start: i32) -> usize {
+    let mut lo = 0_usize;
+    let mut hi = intervals.len();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if intervals[mid].end <= start {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// Aggregate of the methylation records overlapping one target in one sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct TargetSummary {
+    num_positions: usize,
+    sum_total_coverage: i32,
+    weighted_fraction: f32,
+    /// Summed `fraction * coverage`, the methylated read count.
+    sum_methylated: f32,
+    /// Spread of the covered records' fractions, with `--stats`.
+    stats: Option<FractionStats>,
+    /// Overlapping records per kilobase of target.
+    sites_per_kb: f32,
+    /// Reference CpGs in the target, with `--cpg-bed`.
+    reference_sites: Option<usize>,
+    /// Beta-binomial posterior mean, with `--shrink`.
+    shrunk_fraction: Option<f32>,
+    /// Confidence bounds of the methylation level, with `--ci`.
+    ci: Option<(f32, f32)>,
+    /// The fraction was filled in by `--impute` rather than measured.
+    imputed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FractionStats {
+    median: f32,
+    /// Sample standard deviation; 0 for a single record.
+    sd: f32,
+    min: f32,
+    max: f32,
+    /// Mean binary entropy of the fractions, in bits: 1 for uniformly
+    /// half-methylated sites, 0 for fully (un)methylated ones.
+    entropy: f32,
+}
+
+impl FractionStats {
+    /// Statistics of `fractions`, which are sorted in place; `None` when empty.
+    fn compute(fractions: &mut [f32]) -> Option<FractionStats> {
+        let n = fractions.len();
+        if n == 0 {
+            return None;
+        }
+        fractions.sort_by(f32::total_cmp);
+        let median = if n % 2 == 1 {
+            fractions[n / 2]
+        } else {
+            (fractions[n / 2 - 1] + fractions[n / 2]) / 2.0
+        };
+        let mean = fractions.iter().sum::<f32>() / n as f32;
+        let sd = if n > 1 {
+            let squares: f32 = fractions.iter().map(|f| (f - mean).powi(2)).sum();
+            (squares / (n - 1) as f32).sqrt()
+        } else {
+            0.0
+        };
+        let entropy = fractions.iter().map(|&f| binary_entropy(f)).sum::<f32>() / n as f32;
+        Some(FractionStats {
+            median,
+            sd,
+            min: fractions[0],
+            max: fractions[n - 1],
+            entropy,
+        })
+    }
+
+    fn get(&self, stat: Stat) -> f32 {
+        match stat {
+            Stat::Median => self.median,
+            Stat::Sd => self.sd,
+            Stat::Min => self.min,
+            Stat::Max => self.max,
+            Stat::Entropy => self.entropy,
+        }
+    }
+}
+
+fn binary_entropy(fraction: f32) -> f32 {
+    let p = fraction.clamp(0.0, 1.0);
+    if p == 0.0 || p == 1.0 {
+        return 0.0;
+    }
+    -p * p.log2() - (1.0 - p) * (1.0 - p).log2()
+}
+
+impl TargetSummary {
+    /// Methylated reads, rounded back to a count.
+    fn methylated(&self) -> i32 {
+        self.sum_methylated.round() as i32
+    }
+}
+
+fn summarize_ranges(
+    ranges: &MethRanges,
+    target: &TargetInterval,
+    strand: Strand,
+    aggregation: &Aggregation,
+) -> TargetSummary {
+    let intervals = ranges
+        .by_chrom
+        .get(&target.chrom)
+        .map_or(&[][..], Vec::as_slice);
+    summarize(intervals, target, strand, aggregation)
+}
+
+/// The sorted intervals of `target`'s chromosome that overlap it and lie on `strand`.
+fn overlapping<'a>(
+    intervals: &'a [MethInterval],
+    target: &'a TargetInterval,
+    strand: Strand,
+) -> impl Iterator<Item = &'a MethInterval> {
+    intervals[lower_bound_end(intervals, target.start)..]
+        .iter()
+        .take_while(|iv| iv.start < target.end)
+        .filter(move |iv| iv.end > target.start && iv.strand.matches(strand))
+}
+
+/// How the fractions of the records overlapping a target are combined.
+#[derive(Debug, Clone, Default)]
+struct Aggregation {
+    mean: MeanMode,
+    /// Collect the record fractions for [`FractionStats`].
+    stats: bool,
+    /// Scale each record by the fraction of its bases inside the target.
+    overlap_weighted: bool,
+    /// Share of the lowest and of the highest record fractions left out of the mean.
+    trim: f64,
+    /// Clamp the trimmed fractions to the extremes that remain instead of dropping them.
+    winsorize: bool,
+    /// Largest coverage weight of a record in the weighted mean.
+    weight_cap: Option<i32>,
+}
+
+impl Aggregation {
+    /// Weight of a record overlapping `target` in the mean fraction.
+    fn weight(&self, iv: &MethInterval, target: &TargetInterval) -> f32 {
+        if iv.coverage <= 0 {
+            return 0.0;
+        }
+        let weight = match self.mean {
+            MeanMode::Weighted => {
+                self.weight_cap
+                    .map_or(iv.coverage, |cap| iv.coverage.min(cap)) as f32
+            }
+            MeanMode::Unweighted => 1.0,
+        };
+        let length = iv.end - iv.start;
+        if self.overlap_weighted && length > 1 {
+            let overlap = iv.end.min(target.end) - iv.start.max(target.start);
+            weight * overlap as f32 / length as f32
+        } else {
+            weight
+        }
+    }
+}
+
+/// `--mean-mode` choices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+enum MeanMode {
+    /// Mean of the fractions weighted by coverage.
+    #[default]
+    Weighted,
+    /// Plain mean of the fractions of covered records.
+    Unweighted,
+}
+
+/// Aggregates the sorted intervals of `target`'s chromosome that overlap it
+/// and lie on `strand`.
+fn summarize(
+    intervals: &[MethInterval],
+    target: &TargetInterval,
+    strand: Strand,
+    aggregation: &Aggregation,
+) -> TargetSummary {
+    let mut num_positions = 0_usize;
+    let mut sum_total_coverage = 0_i32;
+    let mut sum_meth_coverage = 0_f32;
+    let mut sum_weight = 0_f32;
+    let mut sum_weighted_fraction = 0_f32;
+    let mut fractions = Vec::new();
+    let mut weighted = Vec::new();
+
+    for iv in overlapping(intervals, target, strand) {
+        num_positions += 1;
+        sum_total_coverage += iv.coverage;
+        sum_meth_coverage += iv.fraction * iv.coverage as f32;
+        let weight = aggregation.weight(iv, target);
+        sum_weight += weight;
+        sum_weighted_fraction += iv.fraction * weight;
+        if aggregation.stats && iv.coverage > 0 {
+            fractions.push(iv.fraction);
+        }
+        if aggregation.trim > 0.0 && weight > 0.0 {
+            weighted.push((iv.fraction, weight));
+        }
+    }
+
+    let weighted_fraction = if aggregation.trim > 0.0 {
+        trimmed_mean(&mut weighted, aggregation.trim, aggregation.winsorize)
+    } else if sum_weight > 0.0 {
+        sum_weighted_fraction / sum_weight
+    } else {
+        0.0
+    };
+
+    TargetSummary {
+        num_positions,
+        sum_total_coverage,
+        weighted_fraction,
+        sum_methylated: sum_meth_coverage,
+        stats: FractionStats::compute(&mut fractions),
+        sites_per_kb: match target.end - target.start {
+            length if length > 0 => num_positions as f32 * 1000.0 / length as f32,
+            _ => 0.0,
+        },
+        reference_sites: None,
+        shrunk_fraction: None,
+        ci: None,
+        imputed: false,
+    }
+}
+
+/// Weighted mean of `(fraction, weight)` pairs without their `trim` share of
+/// lowest and highest fractions, or with those clamped when `winsorize`.
+fn trimmed_mean(records: &mut [(f32, f32)], trim: f64, winsorize: bool) -> f32 {
+    records.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let n = records.len();
+    let k = (trim * n as f64).floor() as usize;
+    if n == 0 || 2 * k >= n {
+        return 0.0;
+    }
+    if winsorize {
+        let (low, high) = (records[k].0, records[n - 1 - k].0);
+        for record in records.iter_mut() {
+            record.0 = record.0.clamp(low, high);
+        }
+    }
+    let kept = if winsorize {
+        &records[..]
+    } else {
+        &records[k..n - k]
+    };
+    let sum_weight: f32 = kept.iter().map(|&(_, weight)| weight).sum();
+    let sum_weighted: f32 = kept
+        .iter()
+        .map(|&(fraction, weight)| fraction * weight)
+        .sum();
+    sum_weighted / sum_weight
+}
+
+/// A `--stats` summary of the per-record fractions of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+enum Stat {
+    Median,
+    Sd,
+    Min,
+    Max,
+    Entropy,
+}
+
+/// Detects the format of a methylation file from its first lines and reports it on stderr.
+fn detect_format(path: &Path) -> Result<Format, Box<dyn Error>> {
+    if is_stdin(path) {
+        return Err("Error: --format auto cannot inspect stdin; pass an explicit --format".into());
+    }
+    if !remote::is_url(path) && bam::sniff(path)?.is_some() {
+        return Ok(Format::Generic);
+    }
+    let lines = compression::open(path)?
+        .lines()
+        .take(DETECT_LINES)
+        .collect::<Result<Vec<_>, _>>()?;
+    let format = Format::detect(lines.iter().map(String::as_str));
+    log::info(|| format!("detected path={} format={}", path.display(), format.name()));
+    Ok(format)
+}
+
+/// Lines inspected by `--format auto`; enough to get past the usual header lines.
+const DETECT_LINES: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn finds_first_candidate_interval_with_binary_search() {
+        let intervals = vec![
+            MethInterval {
+                start: 1,
+                end: 2,
+                fraction: 0.0,
+                coverage: 1,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 5,
+                end: 6,
+                fraction: 0.0,
+                coverage: 1,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 10,
+                end: 11,
+                fraction: 0.0,
+                coverage: 1,
+                strand: Strand::Unknown,
+            },
+        ];
+        assert_eq!(lower_bound_end(&intervals, 0), 0);
+        assert_eq!(lower_bound_end(&intervals, 2), 1);
+        assert_eq!(lower_bound_end(&intervals, 6), 2);
+        assert_eq!(lower_bound_end(&intervals, 11), 3);
+    }
+
+    #[test]
+    fn bismark_cov_preset_uses_counts() {
+        let layout = Format::BismarkCov.layout();
+        let fields = ["chr1", "101", "101", "75.0", "3", "1"];
+        let (fraction, coverage) = record_values(&fields, &layout, false).unwrap();
+        assert_eq!(coverage, 4);
+        assert!((fraction - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn splits_only_the_fields_a_layout_reads() {
+        let line = "chr1\t10\t11\tm\t12\t+\t10 11  255,0,0\t12 25.00\r\n";
+        let mut fields = [""; 16];
+        let n = split_fields(line, &mut fields);
+        assert_eq!(&fields[..n], line.split_whitespace().collect::<Vec<_>>());
+        let n = split_fields(line, &mut fields[..4]);
+        assert_eq!(&fields[..n], ["chr1", "10", "11", "m"]);
+        assert_eq!(split_fields("  \t\n", &mut fields), 0);
+        let n = split_fields("chr\u{e9}1\u{a0}5 6", &mut fields);
+        assert_eq!(&fields[..n], ["chr\u{e9}1", "5", "6"]);
+    }
+
+    #[test]
+    fn skips_records_off_the_target_chromosomes() {
+        let layout = Format::Generic.layout();
+        let filter = RecordFilter {
+            chroms: Some(Arc::new(HashSet::from(["chr2".to_string()]))),
+            ..RecordFilter::default()
+        };
+        let kept = |line| {
+            parse_record(line, &layout, &filter)
+                .unwrap()
+                .map(|(chrom, _)| chrom)
+        };
+        assert_eq!(kept("chr1\t10\t11\t0.5\t4\n"), None);
+        assert_eq!(kept("chr2\t10\t11\t0.5\t4\n"), Some("chr2"));
+    }
+
+    #[test]
+    fn bedmethyl_preset_filters_by_mod_code() {
+        let layout = Format::Bedmethyl.layout();
+        let line = "chr1 10 11 m 12 + 10 11 255,0,0 12 25.00 3 9 0 0 0 0 0";
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let filter = RecordFilter {
+            mod_code: Some("h".to_string()),
+            ..RecordFilter::default()
+        };
+        assert!(!filter.accepts(&fields, &layout));
+        assert!(RecordFilter::default().accepts(&fields, &layout));
+
+        let (fraction, coverage) = record_values(&fields, &layout, false).unwrap();
+        assert_eq!(coverage, 12);
+        assert!((fraction - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn methyldackel_preset_skips_track_line() {
+        assert!(format::is_header_line(
+            "track type=\"bedGraph\" description=\"CpG methylation levels\"\n"
+        ));
+        let layout = Format::Methyldackel.layout();
+        let fields = ["chr1", "10", "11", "66", "2", "1"];
+        let (fraction, coverage) = record_values(&fields, &layout, false).unwrap();
+        assert_eq!(coverage, 3);
+        assert!((fraction - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bismark_cx_report_filters_by_context() {
+        let layout = Format::BismarkCx.layout();
+        let cpg = ["chr1", "100", "+", "3", "1", "CG", "CGA"];
+        let chh = ["chr1", "102", "+", "0", "2", "CHH", "CTT"];
+        assert_eq!(record_span(&cpg, &layout, false), Ok((99, 100)));
+
+        let filter = RecordFilter {
+            context: Some(Context::CpG),
+            ..RecordFilter::default()
+        };
+        assert!(filter.accepts(&cpg, &layout));
+        assert!(!filter.accepts(&chh, &layout));
+
+        let (fraction, coverage) = record_values(&cpg, &layout, false).unwrap();
+        assert_eq!(coverage, 4);
+        assert!((fraction - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn allc_classifies_trinucleotide_context() {
+        assert_eq!(Context::classify("CGT"), Some(Context::CpG));
+        assert_eq!(Context::classify("CTG"), Some(Context::Chg));
+        assert_eq!(Context::classify("CAT"), Some(Context::Chh));
+
+        let layout = Format::Allc.layout();
+        let fields = ["1", "5001", "+", "CGA", "2", "8", "1"];
+        assert_eq!(record_span(&fields, &layout, false), Ok((5000, 5001)));
+        let (fraction, coverage) = record_values(&fields, &layout, false).unwrap();
+        assert_eq!(coverage, 8);
+        assert!((fraction - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parses_gzipped_targets() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-targets-{}.bed.gz", std::process::id()));
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        encoder.write_all(b"chr1\t10\t20\nchr2\t5\t6\n").unwrap();
+        encoder.finish().unwrap();
+
+        let targets = parse_targets(&path, true, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!((targets[1].chrom.as_str(), targets[1].start), ("chr2", 5));
+    }
+
+    #[test]
+    fn selects_columns_by_header_name() {
+        let names = ColumnNames {
+            frac: Some("percent_modified".to_string()),
+            cov: Some("valid_coverage".to_string()),
+            ..ColumnNames::default()
+        };
+        let header = "#chrom\tstart\tend\tvalid_coverage\tpercent_modified\n";
+        let layout = names.apply(&Format::Generic.layout(), header).unwrap();
+        assert_eq!((layout.frac_col, layout.cov_col), (5, 4));
+        assert!(names.apply(&layout, "chrom\tstart\tend\n").is_err());
+    }
+
+    #[test]
+    fn detects_common_formats() {
+        let detect = |text: &str| Format::detect(text.lines());
+        assert_eq!(detect("chr1\t10\t10\t75.0\t3\t1\n"), Format::BismarkCov);
+        assert_eq!(
+            detect("track type=\"bedGraph\"\nchr1\t9\t10\t75\t3\t1\n"),
+            Format::Methyldackel
+        );
+        assert_eq!(
+            detect("chr1\t9\t10\tm\t4\t+\t9\t10\t255,0,0\t4\t75.00\t3\t1\t0\t0\t0\t0\t0\n"),
+            Format::Bedmethyl
+        );
+        assert_eq!(detect("chr1\t10\t+\t3\t1\tCG\tCGA\n"), Format::BismarkCx);
+        assert_eq!(detect("chr1\t10\t+\tCGA\t3\t4\t1\n"), Format::Allc);
+        assert_eq!(detect("chr1\t9\t10\t0.75\t4\n"), Format::Generic);
+    }
+
+    #[test]
+    fn reads_target_names_unless_disabled() {
+        let path = std::env::temp_dir().join(format!("methfast-names-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t20\tpromoter_1\t0\t+\nchr1\t30\t40\n").unwrap();
+        let named = parse_targets(&path, true, false).unwrap();
+        let unnamed = parse_targets(&path, false, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(named[0].name.as_deref(), Some("promoter_1"));
+        assert_eq!(named[1].name, None);
+        assert_eq!(unnamed[0].name, None);
+    }
+
+    #[test]
+    fn aggregates_only_matching_strand() {
+        let interval = |start, strand| MethInterval {
+            start,
+            end: start + 1,
+            fraction: 1.0,
+            coverage: 2,
+            strand,
+        };
+        let intervals = [
+            interval(10, Strand::Plus),
+            interval(11, Strand::Minus),
+            interval(12, Strand::Unknown),
+        ];
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 20,
+            name: None,
+            strand: Strand::Minus,
+            extra: Vec::new(),
+        };
+        assert_eq!(
+            summarize(
+                &intervals,
+                &target,
+                Strand::Unknown,
+                &Aggregation::default()
+            )
+            .num_positions,
+            3
+        );
+        assert_eq!(
+            summarize(&intervals, &target, Strand::Minus, &Aggregation::default()).num_positions,
+            2
+        );
+    }
+
+    #[test]
+    fn tiles_chromosomes_from_sizes() {
+        let path = std::env::temp_dir().join(format!("methfast-sizes-{}.txt", std::process::id()));
+        std::fs::write(&path, "chr1\t2500\nchrM\t16\n").unwrap();
+        let tiles = tile_genome(&path, 1000).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let spans: Vec<(&str, i32, i32)> = tiles
+            .iter()
+            .map(|tile| (tile.chrom.as_str(), tile.start, tile.end))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("chr1", 0, 1000),
+                ("chr1", 1000, 2000),
+                ("chr1", 2000, 2500),
+                ("chrM", 0, 16)
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_records_below_min_coverage() {
+        let layout = Format::Generic.layout();
+        let filter = RecordFilter {
+            min_coverage: Some(5),
+            ..RecordFilter::default()
+        };
+        assert!(
+            parse_record("chr1\t10\t11\t0.5\t4\n", &layout, &filter)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            parse_record("chr1\t10\t11\t0.5\t5\n", &layout, &filter)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn averages_fractions_without_weights() {
+        let intervals = vec![
+            MethInterval {
+                start: 1,
+                end: 2,
+                fraction: 1.0,
+                coverage: 30,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 5,
+                end: 6,
+                fraction: 0.0,
+                coverage: 10,
+                strand: Strand::Unknown,
+            },
+        ];
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let unweighted = Aggregation {
+            mean: MeanMode::Unweighted,
+            ..Aggregation::default()
+        };
+        let fraction = |aggregation: &Aggregation| {
+            summarize(&intervals, &target, Strand::Unknown, aggregation).weighted_fraction
+        };
+        assert_eq!(fraction(&Aggregation::default()), 0.75);
+        assert_eq!(fraction(&unweighted), 0.5);
+    }
+
+    #[test]
+    fn weights_records_by_overlapping_bases() {
+        let intervals = vec![
+            MethInterval {
+                start: 0,
+                end: 100,
+                fraction: 1.0,
+                coverage: 10,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 150,
+                end: 151,
+                fraction: 0.0,
+                coverage: 10,
+                strand: Strand::Unknown,
+            },
+        ];
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 90,
+            end: 200,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let overlap_weighted = Aggregation {
+            overlap_weighted: true,
+            ..Aggregation::default()
+        };
+        let summary = summarize(&intervals, &target, Strand::Unknown, &overlap_weighted);
+        assert!((summary.weighted_fraction - 1.0 / 11.0).abs() < 1e-6);
+        assert_eq!(summary.sum_total_coverage, 20);
+        let summary = summarize(
+            &intervals,
+            &target,
+            Strand::Unknown,
+            &Aggregation::default(),
+        );
+        assert_eq!(summary.weighted_fraction, 0.5);
+    }
+
+    #[test]
+    fn caps_coverage_weights() {
+        let intervals = vec![
+            MethInterval {
+                start: 1,
+                end: 2,
+                fraction: 1.0,
+                coverage: 1000,
+                strand: Strand::Unknown,
+            },
+            MethInterval {
+                start: 5,
+                end: 6,
+                fraction: 0.0,
+                coverage: 10,
+                strand: Strand::Unknown,
+            },
+        ];
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 0,
+            end: 10,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let capped = Aggregation {
+            weight_cap: Some(30),
+            ..Aggregation::default()
+        };
+        let summary = summarize(&intervals, &target, Strand::Unknown, &capped);
+        assert_eq!(summary.weighted_fraction, 0.75);
+        assert_eq!(summary.sum_total_coverage, 1010);
+    }
+
+    #[test]
+    fn merges_cpg_strand_pairs() {
+        let record = |start, fraction, coverage, strand| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage,
+            strand,
+        };
+        let merged = destrand(vec![
+            record(10, 1.0, 3, Strand::Plus),
+            record(11, 0.0, 1, Strand::Minus),
+            record(20, 0.5, 2, Strand::Minus),
+            record(30, 0.5, 2, Strand::Plus),
+            record(32, 0.5, 2, Strand::Minus),
+        ]);
+        assert_eq!(merged.len(), 4);
+        assert_eq!((merged[0].start, merged[0].end), (10, 12));
+        assert_eq!((merged[0].coverage, merged[0].fraction), (4, 0.75));
+        assert_eq!(merged[0].strand, Strand::Unknown);
+        assert_eq!(merged[3].start, 32);
+    }
+
+    #[test]
+    fn splits_records_by_context() {
+        let path = std::env::temp_dir().join(format!("methfast-cx-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "chr1\t10\t+\t3\t1\tCG\tCGA\nchr1\t12\t+\t0\t2\tCHH\tCTT\nchr1\t15\t-\t1\t1\tCHG\tCAG\n",
+        )
+        .unwrap();
+        let contexts = [Some(Context::CpG), Some(Context::Chg), Some(Context::Chh)];
+        let layout = Format::BismarkCx.layout();
+        let ranges = parse_meth_bed(
+            &path,
+            &layout,
+            &ColumnNames::default(),
+            &RecordFilter::default(),
+            &contexts,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let fractions: Vec<f32> = ranges
+            .iter()
+            .map(|ranges| ranges.by_chrom["chr1"][0].fraction)
+            .collect();
+        assert_eq!(fractions, vec![0.75, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn sorts_unsorted_records_on_request() {
+        let path = std::env::temp_dir().join(format!("methfast-sort-{}.bed", std::process::id()));
+        std::fs::write(
+            &path,
+            "chr2\t5\t6\t1.0\t1\nchr1\t20\t21\t0.5\t2\nchr1\t10\t11\t0.0\t2\n",
+        )
+        .unwrap();
+        let layout = Format::Generic.layout();
+        let parse = |filter: &RecordFilter| {
+            parse_meth_bed(&path, &layout, &ColumnNames::default(), filter, &[None])
+        };
+        assert!(parse(&RecordFilter::default()).is_err());
+        let sorted = parse(&RecordFilter {
+            sort: true,
+            ..RecordFilter::default()
+        })
+        .unwrap()
+        .remove(0);
+        std::fs::remove_file(&path).unwrap();
+
+        let starts: Vec<i32> = sorted.by_chrom["chr1"].iter().map(|iv| iv.start).collect();
+        assert_eq!(starts, vec![10, 20]);
+    }
+
+    #[test]
+    fn parses_mapped_files_in_line_chunks() {
+        let text = b"a\tb\nccc\n\ndd\ne";
+        let chunks = line_chunks(text, 3);
+        assert_eq!(chunks.concat(), text);
+        assert!(
+            chunks[..chunks.len() - 1]
+                .iter()
+                .all(|chunk| chunk.ends_with(b"\n"))
+        );
+
+        let path = std::env::temp_dir().join(format!("methfast-mapped-{}.bed", std::process::id()));
+        let mut records: String = (0..8)
+            .map(|i| format!("chr1\t{}\t{}\t0.5\t2\n", i * 10, i * 10 + 1))
+            .collect();
+        records.push_str("chr1\t5\t6\t0.5\t2\n");
+        std::fs::write(&path, format!("chrom\tstart\tend\tbeta\tdepth\n{records}")).unwrap();
+        let names = ColumnNames {
+            frac: Some("beta".to_string()),
+            cov: Some("depth".to_string()),
+            ..ColumnNames::default()
+        };
+        let err = parse_meth_bed(
+            &path,
+            &Format::Generic.layout(),
+            &names,
+            &RecordFilter::default(),
+            &[None],
+        )
+        .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!((err.path.as_path(), err.line), (path.as_path(), Some(10)));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::Unsorted {
+                chrom: "chr1".to_string(),
+                record: (5, 6),
+                previous_end: 71,
+            }
+        );
+    }
+
+    #[test]
+    fn resolves_overlapping_records_by_policy() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-overlap-{}.bed", std::process::id()));
+        // A 2-base block overlapping both strands of a CpG, then a lone record.
+        std::fs::write(
+            &path,
+            "chr1\t10\t12\t1.0\t2\t+\nchr1\t11\t12\t0.5\t4\t-\nchr1\t11\t13\t0.0\t6\t-\nchr1\t20\t21\t0.25\t4\t+\n",
+        )
+        .unwrap();
+        let mut layout = Format::Generic.layout();
+        layout.strand_col = 6;
+        let parse = |on_overlap| {
+            let filter = RecordFilter {
+                on_overlap,
+                ..RecordFilter::default()
+            };
+            parse_meth_bed(&path, &layout, &ColumnNames::default(), &filter, &[None])
+                .map(|mut ranges| ranges.remove(0).by_chrom.remove("chr1").unwrap())
+        };
+        let rejected = parse(OnOverlap::Error);
+        let [merged, first, average] = [OnOverlap::Merge, OnOverlap::First, OnOverlap::Average]
+            .map(|policy| parse(policy).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(rejected.is_err());
+        let values = |intervals: &[MethInterval]| -> Vec<(i32, i32, f32, i32, Strand)> {
+            intervals
+                .iter()
+                .map(|iv| (iv.start, iv.end, iv.fraction, iv.coverage, iv.strand))
+                .collect()
+        };
+        assert_eq!(
+            values(&merged),
+            vec![
+                (10, 13, 4.0 / 12.0, 12, Strand::Unknown),
+                (20, 21, 0.25, 4, Strand::Plus)
+            ]
+        );
+        assert_eq!(
+            values(&first),
+            vec![
+                (10, 12, 1.0, 2, Strand::Plus),
+                (20, 21, 0.25, 4, Strand::Plus)
+            ]
+        );
+        assert_eq!(
+            values(&average),
+            vec![
+                (10, 13, 0.5, 4, Strand::Unknown),
+                (20, 21, 0.25, 4, Strand::Plus)
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_bgzf_inputs_across_blocks() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-bgzf-{}.bed.gz", std::process::id()));
+        let mut writer = bgzf::BgzfWriter::new(File::create(&path).unwrap());
+        writeln!(writer, "chrom\tstart\tend\tbeta\tdepth").unwrap();
+        // Enough records for several blocks, so lines straddle block ends.
+        for i in 0..20_000 {
+            writeln!(writer, "chr1\t{}\t{}\t0.25\t{}", i * 10, i * 10 + 1, i % 7).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(bgzf::is_bgzf(&path).unwrap());
+        let names = ColumnNames {
+            frac: Some("beta".to_string()),
+            cov: Some("depth".to_string()),
+            ..ColumnNames::default()
+        };
+        let filter = RecordFilter {
+            min_coverage: Some(1),
+            ..RecordFilter::default()
+        };
+        let ranges = parse_meth_bed(&path, &Format::Generic.layout(), &names, &filter, &[None])
+            .unwrap()
+            .remove(0);
+        std::fs::remove_file(&path).unwrap();
+        let intervals = &ranges.by_chrom["chr1"];
+        assert_eq!(intervals.len(), 20_000 - 20_000_usize.div_ceil(7));
+        assert!(
+            intervals
+                .windows(2)
+                .all(|pair| pair[0].start < pair[1].start)
+        );
+        assert_eq!((intervals[0].start, intervals[0].coverage), (10, 1));
+    }
+
+    #[test]
+    fn strict_mode_rejects_malformed_numbers() {
+        let path = std::env::temp_dir().join(format!("methfast-strict-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t0.5\t4\nchr1\t20\t21\tNA\t4\n").unwrap();
+        let parse = |strict| {
+            let filter = RecordFilter {
+                strict,
+                ..RecordFilter::default()
+            };
+            parse_meth_bed(
+                &path,
+                &Format::Generic.layout(),
+                &ColumnNames::default(),
+                &filter,
+                &[None],
+            )
+        };
+        let lossy = parse(false);
+        let strict = parse(true);
+        std::fs::remove_file(&path).unwrap();
+
+        let lossy = lossy.unwrap().remove(0);
+        assert_eq!(lossy.records("chr1")[1].fraction, 0.0);
+        let err = strict.unwrap_err();
+        let err = err.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(err.line, Some(2));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::InvalidNumber {
+                column: 4,
+                value: "NA".to_string(),
+            }
+        );
+        let fields = ["chr1", "1e3x", "1001", "0.5", "4"];
+        assert!(record_span(&fields, &Format::Generic.layout(), true).is_err());
+    }
+}
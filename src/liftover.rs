@@ -0,0 +1,225 @@
+//! UCSC chain files, for lifting targets between assemblies with `--liftover`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::{Strand, TargetInterval, compression};
+
+/// An ungapped aligned block of one chain.
+#[derive(Debug, Clone)]
+struct Block {
+    chain: usize,
+    /// Block on the source (`t`) assembly.
+    start: i32,
+    end: i32,
+    /// Start of the block on the chain's query strand.
+    q_start: i32,
+}
+
+/// Query side of a chain.
+#[derive(Debug, Clone)]
+struct Query {
+    chrom: String,
+    size: i32,
+    reverse: bool,
+}
+
+/// Why a target could not be lifted, worded as by UCSC `liftOver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmapped {
+    Deleted,
+    PartiallyDeleted,
+}
+
+impl Unmapped {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Unmapped::Deleted => "Deleted in new",
+            Unmapped::PartiallyDeleted => "Partially deleted in new",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chains {
+    queries: Vec<Query>,
+    /// Blocks of each source chromosome sorted by start, with the longest
+    /// block length bounding overlap searches.
+    by_chrom: HashMap<String, (Vec<Block>, i32)>,
+}
+
+impl Chains {
+    pub fn read(path: &Path) -> Result<Chains, Box<dyn Error>> {
+        let invalid = |line: &str| format!("Error: {}: invalid chain line: {line}", path.display());
+        let mut chains = Chains::default();
+        // Source chromosome and the current positions on both assemblies.
+        let mut current: Option<(String, i32, i32)> = None;
+        for line in compression::open(path)?.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                [first, ..] if first.starts_with('#') => {}
+                [
+                    "chain",
+                    _score,
+                    t_name,
+                    _t_size,
+                    _t_strand,
+                    t_start,
+                    _t_end,
+                    q_name,
+                    q_size,
+                    q_strand,
+                    q_start,
+                    _q_end,
+                    ..,
+                ] => {
+                    let parse = |value: &str| value.parse::<i32>().map_err(|_| invalid(&line));
+                    chains.queries.push(Query {
+                        chrom: q_name.to_string(),
+                        size: parse(q_size)?,
+                        reverse: *q_strand == "-",
+                    });
+                    current = Some((t_name.to_string(), parse(t_start)?, parse(q_start)?));
+                }
+                [size, gaps @ ..] if gaps.len() == 2 || gaps.is_empty() => {
+                    let Some((chrom, t, q)) = &mut current else {
+                        return Err(invalid(&line).into());
+                    };
+                    let parse = |value: &str| value.parse::<i32>().map_err(|_| invalid(&line));
+                    let size = parse(size)?;
+                    let entry = chains.by_chrom.entry(chrom.clone()).or_default();
+                    entry.0.push(Block {
+                        chain: chains.queries.len() - 1,
+                        start: *t,
+                        end: *t + size,
+                        q_start: *q,
+                    });
+                    entry.1 = entry.1.max(size);
+                    if let [dt, dq] = gaps {
+                        *t += size + parse(dt)?;
+                        *q += size + parse(dq)?;
+                    } else {
+                        current = None;
+                    }
+                }
+                _ => return Err(invalid(&line).into()),
+            }
+        }
+        for (blocks, _) in chains.by_chrom.values_mut() {
+            blocks.sort_by_key(|block| block.start);
+        }
+        Ok(chains)
+    }
+
+    /// Lifts `target` through the chain aligning most of its bases, which
+    /// must cover at least `min_match` of them. The lifted interval spans the
+    /// first to the last aligned base; the strand flips on reverse chains.
+    pub fn lift(
+        &self,
+        target: &TargetInterval,
+        min_match: f64,
+    ) -> Result<TargetInterval, Unmapped> {
+        let (blocks, longest) = self.by_chrom.get(&target.chrom).ok_or(Unmapped::Deleted)?;
+        let first = blocks.partition_point(|block| block.start <= target.start - longest);
+        let overlapping: Vec<&Block> = blocks[first..]
+            .iter()
+            .take_while(|block| block.start < target.end)
+            .filter(|block| block.end > target.start)
+            .collect();
+        let mut aligned: HashMap<usize, i64> = HashMap::new();
+        for block in &overlapping {
+            let bases = block.end.min(target.end) - block.start.max(target.start);
+            *aligned.entry(block.chain).or_default() += bases as i64;
+        }
+        // Ties go to the chain listed first, which UCSC orders by score.
+        let (chain, bases) = aligned
+            .into_iter()
+            .max_by_key(|&(chain, bases)| (bases, std::cmp::Reverse(chain)))
+            .ok_or(Unmapped::Deleted)?;
+        if (bases as f64) < min_match * (target.end - target.start) as f64 {
+            return Err(Unmapped::PartiallyDeleted);
+        }
+        let query = &self.queries[chain];
+        let (start, end) = overlapping
+            .iter()
+            .filter(|block| block.chain == chain)
+            .map(|block| {
+                let start = block.q_start + target.start.max(block.start) - block.start;
+                let end = block.q_start + target.end.min(block.end) - block.start;
+                if query.reverse {
+                    (query.size - end, query.size - start)
+                } else {
+                    (start, end)
+                }
+            })
+            .fold((i32::MAX, i32::MIN), |(lo, hi), (start, end)| {
+                (lo.min(start), hi.max(end))
+            });
+        let strand = match (target.strand, query.reverse) {
+            (Strand::Plus, true) => Strand::Minus,
+            (Strand::Minus, true) => Strand::Plus,
+            (strand, _) => strand,
+        };
+        Ok(TargetInterval {
+            chrom: query.chrom.clone(),
+            start,
+            end,
+            strand,
+            ..target.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifts_through_forward_and_reverse_chains() {
+        let path = std::env::temp_dir().join(format!("methfast-chain-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "chain 100 chr1 1000 + 100 300 chr1 2000 + 1100 1310\n\
+             50 10 20\n\
+             50\n\n\
+             chain 50 chr2 500 + 0 100 chr3 400 - 0 100\n\
+             100\n",
+        )
+        .unwrap();
+        let chains = Chains::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let target = |chrom: &str, start, end| TargetInterval {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            name: None,
+            strand: Strand::Plus,
+            extra: Vec::new(),
+        };
+        let lifted = chains.lift(&target("chr1", 110, 140), 0.95).unwrap();
+        assert_eq!((lifted.start, lifted.end), (1110, 1140));
+        // Spans the 10-base gap; 50 of 60 bases align.
+        let spanning = target("chr1", 120, 180);
+        assert_eq!(
+            chains.lift(&spanning, 0.95).unwrap_err(),
+            Unmapped::PartiallyDeleted
+        );
+        let lifted = chains.lift(&spanning, 0.8).unwrap();
+        assert_eq!((lifted.start, lifted.end), (1120, 1190));
+        let reversed = chains.lift(&target("chr2", 10, 20), 0.95).unwrap();
+        assert_eq!(
+            (reversed.chrom.as_str(), reversed.start, reversed.end),
+            ("chr3", 380, 390)
+        );
+        assert_eq!(reversed.strand, Strand::Minus);
+        assert_eq!(
+            chains.lift(&target("chr1", 0, 50), 0.95).unwrap_err(),
+            Unmapped::Deleted
+        );
+    }
+}
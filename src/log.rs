@@ -0,0 +1,113 @@
+//! `-v/--verbose`: what a run reads and how long its stages take, logged on
+//! stderr as `key=value` lines. `-v` logs inputs, formats, record and
+//! chromosome counts and stage timings; `-vv` adds the records of each
+//! chromosome and the lines skipped while parsing.
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Logs at `verbosity` (the count of `-v`) for the rest of the run, timed
+/// from now.
+pub fn set_verbosity(verbosity: u8) {
+    START.get_or_init(Instant::now);
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Info = 1,
+    Debug = 2,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+fn enabled(level: Level) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// A log line of `message` at `elapsed` into the run.
+fn line(level: Level, elapsed: Duration, message: &str) -> String {
+    format!(
+        "methfast[+{:.3}s] {}: {message}",
+        elapsed.as_secs_f64(),
+        level.name()
+    )
+}
+
+fn log(level: Level, message: impl FnOnce() -> String) {
+    if enabled(level) {
+        let elapsed = START.get_or_init(Instant::now).elapsed();
+        let _ = writeln!(
+            std::io::stderr().lock(),
+            "{}",
+            line(level, elapsed, &message())
+        );
+    }
+}
+
+/// Whether `-vv` is in effect, for messages too costly to gather otherwise.
+pub fn debugging() -> bool {
+    enabled(Level::Debug)
+}
+
+/// Logs `message`, built only when logging, at `-v`.
+pub fn info(message: impl FnOnce() -> String) {
+    log(Level::Info, message);
+}
+
+/// Logs `message`, built only when logging, at `-vv`.
+pub fn debug(message: impl FnOnce() -> String) {
+    log(Level::Debug, message);
+}
+
+/// A stage of a run, whose time is logged at `-v` when it is dropped.
+pub struct Stage {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Stage {
+    pub fn start(name: &'static str) -> Stage {
+        Stage {
+            name,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        info(|| format!("stage={} seconds={seconds:.3}", self.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_lines_with_level_and_time() {
+        assert_eq!(
+            line(
+                Level::Debug,
+                Duration::from_millis(1250),
+                "records chrom=chr1 n=3"
+            ),
+            "methfast[+1.250s] debug: records chrom=chr1 n=3"
+        );
+        assert!(Level::Info < Level::Debug);
+    }
+}
@@ -0,0 +1,306 @@
+//! `methfast matrix`: a targets-by-samples matrix from a sample manifest.
+//!
+//! Each sample is streamed once and its records are added to the targets they
+//! overlap, so memory grows with the number of targets and samples rather than
+//! with the size of the inputs.
+
+use crate::parallel::*;
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use crate::cli::{Field, FractionFormat, format_row, header_line, write_lines, write_parquet};
+use crate::error::ThreadError;
+use crate::format::{Format, Layout};
+use crate::samples::{self, SampleSpec};
+use crate::{
+    Aggregation, MethInterval, RecordFilter, TargetInterval, TargetSummary, bam, compression,
+    detect_format, is_stream, parse_record, parse_targets, progress,
+};
+
+#[derive(Args, Debug)]
+pub struct MatrixArgs {
+    #[arg(
+        long = "samples",
+        value_name = "TSV",
+        help = "Sample manifest with label<TAB>path[<TAB>format] lines"
+    )]
+    samples: PathBuf,
+    #[arg(value_name = "TARGET_BED", help = "Target intervals")]
+    targets: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Format of the samples without one in the manifest"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "coverage",
+        help = "Also write a coverage column per sample (tsv output)"
+    )]
+    coverage: bool,
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = MatrixFormat::Tsv,
+        help = "Output format"
+    )]
+    output_format: MatrixFormat,
+    #[arg(
+        long = "na-value",
+        value_name = "STRING",
+        default_value = "NA",
+        help = "Written for targets without coverage in a sample (tsv output)"
+    )]
+    na_value: String,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(long = "no-names", help = "Leave the BED names of the targets out")]
+    no_names: bool,
+    #[arg(
+        long = "progress",
+        help = "Report the bytes of each sample parsed on stderr"
+    )]
+    progress: bool,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MatrixFormat {
+    /// One fraction column (and optionally a coverage column) per sample.
+    Tsv,
+    /// `n_sites`, coverage and fraction columns per sample.
+    Parquet,
+}
+
+/// Targets of each chromosome sorted by start, with the longest target length
+/// bounding the search for the targets overlapping a record.
+pub struct TargetIndex {
+    by_chrom: HashMap<String, (Vec<usize>, i32)>,
+}
+
+impl TargetIndex {
+    pub fn new(targets: &[TargetInterval]) -> TargetIndex {
+        let mut by_chrom: HashMap<String, (Vec<usize>, i32)> = HashMap::new();
+        for (i, target) in targets.iter().enumerate() {
+            let entry = by_chrom.entry(target.chrom.clone()).or_default();
+            entry.0.push(i);
+            entry.1 = entry.1.max(target.end - target.start);
+        }
+        for (order, _) in by_chrom.values_mut() {
+            order.sort_by_key(|&i| targets[i].start);
+        }
+        TargetIndex { by_chrom }
+    }
+
+    /// Indices of the targets overlapping `iv` on `chrom`.
+    fn overlapping<'a>(
+        &'a self,
+        targets: &'a [TargetInterval],
+        chrom: &str,
+        iv: &'a MethInterval,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let (order, longest): (&[usize], i32) = match self.by_chrom.get(chrom) {
+            Some((order, longest)) => (order, *longest),
+            None => (&[], 0),
+        };
+        let first = order.partition_point(|&i| targets[i].start <= iv.start - longest);
+        order[first..]
+            .iter()
+            .copied()
+            .take_while(move |&i| targets[i].start < iv.end)
+            .filter(move |&i| targets[i].end > iv.start)
+    }
+}
+
+/// Streams one sample, adding every record to the summaries of the targets it
+/// overlaps.
+fn summarize_sample(
+    spec: &SampleSpec,
+    layout: &Layout,
+    filter: &RecordFilter,
+    targets: &[TargetInterval],
+    index: &TargetIndex,
+) -> Result<Vec<TargetSummary>, Box<dyn Error>> {
+    let aggregation = Aggregation::default();
+    let mut summaries = vec![TargetSummary::default(); targets.len()];
+    let mut weights = vec![0_f32; targets.len()];
+    let progress = progress::Progress::bytes(&spec.path, None);
+    let mut reader = compression::open(&spec.path)?;
+    let mut line = String::new();
+    let mut linenum = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        linenum += 1;
+        progress.add(line.len() as u64);
+        let record = parse_record(&line, layout, filter)
+            .map_err(|kind| kind.at(&spec.path, Some(linenum)))?;
+        let Some((chrom, iv)) = record else {
+            continue;
+        };
+        for i in index.overlapping(targets, chrom, &iv) {
+            let weight = aggregation.weight(&iv, &targets[i]);
+            let summary = &mut summaries[i];
+            summary.num_positions += 1;
+            summary.sum_total_coverage += iv.coverage;
+            summary.sum_methylated += iv.fraction * iv.coverage as f32;
+            // The weighted sum is kept in `weighted_fraction` until the end.
+            summary.weighted_fraction += iv.fraction * weight;
+            weights[i] += weight;
+        }
+    }
+    for (summary, weight) in summaries.iter_mut().zip(weights) {
+        summary.weighted_fraction = if weight > 0.0 {
+            summary.weighted_fraction / weight
+        } else {
+            0.0
+        };
+    }
+    Ok(summaries)
+}
+
+/// Resolves the format of a manifest sample and streams it.
+pub fn summarize_spec(
+    spec: &SampleSpec,
+    filter: &RecordFilter,
+    targets: &[TargetInterval],
+    index: &TargetIndex,
+) -> Result<Vec<TargetSummary>, Box<dyn Error>> {
+    if !is_stream(&spec.path) && bam::sniff(&spec.path)?.is_some() {
+        return Err(format!(
+            "Error: {}: methfast matrix reads methylation files, not alignments",
+            spec.path.display()
+        )
+        .into());
+    }
+    let format = match spec.format {
+        Format::Auto => detect_format(&spec.path)?,
+        format => format,
+    };
+    summarize_sample(spec, &format.layout(), filter, targets, index)
+}
+
+pub fn run(args: &MatrixArgs) -> Result<(), Box<dyn Error>> {
+    if args.progress {
+        progress::enable();
+    }
+    let specs = samples::read_manifest(&args.samples, args.format)?;
+    let mut targets = parse_targets(&args.targets, !args.no_names, false)?;
+    let named = targets.iter().any(|target| target.name.is_some());
+    if named {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+    let index = TargetIndex::new(&targets);
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+
+    let columns = specs
+        .par_iter()
+        .map(|spec| summarize_spec(spec, &filter, &targets, &index).map_err(ThreadError::from))
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+    let rows: Vec<Vec<TargetSummary>> = (0..targets.len())
+        .map(|i| columns.iter().map(|column| column[i]).collect())
+        .collect();
+
+    match args.output_format {
+        MatrixFormat::Parquet => {
+            let header = header_line(Some(&specs), named, false, &crate::cli::SUMMARY_FIELDS);
+            write_parquet(args.output.as_deref(), &header, named, &targets, &rows)
+        }
+        MatrixFormat::Tsv => {
+            let fields: &[Field] = if args.coverage {
+                &[Field::Fraction, Field::Coverage]
+            } else {
+                &[Field::Fraction]
+            };
+            let fractions = FractionFormat {
+                precision: args.precision,
+                na_value: Some(args.na_value.clone()),
+                ..FractionFormat::default()
+            };
+            let lines: Vec<String> = targets
+                .iter()
+                .zip(&rows)
+                .map(|(target, row)| format_row(target, row, fields, &fractions))
+                .collect();
+            let header = header_line(Some(&specs), named, false, fields);
+            write_lines(args.output.as_deref(), Some(&header), &lines)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_samples_into_overlapping_targets() {
+        let dir = std::env::temp_dir().join(format!("methfast-matrix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = dir.join("s1.bed");
+        std::fs::write(
+            &sample,
+            "chr1\t10\t11\t1.0\t3\nchr1\t150\t151\t0.5\t2\nchr1\t160\t161\t0.0\t2\n",
+        )
+        .unwrap();
+        let targets = vec![
+            TargetInterval {
+                chrom: "chr1".to_string(),
+                start: 100,
+                end: 200,
+                name: None,
+                strand: crate::Strand::Unknown,
+                extra: Vec::new(),
+            },
+            TargetInterval {
+                chrom: "chr1".to_string(),
+                start: 0,
+                end: 155,
+                name: None,
+                strand: crate::Strand::Unknown,
+                extra: Vec::new(),
+            },
+        ];
+        let spec = SampleSpec::from_path(&sample, Format::Generic);
+        let summaries = summarize_sample(
+            &spec,
+            &Format::Generic.layout(),
+            &RecordFilter::default(),
+            &targets,
+            &TargetIndex::new(&targets),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            (summaries[0].num_positions, summaries[0].weighted_fraction),
+            (2, 0.25)
+        );
+        assert_eq!(
+            (summaries[1].num_positions, summaries[1].weighted_fraction),
+            (2, 0.8)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! `--max-memory`: a budget for the methylation records held in memory. The
+//! records of the inputs are estimated from their size before loading, and
+//! the records actually stored are counted against the budget while parsing.
+
+use flate2::read::MultiGzDecoder;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::progress::human_bytes;
+
+static LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+static USED: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes sampled from the start of an input to estimate its records.
+const SAMPLE_BYTES: u64 = 1 << 20;
+
+/// Records counted before they are charged to the shared budget.
+const CHARGE_EVERY: u64 = 1 << 16;
+
+/// Parses a size like `8G`, `512M`, `1.5GiB` or `1000000` (bytes), in binary units.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size {value}; expected e.g. 8G or 512M");
+    let upper = value.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches('B').trim_end_matches('I');
+    let (number, shift) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 10),
+        Some('M') => (&number[..number.len() - 1], 20),
+        Some('G') => (&number[..number.len() - 1], 30),
+        Some('T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(size) if size > 0.0 && size.is_finite() => Ok((size * (1u64 << shift) as f64) as u64),
+        _ => Err(invalid()),
+    }
+}
+
+/// Sets the budget for the rest of the run.
+pub fn set_limit(bytes: u64) {
+    LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+fn charge(bytes: u64) -> Result<(), Box<dyn Error>> {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let used = USED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    if used > limit {
+        return Err(format!(
+            "Error: the parsed methylation records exceed --max-memory {}; \
+             use --streaming or --compact, bgzip and tabix-index the inputs, or raise the limit",
+            human_bytes(limit)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Counts the records stored while parsing one input against the budget.
+pub struct Meter {
+    record_bytes: u64,
+    pending: u64,
+}
+
+impl Meter {
+    /// A meter for records taking `record_bytes` each.
+    pub fn new(record_bytes: usize) -> Meter {
+        Meter {
+            record_bytes: record_bytes as u64,
+            pending: 0,
+        }
+    }
+
+    /// Counts one more stored record; an error once the budget is exceeded.
+    pub fn record(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending += 1;
+        if self.pending == CHARGE_EVERY {
+            self.pending = 0;
+            charge(CHARGE_EVERY * self.record_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The memory the records of the methylation file at `path` would take at
+/// `record_bytes` each, from its size and the length of its first lines.
+/// gzip and bgzip inputs are scaled by the compression ratio of their start;
+/// `None` for inputs of unknown size, zstd and xz.
+pub fn estimate(path: &Path, record_bytes: usize) -> Result<Option<u64>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut start = Vec::new();
+    file.take(SAMPLE_BYTES).read_to_end(&mut start)?;
+    let (text, ratio) = if start.starts_with(&[0x1F, 0x8B]) {
+        let mut text = Vec::new();
+        // The sample ends mid-stream, so the decoder stops there with an error.
+        let _ = MultiGzDecoder::new(&start[..]).read_to_end(&mut text);
+        let ratio = text.len() as f64 / start.len().max(1) as f64;
+        (text, ratio)
+    } else if start.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) || start.starts_with(b"\xFD7zXZ") {
+        return Ok(None);
+    } else {
+        (start, 1.0)
+    };
+    let lines = text.iter().filter(|&&byte| byte == b'\n').count();
+    if lines == 0 {
+        return Ok(Some(0));
+    }
+    let records = size as f64 * ratio * lines as f64 / text.len() as f64;
+    Ok(Some((records * record_bytes as f64) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_and_estimates_records() {
+        assert_eq!(parse_size("8G"), Ok(8 << 30));
+        assert_eq!(parse_size("512m"), Ok(512 << 20));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("0").is_err());
+
+        let path = std::env::temp_dir().join(format!("methfast-memory-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t0.5\t4\n".repeat(1000)).unwrap();
+        let estimate = estimate(&path, 20).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(estimate, Some(20_000));
+    }
+}
@@ -0,0 +1,160 @@
+//! `methfast merge`: pools replicate per-CpG files by position.
+
+use crate::parallel::*;
+use clap::Args;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::write_lines;
+use crate::error::ThreadError;
+use crate::format::{ColumnNames, Format};
+use crate::{MethInterval, RecordFilter, detect_format, parse_meth_bed};
+
+#[derive(Args, Debug)]
+pub struct MergeArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        num_args = 2..,
+        required = true,
+        help = "Per-CpG inputs to pool"
+    )]
+    inputs: Vec<PathBuf>,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format (detected per file by default)"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore input records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "min-samples",
+        value_name = "N",
+        default_value_t = 1,
+        help = "Only write positions present in at least N inputs"
+    )]
+    min_samples: usize,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// A pooled position: summed coverage and methylated reads, and the number of
+/// inputs holding it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Pooled {
+    coverage: i32,
+    methylated: f64,
+    samples: usize,
+}
+
+/// Pools the records of every input by `(start, end)`, in position order.
+fn pool(inputs: &[&[MethInterval]]) -> BTreeMap<(i32, i32), Pooled> {
+    let mut pooled: BTreeMap<(i32, i32), Pooled> = BTreeMap::new();
+    for intervals in inputs {
+        for iv in intervals.iter().filter(|iv| iv.coverage > 0) {
+            let entry = pooled.entry((iv.start, iv.end)).or_default();
+            entry.coverage += iv.coverage;
+            entry.methylated += iv.fraction as f64 * iv.coverage as f64;
+            entry.samples += 1;
+        }
+    }
+    pooled
+}
+
+pub fn run(args: &MergeArgs) -> Result<(), Box<dyn Error>> {
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let samples = args
+        .inputs
+        .par_iter()
+        .map(|path| -> Result<_, ThreadError> {
+            let format = match args.format {
+                Format::Auto => detect_format(path).map_err(ThreadError::from)?,
+                format => format,
+            };
+            let mut ranges = parse_meth_bed(
+                path,
+                &format.layout(),
+                &ColumnNames::default(),
+                &filter,
+                &[None],
+            )
+            .map_err(ThreadError::from)?;
+            Ok(ranges.remove(0))
+        })
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+
+    let mut chroms: Vec<&String> = samples
+        .iter()
+        .flat_map(|sample| sample.by_chrom.keys())
+        .collect();
+    chroms.sort();
+    chroms.dedup();
+    let merged: HashMap<&String, BTreeMap<(i32, i32), Pooled>> = chroms
+        .par_iter()
+        .map(|&chrom| {
+            let inputs: Vec<&[MethInterval]> = samples
+                .iter()
+                .filter_map(|sample| sample.by_chrom.get(chrom).map(Vec::as_slice))
+                .collect();
+            (chrom, pool(&inputs))
+        })
+        .collect();
+
+    let mut lines = Vec::new();
+    for chrom in &chroms {
+        for (&(start, end), pooled) in &merged[chrom] {
+            if pooled.samples < args.min_samples {
+                continue;
+            }
+            let fraction = pooled.methylated / pooled.coverage as f64;
+            lines.push(format!(
+                "{chrom}\t{start}\t{end}\t{fraction:.*}\t{}",
+                args.precision, pooled.coverage
+            ));
+        }
+    }
+    write_lines(args.output.as_deref(), None, &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn sums_counts_by_position() {
+        let site = |start, fraction, coverage| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage,
+            strand: Strand::Unknown,
+        };
+        let first = vec![site(10, 1.0, 2), site(20, 0.5, 4)];
+        let second = vec![site(10, 0.0, 2), site(30, 0.25, 4), site(40, 0.0, 0)];
+        let pooled = pool(&[&first, &second]);
+        assert_eq!(pooled.len(), 3);
+        let merged = pooled[&(10, 11)];
+        assert_eq!(
+            (merged.coverage, merged.methylated, merged.samples),
+            (4, 2.0, 2)
+        );
+        assert_eq!(pooled[&(30, 31)].samples, 1);
+    }
+}
@@ -0,0 +1,402 @@
+//! `methfast index`: a binary copy of the parsed records of a methylation file
+//! (`<input>.mfi`), loaded by the main command instead of re-parsing the text.
+//!
+//! Layout, little-endian: the magic `MFI\x01`; the size and modification time
+//! (seconds) of the indexed file; the format name and the `--mod-code` it was
+//! parsed with, each as a `u8` length and bytes; the chromosome count (`u32`)
+//! and per chromosome its name (`u16` length and bytes), record count and
+//! byte offset (both `u64`); then the records of each chromosome, 17 bytes
+//! each: start, end (`i32`), fraction (`f32`), coverage (`i32`) and strand
+//! (`u8`: 0 unknown, 1 plus, 2 minus).
+
+use clap::Args;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::format::{ColumnNames, Format};
+use crate::{MethInterval, MethRanges, RecordFilter, Strand, detect_format, parse_meth_bed};
+
+const MAGIC: &[u8; 4] = b"MFI\x01";
+const RECORD_BYTES: usize = 17;
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    #[arg(value_name = "METHYLATION_BED", help = "Methylation file to index")]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "mod-code",
+        value_name = "CODE",
+        help = "Only index bedMethyl records with this modification code"
+    )]
+    mod_code: Option<String>,
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "Index path (default: <METHYLATION_BED>.mfi)"
+    )]
+    output: Option<PathBuf>,
+}
+
+/// Where the main command looks for the index of `path`.
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".mfi");
+    PathBuf::from(name)
+}
+
+/// Size and modification time of `path`, which an index must match to be used.
+fn source_stamp(path: &Path) -> Result<(u64, u64), Box<dyn Error>> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok((metadata.len(), modified))
+}
+
+fn write_name(out: &mut impl Write, name: &str) -> Result<(), Box<dyn Error>> {
+    let length = u8::try_from(name.len()).map_err(|_| format!("name too long: {name}"))?;
+    out.write_all(&[length])?;
+    out.write_all(name.as_bytes())?;
+    Ok(())
+}
+
+fn read_bytes(input: &mut impl Read, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = vec![0; length];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8_name(input: &mut impl Read) -> Result<String, Box<dyn Error>> {
+    let length = read_bytes(input, 1)?[0] as usize;
+    Ok(String::from_utf8(read_bytes(input, length)?)?)
+}
+
+fn read_u64(input: &mut impl Read) -> Result<u64, Box<dyn Error>> {
+    Ok(u64::from_le_bytes(
+        read_bytes(input, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Writes `ranges`, parsed as `format` with `mod_code` from a file with
+/// size and modification time `stamp`, to `out`.
+fn write_index(
+    out: &mut impl Write,
+    stamp: (u64, u64),
+    format: Format,
+    mod_code: Option<&str>,
+    ranges: &MethRanges,
+) -> Result<(), Box<dyn Error>> {
+    out.write_all(MAGIC)?;
+    out.write_all(&stamp.0.to_le_bytes())?;
+    out.write_all(&stamp.1.to_le_bytes())?;
+    write_name(out, format.name())?;
+    write_name(out, mod_code.unwrap_or(""))?;
+
+    let mut chroms: Vec<&String> = ranges.by_chrom.keys().collect();
+    chroms.sort();
+    let directory: usize = chroms.iter().map(|chrom| 2 + chrom.len() + 16).sum();
+    let header = MAGIC.len() + 16 + 2 + format.name().len() + mod_code.map_or(0, str::len) + 4;
+    out.write_all(&(chroms.len() as u32).to_le_bytes())?;
+    let mut offset = (header + directory) as u64;
+    for chrom in &chroms {
+        let length = u16::try_from(chrom.len()).map_err(|_| format!("name too long: {chrom}"))?;
+        let count = ranges.by_chrom[*chrom].len() as u64;
+        out.write_all(&length.to_le_bytes())?;
+        out.write_all(chrom.as_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+        out.write_all(&offset.to_le_bytes())?;
+        offset += count * RECORD_BYTES as u64;
+    }
+    for chrom in &chroms {
+        for iv in &ranges.by_chrom[*chrom] {
+            out.write_all(&iv.start.to_le_bytes())?;
+            out.write_all(&iv.end.to_le_bytes())?;
+            out.write_all(&iv.fraction.to_le_bytes())?;
+            out.write_all(&iv.coverage.to_le_bytes())?;
+            out.write_all(&[match iv.strand {
+                Strand::Unknown => 0,
+                Strand::Plus => 1,
+                Strand::Minus => 2,
+            }])?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the records of an index written by [`write_index`].
+fn read_index(
+    input: &mut (impl Read + Seek),
+    stamp: (u64, u64),
+    format: Format,
+    mod_code: Option<&str>,
+    filter: &RecordFilter,
+) -> Result<Option<MethRanges>, Box<dyn Error>> {
+    if read_bytes(input, 4)? != MAGIC {
+        return Err("unrecognized index format".into());
+    }
+    let indexed = (read_u64(input)?, read_u64(input)?);
+    let indexed_format = read_u8_name(input)?;
+    let indexed_mod_code = read_u8_name(input)?;
+    if indexed != stamp
+        || indexed_format != format.name()
+        || indexed_mod_code != mod_code.unwrap_or("")
+    {
+        return Ok(None);
+    }
+
+    let n_chroms = u32::from_le_bytes(read_bytes(input, 4)?.try_into().unwrap());
+    let mut directory = Vec::with_capacity(n_chroms as usize);
+    for _ in 0..n_chroms {
+        let length = u16::from_le_bytes(read_bytes(input, 2)?.try_into().unwrap());
+        let chrom = String::from_utf8(read_bytes(input, length as usize)?)?;
+        directory.push((chrom, read_u64(input)?, read_u64(input)?));
+    }
+    let mut by_chrom = HashMap::new();
+    for (chrom, count, offset) in directory {
+        if !filter.keeps_chrom(&chrom) {
+            continue;
+        }
+        input.seek(SeekFrom::Start(offset))?;
+        let bytes = read_bytes(input, count as usize * RECORD_BYTES)?;
+        let field =
+            |record: &[u8], at: usize| -> [u8; 4] { record[at..at + 4].try_into().unwrap() };
+        let intervals: Vec<MethInterval> = bytes
+            .chunks_exact(RECORD_BYTES)
+            .filter_map(|record| {
+                let mut iv = MethInterval {
+                    start: i32::from_le_bytes(field(record, 0)),
+                    end: i32::from_le_bytes(field(record, 4)),
+                    fraction: f32::from_le_bytes(field(record, 8)),
+                    coverage: i32::from_le_bytes(field(record, 12)),
+                    strand: match record[16] {
+                        1 => Strand::Plus,
+                        2 => Strand::Minus,
+                        _ => Strand::Unknown,
+                    },
+                };
+                let masked = filter
+                    .blacklist
+                    .as_ref()
+                    .is_some_and(|blacklist| blacklist.masks(&chrom, &iv));
+                (!masked && filter.apply(&mut iv)).then_some(iv)
+            })
+            .collect();
+        by_chrom.insert(chrom, intervals);
+    }
+    Ok(Some(MethRanges { by_chrom }))
+}
+
+/// Reads the index at `index` of the file at `path`, or `None` when it
+/// does not match the file or `format` and `filter`.
+fn open_index(
+    index: &Path,
+    path: &Path,
+    format: Format,
+    filter: &RecordFilter,
+) -> Result<Option<MethRanges>, Box<dyn Error>> {
+    let mut input = BufReader::new(File::open(index)?);
+    Ok(read_index(
+        &mut input,
+        source_stamp(path)?,
+        format,
+        filter.mod_code.as_deref(),
+        filter,
+    )
+    .map_err(|err| format!("Error: {}: {err}", index.display()))?)
+}
+
+/// Loads the `.mfi` index of `path` when one exists, was built from the
+/// current file with the same format and `--mod-code`, applying the coverage
+/// thresholds and blacklist of `filter`. A stale index is reported and skipped.
+pub fn load(
+    path: &Path,
+    format: Format,
+    filter: &RecordFilter,
+) -> Result<Option<MethRanges>, Box<dyn Error>> {
+    let index = index_path(path);
+    if !index.exists() {
+        return Ok(None);
+    }
+    let ranges = open_index(&index, path, format, filter)?;
+    if ranges.is_none() {
+        eprintln!(
+            "{}: index does not match the file or its options; re-parsing (rerun `methfast index`)",
+            index.display()
+        );
+    }
+    Ok(ranges)
+}
+
+/// `--cache`: loads the index of `path`, writing it first when it is missing or
+/// stale. `None` when the index cannot be written, with a note on stderr.
+pub fn load_cached(
+    path: &Path,
+    format: Format,
+    filter: &RecordFilter,
+) -> Result<Option<MethRanges>, Box<dyn Error>> {
+    let index = index_path(path);
+    if index.exists()
+        && let Some(ranges) = open_index(&index, path, format, filter)?
+    {
+        return Ok(Some(ranges));
+    }
+    let all = RecordFilter {
+        mod_code: filter.mod_code.clone(),
+        sort: filter.sort,
+        ..RecordFilter::default()
+    };
+    let ranges = parse_meth_bed(
+        path,
+        &format.layout(),
+        &ColumnNames::default(),
+        &all,
+        &[None],
+    )?
+    .remove(0);
+    // Written under a temporary name, so concurrent runs never read half an index.
+    let mut partial = index.clone().into_os_string();
+    partial.push(format!(".{}.tmp", std::process::id()));
+    let partial = PathBuf::from(partial);
+    let written = build(&partial, path, format, filter.mod_code.as_deref(), &ranges)
+        .and_then(|()| Ok(std::fs::rename(&partial, &index)?));
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&partial);
+        eprintln!(
+            "{}: could not write the cache ({err}); parsing without it",
+            index.display()
+        );
+        return Ok(None);
+    }
+    open_index(&index, path, format, filter)
+}
+
+/// Writes the index of `ranges`, parsed from the file at `path`, to `output`.
+fn build(
+    output: &Path,
+    path: &Path,
+    format: Format,
+    mod_code: Option<&str>,
+    ranges: &MethRanges,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = BufWriter::new(File::create(output)?);
+    write_index(&mut out, source_stamp(path)?, format, mod_code, ranges)?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn run(args: &IndexArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        mod_code: args.mod_code.clone(),
+        ..RecordFilter::default()
+    };
+    let ranges = parse_meth_bed(
+        &args.input,
+        &format.layout(),
+        &ColumnNames::default(),
+        &filter,
+        &[None],
+    )?
+    .remove(0);
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| index_path(&args.input));
+    build(
+        &output,
+        &args.input,
+        format,
+        args.mod_code.as_deref(),
+        &ranges,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips_records_and_rejects_stale_indexes() {
+        let iv = |start, fraction, coverage, strand| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage,
+            strand,
+        };
+        let ranges = MethRanges {
+            by_chrom: HashMap::from([
+                (
+                    "chr1".to_string(),
+                    vec![iv(10, 0.5, 4, Strand::Plus), iv(20, 1.0, 1, Strand::Minus)],
+                ),
+                ("chr2".to_string(), vec![iv(5, 0.25, 8, Strand::Unknown)]),
+            ]),
+        };
+        let mut bytes = Vec::new();
+        write_index(&mut bytes, (100, 7), Format::Generic, None, &ranges).unwrap();
+        let filter = RecordFilter {
+            min_coverage: Some(2),
+            ..RecordFilter::default()
+        };
+        let read = |stamp, mod_code| {
+            read_index(
+                &mut Cursor::new(&bytes),
+                stamp,
+                Format::Generic,
+                mod_code,
+                &filter,
+            )
+            .unwrap()
+        };
+
+        let loaded = read((100, 7), None).unwrap();
+        let chr1 = &loaded.by_chrom["chr1"];
+        assert_eq!(chr1.len(), 1);
+        assert_eq!((chr1[0].start, chr1[0].fraction), (10, 0.5));
+        assert_eq!(chr1[0].strand, Strand::Plus);
+        assert_eq!(loaded.by_chrom["chr2"][0].coverage, 8);
+        assert!(read((100, 8), None).is_none());
+        assert!(read((100, 7), Some("m")).is_none());
+    }
+
+    #[test]
+    fn caches_parsed_records_next_to_the_file() {
+        let path = std::env::temp_dir().join(format!("methfast-cache-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t0.5\t4\nchr2\t5\t6\t1.0\t1\n").unwrap();
+        let filter = RecordFilter {
+            min_coverage: Some(2),
+            ..RecordFilter::default()
+        };
+        let first = load_cached(&path, Format::Generic, &filter)
+            .unwrap()
+            .unwrap();
+        assert!(index_path(&path).exists());
+        // The cache holds every record; the filter applies on loading.
+        let all = load(&path, Format::Generic, &RecordFilter::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(all.by_chrom["chr2"].len(), 1);
+        let again = load_cached(&path, Format::Generic, &filter)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.by_chrom["chr1"].len(), 1);
+        assert!(again.by_chrom["chr2"].is_empty());
+        std::fs::remove_file(index_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+//! Read-only memory maps of plain-text inputs, so they are parsed in place
+//! rather than copied line by line.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub struct Mmap {
+        ptr: *const u8,
+        len: usize,
+    }
+
+    // The mapping is private and read-only, and unmapped only on drop.
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        pub fn map(file: &File, len: usize) -> io::Result<Mmap> {
+            if len == 0 {
+                return Ok(Mmap {
+                    ptr: std::ptr::NonNull::dangling().as_ptr(),
+                    len,
+                });
+            }
+            // SAFETY: a fresh read-only mapping of `len` bytes of an open file.
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mmap {
+                ptr: ptr as *const u8,
+                len,
+            })
+        }
+
+        pub fn bytes(&self) -> &[u8] {
+            // SAFETY: `ptr` maps `len` readable bytes for the lifetime of `self`.
+            unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                // SAFETY: unmaps the mapping created in `map`.
+                unsafe {
+                    libc::munmap(self.ptr as *mut libc::c_void, self.len);
+                }
+            }
+        }
+    }
+}
+
+/// Elsewhere the file is read into memory instead.
+#[cfg(not(unix))]
+mod imp {
+    use std::fs::File;
+    use std::io::{self, Read};
+
+    pub struct Mmap(Vec<u8>);
+
+    impl Mmap {
+        pub fn map(mut file: &File, len: usize) -> io::Result<Mmap> {
+            let mut bytes = Vec::with_capacity(len);
+            file.read_to_end(&mut bytes)?;
+            Ok(Mmap(bytes))
+        }
+
+        pub fn bytes(&self) -> &[u8] {
+            &self.0
+        }
+    }
+}
+
+/// The bytes of a file mapped read-only. A file truncated by another
+/// process while mapped makes further reads fault, as with any mmap.
+pub struct Mmap(imp::Mmap);
+
+impl Mmap {
+    pub fn map(file: &File) -> io::Result<Mmap> {
+        let len = usize::try_from(file.metadata()?.len()).map_err(io::Error::other)?;
+        imp::Mmap::map(file, len).map(Mmap)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.bytes()
+    }
+}
@@ -0,0 +1,188 @@
+//! `methfast nearest`: the closest covered records up- and downstream of each
+//! target, to tell targets lacking coverage from CpG-free ones.
+
+use clap::Args;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::{format_target, write_lines};
+use crate::format::{ColumnNames, Format};
+use crate::{
+    MethInterval, RecordFilter, Strand, TargetInterval, detect_format, meth_index, parse_meth_bed,
+    parse_targets,
+};
+
+#[derive(Args, Debug)]
+pub struct NearestArgs {
+    #[arg(value_name = "METHYLATION_BED", help = "Methylation input")]
+    input: PathBuf,
+    #[arg(value_name = "TARGET_BED", help = "Targets to annotate")]
+    targets: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "empty-only",
+        help = "Only write targets without overlapping records"
+    )]
+    empty_only: bool,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// Records overlapping a target and the nearest ones on either side, with
+/// their distance in bases (0 when book-ended).
+struct Neighbours<'a> {
+    overlapping: usize,
+    upstream: Option<(i32, &'a MethInterval)>,
+    downstream: Option<(i32, &'a MethInterval)>,
+}
+
+/// Looks `target` up in the sorted, non-overlapping records of its
+/// chromosome; up- and downstream follow the strand of the target.
+fn neighbours<'a>(intervals: &'a [MethInterval], target: &TargetInterval) -> Neighbours<'a> {
+    let before = intervals.partition_point(|iv| iv.end <= target.start);
+    let after = intervals.partition_point(|iv| iv.start < target.end);
+    let left = before
+        .checked_sub(1)
+        .map(|i| (target.start - intervals[i].end, &intervals[i]));
+    let right = intervals.get(after).map(|iv| (iv.start - target.end, iv));
+    let (upstream, downstream) = match target.strand {
+        Strand::Minus => (right, left),
+        _ => (left, right),
+    };
+    Neighbours {
+        overlapping: after.saturating_sub(before),
+        upstream,
+        downstream,
+    }
+}
+
+/// `distance, fraction, coverage` of a neighbour, `NA` when there is none.
+fn format_neighbour(neighbour: Option<(i32, &MethInterval)>, precision: usize) -> String {
+    match neighbour {
+        Some((distance, iv)) => format!(
+            "{distance}\t{fraction:.precision$}\t{coverage}",
+            fraction = iv.fraction,
+            coverage = iv.coverage
+        ),
+        None => "NA\tNA\tNA".to_string(),
+    }
+}
+
+pub fn run(args: &NearestArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let ranges = match meth_index::load(&args.input, format, &filter)? {
+        Some(ranges) => ranges,
+        None => parse_meth_bed(
+            &args.input,
+            &format.layout(),
+            &ColumnNames::default(),
+            &filter,
+            &[None],
+        )?
+        .remove(0),
+    };
+    let mut targets: Vec<TargetInterval> = parse_targets(&args.targets, true, false)?;
+    let named = targets.iter().any(|target| target.name.is_some());
+    if named {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+
+    let mut header = "chrom\tstart\tend".to_string();
+    if named {
+        header.push_str("\tname");
+    }
+    header.push_str(
+        "\tn_sites\tupstream_distance\tupstream_fraction\tupstream_coverage\
+         \tdownstream_distance\tdownstream_fraction\tdownstream_coverage",
+    );
+    let lines: Vec<String> = targets
+        .iter()
+        .filter_map(|target| {
+            let intervals = ranges
+                .by_chrom
+                .get(&target.chrom)
+                .map_or(&[][..], Vec::as_slice);
+            let found = neighbours(intervals, target);
+            if args.empty_only && found.overlapping > 0 {
+                return None;
+            }
+            Some(format!(
+                "{}\t{}\t{}\t{}",
+                format_target(target),
+                found.overlapping,
+                format_neighbour(found.upstream, args.precision),
+                format_neighbour(found.downstream, args.precision)
+            ))
+        })
+        .collect();
+    write_lines(args.output.as_deref(), Some(&header), &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_closest_records_on_both_sides() {
+        let site = |start, fraction| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage: 3,
+            strand: Strand::Unknown,
+        };
+        let intervals = vec![site(100, 1.0), site(150, 0.5), site(400, 0.0)];
+        let target = |start, end, strand| TargetInterval {
+            chrom: "chr1".to_string(),
+            start,
+            end,
+            name: None,
+            strand,
+            extra: Vec::new(),
+        };
+
+        let at = |neighbour: Option<(i32, &MethInterval)>| neighbour.map(|(d, iv)| (d, iv.start));
+        let empty = neighbours(&intervals, &target(200, 300, Strand::Unknown));
+        assert_eq!(empty.overlapping, 0);
+        assert_eq!(at(empty.upstream), Some((49, 150)));
+        assert_eq!(at(empty.downstream), Some((100, 400)));
+        assert_eq!(format_neighbour(empty.upstream, 2), "49\t0.50\t3");
+
+        let reverse = neighbours(&intervals, &target(200, 300, Strand::Minus));
+        assert_eq!(at(reverse.upstream), Some((100, 400)));
+
+        let covered = neighbours(&intervals, &target(101, 401, Strand::Unknown));
+        assert_eq!(covered.overlapping, 2);
+        assert_eq!(at(covered.upstream), Some((0, 100)));
+        assert!(covered.downstream.is_none());
+        assert_eq!(format_neighbour(covered.downstream, 2), "NA\tNA\tNA");
+    }
+}
@@ -0,0 +1,241 @@
+//! Number parsing and formatting for the text hot paths. Record columns are
+//! parsed by scanning the bytes of plain integers and short decimals, leaving
+//! anything else to `str::parse`; output numbers are written into the line
+//! being built instead of formatted into strings of their own. Both give
+//! exactly what `str::parse` and `format!` would.
+
+use std::fmt::Write;
+
+/// Exact powers of ten as `f32`; 10^10 is the largest one.
+const POWERS_OF_TEN: [f32; 11] = [1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+/// Parses a decimal `i32` with an optional sign, as `str::parse::<i32>`.
+pub fn parse_i32(s: &str) -> Option<i32> {
+    let bytes = s.as_bytes();
+    let (negative, digits) = match bytes {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, bytes),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for &byte in digits {
+        let digit = byte.wrapping_sub(b'0');
+        if digit > 9 {
+            return None;
+        }
+        value = value * 10 + digit as u64;
+        if value > 1 << 31 {
+            return None;
+        }
+    }
+    match negative {
+        true => Some((value as i64).wrapping_neg() as i32),
+        false => i32::try_from(value).ok(),
+    }
+}
+
+/// Parses an `f32` as `str::parse::<f32>`. Decimals like `0.5625` or `87.5`
+/// whose digits fit in 24 bits are one exact division, which rounds as the
+/// full parser does; other inputs go to it.
+pub fn parse_f32(s: &str) -> Option<f32> {
+    fast_f32(s.as_bytes()).or_else(|| s.parse().ok())
+}
+
+fn fast_f32(bytes: &[u8]) -> Option<f32> {
+    let (negative, rest) = match bytes {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, bytes),
+    };
+    let point = rest.iter().position(|&byte| byte == b'.');
+    let (whole, fraction) = match point {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, &[][..]),
+    };
+    if whole.is_empty() || (point.is_some() && fraction.is_empty()) {
+        return None;
+    }
+    if fraction.len() >= POWERS_OF_TEN.len() {
+        return None;
+    }
+    let mut mantissa: u32 = 0;
+    for &byte in whole.iter().chain(fraction) {
+        let digit = byte.wrapping_sub(b'0');
+        if digit > 9 {
+            return None;
+        }
+        mantissa = mantissa * 10 + digit as u32;
+        if mantissa > 1 << 24 {
+            return None;
+        }
+    }
+    let value = mantissa as f32 / POWERS_OF_TEN[fraction.len()];
+    Some(if negative { -value } else { value })
+}
+
+/// Appends the decimal digits of `value`.
+pub fn push_int(out: &mut String, value: impl Into<i64>) {
+    let value: i64 = value.into();
+    if value < 0 {
+        out.push('-');
+    }
+    push_digits(out, value.unsigned_abs() as u128, 0);
+}
+
+/// Appends the digits of `value`, left-padded with zeros to `width`.
+fn push_digits(out: &mut String, mut value: u128, width: usize) {
+    let mut digits = [0u8; 40];
+    let mut i = digits.len();
+    while value > 0 || digits.len() - i < width.max(1) {
+        i -= 1;
+        digits[i] = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+    // The buffer holds ASCII digits only.
+    out.push_str(std::str::from_utf8(&digits[i..]).unwrap());
+}
+
+/// Appends `value` with `precision` decimals, as `{value:.precision$}`.
+///
+/// A finite `f32` is `mantissa * 2^exponent`, so `value * 10^precision` is
+/// rounded exactly in integers, half to even as `format!` does.
+pub fn push_fixed(out: &mut String, value: f32, precision: usize) {
+    if !value.is_finite() || precision >= POWERS_OF_TEN.len() {
+        let _ = write!(out, "{value:.precision$}");
+        return;
+    }
+    let bits = value.to_bits();
+    let biased = ((bits >> 23) & 0xFF) as i32;
+    let (mantissa, exponent) = match biased {
+        0 => ((bits & 0x7F_FFFF) as u128, -149),
+        _ => (((bits & 0x7F_FFFF) | 0x80_0000) as u128, biased - 150),
+    };
+    if exponent > 64 {
+        let _ = write!(out, "{value:.precision$}");
+        return;
+    }
+    let scale = 10u128.pow(precision as u32);
+    let scaled = if exponent >= 0 {
+        (mantissa << exponent) * scale
+    } else {
+        let shift = -exponent as u32;
+        let numerator = mantissa * scale;
+        if shift >= 64 {
+            // Below 2^-40 and a numerator under 2^54: rounds to zero.
+            0
+        } else {
+            let quotient = numerator >> shift;
+            let remainder = numerator & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            match remainder.cmp(&half) {
+                std::cmp::Ordering::Greater => quotient + 1,
+                std::cmp::Ordering::Equal => quotient + (quotient & 1),
+                std::cmp::Ordering::Less => quotient,
+            }
+        }
+    };
+    if value.is_sign_negative() {
+        out.push('-');
+    }
+    push_digits(out, scaled / scale, 0);
+    if precision > 0 {
+        out.push('.');
+        push_digits(out, scaled % scale, precision);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_str_parse() {
+        let inputs = [
+            "0",
+            "-0",
+            "+17",
+            "42",
+            "0012",
+            "2147483647",
+            "2147483648",
+            "-2147483648",
+            "-2147483649",
+            "99999999999",
+            "",
+            "-",
+            "+",
+            "1.5",
+            "0.569",
+            "87.50",
+            ".5",
+            "5.",
+            ".",
+            "-0.0",
+            "16777216",
+            "16777217",
+            "0.1234567891",
+            "0.12345678912",
+            "1e3",
+            "inf",
+            "NaN",
+            "1,5",
+            " 1",
+            "0x10",
+        ];
+        for input in inputs {
+            assert_eq!(parse_i32(input), input.parse().ok(), "{input:?}");
+            let expected: Option<f32> = input.parse().ok();
+            assert_eq!(
+                parse_f32(input).map(f32::to_bits),
+                expected.map(f32::to_bits),
+                "{input:?}"
+            );
+        }
+        for thousandths in 0..=100_000 {
+            let input = format!("{}.{:03}", thousandths / 1000, thousandths % 1000);
+            assert_eq!(parse_f32(&input), input.parse().ok(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn formats_as_format_does() {
+        let mut values = vec![
+            0.0f32,
+            -0.0,
+            0.25,
+            0.125,
+            0.5,
+            1.5,
+            2.5,
+            0.375,
+            1.0 / 3.0,
+            0.99995,
+            0.999_999_9,
+            99.99995,
+            16_777_216.0,
+            3.4e38,
+            -7.25,
+            1e-30,
+            f32::MIN_POSITIVE / 3.0,
+            f32::NAN,
+            f32::INFINITY,
+        ];
+        values.extend((0..=20_000).map(|i| i as f32 / 20_000.0));
+        values.extend((0..=20_000).map(|i| i as f32 / 200.0));
+        for value in values {
+            for precision in [0, 1, 2, 4, 6, 9, 12] {
+                let mut out = String::new();
+                push_fixed(&mut out, value, precision);
+                assert_eq!(out, format!("{value:.precision$}"), "{value} {precision}");
+            }
+        }
+        for value in [0, 7, -7, 10, 1_000_000, i32::MIN as i64, i64::MIN, i64::MAX] {
+            let mut out = String::new();
+            push_int(&mut out, value);
+            assert_eq!(out, value.to_string());
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! The data parallelism used across the crate: rayon with the default
+//! `parallel` feature, or the same calls run one after another without it,
+//! for targets without threads such as `wasm32-unknown-unknown`.
+
+#[cfg(feature = "parallel")]
+pub use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+pub use rayon::{current_num_threads, join};
+
+#[cfg(not(feature = "parallel"))]
+pub use sequential::*;
+
+/// Sets the number of worker threads of the global pool.
+#[cfg(feature = "parallel")]
+pub fn set_threads(threads: usize) {
+    let _ = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global();
+}
+
+/// Sets the number of worker threads of the global pool.
+#[cfg(not(feature = "parallel"))]
+pub fn set_threads(_threads: usize) {}
+
+#[cfg(not(feature = "parallel"))]
+mod sequential {
+    /// `par_iter()` as `iter()`.
+    pub trait IntoParallelRefIterator<'a> {
+        type Iter: Iterator;
+        fn par_iter(&'a self) -> Sequential<Self::Iter>;
+    }
+
+    impl<'a, T: 'a + ?Sized> IntoParallelRefIterator<'a> for T
+    where
+        &'a T: IntoIterator,
+    {
+        type Iter = <&'a T as IntoIterator>::IntoIter;
+
+        fn par_iter(&'a self) -> Sequential<Self::Iter> {
+            Sequential(self.into_iter())
+        }
+    }
+
+    /// An iterator standing in for a rayon one: an `Iterator`, plus rayon's
+    /// `fold` and `reduce`, which take closures making the initial value.
+    pub struct Sequential<I>(I);
+
+    impl<I: Iterator> Iterator for Sequential<I> {
+        type Item = I::Item;
+
+        fn next(&mut self) -> Option<I::Item> {
+            self.0.next()
+        }
+    }
+
+    impl<I: Iterator> Sequential<I> {
+        pub fn fold<T>(
+            self,
+            identity: impl Fn() -> T,
+            fold: impl FnMut(T, I::Item) -> T,
+        ) -> Sequential<std::iter::Once<T>> {
+            Sequential(std::iter::once(self.0.fold(identity(), fold)))
+        }
+
+        pub fn reduce(
+            self,
+            identity: impl Fn() -> I::Item,
+            reduce: impl FnMut(I::Item, I::Item) -> I::Item,
+        ) -> I::Item {
+            self.0.fold(identity(), reduce)
+        }
+    }
+
+    /// rayon's `map_init`, with a single state for the whole iterator.
+    pub trait MapInit: Iterator + Sized {
+        fn map_init<T, R>(
+            self,
+            init: impl Fn() -> T,
+            map: impl Fn(&mut T, Self::Item) -> R,
+        ) -> impl Iterator<Item = R> {
+            let mut state = init();
+            self.map(move |item| map(&mut state, item))
+        }
+    }
+
+    impl<I: Iterator> MapInit for I {}
+
+    pub fn current_num_threads() -> usize {
+        1
+    }
+
+    pub fn join<A: FnOnce() -> RA, B: FnOnce() -> RB, RA, RB>(a: A, b: B) -> (RA, RB) {
+        (a(), b())
+    }
+}
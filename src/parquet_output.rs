@@ -0,0 +1,128 @@
+//! Apache Parquet output, one row per target.
+
+use arrow_array::{ArrayRef, Float32Array, Int32Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::error::Error;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::{TargetInterval, TargetSummary};
+
+/// Targets per record batch.
+const BATCH_ROWS: usize = 65_536;
+
+/// Writes zstd-compressed Parquet with the columns named by `columns`: the
+/// target coordinates (plus `name` when `named`) followed by a
+/// `n_sites`/coverage/fraction triple per entry of each row's summaries.
+pub fn write_parquet(
+    out: impl Write + Send,
+    columns: &[&str],
+    named: bool,
+    targets: &[TargetInterval],
+    rows: &[Vec<TargetSummary>],
+) -> Result<(), Box<dyn Error>> {
+    let target_columns = if named { 4 } else { 3 };
+    let mut fields = vec![
+        Field::new(columns[0], DataType::Utf8, false),
+        Field::new(columns[1], DataType::Int32, false),
+        Field::new(columns[2], DataType::Int32, false),
+    ];
+    if named {
+        fields.push(Field::new(columns[3], DataType::Utf8, false));
+    }
+    for triple in columns[target_columns..].chunks(3) {
+        fields.push(Field::new(triple[0], DataType::UInt64, false));
+        fields.push(Field::new(triple[1], DataType::Int32, false));
+        fields.push(Field::new(triple[2], DataType::Float32, false));
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let mut writer = ArrowWriter::try_new(out, schema.clone(), Some(props))?;
+
+    let n_summaries = (columns.len() - target_columns) / 3;
+    for (targets, rows) in targets.chunks(BATCH_ROWS).zip(rows.chunks(BATCH_ROWS)) {
+        let mut arrays: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(
+                targets.iter().map(|t| t.chrom.as_str()),
+            )),
+            Arc::new(Int32Array::from_iter_values(
+                targets.iter().map(|t| t.start),
+            )),
+            Arc::new(Int32Array::from_iter_values(targets.iter().map(|t| t.end))),
+        ];
+        if named {
+            arrays.push(Arc::new(StringArray::from_iter_values(
+                targets.iter().map(|t| t.name.as_deref().unwrap_or(".")),
+            )));
+        }
+        for i in 0..n_summaries {
+            arrays.push(Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|row| row[i].num_positions as u64),
+            )));
+            arrays.push(Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|row| row[i].sum_total_coverage),
+            )));
+            arrays.push(Arc::new(Float32Array::from_iter_values(
+                rows.iter().map(|row| row[i].weighted_fraction),
+            )));
+        }
+        writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn writes_one_row_per_target() {
+        let targets: Vec<TargetInterval> = (0..3)
+            .map(|i| TargetInterval {
+                chrom: "chr1".to_string(),
+                start: i * 100,
+                end: i * 100 + 100,
+                name: None,
+                strand: Strand::Unknown,
+                extra: Vec::new(),
+            })
+            .collect();
+        let rows = vec![vec![TargetSummary::default()]; 3];
+        let columns = [
+            "chrom",
+            "start",
+            "end",
+            "n_sites",
+            "total_coverage",
+            "weighted_fraction",
+        ];
+        let path =
+            std::env::temp_dir().join(format!("methfast-parquet-{}.parquet", std::process::id()));
+        write_parquet(
+            std::fs::File::create(&path).unwrap(),
+            &columns,
+            false,
+            &targets,
+            &rows,
+        )
+        .unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 3);
+        assert_eq!(metadata.schema_descr().num_columns(), 6);
+        assert_eq!(
+            metadata.schema_descr().column(5).name(),
+            "weighted_fraction"
+        );
+    }
+}
@@ -0,0 +1,514 @@
+//! `methfast pca`: principal components and hierarchical clustering of the
+//! samples of a region-by-sample methylation matrix.
+
+use crate::parallel::*;
+use clap::Args;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use crate::cli::{format_target, write_lines};
+use crate::error::ThreadError;
+use crate::format::Format;
+use crate::matrix::{TargetIndex, summarize_spec};
+use crate::{RecordFilter, compression, parse_targets, samples};
+
+#[derive(Args, Debug)]
+pub struct PcaArgs {
+    #[arg(
+        long = "samples",
+        value_name = "TSV",
+        conflicts_with = "matrix",
+        requires = "targets",
+        help = "Sample manifest to build the matrix from, over TARGET_BED"
+    )]
+    samples: Option<PathBuf>,
+    #[arg(value_name = "TARGET_BED", help = "Target intervals, with --samples")]
+    targets: Option<PathBuf>,
+    #[arg(
+        long = "matrix",
+        value_name = "TSV",
+        help = "Matrix written by `methfast matrix`; its `<label>_fraction` columns are the samples"
+    )]
+    matrix: Option<PathBuf>,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Format of the samples without one in the manifest"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "top",
+        value_name = "N",
+        help = "Only use the N regions whose fraction varies most across samples"
+    )]
+    top: Option<usize>,
+    #[arg(
+        long = "components",
+        value_name = "K",
+        default_value_t = 2,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Principal components to write"
+    )]
+    components: usize,
+    #[arg(
+        long = "loadings",
+        value_name = "FILE",
+        help = "Write the loading of every region on each component to FILE"
+    )]
+    loadings: Option<PathBuf>,
+    #[arg(
+        long = "tree",
+        value_name = "FILE",
+        help = "Write the average-linkage clustering of the samples to FILE"
+    )]
+    tree: Option<PathBuf>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of the output"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// Sample labels, and the region columns and per-sample fractions of every
+/// region covered in all samples.
+struct Matrix {
+    samples: Vec<String>,
+    /// Header of the region columns, `chrom, start, end` and maybe `name`.
+    region_header: String,
+    regions: Vec<String>,
+    rows: Vec<Vec<f64>>,
+}
+
+fn read_matrix(path: &std::path::Path) -> Result<Matrix, Box<dyn Error>> {
+    let mut lines = compression::open(path)?.lines();
+    let header = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| format!("Error: {} is empty", path.display()))?;
+    let mut samples = Vec::new();
+    let mut columns = Vec::new();
+    for (i, name) in header.split('\t').enumerate() {
+        if let Some(label) = name.strip_suffix("_fraction") {
+            samples.push(label.to_string());
+            columns.push(i);
+        }
+    }
+    if samples.is_empty() {
+        return Err(format!("Error: {}: no `<label>_fraction` columns", path.display()).into());
+    }
+    let region_columns = if header.split('\t').nth(3) == Some("name") {
+        4
+    } else {
+        3
+    };
+    let mut matrix = Matrix {
+        samples,
+        region_header: header
+            .split('\t')
+            .take(region_columns)
+            .collect::<Vec<_>>()
+            .join("\t"),
+        regions: Vec::new(),
+        rows: Vec::new(),
+    };
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let row: Option<Vec<f64>> = columns
+            .iter()
+            .map(|&i| fields.get(i).and_then(|value| value.parse().ok()))
+            .collect();
+        if let Some(row) = row {
+            matrix
+                .regions
+                .push(fields[..region_columns.min(fields.len())].join("\t"));
+            matrix.rows.push(row);
+        }
+    }
+    Ok(matrix)
+}
+
+fn build_matrix(args: &PcaArgs, manifest: &std::path::Path) -> Result<Matrix, Box<dyn Error>> {
+    let specs = samples::read_manifest(manifest, args.format)?;
+    let targets_path = args
+        .targets
+        .as_deref()
+        .ok_or("Error: expected TARGET_BED")?;
+    let mut targets = parse_targets(targets_path, true, false)?;
+    let named = targets.iter().any(|target| target.name.is_some());
+    if named {
+        for target in &mut targets {
+            target.name.get_or_insert_with(|| ".".to_string());
+        }
+    }
+    let index = TargetIndex::new(&targets);
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let columns = specs
+        .par_iter()
+        .map(|spec| summarize_spec(spec, &filter, &targets, &index).map_err(ThreadError::from))
+        .collect::<Result<Vec<_>, ThreadError>>()?;
+    let mut matrix = Matrix {
+        samples: specs.iter().map(|spec| spec.label.clone()).collect(),
+        region_header: if named {
+            "chrom\tstart\tend\tname"
+        } else {
+            "chrom\tstart\tend"
+        }
+        .to_string(),
+        regions: Vec::new(),
+        rows: Vec::new(),
+    };
+    for (i, target) in targets.iter().enumerate() {
+        let row: Option<Vec<f64>> = columns
+            .iter()
+            .map(|column| {
+                (column[i].sum_total_coverage > 0).then_some(column[i].weighted_fraction as f64)
+            })
+            .collect();
+        if let Some(row) = row {
+            matrix.regions.push(format_target(target));
+            matrix.rows.push(row);
+        }
+    }
+    Ok(matrix)
+}
+
+/// Keeps the `top` rows of highest variance, in their original order.
+fn most_variable(matrix: &mut Matrix, top: usize) {
+    if matrix.rows.len() <= top {
+        return;
+    }
+    let variance = |row: &[f64]| {
+        let mean = row.iter().sum::<f64>() / row.len() as f64;
+        row.iter().map(|x| (x - mean).powi(2)).sum::<f64>()
+    };
+    let mut order: Vec<usize> = (0..matrix.rows.len()).collect();
+    order.sort_by(|&a, &b| variance(&matrix.rows[b]).total_cmp(&variance(&matrix.rows[a])));
+    order.truncate(top);
+    order.sort_unstable();
+    matrix.regions = order.iter().map(|&i| matrix.regions[i].clone()).collect();
+    matrix.rows = order
+        .iter()
+        .map(|&i| std::mem::take(&mut matrix.rows[i]))
+        .collect();
+}
+
+/// Eigenvalues and eigenvectors (as columns) of the symmetric matrix `a` by
+/// cyclic Jacobi rotations, in decreasing order of eigenvalue.
+fn symmetric_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    let mut v: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                // A <- J' A J, V <- V J for the rotation J in the (p, q) plane.
+                let rotate_columns = |row: &mut Vec<f64>| {
+                    let (xp, xq) = (row[p], row[q]);
+                    row[p] = c * xp - s * xq;
+                    row[q] = s * xp + c * xq;
+                };
+                a.iter_mut().for_each(rotate_columns);
+                v.iter_mut().for_each(rotate_columns);
+                let (row_p, row_q) = (a[p].clone(), a[q].clone());
+                a[p] = row_p
+                    .iter()
+                    .zip(&row_q)
+                    .map(|(x, y)| c * x - s * y)
+                    .collect();
+                a[q] = row_p
+                    .iter()
+                    .zip(&row_q)
+                    .map(|(x, y)| s * x + c * y)
+                    .collect();
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[j][j].total_cmp(&a[i][i]));
+    let values = order.iter().map(|&i| a[i][i]).collect();
+    let vectors = (0..n)
+        .map(|row| order.iter().map(|&i| v[row][i]).collect())
+        .collect();
+    (values, vectors)
+}
+
+/// PCA of the samples (columns of `rows`, centered per row): sample
+/// coordinates, region loadings and the variance share of each component.
+struct Pca {
+    coordinates: Vec<Vec<f64>>,
+    loadings: Vec<Vec<f64>>,
+    explained: Vec<f64>,
+}
+
+fn pca(rows: &[Vec<f64>], components: usize) -> Pca {
+    let n = rows.first().map_or(0, Vec::len);
+    let centered: Vec<Vec<f64>> = rows
+        .iter()
+        .map(|row| {
+            let mean = row.iter().sum::<f64>() / n as f64;
+            row.iter().map(|x| x - mean).collect()
+        })
+        .collect();
+    // The samples-by-samples Gram matrix shares its non-zero eigenvalues with
+    // the covariance of the regions and stays small for any number of regions.
+    let gram: Vec<Vec<f64>> = (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| centered.iter().map(|row| row[i] * row[j]).sum())
+                .collect()
+        })
+        .collect();
+    let (values, vectors) = symmetric_eigen(gram);
+    let total: f64 = values.iter().map(|value| value.max(0.0)).sum();
+    let k = components.min(n);
+    let scale: Vec<f64> = values[..k]
+        .iter()
+        .map(|value| value.max(0.0).sqrt())
+        .collect();
+    let coordinates = (0..n)
+        .map(|i| (0..k).map(|c| vectors[i][c] * scale[c]).collect())
+        .collect();
+    let loadings = centered
+        .iter()
+        .map(|row| {
+            (0..k)
+                .map(|c| match scale[c] {
+                    0.0 => 0.0,
+                    scale => (0..n).map(|i| row[i] * vectors[i][c]).sum::<f64>() / scale,
+                })
+                .collect()
+        })
+        .collect();
+    let explained = values[..k]
+        .iter()
+        .map(|value| {
+            if total > 0.0 {
+                value.max(0.0) / total
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    Pca {
+        coordinates,
+        loadings,
+        explained,
+    }
+}
+
+/// Average-linkage (UPGMA) clustering on Euclidean distances between the
+/// columns of `rows`, as `(left, right, height)` merges in the order of R's
+/// `hclust`: leaves are `-1..-n`, earlier merges `1..`.
+fn cluster(rows: &[Vec<f64>], n: usize) -> Vec<(i64, i64, f64)> {
+    let mut distance: Vec<Vec<f64>> = (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| {
+                    rows.iter()
+                        .map(|row| (row[i] - row[j]).powi(2))
+                        .sum::<f64>()
+                        .sqrt()
+                })
+                .collect()
+        })
+        .collect();
+    // Active clusters: id and size, indexed like `distance`.
+    let mut clusters: Vec<Option<(i64, usize)>> =
+        (0..n).map(|i| Some((-(i as i64) - 1, 1))).collect();
+    let mut merges = Vec::with_capacity(n.saturating_sub(1));
+    for step in 1..n {
+        let (mut a, mut b, mut best) = (0, 0, f64::INFINITY);
+        for i in 0..n {
+            for j in i + 1..n {
+                if clusters[i].is_some() && clusters[j].is_some() && distance[i][j] < best {
+                    (a, b, best) = (i, j, distance[i][j]);
+                }
+            }
+        }
+        let (id_a, size_a) = clusters[a].unwrap();
+        let (id_b, size_b) = clusters[b].unwrap();
+        for k in 0..n {
+            if clusters[k].is_some() && k != a && k != b {
+                let merged = (distance[a][k] * size_a as f64 + distance[b][k] * size_b as f64)
+                    / (size_a + size_b) as f64;
+                distance[a][k] = merged;
+                distance[k][a] = merged;
+            }
+        }
+        // Singletons come first, the lower sample first between two.
+        let (left, right) = match (id_a, id_b) {
+            (x, y) if x < 0 && y < 0 => (x.max(y), x.min(y)),
+            (x, y) => (x.min(y), x.max(y)),
+        };
+        merges.push((left, right, best));
+        clusters[a] = Some((step as i64, size_a + size_b));
+        clusters[b] = None;
+    }
+    merges
+}
+
+pub fn run(args: &PcaArgs) -> Result<(), Box<dyn Error>> {
+    let mut matrix = match (&args.matrix, &args.samples) {
+        (Some(path), _) => read_matrix(path)?,
+        (None, Some(manifest)) => build_matrix(args, manifest)?,
+        (None, None) => return Err("Error: expected --matrix or --samples".into()),
+    };
+    if let Some(top) = args.top {
+        most_variable(&mut matrix, top);
+    }
+    let n = matrix.samples.len();
+    if n < 2 || matrix.rows.is_empty() {
+        return Err("Error: PCA needs two samples and a region covered in all of them".into());
+    }
+    eprintln!(
+        "pca: {} regions covered in all {n} samples",
+        matrix.rows.len()
+    );
+
+    let result = pca(&matrix.rows, args.components);
+    let precision = args.precision;
+    let components: Vec<String> = (1..=result.explained.len())
+        .map(|c| format!("PC{c}"))
+        .collect();
+    for (name, share) in components.iter().zip(&result.explained) {
+        eprintln!("{name}: {:.1}% of variance", share * 100.0);
+    }
+    let format_values = |values: &[f64]| {
+        values
+            .iter()
+            .map(|value| format!("{value:.precision$}"))
+            .collect::<Vec<_>>()
+            .join("\t")
+    };
+    let lines: Vec<String> = matrix
+        .samples
+        .iter()
+        .zip(&result.coordinates)
+        .map(|(sample, coordinates)| format!("{sample}\t{}", format_values(coordinates)))
+        .collect();
+    write_lines(
+        args.output.as_deref(),
+        Some(&format!("sample\t{}", components.join("\t"))),
+        &lines,
+    )?;
+
+    if let Some(path) = &args.loadings {
+        let lines: Vec<String> = matrix
+            .regions
+            .iter()
+            .zip(&result.loadings)
+            .map(|(region, loadings)| format!("{region}\t{}", format_values(loadings)))
+            .collect();
+        write_lines(
+            Some(path),
+            Some(&format!(
+                "{}\t{}",
+                matrix.region_header,
+                components.join("\t")
+            )),
+            &lines,
+        )?;
+    }
+    if let Some(path) = &args.tree {
+        let label = |id: i64| match id {
+            id if id < 0 => matrix.samples[(-id - 1) as usize].clone(),
+            id => format!("#{id}"),
+        };
+        let lines: Vec<String> = cluster(&matrix.rows, n)
+            .iter()
+            .enumerate()
+            .map(|(i, &(left, right, height))| {
+                format!(
+                    "#{}\t{}\t{}\t{height:.precision$}",
+                    i + 1,
+                    label(left),
+                    label(right)
+                )
+            })
+            .collect();
+        write_lines(Some(path), Some("cluster\tleft\tright\theight"), &lines)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_sample_groups() {
+        // Samples 1-2 and 3-4 differ at every region.
+        let rows = vec![
+            vec![0.1, 0.2, 0.8, 0.9],
+            vec![0.9, 0.8, 0.2, 0.1],
+            vec![0.5, 0.5, 0.5, 0.5],
+        ];
+        let result = pca(&rows, 2);
+        let pc1: Vec<f64> = result.coordinates.iter().map(|c| c[0]).collect();
+        assert!(pc1[0] * pc1[1] > 0.0 && pc1[2] * pc1[3] > 0.0 && pc1[0] * pc1[2] < 0.0);
+        assert!(result.explained[0] > 0.95);
+        // The constant region does not load on any component.
+        assert!(result.loadings[2][0].abs() < 1e-9);
+
+        let merges = cluster(&rows, 4);
+        assert_eq!((merges[0].0, merges[0].1), (-1, -2));
+        assert_eq!((merges[1].0, merges[1].1), (-3, -4));
+        assert_eq!((merges[2].0, merges[2].1), (1, 2));
+        assert!((merges[0].2 - 2.0_f64.sqrt() * 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn recovers_the_component_of_a_rank_one_matrix() {
+        // Region r holds offset + u[r] * v[i] in sample i, with v centered.
+        let u = [1.0, 2.0, -2.0];
+        let v = [-3.0, -1.0, 1.0, 3.0];
+        let rows: Vec<Vec<f64>> = u
+            .iter()
+            .zip([0.5, 0.1, 0.9])
+            .map(|(u, offset)| v.iter().map(|v| offset + u * v).collect())
+            .collect();
+        let result = pca(&rows, 2);
+        // The eigenvector's sign is arbitrary; |u| = 3.
+        let sign = result.coordinates[3][0].signum();
+        for (coordinate, v) in result.coordinates.iter().zip(v) {
+            assert!((coordinate[0] - sign * 3.0 * v).abs() < 1e-9);
+            assert!(coordinate[1].abs() < 1e-6);
+        }
+        for (loading, u) in result.loadings.iter().zip(u) {
+            assert!((loading[0] - sign * u / 3.0).abs() < 1e-9);
+        }
+        assert!((result.explained[0] - 1.0).abs() < 1e-9);
+        assert!(result.explained[1].abs() < 1e-9);
+    }
+}
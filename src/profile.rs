@@ -0,0 +1,295 @@
+//! `methfast profile`: metagene profiles over scaled regions or anchor points.
+
+use crate::parallel::*;
+use clap::{Args, ValueEnum};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::write_lines;
+use crate::format::{ColumnNames, Format};
+use crate::{
+    Aggregation, MethRanges, RecordFilter, Strand, TargetInterval, detect_format, parse_meth_bed,
+    parse_targets, summarize,
+};
+
+#[derive(Args, Debug)]
+pub struct ProfileArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        help = "Methylation input; `-` for stdin"
+    )]
+    input: PathBuf,
+    #[arg(
+        value_name = "TARGET_BED",
+        help = "Regions to profile; a sixth-column strand orients them"
+    )]
+    targets: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "bins",
+        value_name = "N",
+        default_value_t = 20,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Bins over each region, or over the window around --reference-point"
+    )]
+    bins: usize,
+    #[arg(
+        long = "flank",
+        value_name = "BP",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(i32).range(0..),
+        help = "Bases profiled upstream and downstream of each region or anchor"
+    )]
+    flank: i32,
+    #[arg(
+        long = "flank-bins",
+        value_name = "N",
+        default_value_t = 10,
+        help = "Bins over each flank of a scaled region"
+    )]
+    flank_bins: usize,
+    #[arg(
+        long = "reference-point",
+        value_enum,
+        requires = "flank",
+        help = "Profile --flank bases around this point of each region instead of scaling regions"
+    )]
+    reference_point: Option<ReferencePoint>,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReferencePoint {
+    /// The 5' end on the region's strand (start for `+` and unstranded regions).
+    Tss,
+    /// The 3' end on the region's strand.
+    Tes,
+    Center,
+}
+
+/// Part of the profile a bin belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Upstream,
+    Body,
+    Downstream,
+}
+
+impl Segment {
+    fn name(self) -> &'static str {
+        match self {
+            Segment::Upstream => "upstream",
+            Segment::Body => "body",
+            Segment::Downstream => "downstream",
+        }
+    }
+}
+
+/// Splits `[start, end)` into `n` bins of (nearly) equal width.
+pub fn split(start: i32, end: i32, n: usize) -> impl Iterator<Item = (i32, i32)> {
+    let length = (end - start) as i64;
+    (0..n).map(move |i| {
+        let bound = |i: usize| start + (length * i as i64 / n as i64) as i32;
+        (bound(i), bound(i + 1))
+    })
+}
+
+/// The segment of every profile bin, in profile order.
+fn segments(args: &ProfileArgs) -> Vec<Segment> {
+    match args.reference_point {
+        Some(_) => (0..args.bins)
+            .map(|i| {
+                if i < args.bins / 2 {
+                    Segment::Upstream
+                } else {
+                    Segment::Downstream
+                }
+            })
+            .collect(),
+        None => {
+            let flank_bins = if args.flank > 0 { args.flank_bins } else { 0 };
+            std::iter::repeat_n(Segment::Upstream, flank_bins)
+                .chain(std::iter::repeat_n(Segment::Body, args.bins))
+                .chain(std::iter::repeat_n(Segment::Downstream, flank_bins))
+                .collect()
+        }
+    }
+}
+
+/// Genomic bins of `target` in profile order: 5' to 3' on the target's strand.
+fn target_bins(target: &TargetInterval, args: &ProfileArgs) -> Vec<(i32, i32)> {
+    let minus = target.strand == Strand::Minus;
+    let mut bins: Vec<(i32, i32)> = match args.reference_point {
+        Some(point) => {
+            let anchor = match (point, minus) {
+                (ReferencePoint::Center, _) => target.start + (target.end - target.start) / 2,
+                (ReferencePoint::Tss, false) | (ReferencePoint::Tes, true) => target.start,
+                (ReferencePoint::Tss, true) | (ReferencePoint::Tes, false) => target.end,
+            };
+            split(anchor - args.flank, anchor + args.flank, args.bins).collect()
+        }
+        None => {
+            let flank_bins = if args.flank > 0 { args.flank_bins } else { 0 };
+            split(target.start - args.flank, target.start, flank_bins)
+                .chain(split(target.start, target.end, args.bins))
+                .chain(split(target.end, target.end + args.flank, flank_bins))
+                .collect()
+        }
+    };
+    if minus {
+        bins.reverse();
+    }
+    bins
+}
+
+/// Per-bin totals: summed target fractions, contributing targets and reads.
+type Profile = Vec<(f64, usize, i64)>;
+
+/// Averages the weighted fraction of every bin over the targets covering it.
+fn profile(ranges: &MethRanges, targets: &[TargetInterval], args: &ProfileArgs) -> Profile {
+    let n_bins = segments(args).len();
+    let aggregation = Aggregation::default();
+    targets
+        .par_iter()
+        .fold(
+            || vec![(0.0, 0, 0); n_bins],
+            |mut totals: Profile, target| {
+                let Some(intervals) = ranges.by_chrom.get(&target.chrom) else {
+                    return totals;
+                };
+                for (total, (start, end)) in totals.iter_mut().zip(target_bins(target, args)) {
+                    if end <= start.max(0) {
+                        continue;
+                    }
+                    let bin = TargetInterval {
+                        chrom: target.chrom.clone(),
+                        start: start.max(0),
+                        end,
+                        name: None,
+                        strand: target.strand,
+                        extra: Vec::new(),
+                    };
+                    let summary = summarize(intervals, &bin, Strand::Unknown, &aggregation);
+                    if summary.sum_total_coverage > 0 {
+                        total.0 += summary.weighted_fraction as f64;
+                        total.1 += 1;
+                        total.2 += summary.sum_total_coverage as i64;
+                    }
+                }
+                totals
+            },
+        )
+        .reduce(
+            || vec![(0.0, 0, 0); n_bins],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    a.0 += b.0;
+                    a.1 += b.1;
+                    a.2 += b.2;
+                }
+                a
+            },
+        )
+}
+
+pub fn run(args: &ProfileArgs) -> Result<(), Box<dyn Error>> {
+    if args.reference_point.is_some() && args.flank == 0 {
+        return Err("Error: --reference-point needs a --flank above 0".into());
+    }
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let ranges = parse_meth_bed(
+        &args.input,
+        &format.layout(),
+        &ColumnNames::default(),
+        &filter,
+        &[None],
+    )?
+    .remove(0);
+    let targets = parse_targets(&args.targets, false, false)?;
+    let lines: Vec<String> = segments(args)
+        .iter()
+        .zip(profile(&ranges, &targets, args))
+        .enumerate()
+        .map(|(bin, (segment, (sum_fraction, n_targets, coverage)))| {
+            let fraction = if n_targets > 0 {
+                format!("{:.*}", args.precision, sum_fraction / n_targets as f64)
+            } else {
+                "NA".to_string()
+            };
+            format!(
+                "{bin}\t{}\t{fraction}\t{n_targets}\t{coverage}",
+                segment.name()
+            )
+        })
+        .collect();
+    write_lines(
+        args.output.as_deref(),
+        Some("bin\tsegment\tfraction\tn_targets\tcoverage"),
+        &lines,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Wrapper {
+        #[command(flatten)]
+        args: ProfileArgs,
+    }
+
+    #[test]
+    fn orients_bins_by_strand() {
+        let args =
+            Wrapper::parse_from(["profile", "m.bed", "t.bed", "--bins", "2", "--flank", "10"]).args;
+        let mut target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 100,
+            end: 200,
+            name: None,
+            strand: Strand::Plus,
+            extra: Vec::new(),
+        };
+        assert_eq!(segments(&args).len(), 22);
+        let bins = target_bins(&target, &args);
+        assert_eq!(
+            (bins[0], bins[10], bins[21]),
+            ((90, 91), (100, 150), (209, 210))
+        );
+        target.strand = Strand::Minus;
+        let bins = target_bins(&target, &args);
+        assert_eq!(
+            (bins[0], bins[10], bins[21]),
+            ((209, 210), (150, 200), (90, 91))
+        );
+    }
+}
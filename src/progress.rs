@@ -0,0 +1,151 @@
+//! `--progress`: how much of each input has been parsed and how many targets
+//! are summarized, reported on stderr. A terminal gets one line redrawn a few
+//! times a second, a log file a line every ten seconds.
+
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns reporting on for the rest of the run.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Bytes,
+    Targets,
+}
+
+/// A counter of work done, drawn on stderr while reporting is enabled and
+/// once more, complete, when dropped.
+pub struct Progress {
+    /// The input being parsed, or `None` for targets.
+    path: Option<PathBuf>,
+    unit: Unit,
+    total: Option<u64>,
+    done: AtomicU64,
+    /// When the counter was last drawn; `None` when reporting is off.
+    drawn: Option<Mutex<Instant>>,
+}
+
+impl Progress {
+    fn new(path: Option<PathBuf>, unit: Unit, total: Option<u64>) -> Progress {
+        Progress {
+            path,
+            unit,
+            total,
+            done: AtomicU64::new(0),
+            drawn: ENABLED
+                .load(Ordering::Relaxed)
+                .then(|| Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Bytes of `path` parsed, out of `total` when its size is known.
+    pub fn bytes(path: &Path, total: Option<u64>) -> Progress {
+        Progress::new(Some(path.to_path_buf()), Unit::Bytes, total)
+    }
+
+    /// Targets summarized out of `total`.
+    pub fn targets(total: usize) -> Progress {
+        Progress::new(None, Unit::Targets, Some(total as u64))
+    }
+
+    /// The input of a [`Progress::bytes`].
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new("-"))
+    }
+
+    /// Counts `n` more bytes or targets, redrawing when it is time to.
+    pub fn add(&self, n: u64) {
+        let Some(drawn) = &self.drawn else {
+            return;
+        };
+        let done = self.done.fetch_add(n, Ordering::Relaxed) + n;
+        let interval = match std::io::stderr().is_terminal() {
+            true => Duration::from_millis(200),
+            false => Duration::from_secs(10),
+        };
+        // Another thread is drawing; this update shows on the next one.
+        if let Ok(mut drawn) = drawn.try_lock()
+            && drawn.elapsed() >= interval
+        {
+            *drawn = Instant::now();
+            self.draw(done, false);
+        }
+    }
+
+    fn draw(&self, done: u64, finished: bool) {
+        let mut status = match &self.path {
+            Some(path) => format!("{}: {}", path.display(), self.amount(done)),
+            None => format!("targets: {}", self.amount(done)),
+        };
+        if let Some(total) = self.total {
+            match self.unit {
+                Unit::Bytes => status.push_str(&format!(" of {}", self.amount(total))),
+                Unit::Targets => status.push_str(&format!("/{total}")),
+            }
+            if total > 0 {
+                status.push_str(&format!(" ({:.0}%)", done as f64 * 100.0 / total as f64));
+            }
+        }
+        let mut stderr = std::io::stderr().lock();
+        let _ = match (stderr.is_terminal(), finished) {
+            (true, false) => write!(stderr, "\r\x1b[K{status}"),
+            (true, true) => writeln!(stderr, "\r\x1b[K{status}"),
+            (false, _) => writeln!(stderr, "{status}"),
+        };
+    }
+
+    fn amount(&self, n: u64) -> String {
+        match self.unit {
+            Unit::Bytes => human_bytes(n),
+            Unit::Targets => n.to_string(),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            let done = *self.done.get_mut();
+            self.draw(done, true);
+        }
+    }
+}
+
+/// `n` bytes in binary units with one decimal, e.g. `1.5 GiB`.
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{n} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_sizes_in_binary_units() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(3 << 30), "3.0 GiB");
+        // Counting without reporting is free of side effects.
+        let progress = Progress::targets(10);
+        progress.add(4);
+        assert!(progress.drawn.is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! The native half of the `methfast` Python module in `python/`: the
+//! aggregates of records loaded by `methfast_open` over a set of targets,
+//! handed over as an Arrow record batch through the Arrow C data interface,
+//! which pyarrow imports without copying the columns.
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::sync::Arc;
+
+use arrow_array::ffi::{FFI_ArrowArray, FFI_ArrowSchema, to_ffi};
+use arrow_array::{
+    Array, ArrayRef, Float32Array, Int32Array, RecordBatch, StringArray, StructArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+
+use crate::ffi::{guard, string_arg};
+use crate::{Aggregate, MethRanges, TargetSet, aggregate};
+
+/// The record batch of `aggregates`, one row each with the fields of
+/// [`Aggregate`] as columns; `name` and `fraction` are null where `None`.
+fn aggregate_batch(aggregates: &[Aggregate]) -> Result<RecordBatch, Box<dyn Error>> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("chrom", DataType::Utf8, false),
+        Field::new("start", DataType::Int32, false),
+        Field::new("end", DataType::Int32, false),
+        Field::new("name", DataType::Utf8, true),
+        Field::new("num_positions", DataType::UInt64, false),
+        Field::new("coverage", DataType::Int32, false),
+        Field::new("methylated", DataType::Int32, false),
+        Field::new("fraction", DataType::Float32, true),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            aggregates.iter().map(|a| a.chrom.as_str()),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            aggregates.iter().map(|a| a.start),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            aggregates.iter().map(|a| a.end),
+        )),
+        Arc::new(StringArray::from_iter(
+            aggregates.iter().map(|a| a.name.as_deref()),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            aggregates.iter().map(|a| a.num_positions as u64),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            aggregates.iter().map(|a| a.coverage),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            aggregates.iter().map(|a| a.methylated),
+        )),
+        Arc::new(Float32Array::from_iter(
+            aggregates.iter().map(|a| a.fraction),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Aggregates `ranges` over `targets` into `out_array` and `out_schema`, the
+/// struct array of a record batch. Returns 0, or -1 on error.
+///
+/// # Safety
+///
+/// `ranges` must come from `methfast_open` and not be freed; the outputs must
+/// point to writable `ArrowArray` and `ArrowSchema` structs.
+unsafe fn export(
+    ranges: *const MethRanges,
+    targets: impl FnOnce() -> Result<TargetSet, String>,
+    out_array: *mut FFI_ArrowArray,
+    out_schema: *mut FFI_ArrowSchema,
+) -> i32 {
+    guard(-1, || {
+        if ranges.is_null() || out_array.is_null() || out_schema.is_null() {
+            return Err("Error: ranges and the outputs must not be NULL".to_string());
+        }
+        let targets = targets()?;
+        // SAFETY: `ranges` is a live handle from `methfast_open`.
+        let aggregates = aggregate(unsafe { &*ranges }, &targets);
+        let batch = aggregate_batch(&aggregates).map_err(|err| format!("Error: {err}"))?;
+        let (array, schema) =
+            to_ffi(&StructArray::from(batch).to_data()).map_err(|err| format!("Error: {err}"))?;
+        // SAFETY: the outputs point to writable structs; the caller now owns
+        // the exported ones and releases them through their callbacks.
+        unsafe {
+            out_array.write(array);
+            out_schema.write(schema);
+        }
+        Ok(0)
+    })
+}
+
+/// Aggregates the records of `ranges` over the targets of the BED or
+/// GTF/GFF3 file at `path`, in file order.
+///
+/// # Safety
+///
+/// As for [`export`], and `path` must be a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn methfast_aggregate_file_arrow(
+    ranges: *const MethRanges,
+    path: *const c_char,
+    out_array: *mut FFI_ArrowArray,
+    out_schema: *mut FFI_ArrowSchema,
+) -> i32 {
+    let targets = || {
+        // SAFETY: forwarded from the caller.
+        let path = unsafe { string_arg(path, "path") }?;
+        TargetSet::read(path).map_err(|err| err.to_string())
+    };
+    // SAFETY: forwarded from the caller.
+    unsafe { export(ranges, targets, out_array, out_schema) }
+}
+
+/// Aggregates the records of `ranges` over the `n` 0-based, half-open
+/// targets `[starts[i], ends[i])` of `chroms[i]`, named by `names[i]` when
+/// `names` and the entry are not NULL.
+///
+/// # Safety
+///
+/// As for [`export`]; `chroms`, `starts` and `ends` must point to `n`
+/// entries, as must `names` when not NULL, and the strings be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn methfast_aggregate_arrow(
+    ranges: *const MethRanges,
+    chroms: *const *const c_char,
+    starts: *const i32,
+    ends: *const i32,
+    names: *const *const c_char,
+    n: usize,
+    out_array: *mut FFI_ArrowArray,
+    out_schema: *mut FFI_ArrowSchema,
+) -> i32 {
+    let targets = || {
+        if n > 0 && (chroms.is_null() || starts.is_null() || ends.is_null()) {
+            return Err("Error: chroms, starts and ends must not be NULL".to_string());
+        }
+        let mut targets = TargetSet::default();
+        for i in 0..n {
+            // SAFETY: the arrays hold `n` entries.
+            let (chrom, start, end) = unsafe { (*chroms.add(i), *starts.add(i), *ends.add(i)) };
+            // SAFETY: forwarded from the caller.
+            let chrom = unsafe { string_arg(chrom, "chrom") }?;
+            let name = match names.is_null() {
+                true => None,
+                // SAFETY: `names` holds `n` entries when not NULL.
+                false => match unsafe { *names.add(i) } {
+                    name if name.is_null() => None,
+                    // SAFETY: forwarded from the caller.
+                    name => Some(unsafe { string_arg(name, "name") }?.to_string()),
+                },
+            };
+            targets.push(chrom, start, end, name);
+        }
+        Ok(targets)
+    };
+    // SAFETY: forwarded from the caller.
+    unsafe { export(ranges, targets, out_array, out_schema) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{methfast_free, methfast_open};
+    use arrow_array::cast::AsArray;
+    use arrow_array::ffi::from_ffi;
+    use arrow_array::types::{Float32Type, Int32Type};
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn exports_aggregates_as_an_arrow_batch() {
+        let path = std::env::temp_dir().join(format!("methfast-python-{}.bed", std::process::id()));
+        std::fs::write(&path, "chr1\t10\t11\t1.0\t4\nchr1\t12\t13\t0.5\t4\n").unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let ranges = unsafe { methfast_open(c_path.as_ptr(), c"generic".as_ptr(), 0) };
+        std::fs::remove_file(&path).unwrap();
+        assert!(!ranges.is_null());
+
+        let chroms = [c"chr1".as_ptr(), c"chr2".as_ptr()];
+        let names = [c"first".as_ptr(), ptr::null()];
+        let mut array = FFI_ArrowArray::empty();
+        let mut schema = FFI_ArrowSchema::empty();
+        let status = unsafe {
+            methfast_aggregate_arrow(
+                ranges,
+                chroms.as_ptr(),
+                [0, 0].as_ptr(),
+                [20, 20].as_ptr(),
+                names.as_ptr(),
+                2,
+                &mut array,
+                &mut schema,
+            )
+        };
+        unsafe { methfast_free(ranges) };
+        assert_eq!(status, 0);
+
+        let batch = RecordBatch::from(StructArray::from(
+            unsafe { from_ffi(array, &schema) }.unwrap(),
+        ));
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(7).name(), "fraction");
+        let name = batch.column(3).as_string::<i32>();
+        assert_eq!((name.value(0), name.is_null(1)), ("first", true));
+        let methylated = batch.column(6).as_primitive::<Int32Type>();
+        assert_eq!(methylated.values(), &[6, 0]);
+        let fraction = batch.column(7).as_primitive::<Float32Type>();
+        assert_eq!((fraction.value(0), fraction.is_null(1)), (0.75, true));
+    }
+}
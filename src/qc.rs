@@ -0,0 +1,317 @@
+//! `methfast stats`: a quick QC summary of one methylation file.
+
+use clap::{Args, ValueEnum};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Write;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use crate::cli::write_lines;
+use crate::format::Format;
+use crate::json::quote;
+use crate::{MethInterval, RecordFilter, compression, detect_format, parse_record};
+
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        help = "Methylation input; `-` for stdin"
+    )]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "thresholds",
+        value_name = "LIST",
+        value_delimiter = ',',
+        default_value = "1,5,10,20,30",
+        help = "Coverage thresholds for the share of sites covered at least that deep"
+    )]
+    thresholds: Vec<i32>,
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = StatsFormat::Tsv,
+        help = "Report format"
+    )]
+    output_format: StatsFormat,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatsFormat {
+    /// `scope, metric, value` lines; the scope is `all` or a chromosome.
+    Tsv,
+    /// One JSON object.
+    Json,
+}
+
+/// Totals over a set of records.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Totals {
+    sites: u64,
+    covered_sites: u64,
+    coverage: i64,
+    methylated: f64,
+    /// Summed fractions of the covered sites.
+    fractions: f64,
+}
+
+impl Totals {
+    fn add(&mut self, iv: &MethInterval) {
+        self.sites += 1;
+        if iv.coverage > 0 {
+            self.covered_sites += 1;
+            self.coverage += iv.coverage as i64;
+            self.methylated += iv.fraction as f64 * iv.coverage as f64;
+            self.fractions += iv.fraction as f64;
+        }
+    }
+
+    fn mean_coverage(&self) -> f64 {
+        ratio(self.coverage as f64, self.sites as f64)
+    }
+
+    fn weighted_fraction(&self) -> f64 {
+        ratio(self.methylated, self.coverage as f64)
+    }
+
+    fn mean_fraction(&self) -> f64 {
+        ratio(self.fractions, self.covered_sites as f64)
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > 0.0 {
+        numerator / denominator
+    } else {
+        0.0
+    }
+}
+
+/// Everything `stats` reports about a file.
+#[derive(Debug, Default)]
+struct Report {
+    all: Totals,
+    /// Chromosomes in input order.
+    chroms: Vec<(String, Totals)>,
+    /// Sites per coverage value.
+    histogram: BTreeMap<i32, u64>,
+}
+
+impl Report {
+    /// The coverage of the `rank`-th site (0-based) in coverage order.
+    fn coverage_at(&self, rank: u64) -> i32 {
+        let mut seen = 0;
+        for (&coverage, &count) in &self.histogram {
+            seen += count;
+            if seen > rank {
+                return coverage;
+            }
+        }
+        0
+    }
+
+    fn median_coverage(&self) -> i32 {
+        self.coverage_at(self.all.sites.saturating_sub(1) / 2)
+    }
+
+    fn max_coverage(&self) -> i32 {
+        self.histogram.keys().next_back().copied().unwrap_or(0)
+    }
+
+    /// Share of the sites with at least `threshold` reads.
+    fn share_at_least(&self, threshold: i32) -> f64 {
+        let deep: u64 = self
+            .histogram
+            .range(threshold..)
+            .map(|(_, count)| count)
+            .sum();
+        ratio(deep as f64, self.all.sites as f64)
+    }
+}
+
+fn scan(records: impl Iterator<Item = (String, MethInterval)>) -> Report {
+    let mut report = Report::default();
+    let mut chrom_index: HashMap<String, usize> = HashMap::new();
+    for (chrom, iv) in records {
+        report.all.add(&iv);
+        *report.histogram.entry(iv.coverage.max(0)).or_default() += 1;
+        let i = *chrom_index.entry(chrom.clone()).or_insert_with(|| {
+            report.chroms.push((chrom, Totals::default()));
+            report.chroms.len() - 1
+        });
+        report.chroms[i].1.add(&iv);
+    }
+    report
+}
+
+fn format_tsv(report: &Report, thresholds: &[i32]) -> Vec<String> {
+    let mut lines = vec!["scope\tmetric\tvalue".to_string()];
+    let all = &report.all;
+    lines.push(format!("all\tsites\t{}", all.sites));
+    lines.push(format!("all\tcovered_sites\t{}", all.covered_sites));
+    lines.push(format!(
+        "all\tweighted_fraction\t{:.4}",
+        all.weighted_fraction()
+    ));
+    lines.push(format!("all\tmean_fraction\t{:.4}", all.mean_fraction()));
+    lines.push(format!("all\tcoverage_mean\t{:.2}", all.mean_coverage()));
+    lines.push(format!(
+        "all\tcoverage_median\t{}",
+        report.median_coverage()
+    ));
+    lines.push(format!("all\tcoverage_max\t{}", report.max_coverage()));
+    for &threshold in thresholds {
+        lines.push(format!(
+            "all\tshare_coverage_ge_{threshold}\t{:.4}",
+            report.share_at_least(threshold)
+        ));
+    }
+    for (chrom, totals) in &report.chroms {
+        lines.push(format!("{chrom}\tsites\t{}", totals.sites));
+        lines.push(format!(
+            "{chrom}\tweighted_fraction\t{:.4}",
+            totals.weighted_fraction()
+        ));
+        lines.push(format!(
+            "{chrom}\tcoverage_mean\t{:.2}",
+            totals.mean_coverage()
+        ));
+    }
+    lines
+}
+
+fn format_json(report: &Report, thresholds: &[i32]) -> String {
+    let all = &report.all;
+    let mut out = String::new();
+    write!(
+        out,
+        "{{\"sites\":{},\"covered_sites\":{},\"weighted_fraction\":{:.4},\"mean_fraction\":{:.4}",
+        all.sites,
+        all.covered_sites,
+        all.weighted_fraction(),
+        all.mean_fraction()
+    )
+    .unwrap();
+    write!(
+        out,
+        ",\"coverage\":{{\"mean\":{:.2},\"median\":{},\"max\":{},\"share_at_least\":{{",
+        all.mean_coverage(),
+        report.median_coverage(),
+        report.max_coverage()
+    )
+    .unwrap();
+    for (i, &threshold) in thresholds.iter().enumerate() {
+        let separator = if i > 0 { "," } else { "" };
+        write!(
+            out,
+            "{separator}\"{threshold}\":{:.4}",
+            report.share_at_least(threshold)
+        )
+        .unwrap();
+    }
+    out.push_str("}},\"chromosomes\":[");
+    for (i, (chrom, totals)) in report.chroms.iter().enumerate() {
+        let separator = if i > 0 { "," } else { "" };
+        write!(
+            out,
+            "{separator}{{\"chrom\":{},\"sites\":{},\"weighted_fraction\":{:.4},\"coverage_mean\":{:.2}}}",
+            quote(chrom),
+            totals.sites,
+            totals.weighted_fraction(),
+            totals.mean_coverage()
+        )
+        .unwrap();
+    }
+    out.push_str("]}");
+    out
+}
+
+pub fn run(args: &StatsArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let layout = format.layout();
+    let filter = RecordFilter::default();
+    let mut reader = compression::open(&args.input)?;
+    let mut error = None;
+    let mut line = String::new();
+    let mut linenum = 0;
+    // Records are folded into the report as they are read.
+    let report = scan(std::iter::from_fn(|| {
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => linenum += 1,
+                Err(err) => {
+                    error = Some(err.to_string());
+                    return None;
+                }
+            }
+            match parse_record(&line, &layout, &filter) {
+                Ok(Some((chrom, iv))) => return Some((chrom.to_string(), iv)),
+                Ok(None) => {}
+                Err(kind) => {
+                    error = Some(kind.at(&args.input, Some(linenum)).to_string());
+                    return None;
+                }
+            }
+        }
+    }));
+    if let Some(error) = error {
+        return Err(error.into());
+    }
+    let lines = match args.output_format {
+        StatsFormat::Tsv => format_tsv(&report, &args.thresholds),
+        StatsFormat::Json => vec![format_json(&report, &args.thresholds)],
+    };
+    write_lines(args.output.as_deref(), None, &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn summarizes_sites_and_coverage() {
+        let site = |chrom: &str, fraction, coverage| {
+            (
+                chrom.to_string(),
+                MethInterval {
+                    start: 0,
+                    end: 1,
+                    fraction,
+                    coverage,
+                    strand: Strand::Unknown,
+                },
+            )
+        };
+        let report = scan(
+            vec![
+                site("chr2", 1.0, 10),
+                site("chr1", 0.0, 2),
+                site("chr2", 0.5, 4),
+                site("chr2", 0.0, 0),
+            ]
+            .into_iter(),
+        );
+        assert_eq!((report.all.sites, report.all.covered_sites), (4, 3));
+        assert!((report.all.weighted_fraction() - 12.0 / 16.0).abs() < 1e-9);
+        assert!((report.all.mean_fraction() - 0.5).abs() < 1e-9);
+        assert_eq!((report.median_coverage(), report.max_coverage()), (2, 10));
+        assert_eq!(report.share_at_least(4), 0.5);
+        assert_eq!(report.chroms[0].0, "chr2");
+        assert_eq!(report.chroms[0].1.sites, 3);
+    }
+}
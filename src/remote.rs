@@ -0,0 +1,234 @@
+//! Remote inputs (`http://`, `https://`, `s3://`), streamed with `curl`, or
+//! read a window at a time with range requests when a tabix index is present.
+
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::compression::ToolReader;
+
+/// Bytes fetched per range request of a [`RangeReader`].
+const RANGE_BYTES: u64 = 1 << 20;
+
+/// Whether `path` names a remote input rather than a local file.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| {
+        ["http://", "https://", "s3://"]
+            .iter()
+            .any(|scheme| path.starts_with(scheme))
+    })
+}
+
+/// Maps `s3://bucket/key` to the bucket's public HTTPS endpoint, in the
+/// region of `AWS_REGION` or `AWS_DEFAULT_REGION` when set; other URLs are
+/// unchanged.
+fn http_url(url: &str) -> String {
+    let region = std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .ok();
+    s3_endpoint(url, region.as_deref())
+}
+
+fn s3_endpoint(url: &str, region: Option<&str>) -> String {
+    match url.strip_prefix("s3://") {
+        Some(rest) => {
+            let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+            match region {
+                Some(region) => format!("https://{bucket}.s3.{region}.amazonaws.com/{key}"),
+                None => format!("https://{bucket}.s3.amazonaws.com/{key}"),
+            }
+        }
+        None => url.to_string(),
+    }
+}
+
+fn curl(url: &str) -> Command {
+    let mut command = Command::new("curl");
+    command
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .stdin(Stdio::null());
+    command
+}
+
+fn curl_missing(url: &str, err: io::Error) -> String {
+    format!("Error: reading {url} needs the `curl` command: {err}")
+}
+
+/// Streams the body of `path`, failing at end of stream if the download failed.
+pub fn open(path: &Path) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+    let url = http_url(&path.to_string_lossy());
+    let child = curl(&url)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| curl_missing(&url, err))?;
+    Ok(Box::new(ToolReader::new("curl", child)))
+}
+
+/// The whole body of `path`, or `None` when the server answers with an HTTP
+/// error such as 404, as for an index that does not exist.
+pub fn fetch(path: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let url = http_url(&path.to_string_lossy());
+    let output = curl(&url).output().map_err(|err| curl_missing(&url, err))?;
+    match output.status.code() {
+        Some(0) => Ok(Some(output.stdout)),
+        // curl's exit status for HTTP errors under --fail.
+        Some(22) => Ok(None),
+        _ => Err(format!(
+            "Error: reading {url} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into()),
+    }
+}
+
+/// A remote file read through HTTP range requests, [`RANGE_BYTES`] at a time,
+/// so that a tabix-indexed file is only fetched around the queried targets.
+pub struct RangeReader {
+    url: String,
+    pos: u64,
+    /// The fetched bytes starting at `window_start`.
+    window: Vec<u8>,
+    window_start: u64,
+    /// The length of the file, once a request has reached its end.
+    len: Option<u64>,
+    range_bytes: u64,
+}
+
+impl RangeReader {
+    pub fn new(path: &Path) -> RangeReader {
+        RangeReader {
+            url: http_url(&path.to_string_lossy()),
+            pos: 0,
+            window: Vec::new(),
+            window_start: 0,
+            len: None,
+            range_bytes: RANGE_BYTES,
+        }
+    }
+
+    /// Fetches the window starting at `pos`, empty at the end of the file.
+    fn fill(&mut self) -> io::Result<()> {
+        let range = format!("{}-{}", self.pos, self.pos + self.range_bytes - 1);
+        let output = curl(&self.url)
+            .args(["--range", &range, "--write-out", "\n%{http_code}"])
+            .output()
+            .map_err(|err| io::Error::other(curl_missing(&self.url, err)))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // A range past the end: HTTP 416 under --fail, or a failed resume of a file:// URL.
+        let past_end = match output.status.code() {
+            Some(22) => stderr.contains("416"),
+            Some(36) => true,
+            _ => false,
+        };
+        if !output.status.success() && !past_end {
+            return Err(io::Error::other(format!(
+                "Error: range request to {} failed: {}",
+                self.url,
+                stderr.trim()
+            )));
+        }
+        let mut body = output.stdout;
+        let status = match body.iter().rposition(|&b| b == b'\n') {
+            Some(newline) if output.status.success() => {
+                let status = String::from_utf8_lossy(&body[newline + 1..]).into_owned();
+                body.truncate(newline);
+                status
+            }
+            _ => {
+                body.clear();
+                String::new()
+            }
+        };
+        // 200 rather than 206 Partial Content: the server sent the whole file.
+        if status == "200" && (self.pos > 0 || body.len() as u64 > self.range_bytes) {
+            return Err(io::Error::other(format!(
+                "Error: {} does not support range requests; pass --no-index to read it in full",
+                self.url
+            )));
+        }
+        if (body.len() as u64) < self.range_bytes {
+            self.len = Some(self.pos + body.len() as u64);
+        }
+        self.window = body;
+        self.window_start = self.pos;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.len.is_some_and(|len| self.pos >= len) {
+            return Ok(0);
+        }
+        let window_end = self.window_start + self.window.len() as u64;
+        if self.pos < self.window_start || self.pos >= window_end {
+            self.fill()?;
+        }
+        let offset = (self.pos - self.window_start) as usize;
+        let available = &self.window[offset.min(self.window.len())..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => self.pos.saturating_add_signed(delta),
+            SeekFrom::End(_) => {
+                return Err(io::Error::other(
+                    "Error: remote files cannot be read from their end",
+                ));
+            }
+        };
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_s3_urls_to_https() {
+        assert!(is_url(Path::new("s3://encode-public/a/b.bed.gz")));
+        assert!(!is_url(Path::new("data/s3.bed")));
+        assert_eq!(
+            s3_endpoint("s3://encode-public/a/b.bed.gz", None),
+            "https://encode-public.s3.amazonaws.com/a/b.bed.gz"
+        );
+        assert_eq!(
+            s3_endpoint("s3://encode-public/a/b.bed.gz", Some("us-west-2")),
+            "https://encode-public.s3.us-west-2.amazonaws.com/a/b.bed.gz"
+        );
+    }
+
+    #[test]
+    fn reads_and_seeks_through_range_requests() {
+        let path = std::env::temp_dir().join(format!("methfast-remote-{}.txt", std::process::id()));
+        let contents: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        std::fs::write(&path, &contents).unwrap();
+        // curl serves ranges of file:// URLs as a server would.
+        let mut reader = RangeReader::new(Path::new(&format!("file://{}", path.display())));
+        reader.range_bytes = 64;
+        if Command::new("curl").arg("--version").output().is_err() {
+            std::fs::remove_file(&path).unwrap();
+            return;
+        }
+
+        reader.seek(SeekFrom::Start(900)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &contents[900..]);
+        reader.seek(SeekFrom::Start(60)).unwrap();
+        let mut middle = [0; 10];
+        reader.read_exact(&mut middle).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(middle, contents[60..70]);
+        assert_eq!(reader.len, Some(1000));
+    }
+}
@@ -0,0 +1,112 @@
+//! Sample descriptions: labels, paths and per-sample formats.
+
+use clap::ValueEnum;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::format::Format;
+
+/// One methylation input to aggregate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleSpec {
+    pub label: String,
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+impl SampleSpec {
+    /// Describes a positional input, labelled by its file name without extensions.
+    pub fn from_path(path: &Path, format: Format) -> SampleSpec {
+        SampleSpec {
+            label: default_label(path),
+            path: path.to_path_buf(),
+            format,
+        }
+    }
+}
+
+fn default_label(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string());
+    if name == "-" {
+        return "stdin".to_string();
+    }
+    name.split('.').next().unwrap_or(&name).to_string()
+}
+
+/// Reads a `label<TAB>path[<TAB>format]` manifest; samples without a format use `default_format`.
+pub fn read_manifest(
+    path: &Path,
+    default_format: Format,
+) -> Result<Vec<SampleSpec>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut samples = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            return Err(format!(
+                "Error: {} line {}: expected label<TAB>path[<TAB>format]",
+                path.display(),
+                i + 1
+            )
+            .into());
+        }
+        let format = match fields.get(2).map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            Some(name) => Format::from_str(name, true).map_err(|_| {
+                format!(
+                    "Error: {} line {}: unknown format {name}",
+                    path.display(),
+                    i + 1
+                )
+            })?,
+            None => default_format,
+        };
+        samples.push(SampleSpec {
+            label: fields[0].to_string(),
+            path: PathBuf::from(fields[1]),
+            format,
+        });
+    }
+    if samples.is_empty() {
+        return Err(format!("Error: no samples in {}", path.display()).into());
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_manifest_with_optional_format() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-samples-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "# cohort\nctrl\tdata/ctrl.cov.gz\tbismark-cov\ncase\tdata/case.bed\n",
+        )
+        .unwrap();
+        let samples = read_manifest(&path, Format::Generic).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].label, "ctrl");
+        assert_eq!(samples[0].format, Format::BismarkCov);
+        assert_eq!(samples[1].path, PathBuf::from("data/case.bed"));
+        assert_eq!(samples[1].format, Format::Generic);
+    }
+
+    #[test]
+    fn labels_positional_inputs_by_file_stem() {
+        let spec = SampleSpec::from_path(Path::new("/data/s1.cov.gz"), Format::Generic);
+        assert_eq!(spec.label, "s1");
+    }
+}
@@ -0,0 +1,195 @@
+//! `methfast segment`: MethylSeekR-style calling of unmethylated and
+//! low-methylated regions (UMRs and LMRs).
+
+use clap::Args;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::write_lines;
+use crate::format::{ColumnNames, Format};
+use crate::{MethInterval, RecordFilter, detect_format, parse_meth_bed};
+
+#[derive(Args, Debug)]
+pub struct SegmentArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        help = "Per-CpG methylation input; `-` for stdin"
+    )]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        default_value_t = 5,
+        help = "Only use CpGs covered by at least INT reads"
+    )]
+    min_coverage: i32,
+    #[arg(
+        long = "window-cpgs",
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "CpGs pooled around each CpG to smooth its level"
+    )]
+    window_cpgs: usize,
+    #[arg(
+        long = "max-fraction",
+        value_name = "F",
+        default_value_t = 0.5,
+        help = "Smoothed level below which a CpG is hypomethylated"
+    )]
+    max_fraction: f64,
+    #[arg(
+        long = "min-cpgs",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Drop segments with fewer CpGs"
+    )]
+    min_cpgs: usize,
+    #[arg(
+        long = "umr-cpgs",
+        value_name = "N",
+        default_value_t = 30,
+        help = "Segments with at least N CpGs are UMRs, smaller ones LMRs"
+    )]
+    umr_cpgs: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// A run of consecutive hypomethylated CpGs.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    start: i32,
+    end: i32,
+    cpgs: usize,
+    /// Pooled methylation level of the segment's CpGs.
+    fraction: f64,
+}
+
+/// Segments of one chromosome's sorted, covered CpGs whose level, pooled over
+/// `window` CpGs centered on each, is below `max_fraction`.
+fn hypomethylated(
+    cpgs: &[MethInterval],
+    window: usize,
+    max_fraction: f64,
+    min_cpgs: usize,
+) -> Vec<Segment> {
+    let half = window / 2;
+    let low: Vec<bool> = (0..cpgs.len())
+        .map(|i| {
+            let neighbours = &cpgs[i.saturating_sub(half)..(i + window - half).min(cpgs.len())];
+            let (methylated, coverage) = pooled(neighbours);
+            methylated / coverage < max_fraction
+        })
+        .collect();
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < cpgs.len() {
+        if !low[i] {
+            i += 1;
+            continue;
+        }
+        let first = i;
+        while i < cpgs.len() && low[i] {
+            i += 1;
+        }
+        let run = &cpgs[first..i];
+        if run.len() >= min_cpgs {
+            let (methylated, coverage) = pooled(run);
+            segments.push(Segment {
+                start: run[0].start,
+                end: run[run.len() - 1].end,
+                cpgs: run.len(),
+                fraction: methylated / coverage,
+            });
+        }
+    }
+    segments
+}
+
+fn pooled(cpgs: &[MethInterval]) -> (f64, f64) {
+    cpgs.iter().fold((0.0, 0.0), |(methylated, coverage), iv| {
+        (
+            methylated + iv.fraction as f64 * iv.coverage as f64,
+            coverage + iv.coverage as f64,
+        )
+    })
+}
+
+pub fn run(args: &SegmentArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: Some(args.min_coverage.max(1)),
+        ..RecordFilter::default()
+    };
+    let ranges = parse_meth_bed(
+        &args.input,
+        &format.layout(),
+        &ColumnNames::default(),
+        &filter,
+        &[None],
+    )?
+    .remove(0);
+    let mut chroms: Vec<&String> = ranges.by_chrom.keys().collect();
+    chroms.sort();
+    let mut lines = Vec::new();
+    for chrom in chroms {
+        let segments = hypomethylated(
+            &ranges.by_chrom[chrom],
+            args.window_cpgs,
+            args.max_fraction,
+            args.min_cpgs,
+        );
+        for segment in segments {
+            let kind = if segment.cpgs >= args.umr_cpgs {
+                "UMR"
+            } else {
+                "LMR"
+            };
+            lines.push(format!(
+                "{chrom}\t{}\t{}\t{kind}\t{}\t{:.4}",
+                segment.start, segment.end, segment.cpgs, segment.fraction
+            ));
+        }
+    }
+    write_lines(args.output.as_deref(), None, &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn calls_runs_of_low_cpgs() {
+        let fractions = [0.9, 0.9, 0.1, 0.0, 0.1, 0.0, 0.9, 0.9, 0.9, 0.1];
+        let cpgs: Vec<MethInterval> = fractions
+            .iter()
+            .enumerate()
+            .map(|(i, &fraction)| MethInterval {
+                start: i as i32 * 10,
+                end: i as i32 * 10 + 1,
+                fraction,
+                coverage: 10,
+                strand: Strand::Unknown,
+            })
+            .collect();
+        let segments = hypomethylated(&cpgs, 3, 0.5, 2);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(
+            (segments[0].start, segments[0].end, segments[0].cpgs),
+            (20, 51, 4)
+        );
+        assert!((segments[0].fraction - 0.05).abs() < 1e-6);
+    }
+}
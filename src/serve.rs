@@ -0,0 +1,391 @@
+//! `methfast serve`: answers region queries over HTTP from methylation data
+//! loaded once, e.g. `GET /query?region=chr1:1000-2000`.
+
+use clap::Args;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::cli::{FractionFormat, parse_region};
+use crate::format::{ColumnNames, Format, Layout};
+use crate::json::{format_target_json, quote};
+use crate::{
+    Aggregation, MethRanges, RecordFilter, Strand, bgzf, detect_format, meth_index, parse_meth_bed,
+    summarize, tabix,
+};
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[arg(value_name = "METHYLATION_BED", help = "Methylation input to serve")]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "bind",
+        value_name = "ADDRESS",
+        default_value = "127.0.0.1",
+        help = "Address to listen on"
+    )]
+    bind: String,
+    #[arg(
+        long = "port",
+        value_name = "PORT",
+        default_value_t = 8080,
+        help = "Port to listen on"
+    )]
+    port: u16,
+    #[arg(
+        long = "no-index",
+        help = "Load the whole file into memory even when a tabix/CSI index is present"
+    )]
+    no_index: bool,
+}
+
+/// The served records: in memory, or fetched per query through a tabix index.
+enum Store {
+    Ranges(MethRanges),
+    Indexed {
+        path: PathBuf,
+        index: tabix::Index,
+        layout: Layout,
+    },
+}
+
+/// Time a client has to send its request, and to take the answer, before
+/// the connection is closed.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of request line and headers read before answering 431.
+const MAX_REQUEST_BYTES: u64 = 16 << 10;
+
+struct Server {
+    store: Store,
+    filter: RecordFilter,
+    timeout: Duration,
+}
+
+/// Decodes `%XX` escapes and `+` of a query-string value.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()) =>
+            {
+                decoded.push(byte);
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl Server {
+    /// Status and JSON body answering a request for `target` (path and query).
+    fn respond(&self, target: &str) -> (u16, String) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        match path {
+            "/health" => (200, "{\"status\":\"ok\"}".to_string()),
+            "/query" => {
+                let regions: Vec<String> = query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .filter(|(key, _)| *key == "region")
+                    .map(|(_, value)| percent_decode(value))
+                    .collect();
+                if regions.is_empty() {
+                    return error(400, "expected ?region=CHROM:START-END");
+                }
+                let mut objects = Vec::with_capacity(regions.len());
+                for region in &regions {
+                    match self.query(region) {
+                        Ok(object) => objects.push(object),
+                        Err(err) => return error(400, &err),
+                    }
+                }
+                match objects.as_slice() {
+                    [object] => (200, object.clone()),
+                    _ => (200, format!("[{}]", objects.join(","))),
+                }
+            }
+            _ => error(404, "not found"),
+        }
+    }
+
+    /// The aggregate of one `CHROM:START-END` region as a JSON object.
+    fn query(&self, region: &str) -> Result<String, String> {
+        let target = parse_region(region)?;
+        let fetched;
+        let intervals = match &self.store {
+            Store::Ranges(ranges) => ranges
+                .by_chrom
+                .get(&target.chrom)
+                .map_or(&[][..], Vec::as_slice),
+            Store::Indexed {
+                path,
+                index,
+                layout,
+            } => {
+                let mut reader = bgzf::BgzfReader::open(path).map_err(|e| e.to_string())?;
+                fetched = index
+                    .fetch(&mut reader, &target, layout, &self.filter)
+                    .map_err(|e| e.to_string())?;
+                &fetched[..]
+            }
+        };
+        let summary = summarize(intervals, &target, Strand::Unknown, &Aggregation::default());
+        Ok(format_target_json(
+            &target,
+            &[summary],
+            None,
+            false,
+            &FractionFormat::default(),
+        ))
+    }
+
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let (status, body) = match read_request(&stream) {
+            Ok(Some(request_line)) => match request_line.split_whitespace().collect::<Vec<_>>()[..]
+            {
+                ["GET", target, ..] => self.respond(target),
+                _ => error(405, "only GET is supported"),
+            },
+            Ok(None) => error(431, "request line and headers are too large"),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                error(408, "request timed out")
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => error(400, "invalid request"),
+            Err(err) => return Err(err),
+        };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            _ => "Request Header Fields Too Large",
+        };
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()?;
+        if status == 431 {
+            // Closing with the rest of the request unread resets the
+            // connection, which can cut the answer off.
+            stream.shutdown(Shutdown::Write)?;
+            let _ = io::copy(&mut (&stream).take(MAX_REQUEST_BYTES), &mut io::sink());
+        }
+        Ok(())
+    }
+}
+
+/// The request line of the request on `stream`, once its headers are read
+/// (requests carry no body), or `None` past [`MAX_REQUEST_BYTES`].
+fn read_request(stream: &TcpStream) -> io::Result<Option<String>> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_BYTES);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    loop {
+        if reader.limit() == 0 {
+            return Ok(None);
+        }
+        header.clear();
+        // A blank line ends the headers; a client may also hang up early.
+        if reader.read_line(&mut header)? <= 2 {
+            return Ok(Some(request_line));
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> (u16, String) {
+    (status, format!("{{\"error\":{}}}", quote(message)))
+}
+
+pub fn run(args: &ServeArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let index = if args.no_index {
+        None
+    } else {
+        tabix::Index::find(&args.input)?
+    };
+    let store = match index {
+        Some(index) => Store::Indexed {
+            path: args.input.clone(),
+            index,
+            layout: format.layout(),
+        },
+        None => Store::Ranges(match meth_index::load(&args.input, format, &filter)? {
+            Some(ranges) => ranges,
+            None => parse_meth_bed(
+                &args.input,
+                &format.layout(),
+                &ColumnNames::default(),
+                &filter,
+                &[None],
+            )?
+            .remove(0),
+        }),
+    };
+    let server = Server {
+        store,
+        filter,
+        timeout: TIMEOUT,
+    };
+    let listener = TcpListener::bind((args.bind.as_str(), args.port))?;
+    eprintln!(
+        "methfast serve: listening on http://{}",
+        listener.local_addr()?
+    );
+    // One thread per connection, so a slow client never holds up the others;
+    // the timeouts end the threads of clients that stall.
+    std::thread::scope(|scope| {
+        for stream in listener.incoming().flatten() {
+            let server = &server;
+            scope.spawn(move || {
+                if let Err(err) = server.handle(stream) {
+                    eprintln!("methfast serve: {err}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MethInterval;
+    use std::collections::HashMap;
+
+    #[test]
+    fn answers_region_queries() {
+        let site = |start, fraction| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage: 4,
+            strand: Strand::Unknown,
+        };
+        let server = Server {
+            store: Store::Ranges(MethRanges {
+                by_chrom: HashMap::from([(
+                    "chr1".to_string(),
+                    vec![site(1000, 1.0), site(1500, 0.5), site(3000, 0.0)],
+                )]),
+            }),
+            filter: RecordFilter::default(),
+            timeout: TIMEOUT,
+        };
+        let (status, body) = server.respond("/query?region=chr1%3A1001-2000");
+        assert_eq!(status, 200);
+        assert!(body.starts_with("{\"chrom\":\"chr1\",\"start\":1000,\"end\":2000"));
+        assert!(body.contains("\"n_sites\":2"), "{body}");
+        let (status, body) = server.respond("/query?region=chr1:1-10&region=chr1:2900-3100");
+        assert_eq!(status, 200);
+        assert!(body.starts_with('[') && body.matches("\"chrom\"").count() == 2);
+        assert_eq!(server.respond("/query?region=chr1").0, 400);
+        assert_eq!(server.respond("/other").0, 404);
+    }
+
+    /// The status line and body `server` answers `request` with over a
+    /// loopback connection, the client sending `request` then waiting.
+    fn exchange(server: &Server, request: &[u8]) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        server.handle(stream).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn serves_indexed_records_over_tcp() {
+        use crate::bgzf::BgzfWriter;
+        use crate::tabix::IndexBuilder;
+
+        let path =
+            std::env::temp_dir().join(format!("methfast-serve-{}.bed.gz", std::process::id()));
+        let mut writer = BgzfWriter::new(std::fs::File::create(&path).unwrap());
+        let mut builder = IndexBuilder::default();
+        for (start, fraction) in [(1000, "1.0"), (1500, "0.5"), (3000, "0.0")] {
+            let vbeg = writer.virtual_offset();
+            writeln!(writer, "chr1\t{start}\t{}\t{fraction}\t4", start + 1).unwrap();
+            builder
+                .add("chr1", start, start + 1, vbeg, writer.virtual_offset())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        let index_path = path.with_extension("gz.tbi");
+        let mut index = BgzfWriter::new(std::fs::File::create(&index_path).unwrap());
+        index.write_all(&builder.finish(0)).unwrap();
+        index.finish().unwrap();
+        let index = tabix::Index::find(&path).unwrap().unwrap();
+        std::fs::remove_file(&index_path).unwrap();
+        let server = Server {
+            store: Store::Indexed {
+                path: path.clone(),
+                index,
+                layout: Format::Generic.layout(),
+            },
+            filter: RecordFilter::default(),
+            timeout: Duration::from_millis(200),
+        };
+
+        let (status, body) = exchange(
+            &server,
+            b"GET /query?region=chr1:1001-2000 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("\"n_sites\":2"), "{body}");
+        assert!(body.contains("\"weighted_fraction\":0.75"), "{body}");
+        let (status, _) = exchange(&server, b"POST /query HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let mut oversized = b"GET /health HTTP/1.1\r\n".to_vec();
+        oversized.extend(std::iter::repeat_n(b'x', MAX_REQUEST_BYTES as usize));
+        let (status, _) = exchange(&server, &oversized);
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+        // A client that never finishes its headers.
+        let (status, _) = exchange(&server, b"GET /health HTTP/1.1\r\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status, "HTTP/1.1 408 Request Timeout");
+    }
+}
@@ -0,0 +1,262 @@
+//! `methfast smooth`: BSmooth-style local smoothing of per-CpG methylation.
+
+use crate::parallel::*;
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::cli::write_lines;
+use crate::format::{ColumnNames, Format};
+use crate::{MethInterval, RecordFilter, bigwig, detect_format, parse_meth_bed, read_chrom_sizes};
+
+#[derive(Args, Debug)]
+pub struct SmoothArgs {
+    #[arg(
+        value_name = "METHYLATION_BED",
+        help = "Per-CpG methylation input; `-` for stdin"
+    )]
+    input: PathBuf,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = Format::Auto,
+        help = "Input format"
+    )]
+    format: Format,
+    #[arg(
+        long = "bandwidth",
+        value_name = "BP",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(i32).range(1..),
+        help = "Minimum smoothing window width in bases"
+    )]
+    bandwidth: i32,
+    #[arg(
+        long = "min-sites",
+        value_name = "N",
+        default_value_t = 70,
+        help = "Widen the window until it holds at least N covered sites"
+    )]
+    min_sites: usize,
+    #[arg(
+        long = "min-coverage",
+        value_name = "INT",
+        help = "Ignore records with coverage below INT"
+    )]
+    min_coverage: Option<i32>,
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = SmoothFormat::Tsv,
+        help = "Output format"
+    )]
+    output_format: SmoothFormat,
+    #[arg(
+        long = "chrom-sizes",
+        value_name = "FILE",
+        help = "Chromosome sizes for --output-format bigwig"
+    )]
+    chrom_sizes: Option<PathBuf>,
+    #[arg(long = "header", help = "Write a header line to tsv output")]
+    header: bool,
+    #[arg(
+        long = "precision",
+        value_name = "N",
+        default_value_t = 4,
+        help = "Decimal places of fractions"
+    )]
+    precision: usize,
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SmoothFormat {
+    /// chrom, start, end, fraction, coverage and smoothed fraction per site.
+    Tsv,
+    /// Smoothed fraction per site.
+    Bedgraph,
+    /// Smoothed fraction per site as a bigWig track (needs --output and --chrom-sizes).
+    Bigwig,
+}
+
+/// Smoothed fraction of every site of one chromosome, sorted by position.
+///
+/// Each site gets the coverage- and tricube-weighted mean of its neighbours in
+/// a window of at least `bandwidth` bases, widened to hold `min_sites` sites.
+pub fn smooth(intervals: &[MethInterval], bandwidth: i32, min_sites: usize) -> Vec<f32> {
+    let positions: Vec<i64> = intervals.iter().map(|iv| iv.start as i64).collect();
+    (0..intervals.len())
+        .map(|i| {
+            let half = half_width(&positions, i, bandwidth as i64, min_sites);
+            let low = positions.partition_point(|&p| p < positions[i] - half);
+            let high = positions.partition_point(|&p| p <= positions[i] + half);
+            let (mut sum_weight, mut sum_weighted) = (0.0_f64, 0.0_f64);
+            for iv in &intervals[low..high] {
+                let distance = (iv.start as i64 - positions[i]).abs() as f64 / (half + 1) as f64;
+                let weight = (1.0 - distance.powi(3)).powi(3) * iv.coverage as f64;
+                sum_weight += weight;
+                sum_weighted += weight * iv.fraction as f64;
+            }
+            if sum_weight > 0.0 {
+                (sum_weighted / sum_weight) as f32
+            } else {
+                intervals[i].fraction
+            }
+        })
+        .collect()
+}
+
+/// Half the window width around site `i`: `bandwidth / 2`, or the distance to
+/// the farthest of its `min_sites - 1` nearest neighbours if that is larger.
+fn half_width(positions: &[i64], i: usize, bandwidth: i64, min_sites: usize) -> i64 {
+    let center = positions[i];
+    let (mut left, mut right) = (i, i + 1);
+    let mut farthest = 0;
+    for _ in 1..min_sites.min(positions.len()) {
+        let left_distance = (left > 0).then(|| center - positions[left - 1]);
+        let right_distance = positions.get(right).map(|&p| p - center);
+        let distance = match (left_distance, right_distance) {
+            (Some(l), Some(r)) if l <= r => {
+                left -= 1;
+                l
+            }
+            (Some(l), None) => {
+                left -= 1;
+                l
+            }
+            (_, Some(r)) => {
+                right += 1;
+                r
+            }
+            (None, None) => break,
+        };
+        farthest = farthest.max(distance);
+    }
+    (bandwidth / 2).max(farthest)
+}
+
+pub fn run(args: &SmoothArgs) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Format::Auto => detect_format(&args.input)?,
+        format => format,
+    };
+    let filter = RecordFilter {
+        min_coverage: args.min_coverage,
+        ..RecordFilter::default()
+    };
+    let ranges = parse_meth_bed(
+        &args.input,
+        &format.layout(),
+        &ColumnNames::default(),
+        &filter,
+        &[None],
+    )?
+    .remove(0);
+    let mut chroms: Vec<(&String, &Vec<MethInterval>)> = ranges.by_chrom.iter().collect();
+    chroms.sort_by(|a, b| a.0.cmp(b.0));
+    let smoothed: Vec<Vec<f32>> = chroms
+        .par_iter()
+        .map(|(_, intervals)| {
+            // Uncovered records carry no weight but would still be written.
+            let covered: Vec<MethInterval> = intervals
+                .iter()
+                .filter(|iv| iv.coverage > 0)
+                .cloned()
+                .collect();
+            smooth(&covered, args.bandwidth, args.min_sites)
+        })
+        .collect();
+    let sites = chroms
+        .iter()
+        .zip(&smoothed)
+        .flat_map(|((chrom, intervals), values)| {
+            intervals
+                .iter()
+                .filter(|iv| iv.coverage > 0)
+                .zip(values)
+                .map(move |(iv, value)| (chrom.as_str(), iv, *value))
+        });
+
+    match args.output_format {
+        SmoothFormat::Bigwig => {
+            let (Some(output), Some(chrom_sizes)) = (&args.output, &args.chrom_sizes) else {
+                return Err(
+                    "Error: --output-format bigwig requires --output and --chrom-sizes".into(),
+                );
+            };
+            let mut spans: HashMap<String, Vec<bigwig::Span>> = HashMap::new();
+            for (chrom, iv, value) in sites {
+                spans
+                    .entry(chrom.to_string())
+                    .or_default()
+                    .push(bigwig::Span {
+                        start: iv.start,
+                        end: iv.end,
+                        value,
+                    });
+            }
+            let mut out = BufWriter::new(File::create(output)?);
+            bigwig::write_bigwig(&mut out, &read_chrom_sizes(chrom_sizes)?, &spans)?;
+            out.flush()?;
+            Ok(())
+        }
+        SmoothFormat::Bedgraph => {
+            let lines: Vec<String> = sites
+                .map(|(chrom, iv, value)| {
+                    format!(
+                        "{chrom}\t{}\t{}\t{value:.*}",
+                        iv.start, iv.end, args.precision
+                    )
+                })
+                .collect();
+            write_lines(args.output.as_deref(), None, &lines)
+        }
+        SmoothFormat::Tsv => {
+            let lines: Vec<String> = sites
+                .map(|(chrom, iv, value)| {
+                    format!(
+                        "{chrom}\t{}\t{}\t{:.*}\t{}\t{value:.*}",
+                        iv.start, iv.end, args.precision, iv.fraction, iv.coverage, args.precision
+                    )
+                })
+                .collect();
+            let header = args
+                .header
+                .then_some("chrom\tstart\tend\tfraction\tcoverage\tsmoothed_fraction");
+            write_lines(args.output.as_deref(), header, &lines)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Strand;
+
+    #[test]
+    fn pulls_isolated_sites_toward_their_neighbours() {
+        let site = |start, fraction, coverage| MethInterval {
+            start,
+            end: start + 1,
+            fraction,
+            coverage,
+            strand: Strand::Unknown,
+        };
+        let intervals = vec![
+            site(100, 0.9, 20),
+            site(110, 0.1, 1),
+            site(120, 0.9, 20),
+            site(5000, 0.2, 5),
+        ];
+        let smoothed = smooth(&intervals, 100, 2);
+        assert!(smoothed[1] > 0.8);
+        // The distant site is its own window with a bandwidth of 100.
+        assert!((smoothed[3] - 0.2).abs() < 1e-6);
+        // min_sites widens the window to reach the nearest neighbour.
+        assert_eq!(half_width(&[100, 110, 120, 5000], 3, 100, 2), 4880);
+    }
+}
@@ -0,0 +1,219 @@
+//! `--streaming`: summarizes the targets in one sweep over a sorted
+//! methylation file, holding only the records of the targets in progress
+//! instead of the whole file.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::path::Path;
+
+use crate::format::{ColumnNames, Layout};
+use crate::{
+    Aggregation, MethInterval, ParseErrorKind, RecordFilter, Strand, TargetInterval, TargetSummary,
+    compression, parse_record, progress, read_header, summarize,
+};
+
+/// The targets of one chromosome in order of start and the records that may
+/// still overlap the first unfinished one.
+struct Sweep<'a> {
+    targets: &'a [TargetInterval],
+    order: &'a [usize],
+    next: usize,
+    records: VecDeque<MethInterval>,
+}
+
+impl Sweep<'_> {
+    /// Summarizes the targets ending at or before `position`, in order of
+    /// start, and drops the records no target left needs.
+    fn finish_before(
+        &mut self,
+        position: i32,
+        summarize_target: &mut impl FnMut(usize, &[MethInterval]),
+    ) {
+        while let Some(&i) = self.order.get(self.next)
+            && self.targets[i].end <= position
+        {
+            summarize_target(i, self.records.make_contiguous());
+            self.next += 1;
+        }
+        match self.order.get(self.next) {
+            Some(&i) => {
+                let start = self.targets[i].start;
+                while self.records.front().is_some_and(|iv| iv.end <= start) {
+                    self.records.pop_front();
+                }
+            }
+            None => self.records.clear(),
+        }
+    }
+
+    fn push(&mut self, iv: MethInterval) {
+        if let Some(&i) = self.order.get(self.next)
+            && iv.end > self.targets[i].start
+        {
+            self.records.push_back(iv);
+        }
+    }
+}
+
+/// Streams the methylation file at `path` once and summarizes every target
+/// on each of `strands(target)`. The records of each chromosome must be
+/// sorted and contiguous; targets may come in any order.
+pub fn summarize_targets<'t>(
+    path: &Path,
+    layout: &Layout,
+    names: &ColumnNames,
+    filter: &RecordFilter,
+    targets: &'t [TargetInterval],
+    strands: impl Fn(&'t TargetInterval) -> &'t [Strand],
+    aggregation: &Aggregation,
+) -> Result<Vec<Vec<TargetSummary>>, Box<dyn Error>> {
+    let mut by_chrom: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, target) in targets.iter().enumerate() {
+        by_chrom.entry(&target.chrom).or_default().push(i);
+    }
+    for order in by_chrom.values_mut() {
+        order.sort_by_key(|&i| targets[i].start);
+    }
+    let mut rows: Vec<Vec<TargetSummary>> = vec![Vec::new(); targets.len()];
+    let mut summarize_target = |i: usize, intervals: &[MethInterval]| {
+        let target = &targets[i];
+        rows[i] = strands(target)
+            .iter()
+            .map(|&strand| summarize(intervals, target, strand, aggregation))
+            .collect();
+    };
+
+    let progress = progress::Progress::bytes(path, None);
+    let mut reader = compression::open(path)?;
+    let mut line = String::new();
+    let mut linenum: usize = 0;
+    let named_layout;
+    let layout = if names.is_empty() {
+        layout
+    } else {
+        linenum += 1;
+        named_layout = names.apply(layout, &read_header(&mut reader, path)?)?;
+        &named_layout
+    };
+
+    let mut done: HashSet<String> = HashSet::new();
+    let mut chrom = String::new();
+    let mut sweep = Sweep {
+        targets,
+        order: &[],
+        next: 0,
+        records: VecDeque::new(),
+    };
+    let mut prev_end = i32::MIN;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        linenum += 1;
+        progress.add(line.len() as u64);
+        let record =
+            parse_record(&line, layout, filter).map_err(|kind| kind.at(path, Some(linenum)))?;
+        let Some((record_chrom, interval)) = record else {
+            continue;
+        };
+        if record_chrom != chrom {
+            sweep.finish_before(i32::MAX, &mut summarize_target);
+            if !done.insert(record_chrom.to_string()) {
+                return Err(ParseErrorKind::Noncontiguous {
+                    chrom: record_chrom.to_string(),
+                }
+                .at(path, Some(linenum))
+                .into());
+            }
+            chrom = record_chrom.to_string();
+            sweep.order = by_chrom.get(chrom.as_str()).map_or(&[], Vec::as_slice);
+            sweep.next = 0;
+            prev_end = i32::MIN;
+        }
+        if interval.start < prev_end {
+            return Err(ParseErrorKind::Unsorted {
+                chrom,
+                record: (interval.start, interval.end),
+                previous_end: prev_end,
+            }
+            .at(path, Some(linenum))
+            .into());
+        }
+        prev_end = interval.end;
+        sweep.finish_before(interval.start, &mut summarize_target);
+        sweep.push(interval);
+    }
+    sweep.finish_before(i32::MAX, &mut summarize_target);
+
+    // Targets on chromosomes without records.
+    for (i, row) in rows.iter_mut().enumerate() {
+        if row.is_empty() {
+            let target = &targets[i];
+            *row = strands(target)
+                .iter()
+                .map(|&strand| summarize(&[], target, strand, aggregation))
+                .collect();
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+
+    #[test]
+    fn matches_in_memory_summaries() {
+        let path =
+            std::env::temp_dir().join(format!("methfast-streaming-{}.bed", std::process::id()));
+        std::fs::write(
+            &path,
+            "chr2\t5\t6\t0.5\t2\nchr1\t10\t11\t1.0\t3\nchr1\t150\t151\t0.5\t2\nchr1\t160\t161\t0.0\t2\nchr1\t900\t901\t1.0\t1\n",
+        )
+        .unwrap();
+        let target = |chrom: &str, start, end| TargetInterval {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let targets = vec![
+            target("chr1", 100, 200),
+            target("chr1", 0, 155),
+            target("chr1", 140, 152),
+            target("chr2", 0, 10),
+            target("chr3", 0, 10),
+            target("chr1", 500, 600),
+        ];
+        let layout = Format::Generic.layout();
+        let filter = RecordFilter::default();
+        let aggregation = Aggregation::default();
+        let rows = summarize_targets(
+            &path,
+            &layout,
+            &ColumnNames::default(),
+            &filter,
+            &targets,
+            |_| &[Strand::Unknown],
+            &aggregation,
+        )
+        .unwrap();
+        let ranges =
+            crate::parse_meth_bed(&path, &layout, &ColumnNames::default(), &filter, &[None])
+                .unwrap()
+                .remove(0);
+        std::fs::remove_file(&path).unwrap();
+        for (target, row) in targets.iter().zip(&rows) {
+            let expected = crate::summarize_ranges(&ranges, target, Strand::Unknown, &aggregation);
+            assert_eq!(row.len(), 1);
+            assert_eq!(row[0].num_positions, expected.num_positions);
+            assert_eq!(row[0].sum_total_coverage, expected.sum_total_coverage);
+            assert_eq!(row[0].weighted_fraction, expected.weighted_fraction);
+        }
+        assert_eq!(rows[1][0].num_positions, 2);
+    }
+}
@@ -0,0 +1,451 @@
+//! Tabix (`.tbi`) and CSI (`.csi`) index reading for bgzipped methylation
+//! files, and tabix index building for bgzipped output.
+
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
+
+use crate::bgzf::BgzfReader;
+use crate::format::Layout;
+use crate::{
+    MethInterval, OnOverlap, RecordFilter, TargetInterval, parse_record, remote, resolve_overlaps,
+};
+
+/// Binning index over the records of one bgzipped file.
+#[derive(Debug, Clone)]
+pub struct Index {
+    min_shift: u32,
+    depth: u32,
+    /// Sequence names as written in the indexed file.
+    names: Vec<String>,
+    /// Reference ID by target-side chromosome name.
+    ref_ids: HashMap<String, usize>,
+    refs: Vec<RefIndex>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RefIndex {
+    bins: HashMap<u32, Vec<(u64, u64)>>,
+    /// Tabix linear index: smallest virtual offset per 16 kb window.
+    linear: Vec<u64>,
+}
+
+fn index_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+impl Index {
+    /// The chromosomes of the indexed file, under their target-side names.
+    pub fn chroms(&self) -> impl Iterator<Item = &str> {
+        self.ref_ids.keys().map(String::as_str)
+    }
+
+    /// Loads `<path>.tbi` or `<path>.csi` if either exists, downloading it
+    /// for a URL.
+    pub fn find(path: &Path) -> Result<Option<Index>, Box<dyn Error>> {
+        for extension in [".tbi", ".csi"] {
+            let candidate = index_path(path, extension);
+            let compressed = match remote::is_url(path) {
+                true => match remote::fetch(&candidate)? {
+                    Some(compressed) => compressed,
+                    None => continue,
+                },
+                false if candidate.exists() => std::fs::read(&candidate)?,
+                false => continue,
+            };
+            let mut data = Vec::new();
+            MultiGzDecoder::new(compressed.as_slice()).read_to_end(&mut data)?;
+            return Index::parse(&data)
+                .map(Some)
+                .map_err(|err| format!("Error: {}: {err}", candidate.display()).into());
+        }
+        Ok(None)
+    }
+
+    fn parse(data: &[u8]) -> Result<Index, String> {
+        let mut cursor = Cursor { data, pos: 0 };
+        let magic = cursor.take(4)?;
+        let csi = match magic {
+            b"TBI\x01" => false,
+            b"CSI\x01" => true,
+            _ => return Err("unrecognized index format".to_string()),
+        };
+
+        // Both headers carry the tabix column configuration (format, col_seq,
+        // col_beg, col_end, meta, skip) followed by the sequence names.
+        let (min_shift, depth, names, n_ref) = if csi {
+            let min_shift = cursor.u32()?;
+            let depth = cursor.u32()?;
+            let l_aux = cursor.u32()? as usize;
+            let aux = cursor.take(l_aux)?;
+            let names = if l_aux >= 28 {
+                parse_names(&aux[28..])
+            } else {
+                Vec::new()
+            };
+            (min_shift, depth, names, cursor.u32()? as usize)
+        } else {
+            let n_ref = cursor.u32()? as usize;
+            cursor.take(24)?;
+            let l_nm = cursor.u32()? as usize;
+            (14, 5, parse_names(cursor.take(l_nm)?), n_ref)
+        };
+
+        let mut refs = Vec::with_capacity(n_ref);
+        for _ in 0..n_ref {
+            let mut index = RefIndex::default();
+            let n_bin = cursor.u32()?;
+            for _ in 0..n_bin {
+                let bin = cursor.u32()?;
+                if csi {
+                    cursor.u64()?;
+                }
+                let n_chunk = cursor.u32()?;
+                let mut chunks = Vec::with_capacity(n_chunk as usize);
+                for _ in 0..n_chunk {
+                    chunks.push((cursor.u64()?, cursor.u64()?));
+                }
+                index.bins.insert(bin, chunks);
+            }
+            if !csi {
+                let n_intv = cursor.u32()?;
+                for _ in 0..n_intv {
+                    index.linear.push(cursor.u64()?);
+                }
+            }
+            refs.push(index);
+        }
+
+        let ref_ids = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i))
+            .collect();
+        Ok(Index {
+            min_shift,
+            depth,
+            names,
+            ref_ids,
+            refs,
+        })
+    }
+
+    /// Looks sequences up by `rename(name)` instead of their name in the file.
+    pub fn rename_chroms(&mut self, rename: &dyn Fn(&str) -> String) {
+        self.ref_ids = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (rename(name), i))
+            .collect();
+    }
+
+    /// Merged, sorted chunks of virtual offsets that may hold records overlapping `[beg, end)`.
+    fn chunks(&self, ref_id: usize, beg: i32, end: i32) -> Vec<(u64, u64)> {
+        let Some(index) = self.refs.get(ref_id) else {
+            return Vec::new();
+        };
+        let beg = beg.max(0) as u64;
+        let end = (end.max(1) as u64).max(beg + 1);
+        let min_offset = index
+            .linear
+            .get((beg >> self.min_shift) as usize)
+            .or(index.linear.last())
+            .copied()
+            .unwrap_or(0);
+
+        let mut chunks: Vec<(u64, u64)> = reg2bins(beg, end, self.min_shift, self.depth)
+            .into_iter()
+            .filter_map(|bin| index.bins.get(&bin))
+            .flatten()
+            .filter(|&&(_, chunk_end)| chunk_end > min_offset)
+            .copied()
+            .collect();
+        chunks.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(chunks.len());
+        for (chunk_beg, chunk_end) in chunks {
+            match merged.last_mut() {
+                Some(last) if chunk_beg <= last.1 => last.1 = last.1.max(chunk_end),
+                _ => merged.push((chunk_beg.max(min_offset), chunk_end)),
+            }
+        }
+        merged
+    }
+
+    /// Reads the records of `target`'s chromosome that overlap it, in file order.
+    pub fn fetch(
+        &self,
+        reader: &mut BgzfReader,
+        target: &TargetInterval,
+        layout: &Layout,
+        filter: &RecordFilter,
+    ) -> Result<Vec<MethInterval>, Box<dyn Error>> {
+        let mut intervals = Vec::new();
+        let Some(&ref_id) = self.ref_ids.get(&target.chrom) else {
+            return Ok(intervals);
+        };
+        let chrom_name = self.names.get(ref_id).map_or("", String::as_str);
+        let mut line = String::new();
+        for (chunk_beg, chunk_end) in self.chunks(ref_id, target.start, target.end) {
+            reader.seek(chunk_beg)?;
+            while reader.virtual_offset() < chunk_end {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                let record = parse_record(&line, layout, filter)
+                    .map_err(|kind| kind.at(reader.path(), None))?;
+                let Some((chrom, interval)) = record else {
+                    continue;
+                };
+                if chrom != chrom_name || interval.start >= target.end {
+                    break;
+                }
+                if interval.end > target.start {
+                    intervals.push(interval);
+                }
+            }
+        }
+        if filter.on_overlap != OnOverlap::Error {
+            intervals = resolve_overlaps(intervals, filter.on_overlap);
+        }
+        Ok(intervals)
+    }
+}
+
+/// Builds a tabix index for BED-like records written in sorted order.
+#[derive(Debug, Default)]
+pub struct IndexBuilder {
+    names: Vec<String>,
+    refs: Vec<RefIndex>,
+    last_start: i32,
+}
+
+impl IndexBuilder {
+    /// Records a `[beg, end)` record on `chrom` stored between virtual offsets
+    /// `vbeg` and `vend`. Records must be grouped by chromosome and sorted by start.
+    pub fn add(
+        &mut self,
+        chrom: &str,
+        beg: i32,
+        end: i32,
+        vbeg: u64,
+        vend: u64,
+    ) -> Result<(), String> {
+        if self.names.last().is_none_or(|last| last != chrom) {
+            if self.names.iter().any(|name| name == chrom) {
+                return Err(format!("records of {chrom} are not contiguous"));
+            }
+            self.names.push(chrom.to_string());
+            self.refs.push(RefIndex::default());
+        } else if beg < self.last_start {
+            return Err(format!("records of {chrom} are not sorted by start"));
+        }
+        self.last_start = beg;
+
+        let beg = beg.max(0) as u64;
+        let end = (end.max(1) as u64).max(beg + 1);
+        let index = self.refs.last_mut().expect("reference just added");
+        let chunks = index.bins.entry(reg2bin(beg, end)).or_default();
+        match chunks.last_mut() {
+            Some(last) if last.1 == vbeg => last.1 = vend,
+            _ => chunks.push((vbeg, vend)),
+        }
+        let last_window = ((end - 1) >> TBI_MIN_SHIFT) as usize;
+        if index.linear.len() <= last_window {
+            index.linear.resize(last_window + 1, u64::MAX);
+        }
+        for window in (beg >> TBI_MIN_SHIFT) as usize..=last_window {
+            index.linear[window] = index.linear[window].min(vbeg);
+        }
+        Ok(())
+    }
+
+    /// Serializes the (uncompressed) `.tbi` contents for a file whose first
+    /// `skip` lines are not records, with `#` lines skipped too.
+    pub fn finish(self, skip: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"TBI\x01");
+        let names: Vec<u8> = self
+            .names
+            .iter()
+            .flat_map(|name| name.bytes().chain([0]))
+            .collect();
+        // format, col_seq, col_beg, col_end, meta char, skip.
+        let header = [TBX_UCSC, 1, 2, 3, u32::from(b'#'), skip];
+        out.extend_from_slice(&(self.names.len() as u32).to_le_bytes());
+        for value in header {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        out.extend_from_slice(&names);
+        for mut index in self.refs {
+            let mut bins: Vec<_> = index.bins.into_iter().collect();
+            bins.sort_unstable_by_key(|&(bin, _)| bin);
+            out.extend_from_slice(&(bins.len() as u32).to_le_bytes());
+            for (bin, chunks) in bins {
+                out.extend_from_slice(&bin.to_le_bytes());
+                out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+                for (beg, end) in chunks {
+                    out.extend_from_slice(&beg.to_le_bytes());
+                    out.extend_from_slice(&end.to_le_bytes());
+                }
+            }
+            // Windows without records start at the next record, like htslib.
+            let mut next = index.linear.last().copied().unwrap_or(0);
+            for offset in index.linear.iter_mut().rev() {
+                if *offset == u64::MAX {
+                    *offset = next;
+                }
+                next = *offset;
+            }
+            out.extend_from_slice(&(index.linear.len() as u32).to_le_bytes());
+            for offset in index.linear {
+                out.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+        out
+    }
+}
+
+const TBI_MIN_SHIFT: u32 = 14;
+
+/// The tabix format of 0-based, half-open BED coordinates, as `tabix -p bed` writes.
+const TBX_UCSC: u32 = 0x10000;
+
+/// Smallest tabix bin containing `[beg, end)`.
+fn reg2bin(beg: u64, end: u64) -> u32 {
+    let end = end - 1;
+    let mut shift = TBI_MIN_SHIFT;
+    for level in (1..=5).rev() {
+        if beg >> shift == end >> shift {
+            let offset = ((1_u64 << (3 * level)) - 1) / 7;
+            return (offset + (beg >> shift)) as u32;
+        }
+        shift += 3;
+    }
+    0
+}
+
+/// Bins overlapping `[beg, end)` in an index with the given geometry.
+fn reg2bins(beg: u64, end: u64, min_shift: u32, depth: u32) -> Vec<u32> {
+    let end = end - 1;
+    let mut bins = Vec::new();
+    let mut shift = min_shift + depth * 3;
+    for level in 0..=depth {
+        let offset = ((1_u64 << (3 * level)) - 1) / 7;
+        for bin in (offset + (beg >> shift))..=(offset + (end >> shift)) {
+            bins.push(bin as u32);
+        }
+        shift = shift.saturating_sub(3);
+    }
+    bins
+}
+
+fn parse_names(data: &[u8]) -> Vec<String> {
+    data.split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| "truncated index".to_string())?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?;
+        let mut buf = [0_u8; 8];
+        buf.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_overlapping_bins() {
+        assert_eq!(reg2bins(0, 1, 14, 5), vec![0, 1, 9, 73, 585, 4681]);
+        let bins = reg2bins(16_384, 16_385, 14, 5);
+        assert_eq!(bins.last(), Some(&4682));
+    }
+
+    #[test]
+    fn indexes_written_records() {
+        use crate::bgzf::BgzfWriter;
+        use crate::format::Format;
+        use crate::{Strand, TargetInterval};
+        use std::fs::File;
+        use std::io::Write;
+
+        let path =
+            std::env::temp_dir().join(format!("methfast-tabix-{}.bed.gz", std::process::id()));
+        let mut writer = BgzfWriter::new(File::create(&path).unwrap());
+        let mut builder = IndexBuilder::default();
+        for (chrom, count) in [("chr1", 50_000), ("chr2", 10)] {
+            for i in 0..count {
+                let vbeg = writer.virtual_offset();
+                writeln!(writer, "{chrom}\t{}\t{}\t0.5\t2", i * 10, i * 10 + 1).unwrap();
+                builder
+                    .add(chrom, i * 10, i * 10 + 1, vbeg, writer.virtual_offset())
+                    .unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        assert!(builder.add("chr1", 0, 1, 0, 0).is_err());
+
+        let tbi = builder.finish(0);
+        let words: Vec<u32> = tbi[4..32]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        // Two references, BED coordinates in columns 1-3, `#` comments.
+        assert_eq!(words, [2, TBX_UCSC, 1, 2, 3, u32::from(b'#'), 0]);
+        let index = Index::parse(&tbi).unwrap();
+        let target = TargetInterval {
+            chrom: "chr1".to_string(),
+            start: 250_000,
+            end: 250_100,
+            name: None,
+            strand: Strand::Unknown,
+            extra: Vec::new(),
+        };
+        let mut reader = BgzfReader::open(&path).unwrap();
+        let intervals = index
+            .fetch(
+                &mut reader,
+                &target,
+                &Format::Generic.layout(),
+                &RecordFilter::default(),
+            )
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let starts: Vec<i32> = intervals.iter().map(|iv| iv.start).collect();
+        assert_eq!(starts, (25_000..25_010).map(|i| i * 10).collect::<Vec<_>>());
+        assert_eq!(reg2bin(0, 1), 4681);
+    }
+}